@@ -1,20 +1,11 @@
-use btc_p2p::{Command, Message, Network, Payload, ServiceFlags, VersionPayload};
+use btc_p2p::{Network, Peer, PeerConfig, SeedResolver, ServiceFlags};
 use crossbeam_utils::sync::WaitGroup;
 use std::net::SocketAddr;
 use std::time::Duration;
-use tokio::{
-    io::AsyncReadExt,
-    io::AsyncWriteExt,
-    net::{lookup_host, TcpStream},
-    sync::mpsc::channel,
-    time::timeout,
-};
+use tokio::{sync::mpsc::channel, time::timeout};
 
-/// This example connects to a Bitcoin node and performs a handshake.
-const BTC_SEED: &str = "seed.bitcoin.sipa.be";
-
-/// The port of the Bitcoin node.
-const BTC_NODE_PORT: u16 = 8333;
+/// This example connects to Bitcoin nodes of this network and performs a handshake.
+const NETWORK: Network = Network::MainNet;
 
 /// The size of the channels used to communicate between the threads.
 const CHANNELS_BUFFER_SIZE: usize = 1;
@@ -25,12 +16,15 @@ async fn main() -> anyhow::Result<()> {
 
     let (socket_chan_tx, mut socket_chan_rx) = channel::<SocketAddr>(CHANNELS_BUFFER_SIZE);
 
-    tracing::info!("Getting seed from{}", BTC_SEED);
+    // Only ask the seeds for full nodes supporting segwit.
+    let params = NETWORK.params().expect("well known network");
+    let mut seeds = SeedResolver::new(&params);
+    seeds.services = ServiceFlags::NODE_NETWORK | ServiceFlags::NODE_WITNESS;
+
+    tracing::info!("Getting addresses from {:?}", seeds.seeds);
 
     // Get the addresses of the Bitcoin nodes.
-    let addrs = lookup_host((BTC_SEED, BTC_NODE_PORT))
-        .await?
-        .collect::<Vec<_>>();
+    let addrs = seeds.resolve().await;
 
     // Spawn a thread that sends the addresses to the channel.
     tokio::spawn(async move {
@@ -74,54 +68,13 @@ async fn main() -> anyhow::Result<()> {
 async fn handshake(socket: SocketAddr) -> anyhow::Result<()> {
     tracing::info!("Connecting to {}", socket);
 
-    // Connect to the Bitcoin node.
-    let mut tcp_stream = TcpStream::connect(socket).await?;
-
-    // Build the version message, which is the first message sent to the Bitcoin node.
-    let version_msg = Message::new(
-        Network::MainNet,
-        Command::Version,
-        VersionPayload::build(
-            ServiceFlags::NODE_NETWORK,
-            ServiceFlags::NODE_NETWORK,
-            tcp_stream.local_addr()?,
-            ServiceFlags::NODE_NETWORK,
-            socket,
-            rand::random(),
-            0x0,
-            true,
-        ),
-    );
-
-    // Send the version message and wait for the response.
-    tracing::info!("Sending version to {}", socket);
-    let msg_recv = send_and_receive(&mut tcp_stream, version_msg).await?;
-    tracing::info!("Received version {:?} from {}", msg_recv.payload, socket);
-
-    // Send the verack message, to confirm the version message.
-    let verack_msg = Message::new(Network::MainNet, Command::VerAck, Payload::VerAck);
-    tracing::info!("Sending verack to {}", socket);
-    let msg_recv = send_and_receive(&mut tcp_stream, verack_msg).await?;
-    tracing::info!("Received verack {:?} from {}", msg_recv.payload, socket);
-
-    Ok(())
-}
+    let mut config = PeerConfig::new(NETWORK);
+    config.services = ServiceFlags::NODE_NETWORK;
 
-async fn send_and_receive(
-    tcp_stream: &mut TcpStream,
-    msg_send: Message,
-) -> anyhow::Result<Message> {
-    let mut buffer = vec![0u8; 1024];
+    // Connect to the Bitcoin node, the peer sends our version and acknowledges theirs.
+    let peer = Peer::connect(socket, &config).await?;
 
-    tcp_stream.write_all(&msg_send.to_bytes()?).await?;
+    tracing::info!("Negotiated {:?} with {}", peer.info(), socket);
 
-    let n = tcp_stream.read(&mut buffer[..]).await?;
-    if n == 0 {
-        tracing::error!("Failed to read from socket stream");
-        anyhow::bail!("Failed to read from socket stream");
-    }
-
-    let msg_recv = Message::from_bytes(&buffer[..n])?;
-
-    Ok(msg_recv)
+    Ok(())
 }
@@ -0,0 +1,384 @@
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::{
+    io::{Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use super::{
+    encode::{read_compact_size, read_vec_len, write_compact_size},
+    errors::{BTCP2PError, Result},
+    service_flags::ServiceFlags,
+};
+
+/// Maximum number of addresses in an addr or addrv2 message, as in Bitcoin Core
+pub const MAX_ADDR_TO_SEND: usize = 1000;
+
+/// Maximum size of an address in an addrv2 message (BIP155)
+const MAX_ADDRV2_SIZE: usize = 512;
+
+/// Size of an address in an addr message: time, services, IPv6 address and port
+const ADDR_SIZE: usize = 4 + 8 + 16 + 2;
+
+/// Address represents the address of a node on one of the networks of BIP155
+/// https://github.com/bitcoin/bips/blob/master/bip-0155.mediawiki
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Address {
+    Ipv4(Ipv4Addr),
+    Ipv6(Ipv6Addr),
+    /// The ed25519 public key of a Tor v3 onion service.
+    TorV3([u8; 32]),
+    /// The SHA256 hash of an I2P destination.
+    I2p([u8; 32]),
+    Cjdns(Ipv6Addr),
+    /// An address of a network we don't know, kept as received.
+    Unknown {
+        network_id: u8,
+        bytes: Vec<u8>,
+    },
+}
+
+impl Address {
+    /// ip returns the IP address of IPv4 and IPv6 addresses
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Address::Ipv4(ip) => Some(IpAddr::V4(*ip)),
+            Address::Ipv6(ip) => Some(IpAddr::V6(*ip)),
+            _ => None,
+        }
+    }
+
+    /// network_id returns the network ID of the address in addrv2 messages
+    pub(crate) fn network_id(&self) -> u8 {
+        match self {
+            Address::Ipv4(_) => 0x01,
+            Address::Ipv6(_) => 0x02,
+            Address::TorV3(_) => 0x04,
+            Address::I2p(_) => 0x05,
+            Address::Cjdns(_) => 0x06,
+            Address::Unknown { network_id, .. } => *network_id,
+        }
+    }
+
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Address::Ipv4(ip) => ip.octets().to_vec(),
+            Address::Ipv6(ip) | Address::Cjdns(ip) => ip.octets().to_vec(),
+            Address::TorV3(key) | Address::I2p(key) => key.to_vec(),
+            Address::Unknown { bytes, .. } => bytes.clone(),
+        }
+    }
+
+    pub(crate) fn from_bytes(network_id: u8, bytes: Vec<u8>) -> Result<Self> {
+        let invalid = || BTCP2PError::InvalidAddress(network_id);
+
+        Ok(match network_id {
+            0x01 => Address::Ipv4(<[u8; 4]>::try_from(bytes).map_err(|_| invalid())?.into()),
+            0x02 => Address::Ipv6(<[u8; 16]>::try_from(bytes).map_err(|_| invalid())?.into()),
+            0x04 => Address::TorV3(bytes.try_into().map_err(|_| invalid())?),
+            0x05 => Address::I2p(bytes.try_into().map_err(|_| invalid())?),
+            0x06 => Address::Cjdns(<[u8; 16]>::try_from(bytes).map_err(|_| invalid())?.into()),
+            _ => Address::Unknown { network_id, bytes },
+        })
+    }
+}
+
+impl From<IpAddr> for Address {
+    fn from(ip: IpAddr) -> Self {
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => Address::Ipv4(ip),
+            IpAddr::V6(ip) => Address::Ipv6(ip),
+        }
+    }
+}
+
+/// NetAddress represents an address relayed in addr and addrv2 messages
+/// https://developer.bitcoin.org/reference/p2p_networking.html#addr
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetAddress {
+    /// The last time the node was seen, as a Unix epoch time.
+    pub time: u32,
+
+    /// The services supported by the node.
+    pub services: ServiceFlags,
+
+    pub address: Address,
+
+    pub port: u16,
+}
+
+impl NetAddress {
+    pub fn new(time: u32, services: ServiceFlags, socket: SocketAddr) -> Self {
+        Self {
+            time,
+            services,
+            address: socket.ip().into(),
+            port: socket.port(),
+        }
+    }
+
+    /// socket_addr returns the socket address of IPv4 and IPv6 addresses
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        self.address.ip().map(|ip| SocketAddr::new(ip, self.port))
+    }
+
+    /// to_bytes converts the address to bytes as in addr messages, only IP addresses can be encoded
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let ip = match &self.address {
+            Address::Ipv4(ip) => ip.to_ipv6_mapped(),
+            Address::Ipv6(ip) => *ip,
+            address => return Err(BTCP2PError::InvalidAddress(address.network_id())),
+        };
+
+        let mut buffer = Vec::with_capacity(ADDR_SIZE);
+        buffer.write_u32::<LittleEndian>(self.time)?;
+        buffer.write_u64::<LittleEndian>(self.services.to_u64())?;
+        buffer.write_all(&ip.octets())?;
+        buffer.write_u16::<BigEndian>(self.port)?;
+        Ok(buffer)
+    }
+
+    /// from_bytes reads an address as in addr messages
+    pub fn from_bytes(bytes: &mut &[u8]) -> Result<Self> {
+        let time = bytes.read_u32::<LittleEndian>()?;
+        let services = bytes.read_u64::<LittleEndian>()?.into();
+        let mut ip = [0u8; 16];
+        bytes.read_exact(&mut ip)?;
+
+        Ok(Self {
+            time,
+            services,
+            address: IpAddr::V6(ip.into()).into(),
+            port: bytes.read_u16::<BigEndian>()?,
+        })
+    }
+
+    /// to_bytes_v2 converts the address to bytes as in addrv2 messages
+    pub fn to_bytes_v2(&self) -> Result<Vec<u8>> {
+        let address = self.address.to_bytes();
+
+        let mut buffer = vec![];
+        buffer.write_u32::<LittleEndian>(self.time)?;
+        write_compact_size(&mut buffer, self.services.to_u64())?;
+        buffer.write_u8(self.address.network_id())?;
+        write_compact_size(&mut buffer, address.len() as u64)?;
+        buffer.write_all(&address)?;
+        buffer.write_u16::<BigEndian>(self.port)?;
+        Ok(buffer)
+    }
+
+    /// from_bytes_v2 reads an address as in addrv2 messages
+    pub fn from_bytes_v2(bytes: &mut &[u8]) -> Result<Self> {
+        let time = bytes.read_u32::<LittleEndian>()?;
+        let services = read_compact_size(bytes)?.into();
+        let network_id = bytes.read_u8()?;
+
+        let len = read_vec_len(bytes, 1)?;
+        if len > MAX_ADDRV2_SIZE {
+            return Err(BTCP2PError::InvalidAddress(network_id));
+        }
+        let mut address = vec![0u8; len];
+        bytes.read_exact(&mut address)?;
+
+        Ok(Self {
+            time,
+            services,
+            address: Address::from_bytes(network_id, address)?,
+            port: bytes.read_u16::<BigEndian>()?,
+        })
+    }
+}
+
+/// AddrPayload represents the payload of an addr message
+/// https://developer.bitcoin.org/reference/p2p_networking.html#addr
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddrPayload {
+    pub addresses: Vec<NetAddress>,
+}
+
+impl AddrPayload {
+    /// to_bytes converts the payload to bytes
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut buffer = vec![];
+        write_addr_count(&mut buffer, self.addresses.len())?;
+        for address in &self.addresses {
+            buffer.write_all(&address.to_bytes()?)?;
+        }
+        Ok(buffer)
+    }
+
+    /// from_bytes converts bytes to a payload
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self> {
+        let len = read_addr_count(&mut bytes, ADDR_SIZE)?;
+        let addresses = (0..len)
+            .map(|_| NetAddress::from_bytes(&mut bytes))
+            .collect::<Result<_>>()?;
+        Ok(Self { addresses })
+    }
+}
+
+/// AddrV2Payload represents the payload of an addrv2 message
+/// https://github.com/bitcoin/bips/blob/master/bip-0155.mediawiki
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddrV2Payload {
+    pub addresses: Vec<NetAddress>,
+}
+
+impl AddrV2Payload {
+    /// to_bytes converts the payload to bytes
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut buffer = vec![];
+        write_addr_count(&mut buffer, self.addresses.len())?;
+        for address in &self.addresses {
+            buffer.write_all(&address.to_bytes_v2()?)?;
+        }
+        Ok(buffer)
+    }
+
+    /// from_bytes converts bytes to a payload
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self> {
+        // the smallest address has a time, one byte of services, a network ID, one byte of length and a port
+        let len = read_addr_count(&mut bytes, 4 + 1 + 1 + 1 + 2)?;
+        let addresses = (0..len)
+            .map(|_| NetAddress::from_bytes_v2(&mut bytes))
+            .collect::<Result<_>>()?;
+        Ok(Self { addresses })
+    }
+}
+
+fn write_addr_count<W: Write>(writer: &mut W, len: usize) -> Result<()> {
+    if len > MAX_ADDR_TO_SEND {
+        return Err(BTCP2PError::TooManyAddresses(len));
+    }
+    write_compact_size(writer, len as u64)
+}
+
+fn read_addr_count(bytes: &mut &[u8], item_size: usize) -> Result<usize> {
+    let len = read_vec_len(bytes, item_size)?;
+    if len > MAX_ADDR_TO_SEND {
+        return Err(BTCP2PError::TooManyAddresses(len));
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::{Arbitrary, TestResult};
+    use quickcheck_macros::quickcheck;
+
+    fn key(g: &mut quickcheck::Gen) -> [u8; 32] {
+        let mut key = [0u8; 32];
+        key.iter_mut().for_each(|b| *b = u8::arbitrary(g));
+        key
+    }
+
+    impl Arbitrary for Address {
+        fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+            match u8::arbitrary(g) % 6 {
+                0 => Address::Ipv4(Ipv4Addr::arbitrary(g)),
+                1 => Address::Ipv6(Ipv6Addr::arbitrary(g)),
+                2 => Address::TorV3(key(g)),
+                3 => Address::I2p(key(g)),
+                4 => Address::Cjdns(Ipv6Addr::arbitrary(g)),
+                _ => Address::Unknown {
+                    network_id: 0x80 | u8::arbitrary(g),
+                    bytes: Vec::arbitrary(g),
+                },
+            }
+        }
+    }
+
+    impl Arbitrary for NetAddress {
+        fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+            Self {
+                time: u32::arbitrary(g),
+                services: ServiceFlags::arbitrary(g),
+                address: Address::arbitrary(g),
+                port: u16::arbitrary(g),
+            }
+        }
+    }
+
+    impl Arbitrary for AddrPayload {
+        fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+            Self {
+                addresses: Vec::<SocketAddr>::arbitrary(g)
+                    .into_iter()
+                    .map(|socket| {
+                        NetAddress::new(u32::arbitrary(g), ServiceFlags::arbitrary(g), socket)
+                    })
+                    .collect(),
+            }
+        }
+    }
+
+    impl Arbitrary for AddrV2Payload {
+        fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+            Self {
+                addresses: Vec::arbitrary(g),
+            }
+        }
+    }
+
+    #[quickcheck]
+    fn test_addr_to_bytes(payload: AddrPayload) -> TestResult {
+        let bytes = payload.to_bytes().unwrap();
+        TestResult::from_bool(AddrPayload::from_bytes(&bytes).unwrap() == payload)
+    }
+
+    #[quickcheck]
+    fn test_addrv2_to_bytes(payload: AddrV2Payload) -> TestResult {
+        let bytes = payload.to_bytes().unwrap();
+        TestResult::from_bool(AddrV2Payload::from_bytes(&bytes).unwrap() == payload)
+    }
+
+    #[test]
+    fn test_addr_ipv4() {
+        let address = NetAddress::new(
+            0x5f5e1000,
+            ServiceFlags::NODE_NETWORK,
+            "10.0.0.1:8333".parse().unwrap(),
+        );
+        let bytes = address.to_bytes().unwrap();
+        assert_eq!(
+            bytes[12..],
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 10, 0, 0, 1, 0x20, 0x8d]
+        );
+        assert_eq!(
+            address.socket_addr(),
+            Some("10.0.0.1:8333".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_addrv2_invalid_length() {
+        let mut address =
+            NetAddress::new(0, ServiceFlags::UNNAMED, "10.0.0.1:8333".parse().unwrap())
+                .to_bytes_v2()
+                .unwrap();
+        // network ID of IPv6 with the 4 bytes of an IPv4 address
+        address[5] = 0x02;
+        assert!(matches!(
+            NetAddress::from_bytes_v2(&mut &address[..]),
+            Err(BTCP2PError::InvalidAddress(0x02))
+        ));
+    }
+
+    #[test]
+    fn test_too_many_addresses() {
+        let payload = AddrPayload {
+            addresses: vec![
+                NetAddress::new(
+                    0,
+                    ServiceFlags::UNNAMED,
+                    "10.0.0.1:8333".parse().unwrap()
+                );
+                MAX_ADDR_TO_SEND + 1
+            ],
+        };
+        assert!(matches!(
+            payload.to_bytes(),
+            Err(BTCP2PError::TooManyAddresses(_))
+        ));
+    }
+}
@@ -0,0 +1,1051 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use rand::{seq::SliceRandom, Rng};
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use super::{
+    address::{Address, NetAddress},
+    encode::{read_vec_len, write_compact_size},
+    errors::{BTCP2PError, Result},
+    hash::{sha256d, siphash24},
+    message::Message,
+    payload::Payload,
+    peer_manager::{netgroup, AddressSource},
+    seeds::{Resolver, SeedResolver},
+    service_flags::ServiceFlags,
+    time_offsets::unix_time,
+};
+
+/// Number of buckets of the new table, as in Bitcoin Core
+const NEW_BUCKET_COUNT: usize = 1024;
+
+/// Number of buckets of the tried table, as in Bitcoin Core
+const TRIED_BUCKET_COUNT: usize = 256;
+
+/// Number of entries in a bucket
+const BUCKET_SIZE: usize = 64;
+
+/// Number of tried buckets the addresses of a netgroup are spread over
+const TRIED_BUCKETS_PER_GROUP: u64 = 8;
+
+/// Number of new buckets the addresses from a source netgroup are spread over
+const NEW_BUCKETS_PER_SOURCE_GROUP: u64 = 64;
+
+/// Most new buckets an address is placed in
+const NEW_BUCKETS_PER_ADDRESS: u32 = 8;
+
+/// Addresses not seen for this long are terrible (30 days)
+const HORIZON: i64 = 30 * 24 * 60 * 60;
+
+/// Addresses never connected to after this many attempts are terrible
+const RETRIES: u32 = 3;
+
+/// Addresses not connected to for a week after this many attempts are terrible
+const MAX_FAILURES: u32 = 10;
+
+/// Time without success after which MAX_FAILURES applies (7 days)
+const MIN_FAIL: i64 = 7 * 24 * 60 * 60;
+
+/// Penalty applied to the time of addresses relayed by peers (2 hours), as in Bitcoin Core
+pub const GOSSIP_TIME_PENALTY: i64 = 2 * 60 * 60;
+
+/// Largest share of the addresses returned to a getaddr, as in Bitcoin Core
+const GETADDR_MAX_PCT: usize = 23;
+
+/// Version of the file format of the address manager
+const FORMAT_VERSION: u8 = 2;
+
+/// AddrInfo holds what is known about an address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddrInfo {
+    pub address: Address,
+
+    /// The port of the address, always 0 for I2P addresses.
+    pub port: u16,
+
+    /// The services the address was announced with.
+    pub services: ServiceFlags,
+
+    /// The last time the address was seen, as a Unix epoch time.
+    pub time: i64,
+
+    /// The address of the peer which told us about this address.
+    pub source: IpAddr,
+
+    /// The last time we tried to connect.
+    pub last_try: i64,
+
+    /// The last time we connected successfully.
+    pub last_success: i64,
+
+    /// The number of attempts since the last success.
+    pub attempts: u32,
+
+    /// Whether the address is in the tried table.
+    pub in_tried: bool,
+
+    /// The number of new buckets referencing the address.
+    ref_count: u32,
+}
+
+impl AddrInfo {
+    fn new(address: Address, port: u16, services: ServiceFlags, time: i64, source: IpAddr) -> Self {
+        Self {
+            address,
+            port,
+            services,
+            time,
+            source,
+            last_try: 0,
+            last_success: 0,
+            attempts: 0,
+            in_tried: false,
+            ref_count: 0,
+        }
+    }
+
+    /// is_terrible checks if the address is not worth keeping, as Bitcoin Core does
+    pub fn is_terrible(&self, now: i64) -> bool {
+        // tried in the last minute
+        if self.last_try != 0 && now - self.last_try < 60 {
+            return false;
+        }
+        // announced in the future
+        if self.time > now + 10 * 60 {
+            return true;
+        }
+        // not seen in recent history
+        if self.time == 0 || now - self.time > HORIZON {
+            return true;
+        }
+        // never connected after several attempts
+        if self.last_success == 0 && self.attempts >= RETRIES {
+            return true;
+        }
+        // too many failures over the last week
+        now - self.last_success > MIN_FAIL && self.attempts >= MAX_FAILURES
+    }
+
+    /// chance returns the relative chance of the address to be selected
+    pub fn chance(&self, now: i64) -> f64 {
+        let mut chance = 1.0;
+
+        // deprioritize addresses tried in the last 10 minutes
+        if now - self.last_try < 10 * 60 {
+            chance *= 0.01;
+        }
+
+        chance * 0.66f64.powi(self.attempts.min(8) as i32)
+    }
+}
+
+#[derive(Debug)]
+struct AddrManState {
+    key: (u64, u64),
+    next_id: u64,
+    entries: HashMap<u64, AddrInfo>,
+    ids: HashMap<(Address, u16), u64>,
+    new: Vec<Option<u64>>,
+    tried: Vec<Option<u64>>,
+    new_count: usize,
+    tried_count: usize,
+}
+
+/// AddrMan keeps the addresses of nodes we may connect to, modelled on the address manager of Bitcoin Core
+/// https://github.com/bitcoin/bitcoin/blob/master/src/addrman.h
+///
+/// Addresses we never connected to are kept in the new table, addresses we connected to in the tried table.
+/// Both are made of buckets selected by a keyed hash of netgroups: the new bucket depends on the netgroup of the
+/// source, so a single peer can't fill the table, and the tried bucket on the netgroup of the address. An entry
+/// is only replaced by a new one if it is terrible. A tried entry evicted by another one is moved back to the
+/// new table.
+///
+/// Clones share the same addresses. Addresses are kept with their port, onion and I2P addresses are grouped by
+/// network and the first bits of the address. Addresses of unknown networks are not kept.
+#[derive(Debug, Clone)]
+pub struct AddrMan(Arc<Mutex<AddrManState>>);
+
+impl Default for AddrMan {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AddrMan {
+    /// new creates an empty address manager with a random bucketing key
+    pub fn new() -> Self {
+        Self::with_key(rand::random())
+    }
+
+    fn with_key(key: (u64, u64)) -> Self {
+        Self(Arc::new(Mutex::new(AddrManState {
+            key,
+            next_id: 0,
+            entries: HashMap::new(),
+            ids: HashMap::new(),
+            new: vec![None; NEW_BUCKET_COUNT * BUCKET_SIZE],
+            tried: vec![None; TRIED_BUCKET_COUNT * BUCKET_SIZE],
+            new_count: 0,
+            tried_count: 0,
+        })))
+    }
+
+    /// len returns the number of addresses
+    pub fn len(&self) -> usize {
+        self.state().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state().entries.is_empty()
+    }
+
+    /// new_count returns the number of addresses we never connected to
+    pub fn new_count(&self) -> usize {
+        self.state().new_count
+    }
+
+    /// tried_count returns the number of addresses we connected to
+    pub fn tried_count(&self) -> usize {
+        self.state().tried_count
+    }
+
+    /// get returns what is known about an address
+    pub fn get(&self, address: &Address, port: u16) -> Option<AddrInfo> {
+        let state = self.state();
+        let id = state.ids.get(&(canonical(address), port))?;
+        state.entries.get(id).cloned()
+    }
+
+    /// add adds addresses learned from a source, the time of the addresses is reduced by the penalty
+    /// returns the number of addresses which were not known
+    pub fn add(
+        &self,
+        addresses: &[NetAddress],
+        source: IpAddr,
+        time_penalty: i64,
+        now: i64,
+    ) -> usize {
+        let mut state = self.state();
+        let mut added = 0;
+        for address in addresses {
+            let addr = canonical(&address.address);
+            let penalty = if addr.ip() == Some(source.to_canonical()) {
+                0
+            } else {
+                time_penalty
+            };
+            if state.add(
+                (addr, address.port),
+                address.services,
+                address.time as i64,
+                source,
+                penalty,
+                now,
+            ) {
+                added += 1;
+            }
+        }
+        added
+    }
+
+    /// add_gossip adds the addresses of an addr or addrv2 message received from a peer
+    /// addresses with a bogus time are considered seen 5 days ago, as Bitcoin Core does
+    pub fn add_gossip(&self, addresses: &[NetAddress], source: IpAddr, now: i64) -> usize {
+        let addresses = addresses
+            .iter()
+            .map(|address| {
+                let time = address.time as i64;
+                if time <= 100_000_000 || time > now + 10 * 60 {
+                    NetAddress {
+                        time: (now - 5 * 24 * 60 * 60).max(0) as u32,
+                        ..address.clone()
+                    }
+                } else {
+                    address.clone()
+                }
+            })
+            .collect::<Vec<_>>();
+
+        self.add(&addresses, source, GOSSIP_TIME_PENALTY, now)
+    }
+
+    /// add_message adds the addresses of an addr or addrv2 message received from a peer, other messages are ignored
+    pub fn add_message(&self, message: &Message, source: SocketAddr, now: i64) -> usize {
+        match &message.payload {
+            Payload::Addr(payload) => self.add_gossip(&payload.addresses, source.ip(), now),
+            Payload::AddrV2(payload) => self.add_gossip(&payload.addresses, source.ip(), now),
+            _ => 0,
+        }
+    }
+
+    /// add_seeds queries DNS seeds and adds the addresses they return, each seed is a source of its own
+    /// seeds which can't be resolved are skipped, returns the number of addresses which were not known
+    pub async fn add_seeds<R: Resolver>(&self, seeds: &SeedResolver<R>, now: i64) -> usize {
+        let services = seeds.services | ServiceFlags::NODE_NETWORK;
+        let mut added = 0;
+
+        for (_, result) in seeds.lookup_all().await {
+            let Ok(addrs) = result else {
+                continue;
+            };
+            let addrs = addrs
+                .into_iter()
+                .map(|addr| NetAddress::new(now as u32, services, addr))
+                .collect::<Vec<_>>();
+            let Some(source) = addrs.first().and_then(|address| address.address.ip()) else {
+                continue;
+            };
+            added += self.add(&addrs, source, 0, now);
+        }
+
+        added
+    }
+
+    /// good marks an address as connected to successfully, moving it to the tried table
+    pub fn good(&self, address: &Address, port: u16, now: i64) {
+        self.state().good((canonical(address), port), now);
+    }
+
+    /// attempt records a failed attempt to connect to an address
+    pub fn attempt(&self, address: &Address, port: u16, now: i64) {
+        let mut state = self.state();
+        let Some(id) = state.ids.get(&(canonical(address), port)).copied() else {
+            return;
+        };
+        let info = state.entries.get_mut(&id).expect("id of a known address");
+        info.last_try = now;
+        info.attempts += 1;
+    }
+
+    /// select returns an address to connect to, tried and new addresses are equally likely to be selected
+    /// addresses tried recently or failing often are less likely to be selected
+    pub fn select(&self, new_only: bool, now: i64) -> Option<AddrInfo> {
+        let state = self.state();
+        let mut rng = rand::thread_rng();
+
+        if state.new_count == 0 && (new_only || state.tried_count == 0) {
+            return None;
+        }
+        let use_tried =
+            !new_only && state.tried_count > 0 && (state.new_count == 0 || rng.gen_bool(0.5));
+
+        let candidates = state
+            .entries
+            .values()
+            .filter(|info| info.in_tried == use_tried)
+            .collect::<Vec<_>>();
+
+        let mut chance_factor = 1.0;
+        loop {
+            let info = candidates.choose(&mut rng)?;
+            if rng.gen::<f64>() < chance_factor * info.chance(now) {
+                return Some((*info).clone());
+            }
+            chance_factor *= 1.2;
+        }
+    }
+
+    /// get_addr returns random addresses which are not terrible, to answer a getaddr
+    /// at most max_count addresses and 23% of the addresses are returned
+    pub fn get_addr(&self, max_count: usize, now: i64) -> Vec<NetAddress> {
+        let state = self.state();
+        let mut entries = state.entries.values().collect::<Vec<_>>();
+        let count = max_count.min(entries.len() * GETADDR_MAX_PCT / 100);
+        entries.shuffle(&mut rand::thread_rng());
+
+        entries
+            .into_iter()
+            .filter(|info| !info.is_terrible(now))
+            .take(count)
+            .map(|info| NetAddress {
+                time: info.time.max(0) as u32,
+                services: info.services,
+                address: info.address.clone(),
+                port: info.port,
+            })
+            .collect()
+    }
+
+    /// to_bytes serializes the addresses, followed by a checksum
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let state = self.state();
+        let mut buffer = vec![];
+        buffer.write_u8(FORMAT_VERSION)?;
+        buffer.write_u64::<LittleEndian>(state.key.0)?;
+        buffer.write_u64::<LittleEndian>(state.key.1)?;
+
+        // tried entries first, so they keep their place when loaded
+        let mut entries = state.entries.values().collect::<Vec<_>>();
+        entries.sort_by_key(|info| !info.in_tried);
+
+        write_compact_size(&mut buffer, entries.len() as u64)?;
+        for info in entries {
+            buffer.write_u8(info.address.network_id())?;
+            let address = info.address.to_bytes();
+            write_compact_size(&mut buffer, address.len() as u64)?;
+            buffer.write_all(&address)?;
+            buffer.write_u16::<LittleEndian>(info.port)?;
+            buffer.write_u64::<LittleEndian>(info.services.to_u64())?;
+            buffer.write_i64::<LittleEndian>(info.time)?;
+            buffer.write_all(&ipv6(info.source).octets())?;
+            buffer.write_i64::<LittleEndian>(info.last_try)?;
+            buffer.write_i64::<LittleEndian>(info.last_success)?;
+            buffer.write_u32::<LittleEndian>(info.attempts)?;
+            buffer.write_u8(info.in_tried.into())?;
+        }
+
+        let checksum = sha256d(&buffer);
+        buffer.write_all(&checksum)?;
+        Ok(buffer)
+    }
+
+    /// from_bytes deserializes addresses, the buckets are rebuilt from the stored key
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < 32 {
+            return Err(BTCP2PError::InvalidChecksum);
+        }
+        let (mut bytes, checksum) = bytes.split_at(bytes.len() - 32);
+        if sha256d(bytes)[..] != checksum[..] {
+            return Err(BTCP2PError::InvalidChecksum);
+        }
+
+        let version = bytes.read_u8()?;
+        if version != FORMAT_VERSION {
+            return Err(BTCP2PError::UnsupportedFormatVersion(version));
+        }
+        let key = (
+            bytes.read_u64::<LittleEndian>()?,
+            bytes.read_u64::<LittleEndian>()?,
+        );
+        let addrman = AddrMan::with_key(key);
+
+        let len = read_vec_len(&mut bytes, 1 + 1 + 2 + 8 + 8 + 16 + 8 + 8 + 4 + 1)?;
+        {
+            let mut state = addrman.state();
+            for _ in 0..len {
+                let network_id = bytes.read_u8()?;
+                let address_len = read_vec_len(&mut bytes, 1)?;
+                let mut address = vec![0; address_len];
+                bytes.read_exact(&mut address)?;
+                let mut info = AddrInfo::new(
+                    canonical(&Address::from_bytes(network_id, address)?),
+                    bytes.read_u16::<LittleEndian>()?,
+                    bytes.read_u64::<LittleEndian>()?.into(),
+                    bytes.read_i64::<LittleEndian>()?,
+                    read_ip(&mut bytes)?,
+                );
+                info.last_try = bytes.read_i64::<LittleEndian>()?;
+                info.last_success = bytes.read_i64::<LittleEndian>()?;
+                info.attempts = bytes.read_u32::<LittleEndian>()?;
+                let in_tried = bytes.read_u8()? != 0;
+
+                state.restore(info, in_tried);
+            }
+        }
+
+        Ok(addrman)
+    }
+
+    /// save writes the addresses to a file, replacing it atomically
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("new");
+        std::fs::write(&tmp, self.to_bytes()?)?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    /// load reads the addresses saved to a file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        AddrMan::from_bytes(&std::fs::read(path)?)
+    }
+
+    fn state(&self) -> MutexGuard<'_, AddrManState> {
+        self.0.lock().unwrap_or_else(|err| {
+            // a holder panicked, maybe between updates of the tables, which are rebuilt from the entries
+            self.0.clear_poison();
+            let mut state = err.into_inner();
+            state.rebuild();
+            state
+        })
+    }
+}
+
+/// The peer manager connects to selected addresses, a success moves the address to the tried table
+impl AddressSource for AddrMan {
+    fn select(&mut self) -> Option<(Address, u16)> {
+        AddrMan::select(self, false, unix_time()).map(|info| (info.address, info.port))
+    }
+
+    fn connected(&mut self, address: &Address, port: u16) {
+        self.good(address, port, unix_time());
+    }
+
+    fn failed(&mut self, address: &Address, port: u16) {
+        self.attempt(address, port, unix_time());
+    }
+}
+
+impl AddrManState {
+    fn hash(&self, data: &[u8]) -> u64 {
+        siphash24(self.key.0, self.key.1, data)
+    }
+
+    fn tried_bucket(&self, addr: &(Address, u16)) -> usize {
+        let hash1 = self.hash(&addr_key(addr)) % TRIED_BUCKETS_PER_GROUP;
+        let mut data = group_key(&addr.0);
+        data.extend(hash1.to_le_bytes());
+        (self.hash(&data) % TRIED_BUCKET_COUNT as u64) as usize
+    }
+
+    fn new_bucket(&self, addr: &(Address, u16), source: IpAddr) -> usize {
+        let source_group = group_key(&source.into());
+        let mut data = group_key(&addr.0);
+        data.extend(&source_group);
+        let hash1 = self.hash(&data) % NEW_BUCKETS_PER_SOURCE_GROUP;
+
+        let mut data = source_group;
+        data.extend(hash1.to_le_bytes());
+        (self.hash(&data) % NEW_BUCKET_COUNT as u64) as usize
+    }
+
+    /// slot returns the index of the entry of an address in a bucket of a table
+    fn slot(&self, new: bool, bucket: usize, addr: &(Address, u16)) -> usize {
+        let mut data = vec![if new { b'N' } else { b'K' }];
+        data.extend((bucket as u32).to_le_bytes());
+        data.extend(addr_key(addr));
+        bucket * BUCKET_SIZE + (self.hash(&data) % BUCKET_SIZE as u64) as usize
+    }
+
+    fn add(
+        &mut self,
+        addr: (Address, u16),
+        services: ServiceFlags,
+        time: i64,
+        source: IpAddr,
+        penalty: i64,
+        now: i64,
+    ) -> bool {
+        if !is_storable(&addr.0, addr.1) {
+            return false;
+        }
+
+        let (id, is_new) = match self.ids.get(&addr).copied() {
+            Some(id) => {
+                let info = self.entries.get_mut(&id).expect("id of a known address");
+
+                // update the time seen at most once an hour for online addresses, once a day otherwise
+                let online = now - time < 24 * 60 * 60;
+                let update_interval = if online { 60 * 60 } else { 24 * 60 * 60 };
+                if time > 0 && info.time < time - update_interval - penalty {
+                    info.time = (time - penalty).max(0);
+                }
+                info.services |= services;
+
+                if time == 0 || (info.time != 0 && time <= info.time) {
+                    return false;
+                }
+                if info.in_tried || info.ref_count == NEW_BUCKETS_PER_ADDRESS {
+                    return false;
+                }
+                // the more buckets reference the address, the less likely it is added to another one
+                if !rand::thread_rng().gen_ratio(1, 1 << info.ref_count) {
+                    return false;
+                }
+                (id, false)
+            }
+            None => {
+                let id = self.next_id;
+                self.next_id += 1;
+                let info = AddrInfo::new(
+                    addr.0.clone(),
+                    addr.1,
+                    services,
+                    (time - penalty).max(0),
+                    source,
+                );
+                self.entries.insert(id, info);
+                self.ids.insert(addr.clone(), id);
+                self.new_count += 1;
+                (id, true)
+            }
+        };
+
+        let slot = self.slot(true, self.new_bucket(&addr, source), &addr);
+        if self.new[slot] == Some(id) {
+            return is_new;
+        }
+
+        let ref_count = self.entries[&id].ref_count;
+        let insert = match self.new[slot] {
+            None => true,
+            Some(existing) => {
+                let existing = &self.entries[&existing];
+                existing.is_terrible(now) || (existing.ref_count > 1 && ref_count == 0)
+            }
+        };
+
+        if insert {
+            self.clear_new(slot);
+            self.new[slot] = Some(id);
+            self.entries
+                .get_mut(&id)
+                .expect("id of a known address")
+                .ref_count += 1;
+        } else if ref_count == 0 {
+            self.delete(id);
+            return false;
+        }
+
+        is_new
+    }
+
+    /// clear_new empties a slot of the new table, deleting the entry if no other bucket references it
+    fn clear_new(&mut self, slot: usize) {
+        let Some(id) = self.new[slot].take() else {
+            return;
+        };
+        let info = self.entries.get_mut(&id).expect("id of a known address");
+        info.ref_count -= 1;
+        if info.ref_count == 0 {
+            self.delete(id);
+        }
+    }
+
+    fn delete(&mut self, id: u64) {
+        if let Some(info) = self.entries.remove(&id) {
+            self.ids.remove(&(info.address, info.port));
+            self.new_count -= 1;
+        }
+    }
+
+    fn good(&mut self, addr: (Address, u16), now: i64) {
+        let Some(id) = self.ids.get(&addr).copied() else {
+            return;
+        };
+        let info = self.entries.get_mut(&id).expect("id of a known address");
+        info.last_success = now;
+        info.last_try = now;
+        info.attempts = 0;
+        if info.in_tried {
+            return;
+        }
+
+        // remove the entry from every new bucket referencing it
+        for bucket in 0..NEW_BUCKET_COUNT {
+            let slot = self.slot(true, bucket, &addr);
+            if self.new[slot] == Some(id) {
+                self.new[slot] = None;
+            }
+        }
+        self.new_count -= 1;
+
+        let slot = self.slot(false, self.tried_bucket(&addr), &addr);
+        if let Some(evicted) = self.tried[slot].take() {
+            self.tried_count -= 1;
+            self.move_to_new(evicted);
+        }
+
+        self.tried[slot] = Some(id);
+        self.tried_count += 1;
+        let info = self.entries.get_mut(&id).expect("id of a known address");
+        info.in_tried = true;
+        info.ref_count = 0;
+    }
+
+    /// move_to_new moves an entry evicted from the tried table to its new bucket
+    fn move_to_new(&mut self, id: u64) {
+        let info = &self.entries[&id];
+        let addr = (info.address.clone(), info.port);
+        let slot = self.slot(true, self.new_bucket(&addr, info.source), &addr);
+        self.clear_new(slot);
+
+        self.new[slot] = Some(id);
+        self.new_count += 1;
+        let info = self.entries.get_mut(&id).expect("id of a known address");
+        info.in_tried = false;
+        info.ref_count = 1;
+    }
+
+    /// rebuild places every entry again, tried entries first as when they are read from a file
+    fn rebuild(&mut self) {
+        let mut entries = std::mem::take(&mut self.entries)
+            .into_values()
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|info| !info.in_tried);
+
+        self.ids.clear();
+        self.new.fill(None);
+        self.tried.fill(None);
+        self.new_count = 0;
+        self.tried_count = 0;
+        for info in entries {
+            let in_tried = info.in_tried;
+            self.restore(info, in_tried);
+        }
+    }
+
+    /// restore places an entry read from a file
+    fn restore(&mut self, info: AddrInfo, in_tried: bool) {
+        let addr = (info.address.clone(), info.port);
+        if !is_storable(&addr.0, addr.1) || self.ids.contains_key(&addr) {
+            return;
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.ids.insert(addr.clone(), id);
+
+        let tried_slot = self.slot(false, self.tried_bucket(&addr), &addr);
+        if in_tried && self.tried[tried_slot].is_none() {
+            self.tried[tried_slot] = Some(id);
+            self.tried_count += 1;
+            self.entries.insert(
+                id,
+                AddrInfo {
+                    in_tried: true,
+                    ..info
+                },
+            );
+            return;
+        }
+
+        let slot = self.slot(true, self.new_bucket(&addr, info.source), &addr);
+        if self.new[slot].is_some() {
+            self.ids.remove(&addr);
+            return;
+        }
+        self.new[slot] = Some(id);
+        self.new_count += 1;
+        self.entries.insert(
+            id,
+            AddrInfo {
+                in_tried: false,
+                ref_count: 1,
+                ..info
+            },
+        );
+    }
+}
+
+/// canonical maps IPv4-mapped IPv6 addresses to IPv4 so each address has a single key
+fn canonical(address: &Address) -> Address {
+    match address.ip() {
+        Some(ip) => ip.into(),
+        None => address.clone(),
+    }
+}
+
+/// is_storable checks if an address can be connected to, I2P addresses have no port and the others need one
+fn is_storable(address: &Address, port: u16) -> bool {
+    match address {
+        Address::Ipv4(_) | Address::Ipv6(_) => {
+            port != 0 && address.ip().is_some_and(|ip| !ip.is_unspecified())
+        }
+        Address::TorV3(_) | Address::Cjdns(_) => port != 0,
+        Address::I2p(_) => port == 0,
+        Address::Unknown { .. } => false,
+    }
+}
+
+fn ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+fn read_ip(bytes: &mut &[u8]) -> Result<IpAddr> {
+    let mut ip = [0u8; 16];
+    bytes.read_exact(&mut ip)?;
+    Ok(IpAddr::V6(ip.into()).to_canonical())
+}
+
+fn addr_key((address, port): &(Address, u16)) -> Vec<u8> {
+    let mut key = match address.ip() {
+        Some(ip) => ipv6(ip).octets().to_vec(),
+        None => [vec![address.network_id()], address.to_bytes()].concat(),
+    };
+    key.extend(port.to_be_bytes());
+    key
+}
+
+/// group_key returns the bytes identifying the netgroup of an address, local addresses share one group
+/// onion and I2P addresses are grouped by their first four bits and CJDNS addresses by their first 12 bits,
+/// as Bitcoin Core does
+fn group_key(address: &Address) -> Vec<u8> {
+    let bytes = address.to_bytes();
+    match address {
+        Address::TorV3(_) | Address::I2p(_) => vec![address.network_id(), bytes[0] | 0x0f],
+        Address::Cjdns(_) => vec![address.network_id(), bytes[0], bytes[1] | 0x0f],
+        _ => match address.ip().and_then(netgroup) {
+            Some(subnet) => {
+                let mut key = vec![subnet.prefix_len()];
+                key.extend(ipv6(subnet.network()).octets());
+                key
+            }
+            None => vec![0],
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn address(s: &str) -> NetAddress {
+        NetAddress::new(NOW as u32, ServiceFlags::NODE_NETWORK, s.parse().unwrap())
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn get(addrman: &AddrMan, s: &str) -> Option<AddrInfo> {
+        let address = address(s);
+        addrman.get(&address.address, address.port)
+    }
+
+    #[test]
+    fn test_add() {
+        let addrman = AddrMan::new();
+        let added = addrman.add(
+            &[address("1.2.3.4:8333"), address("5.6.7.8:8333")],
+            ip("9.9.9.9"),
+            GOSSIP_TIME_PENALTY,
+            NOW,
+        );
+        assert_eq!(added, 2);
+        assert_eq!(addrman.new_count(), 2);
+
+        let info = get(&addrman, "1.2.3.4:8333").unwrap();
+        assert_eq!(info.time, NOW - GOSSIP_TIME_PENALTY);
+        assert_eq!(info.source, ip("9.9.9.9"));
+
+        // known addresses are not added twice, IPv4-mapped addresses are the same address
+        let added = addrman.add(&[address("[::ffff:1.2.3.4]:8333")], ip("9.9.9.9"), 0, NOW);
+        assert_eq!(added, 0);
+        assert_eq!(addrman.len(), 2);
+
+        // an address announcing itself is not penalized
+        addrman.add(
+            &[address("7.7.7.7:8333")],
+            ip("7.7.7.7"),
+            GOSSIP_TIME_PENALTY,
+            NOW,
+        );
+        assert_eq!(get(&addrman, "7.7.7.7:8333").unwrap().time, NOW);
+    }
+
+    #[test]
+    fn test_add_onion_and_i2p() {
+        let addrman = AddrMan::new();
+        let net_address = |address, port| NetAddress {
+            time: NOW as u32,
+            services: ServiceFlags::NODE_NETWORK,
+            address,
+            port,
+        };
+        let onion = Address::TorV3([7; 32]);
+        let i2p = Address::I2p([9; 32]);
+        let unknown = Address::Unknown {
+            network_id: 0x42,
+            bytes: vec![1, 2],
+        };
+
+        // I2P addresses have no port, the others need one
+        let added = addrman.add(
+            &[
+                net_address(onion.clone(), 8333),
+                net_address(onion.clone(), 0),
+                net_address(i2p.clone(), 0),
+                net_address(i2p.clone(), 8333),
+                net_address(unknown.clone(), 8333),
+            ],
+            ip("9.9.9.9"),
+            0,
+            NOW,
+        );
+        assert_eq!(added, 2);
+        assert!(addrman.get(&onion, 8333).is_some());
+        assert!(addrman.get(&i2p, 0).is_some());
+        assert!(addrman.get(&unknown, 8333).is_none());
+
+        // they are selected as is, to be dialed with Peer::connect_address
+        addrman.good(&i2p, 0, NOW);
+        let selected = AddressSource::select(&mut addrman.clone()).unwrap();
+        assert!(selected == (i2p.clone(), 0) || selected == (onion.clone(), 8333));
+
+        let loaded = AddrMan::from_bytes(&addrman.to_bytes().unwrap()).unwrap();
+        assert_eq!(loaded.get(&onion, 8333), addrman.get(&onion, 8333));
+        assert!(loaded.get(&i2p, 0).unwrap().in_tried);
+    }
+
+    #[test]
+    fn test_good() {
+        let addrman = AddrMan::new();
+        let addr = address("1.2.3.4:8333");
+        addrman.add(std::slice::from_ref(&addr), ip("9.9.9.9"), 0, NOW);
+
+        addrman.attempt(&addr.address, addr.port, NOW);
+        assert_eq!(get(&addrman, "1.2.3.4:8333").unwrap().attempts, 1);
+
+        addrman.good(&addr.address, addr.port, NOW);
+        let info = get(&addrman, "1.2.3.4:8333").unwrap();
+        assert!(info.in_tried);
+        assert_eq!(info.attempts, 0);
+        assert_eq!(info.last_success, NOW);
+        assert_eq!((addrman.new_count(), addrman.tried_count()), (0, 1));
+
+        // tried addresses are selected as well
+        let selected = addrman.select(false, NOW).unwrap();
+        assert_eq!((selected.address, selected.port), (addr.address, addr.port));
+        assert!(addrman.select(true, NOW).is_none());
+    }
+
+    #[test]
+    fn test_terrible() {
+        let mut info = AddrInfo::new(
+            Address::Ipv4([1, 2, 3, 4].into()),
+            8333,
+            ServiceFlags::UNNAMED,
+            NOW,
+            ip("9.9.9.9"),
+        );
+        assert!(!info.is_terrible(NOW));
+        assert!(info.is_terrible(NOW + HORIZON + 1));
+
+        info.attempts = RETRIES;
+        info.last_try = NOW - 120;
+        assert!(info.is_terrible(NOW));
+        // unless tried in the last minute
+        info.last_try = NOW - 30;
+        assert!(!info.is_terrible(NOW));
+    }
+
+    #[test]
+    fn test_source_spreads_over_limited_buckets() {
+        let addrman = AddrMan::new();
+        let addresses = (0..=255u8)
+            .flat_map(|a| (0..16u8).map(move |b| address(&format!("{}.{}.1.1:8333", a, b))))
+            .collect::<Vec<_>>();
+
+        // a single source can only fill the buckets of its netgroup
+        addrman.add(&addresses, ip("9.9.9.9"), 0, NOW);
+        let state = addrman.state();
+        let buckets = (0..NEW_BUCKET_COUNT)
+            .filter(|bucket| {
+                state.new[bucket * BUCKET_SIZE..(bucket + 1) * BUCKET_SIZE]
+                    .iter()
+                    .any(Option::is_some)
+            })
+            .count();
+        assert!(buckets <= NEW_BUCKETS_PER_SOURCE_GROUP as usize);
+        assert!(state.new_count <= NEW_BUCKETS_PER_SOURCE_GROUP as usize * BUCKET_SIZE);
+    }
+
+    #[test]
+    fn test_add_message() {
+        let addrman = AddrMan::new();
+        let mut future = address("1.2.3.4:8333");
+        future.time = (NOW + 60 * 60) as u32;
+        let message = Message::new(
+            crate::network::Network::MainNet,
+            crate::command::Command::Addr,
+            Payload::Addr(crate::AddrPayload {
+                addresses: vec![future],
+            }),
+        );
+
+        let added = addrman.add_message(&message, "9.9.9.9:8333".parse().unwrap(), NOW);
+        assert_eq!(added, 1);
+        // addresses from the future are considered seen 5 days ago
+        let info = get(&addrman, "1.2.3.4:8333").unwrap();
+        assert_eq!(info.time, NOW - 5 * 24 * 60 * 60 - GOSSIP_TIME_PENALTY);
+    }
+
+    #[test]
+    fn test_get_addr() {
+        let addrman = AddrMan::new();
+        let addresses = (1..=100u8)
+            .map(|a| address(&format!("{}.1.1.1:8333", a)))
+            .collect::<Vec<_>>();
+        addrman.add(&addresses, ip("9.9.9.9"), 0, NOW);
+        let len = addrman.len();
+
+        assert_eq!(
+            addrman.get_addr(1000, NOW).len(),
+            len * GETADDR_MAX_PCT / 100
+        );
+        assert_eq!(addrman.get_addr(5, NOW).len(), 5);
+    }
+
+    #[test]
+    fn test_rebuild_after_panic() {
+        let addrman = AddrMan::new();
+        let addresses = (1..=50u8)
+            .map(|a| address(&format!("{}.1.1.1:8333", a)))
+            .collect::<Vec<_>>();
+        addrman.add(&addresses, ip("9.9.9.9"), 0, NOW);
+        addrman.good(&address("1.1.1.1:8333").address, 8333, NOW);
+        let len = addrman.len();
+
+        // a holder panics after clearing the new table but before updating the count
+        let shared = addrman.clone();
+        std::thread::spawn(move || {
+            let mut state = shared.state();
+            state.new.fill(None);
+            panic!("holder panicked");
+        })
+        .join()
+        .unwrap_err();
+
+        assert_eq!(addrman.len(), len);
+        assert_eq!(addrman.tried_count(), 1);
+        let state = addrman.state();
+        assert_eq!(
+            state.new.iter().filter(|slot| slot.is_some()).count(),
+            state.new_count
+        );
+        assert_eq!(state.new_count + state.tried_count, len);
+    }
+
+    #[test]
+    fn test_persistence() {
+        let addrman = AddrMan::new();
+        let addresses = (1..=50u8)
+            .map(|a| address(&format!("{}.1.1.1:8333", a)))
+            .collect::<Vec<_>>();
+        addrman.add(&addresses, ip("9.9.9.9"), 0, NOW);
+        addrman.good(&address("1.1.1.1:8333").address, 8333, NOW);
+        addrman.attempt(&address("2.1.1.1:8333").address, 8333, NOW);
+
+        let path = std::env::temp_dir().join(format!("addrman-{}.dat", rand::random::<u64>()));
+        addrman.save(&path).unwrap();
+        let loaded = AddrMan::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded.len(), addrman.len());
+        assert_eq!(loaded.tried_count(), 1);
+        for address in &addresses {
+            assert_eq!(
+                loaded.get(&address.address, address.port),
+                addrman.get(&address.address, address.port)
+            );
+        }
+
+        let mut bytes = addrman.to_bytes().unwrap();
+        bytes[20] ^= 1;
+        assert!(matches!(
+            AddrMan::from_bytes(&bytes),
+            Err(BTCP2PError::InvalidChecksum)
+        ));
+    }
+}
@@ -0,0 +1,431 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::{Duration, Instant},
+};
+
+use super::{
+    errors::{BTCP2PError, Result},
+    sync::lock,
+};
+
+/// Misbehavior score at which a peer is banned, as in Bitcoin Core
+pub const DEFAULT_BAN_THRESHOLD: u32 = 100;
+
+/// Default time a peer stays banned, as in Bitcoin Core
+pub const DEFAULT_BAN_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Subnet represents a range of IP addresses, a single address is a subnet with a full prefix
+///
+/// IPv4-mapped IPv6 addresses are treated as the IPv4 address they map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Subnet {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Subnet {
+    /// new creates the subnet of the first prefix_len bits of an address
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Self> {
+        let addr = addr.to_canonical();
+        let network = match addr {
+            IpAddr::V4(ip) if prefix_len <= 32 => {
+                IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask_u32(prefix_len)))
+            }
+            IpAddr::V6(ip) if prefix_len <= 128 => {
+                IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask_u128(prefix_len)))
+            }
+            _ => {
+                return Err(BTCP2PError::InvalidSubnet(format!(
+                    "{}/{}",
+                    addr, prefix_len
+                )))
+            }
+        };
+
+        Ok(Self {
+            network,
+            prefix_len,
+        })
+    }
+
+    /// host creates the subnet holding a single address
+    pub fn host(addr: IpAddr) -> Self {
+        let addr = addr.to_canonical();
+        let prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+        Self {
+            network: addr,
+            prefix_len,
+        }
+    }
+
+    pub fn network(&self) -> IpAddr {
+        self.network
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    /// contains checks if an address belongs to the subnet
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.network, addr.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                u32::from(ip) & mask_u32(self.prefix_len) == u32::from(network)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                u128::from(ip) & mask_u128(self.prefix_len) == u128::from(network)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn mask_u32(prefix_len: u8) -> u32 {
+    u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0)
+}
+
+fn mask_u128(prefix_len: u8) -> u128 {
+    u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0)
+}
+
+impl From<IpAddr> for Subnet {
+    fn from(addr: IpAddr) -> Self {
+        Subnet::host(addr)
+    }
+}
+
+/// Parses `1.2.3.0/24`, `2001:db8::/32` or a single address
+impl FromStr for Subnet {
+    type Err = BTCP2PError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || BTCP2PError::InvalidSubnet(s.to_string());
+
+        match s.split_once('/') {
+            None => Ok(Subnet::host(s.parse().map_err(|_| invalid())?)),
+            Some((addr, prefix_len)) => Subnet::new(
+                addr.parse().map_err(|_| invalid())?,
+                prefix_len.parse().map_err(|_| invalid())?,
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Subnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+/// Ban holds a banned subnet and when the ban expires
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ban {
+    pub subnet: Subnet,
+    pub until: Instant,
+}
+
+/// Misbehavior holds the misbehavior score of a connection, clones share the same score
+///
+/// As in Bitcoin Core the score belongs to the connection rather than to its address: it is dropped with the
+/// connection, so a peer which reconnects starts from zero and scores never pile up for addresses.
+#[derive(Debug, Clone, Default)]
+pub struct Misbehavior(Arc<AtomicU32>);
+
+impl Misbehavior {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// score returns the penalties added so far
+    pub fn score(&self) -> u32 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// add adds a penalty, returns the new score
+    fn add(&self, penalty: u32) -> u32 {
+        let previous = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |score| {
+                Some(score.saturating_add(penalty))
+            })
+            .expect("update always succeeds");
+        previous.saturating_add(penalty)
+    }
+}
+
+#[derive(Debug)]
+struct BanState {
+    threshold: u32,
+    duration: Duration,
+    bans: HashMap<Subnet, Instant>,
+}
+
+/// BanManager bans peers once the misbehavior score of their connection crosses a threshold
+///
+/// Clones share the same bans. Protocol errors are scored with `BTCP2PError::penalty`, the application
+/// can add its own penalties with `misbehaving`. Expired bans are removed as the bans are listed.
+#[derive(Debug, Clone)]
+pub struct BanManager(Arc<Mutex<BanState>>);
+
+impl Default for BanManager {
+    fn default() -> Self {
+        Self::new(DEFAULT_BAN_THRESHOLD, DEFAULT_BAN_DURATION)
+    }
+}
+
+impl BanManager {
+    pub fn new(threshold: u32, duration: Duration) -> Self {
+        Self(Arc::new(Mutex::new(BanState {
+            threshold,
+            duration,
+            bans: HashMap::new(),
+        })))
+    }
+
+    /// misbehaving adds a penalty to the score of a connection, returns whether the score reached the threshold
+    /// so the peer must be disconnected
+    /// the address is banned once, as the score crosses the threshold, unless it is unspecified, as for peers
+    /// reached by name, or loopback, as for onion peers forwarded by Tor: like Bitcoin Core, local peers are
+    /// disconnected but never banned
+    pub fn misbehaving(
+        &self,
+        addr: IpAddr,
+        misbehavior: &Misbehavior,
+        penalty: u32,
+        now: Instant,
+    ) -> bool {
+        let mut state = self.state();
+        let score = misbehavior.add(penalty);
+        if score < state.threshold {
+            return false;
+        }
+
+        let addr = addr.to_canonical();
+        let crossed = score - penalty < state.threshold;
+        if crossed && !addr.is_unspecified() && !addr.is_loopback() {
+            let until = now + state.duration;
+            state.bans.insert(Subnet::host(addr), until);
+        }
+        true
+    }
+
+    /// report scores a protocol error of a connection, returns whether the peer must be disconnected
+    pub fn report(
+        &self,
+        addr: IpAddr,
+        misbehavior: &Misbehavior,
+        err: &BTCP2PError,
+        now: Instant,
+    ) -> bool {
+        self.misbehaving(addr, misbehavior, err.penalty(), now)
+    }
+
+    /// ban bans a subnet for a duration, replacing any ban of the same subnet
+    pub fn ban(&self, subnet: Subnet, duration: Duration, now: Instant) {
+        self.state().bans.insert(subnet, now + duration);
+    }
+
+    /// unban lifts the ban of a subnet, returns whether it was banned
+    pub fn unban(&self, subnet: &Subnet) -> bool {
+        self.state().bans.remove(subnet).is_some()
+    }
+
+    /// is_banned checks if an address belongs to a subnet banned at this time
+    pub fn is_banned(&self, addr: IpAddr, now: Instant) -> bool {
+        self.state()
+            .bans
+            .iter()
+            .any(|(subnet, until)| now < *until && subnet.contains(addr))
+    }
+
+    /// bans returns the bans in effect, sorted by expiry
+    pub fn bans(&self, now: Instant) -> Vec<Ban> {
+        let mut state = self.state();
+        state.bans.retain(|_, until| now < *until);
+
+        let mut bans = state
+            .bans
+            .iter()
+            .map(|(subnet, until)| Ban {
+                subnet: *subnet,
+                until: *until,
+            })
+            .collect::<Vec<_>>();
+        bans.sort_by_key(|ban| ban.until);
+        bans
+    }
+
+    /// clear lifts every ban
+    pub fn clear(&self) {
+        self.state().bans.clear();
+    }
+
+    fn state(&self) -> MutexGuard<'_, BanState> {
+        lock(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::TestResult;
+    use quickcheck_macros::quickcheck;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_subnet() {
+        let subnet: Subnet = "10.1.2.3/16".parse().unwrap();
+        assert_eq!(subnet.to_string(), "10.1.0.0/16");
+        assert!(subnet.contains(ip("10.1.200.1")));
+        assert!(subnet.contains(ip("::ffff:10.1.0.9")));
+        assert!(!subnet.contains(ip("10.2.0.1")));
+        assert!(!subnet.contains(ip("2001:db8::1")));
+
+        let subnet: Subnet = "2001:db8::/32".parse().unwrap();
+        assert!(subnet.contains(ip("2001:db8:1::1")));
+        assert!(!subnet.contains(ip("2001:db9::1")));
+
+        let any: Subnet = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains(ip("255.255.255.255")));
+
+        assert_eq!(
+            "1.2.3.4".parse::<Subnet>().unwrap(),
+            Subnet::host(ip("1.2.3.4"))
+        );
+        assert!("1.2.3.4/33".parse::<Subnet>().is_err());
+        assert!("1.2.3/8".parse::<Subnet>().is_err());
+    }
+
+    #[quickcheck]
+    fn test_subnet_contains_its_addresses(addr: IpAddr, prefix_len: u8) -> TestResult {
+        let max = if addr.to_canonical().is_ipv4() {
+            32
+        } else {
+            128
+        };
+        if prefix_len > max {
+            return TestResult::discard();
+        }
+        let subnet = Subnet::new(addr, prefix_len).unwrap();
+        TestResult::from_bool(subnet.contains(addr) && Subnet::host(addr).contains(addr))
+    }
+
+    #[test]
+    fn test_misbehaving() {
+        let now = Instant::now();
+        let bans = BanManager::new(100, Duration::from_secs(60));
+        let addr = ip("1.2.3.4");
+        let misbehavior = Misbehavior::new();
+
+        assert!(!bans.misbehaving(addr, &misbehavior, 60, now));
+        assert_eq!(misbehavior.score(), 60);
+        assert!(!bans.is_banned(addr, now));
+
+        // a new connection of the same address starts from zero
+        let reconnected = Misbehavior::new();
+        assert!(!bans.misbehaving(addr, &reconnected, 60, now));
+        assert!(!bans.is_banned(addr, now));
+
+        // clones share the score
+        assert!(bans.misbehaving(addr, &misbehavior.clone(), 40, now));
+        assert!(bans.is_banned(addr, now));
+        assert!(!bans.is_banned(ip("1.2.3.5"), now));
+        assert_eq!(misbehavior.score(), 100);
+
+        // the peer stays misbehaving, but the address is only banned as the threshold is crossed
+        let later = now + Duration::from_secs(30);
+        assert!(bans.misbehaving(addr, &misbehavior, 10, later));
+        assert_eq!(bans.bans(now)[0].until, now + Duration::from_secs(60));
+
+        // the ban expires
+        assert!(!bans.is_banned(addr, now + Duration::from_secs(60)));
+        assert!(bans.bans(now + Duration::from_secs(60)).is_empty());
+    }
+
+    #[test]
+    fn test_local_peers_not_banned() {
+        let now = Instant::now();
+        let bans = BanManager::default();
+
+        // peers reached by name and onion peers forwarded by Tor are disconnected without a ban
+        for addr in [
+            ip("::"),
+            ip("0.0.0.0"),
+            ip("127.0.0.1"),
+            ip("::1"),
+            ip("::ffff:127.0.0.1"),
+        ] {
+            let misbehavior = Misbehavior::new();
+            assert!(!bans.misbehaving(addr, &misbehavior, 99, now));
+            assert!(bans.misbehaving(addr, &misbehavior, 1, now));
+            assert!(!bans.is_banned(addr, now));
+        }
+        assert!(bans.bans(now).is_empty());
+    }
+
+    #[test]
+    fn test_report() {
+        let now = Instant::now();
+        let bans = BanManager::default();
+        let misbehavior = Misbehavior::new();
+        assert!(!bans.report(ip("1.2.3.4"), &misbehavior, &BTCP2PError::PingTimeout, now));
+        assert_eq!(misbehavior.score(), 0);
+        // a wrong network only disconnects
+        assert!(!bans.report(
+            ip("1.2.3.4"),
+            &misbehavior,
+            &BTCP2PError::NetworkMismatch(crate::network::Network::MainNet),
+            now
+        ));
+        assert_eq!(misbehavior.score(), 0);
+        assert!(bans.report(
+            ip("1.2.3.4"),
+            &misbehavior,
+            &BTCP2PError::InvalidProofOfWork,
+            now
+        ));
+    }
+
+    #[test]
+    fn test_ban_subnet() {
+        let now = Instant::now();
+        let bans = BanManager::default();
+        let subnet: Subnet = "192.168.0.0/24".parse().unwrap();
+        let host = Subnet::host(ip("10.0.0.1"));
+
+        bans.ban(subnet, Duration::from_secs(10), now);
+        bans.ban(host, Duration::from_secs(5), now);
+        assert!(bans.is_banned(ip("192.168.0.77"), now));
+        assert_eq!(
+            bans.bans(now),
+            vec![
+                Ban {
+                    subnet: host,
+                    until: now + Duration::from_secs(5)
+                },
+                Ban {
+                    subnet,
+                    until: now + Duration::from_secs(10)
+                },
+            ]
+        );
+
+        assert!(bans.unban(&subnet));
+        assert!(!bans.unban(&subnet));
+        assert!(!bans.is_banned(ip("192.168.0.77"), now));
+
+        // clones share the bans
+        bans.clone().clear();
+        assert!(bans.bans(now).is_empty());
+    }
+}
@@ -0,0 +1,325 @@
+use std::{
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use super::sync::lock;
+
+/// Length of the cycle of the upload target, as `-maxuploadtarget` of Bitcoin Core
+pub const UPLOAD_TARGET_CYCLE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Blocks older than the tip by more than this are historical, they stop being served once the upload
+/// target is near
+pub const HISTORICAL_BLOCK_AGE: u32 = 7 * 24 * 60 * 60;
+
+/// Maximum size of a serialized block, room for a block every ten minutes is kept in the upload target
+const MAX_BLOCK_SERIALIZED_SIZE: u64 = 4_000_000;
+const BLOCK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// TokenBucket limits a flow of bytes to a rate, with bursts up to its capacity
+///
+/// Messages are never split, so a message larger than the tokens left is let through and the bucket goes
+/// into debt, the returned delay is the time it takes to pay the debt back.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenBucket {
+    rate: u64,
+    capacity: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// new creates a full bucket refilled with rate bytes per second, holding a second of bytes
+    pub fn new(rate: u64, now: Instant) -> Self {
+        Self::with_capacity(rate, rate, now)
+    }
+
+    pub fn with_capacity(rate: u64, capacity: u64, now: Instant) -> Self {
+        Self {
+            rate: rate.max(1),
+            capacity,
+            tokens: capacity as f64,
+            last: now,
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// consume takes bytes from the bucket, returns how long to wait before sending them
+    pub fn consume(&mut self, bytes: usize, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = self.last.max(now);
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.capacity as f64);
+        self.tokens -= bytes as f64;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate as f64)
+        }
+    }
+}
+
+/// BandwidthLimits configures the rate limits in bytes per second and the daily upload target in bytes
+/// a limit of None is unlimited
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BandwidthLimits {
+    /// Rate of all uploads.
+    pub upload_rate: Option<u64>,
+
+    /// Rate of all downloads.
+    pub download_rate: Option<u64>,
+
+    /// Rate of uploads to each peer.
+    pub peer_upload_rate: Option<u64>,
+
+    /// Rate of downloads from each peer.
+    pub peer_download_rate: Option<u64>,
+
+    /// Bytes to upload per cycle, historical blocks are not served once it is near.
+    pub upload_target: Option<u64>,
+}
+
+/// Bandwidth applies the bandwidth limits to every peer, clones share the global limits and the upload target
+///
+/// Limits are applied to framed messages after the handshake. Peers wait for the tokens of each message they
+/// send, and wait for the tokens of each message they receive before reading the next one, so the remote side
+/// is slowed down by TCP flow control.
+#[derive(Debug, Clone)]
+pub struct Bandwidth(Arc<Mutex<BandwidthState>>);
+
+#[derive(Debug)]
+struct BandwidthState {
+    limits: BandwidthLimits,
+    upload: Option<TokenBucket>,
+    download: Option<TokenBucket>,
+    cycle_start: Option<Instant>,
+    uploaded: u64,
+}
+
+impl Default for Bandwidth {
+    fn default() -> Self {
+        Self::new(BandwidthLimits::default())
+    }
+}
+
+impl Bandwidth {
+    pub fn new(limits: BandwidthLimits) -> Self {
+        let now = Instant::now();
+        Self(Arc::new(Mutex::new(BandwidthState {
+            limits,
+            upload: limits.upload_rate.map(|rate| TokenBucket::new(rate, now)),
+            download: limits.download_rate.map(|rate| TokenBucket::new(rate, now)),
+            cycle_start: None,
+            uploaded: 0,
+        })))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BandwidthState> {
+        lock(&self.0)
+    }
+
+    pub fn limits(&self) -> BandwidthLimits {
+        self.lock().limits
+    }
+
+    /// uploaded returns the bytes sent during the current cycle of the upload target
+    pub fn uploaded(&self, now: Instant) -> u64 {
+        let mut state = self.lock();
+        state.roll_cycle(now);
+        state.uploaded
+    }
+
+    /// upload_target_left returns the bytes left to send during the current cycle, None without a target
+    pub fn upload_target_left(&self, now: Instant) -> Option<u64> {
+        let mut state = self.lock();
+        state.roll_cycle(now);
+        let target = state.limits.upload_target?;
+        Some(target.saturating_sub(state.uploaded))
+    }
+
+    /// upload_target_reached checks if the upload target of the current cycle is used up
+    pub fn upload_target_reached(&self, now: Instant) -> bool {
+        self.upload_target_left(now) == Some(0)
+    }
+
+    /// serve_historical_block checks if a block may be sent to a peer which asked for it
+    ///
+    /// Blocks older than the tip by more than `HISTORICAL_BLOCK_AGE` are not served once the bytes left in the
+    /// cycle are less than a full block every ten minutes until the cycle ends, so recent blocks can still be
+    /// relayed. Bitcoin Core disconnects peers asking for historical blocks at that point.
+    pub fn serve_historical_block(&self, block_time: u32, tip_time: u32, now: Instant) -> bool {
+        if tip_time.saturating_sub(block_time) <= HISTORICAL_BLOCK_AGE {
+            return true;
+        }
+
+        let mut state = self.lock();
+        state.roll_cycle(now);
+        let Some(target) = state.limits.upload_target else {
+            return true;
+        };
+
+        let cycle_end = state.cycle_start.unwrap_or(now) + UPLOAD_TARGET_CYCLE;
+        let time_left = cycle_end.saturating_duration_since(now);
+        let buffer = (time_left.as_secs() / BLOCK_INTERVAL.as_secs()) * MAX_BLOCK_SERIALIZED_SIZE;
+        buffer < target && state.uploaded < target - buffer
+    }
+}
+
+impl BandwidthState {
+    /// roll_cycle starts a new cycle of the upload target once the current one is over
+    fn roll_cycle(&mut self, now: Instant) {
+        match self.cycle_start {
+            Some(start) if now < start + UPLOAD_TARGET_CYCLE => {}
+            _ => {
+                self.cycle_start = Some(now);
+                self.uploaded = 0;
+            }
+        }
+    }
+}
+
+/// Direction is the flow of bytes a throttle limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Upload,
+    Download,
+}
+
+/// Throttle limits the messages of a peer in one direction, by the limits of the peer and the global limits
+#[derive(Debug)]
+pub(crate) struct Throttle {
+    bandwidth: Bandwidth,
+    direction: Direction,
+    bucket: Option<TokenBucket>,
+}
+
+impl Throttle {
+    pub(crate) fn upload(bandwidth: &Bandwidth, now: Instant) -> Self {
+        let rate = bandwidth.limits().peer_upload_rate;
+        Self {
+            bandwidth: bandwidth.clone(),
+            direction: Direction::Upload,
+            bucket: rate.map(|rate| TokenBucket::new(rate, now)),
+        }
+    }
+
+    pub(crate) fn download(bandwidth: &Bandwidth, now: Instant) -> Self {
+        let rate = bandwidth.limits().peer_download_rate;
+        Self {
+            bandwidth: bandwidth.clone(),
+            direction: Direction::Download,
+            bucket: rate.map(|rate| TokenBucket::new(rate, now)),
+        }
+    }
+
+    /// delay accounts a message of the given size, returns how long to wait before passing it on
+    pub(crate) fn delay(&mut self, bytes: usize, now: Instant) -> Duration {
+        let peer = self
+            .bucket
+            .as_mut()
+            .map_or(Duration::ZERO, |bucket| bucket.consume(bytes, now));
+
+        let mut state = self.bandwidth.lock();
+        let global = match self.direction {
+            Direction::Upload => {
+                state.roll_cycle(now);
+                state.uploaded += bytes as u64;
+                state.upload.as_mut()
+            }
+            Direction::Download => state.download.as_mut(),
+        }
+        .map_or(Duration::ZERO, |bucket| bucket.consume(bytes, now));
+
+        peer.max(global)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(1000, now);
+
+        // a full bucket lets a second of bytes through
+        assert_eq!(bucket.consume(600, now), Duration::ZERO);
+        assert_eq!(bucket.consume(400, now), Duration::ZERO);
+
+        // larger messages go into debt
+        assert_eq!(bucket.consume(500, now), Duration::from_millis(500));
+        assert_eq!(
+            bucket.consume(0, now + Duration::from_millis(500)),
+            Duration::ZERO
+        );
+
+        // tokens do not pile up past the capacity
+        let later = now + Duration::from_secs(60);
+        assert_eq!(bucket.consume(1000, later), Duration::ZERO);
+        assert_eq!(bucket.consume(2000, later), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_throttle() {
+        let now = Instant::now();
+        let bandwidth = Bandwidth::new(BandwidthLimits {
+            upload_rate: Some(1000),
+            peer_upload_rate: Some(500),
+            ..Default::default()
+        });
+        let mut first = Throttle::upload(&bandwidth, now);
+        let mut second = Throttle::upload(&bandwidth, now);
+
+        // the peer limit is hit first
+        assert_eq!(first.delay(1000, now), Duration::from_secs(1));
+
+        // then the global limit, which the first peer used up
+        assert_eq!(second.delay(500, now), Duration::from_millis(500));
+        assert_eq!(bandwidth.uploaded(now), 1500);
+
+        // downloads have no limit
+        let mut download = Throttle::download(&bandwidth, now);
+        assert_eq!(download.delay(1_000_000, now), Duration::ZERO);
+        assert_eq!(bandwidth.uploaded(now), 1500);
+    }
+
+    #[test]
+    fn test_upload_target() {
+        let now = Instant::now();
+        let day = 144 * MAX_BLOCK_SERIALIZED_SIZE;
+        let bandwidth = Bandwidth::new(BandwidthLimits {
+            upload_target: Some(day + 10_000_000),
+            ..Default::default()
+        });
+        let mut throttle = Throttle::upload(&bandwidth, now);
+        let tip_time = 1_700_000_000;
+        let historical = tip_time - HISTORICAL_BLOCK_AGE - 1;
+
+        assert!(bandwidth.serve_historical_block(historical, tip_time, now));
+        throttle.delay(9_000_000, now);
+        assert!(bandwidth.serve_historical_block(historical, tip_time, now));
+
+        // the rest of the target is kept for recent blocks
+        throttle.delay(1_000_000, now);
+        assert!(!bandwidth.serve_historical_block(historical, tip_time, now));
+        assert!(bandwidth.serve_historical_block(historical + 1, tip_time, now));
+        assert!(!bandwidth.upload_target_reached(now));
+        assert_eq!(bandwidth.upload_target_left(now), Some(day));
+
+        // as the cycle goes on, less room is kept
+        let evening = now + UPLOAD_TARGET_CYCLE / 2;
+        assert!(bandwidth.serve_historical_block(historical, tip_time, evening));
+
+        throttle.delay(day as usize, evening);
+        assert!(bandwidth.upload_target_reached(evening));
+
+        // the target is reset with the cycle
+        let tomorrow = now + UPLOAD_TARGET_CYCLE;
+        assert_eq!(bandwidth.uploaded(tomorrow), 0);
+        assert!(bandwidth.serve_historical_block(historical, tip_time, tomorrow));
+    }
+}
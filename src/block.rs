@@ -0,0 +1,249 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Read, Write};
+
+use super::{
+    encode::{read_compact_size, read_hashes, read_vec_len, write_compact_size, write_hashes},
+    errors::{BTCP2PError, Result},
+    hash::sha256d,
+    pow::U256,
+};
+
+/// Size of a serialized block header
+pub const BLOCK_HEADER_SIZE: usize = 80;
+
+/// Maximum number of headers in a headers message, as in Bitcoin Core
+pub const MAX_HEADERS_RESULTS: usize = 2000;
+
+/// Maximum number of hashes in a block locator, as in Bitcoin Core
+pub const MAX_LOCATOR_SIZE: usize = 101;
+
+/// BlockHeader represents the header of a block
+/// https://developer.bitcoin.org/reference/block_chain.html#block-headers
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockHeader {
+    /// The block version number indicates which set of block validation rules to follow.
+    pub version: i32,
+
+    /// The hash of the previous block header in byte order.
+    pub prev_blockhash: [u8; 32],
+
+    /// The merkle root of the transactions of the block in byte order.
+    pub merkle_root: [u8; 32],
+
+    /// The Unix epoch time when the miner started hashing the header.
+    pub time: u32,
+
+    /// The target threshold this header's hash must be less than or equal to, in compact format.
+    pub bits: u32,
+
+    /// An arbitrary number miners change to modify the header hash.
+    pub nonce: u32,
+}
+
+impl BlockHeader {
+    /// to_bytes converts the header to bytes
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut buffer = Vec::with_capacity(BLOCK_HEADER_SIZE);
+        buffer.write_i32::<LittleEndian>(self.version)?;
+        buffer.write_all(&self.prev_blockhash)?;
+        buffer.write_all(&self.merkle_root)?;
+        buffer.write_u32::<LittleEndian>(self.time)?;
+        buffer.write_u32::<LittleEndian>(self.bits)?;
+        buffer.write_u32::<LittleEndian>(self.nonce)?;
+        Ok(buffer)
+    }
+
+    /// from_bytes converts bytes to a header
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self> {
+        Self::read(&mut bytes)
+    }
+
+    /// read reads a header from a reader, leaving any trailing bytes
+    pub(crate) fn read<R: Read>(reader: &mut R) -> Result<Self> {
+        let version = reader.read_i32::<LittleEndian>()?;
+        let mut prev_blockhash = [0u8; 32];
+        reader.read_exact(&mut prev_blockhash)?;
+        let mut merkle_root = [0u8; 32];
+        reader.read_exact(&mut merkle_root)?;
+
+        Ok(Self {
+            version,
+            prev_blockhash,
+            merkle_root,
+            time: reader.read_u32::<LittleEndian>()?,
+            bits: reader.read_u32::<LittleEndian>()?,
+            nonce: reader.read_u32::<LittleEndian>()?,
+        })
+    }
+
+    /// hash returns the block hash in byte order, the double SHA256 of the serialized header
+    pub fn hash(&self) -> [u8; 32] {
+        let bytes = self.to_bytes().expect("write header to vec");
+        sha256d(&bytes)
+    }
+
+    /// work returns the expected number of hashes to find the header, zero if its bits are invalid
+    pub fn work(&self) -> U256 {
+        match U256::from_compact(self.bits) {
+            Some(target) if !target.is_zero() => target.work(),
+            _ => U256::ZERO,
+        }
+    }
+}
+
+/// GetHeadersPayload represents the payload of a getheaders message
+/// https://developer.bitcoin.org/reference/p2p_networking.html#getheaders
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GetHeadersPayload {
+    /// The protocol version of the transmitting node.
+    pub version: u32,
+
+    /// Hashes of our best chain from the tip back to genesis, spaced further apart as they go back.
+    pub locator: Vec<[u8; 32]>,
+
+    /// The hash of the last header to send, zero to send as many as possible.
+    pub stop_hash: [u8; 32],
+}
+
+impl GetHeadersPayload {
+    /// to_bytes converts the payload to bytes
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut buffer = vec![];
+        buffer.write_u32::<LittleEndian>(self.version)?;
+        write_hashes(&mut buffer, &self.locator)?;
+        buffer.write_all(&self.stop_hash)?;
+        Ok(buffer)
+    }
+
+    /// from_bytes converts bytes to a payload
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self> {
+        let version = bytes.read_u32::<LittleEndian>()?;
+        let locator = read_hashes(&mut bytes)?;
+        if locator.len() > MAX_LOCATOR_SIZE {
+            return Err(BTCP2PError::TooManyLocatorHashes(locator.len()));
+        }
+        let mut stop_hash = [0u8; 32];
+        bytes.read_exact(&mut stop_hash)?;
+
+        Ok(Self {
+            version,
+            locator,
+            stop_hash,
+        })
+    }
+}
+
+/// HeadersPayload represents the payload of a headers message
+/// https://developer.bitcoin.org/reference/p2p_networking.html#headers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeadersPayload {
+    pub headers: Vec<BlockHeader>,
+}
+
+impl HeadersPayload {
+    /// to_bytes converts the payload to bytes, each header is followed by an empty transaction count
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut buffer = vec![];
+        write_compact_size(&mut buffer, self.headers.len() as u64)?;
+        for header in &self.headers {
+            buffer.write_all(&header.to_bytes()?)?;
+            write_compact_size(&mut buffer, 0)?;
+        }
+        Ok(buffer)
+    }
+
+    /// from_bytes converts bytes to a payload, transaction counts are ignored as Bitcoin Core does
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self> {
+        let len = read_vec_len(&mut bytes, BLOCK_HEADER_SIZE + 1)?;
+        if len > MAX_HEADERS_RESULTS {
+            return Err(BTCP2PError::TooManyHeaders(len));
+        }
+
+        let headers = (0..len)
+            .map(|_| {
+                let header = BlockHeader::read(&mut bytes)?;
+                read_compact_size(&mut bytes)?;
+                Ok(header)
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { headers })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::{Arbitrary, TestResult};
+    use quickcheck_macros::quickcheck;
+
+    impl Arbitrary for BlockHeader {
+        fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+            Self {
+                version: i32::arbitrary(g),
+                prev_blockhash: [u8::arbitrary(g); 32],
+                merkle_root: [u8::arbitrary(g); 32],
+                time: u32::arbitrary(g),
+                bits: u32::arbitrary(g),
+                nonce: u32::arbitrary(g),
+            }
+        }
+    }
+
+    impl Arbitrary for GetHeadersPayload {
+        fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+            let len = usize::arbitrary(g) % MAX_LOCATOR_SIZE;
+            Self {
+                version: u32::arbitrary(g),
+                locator: (0..len).map(|_| [u8::arbitrary(g); 32]).collect(),
+                stop_hash: [u8::arbitrary(g); 32],
+            }
+        }
+    }
+
+    impl Arbitrary for HeadersPayload {
+        fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+            Self {
+                headers: Vec::arbitrary(g),
+            }
+        }
+    }
+
+    #[quickcheck]
+    fn test_headers_to_bytes(payload: HeadersPayload) -> TestResult {
+        let bytes = payload.to_bytes().unwrap();
+        TestResult::from_bool(payload == HeadersPayload::from_bytes(&bytes).unwrap())
+    }
+
+    #[test]
+    fn test_too_many_headers() {
+        let headers = HeadersPayload {
+            headers: vec![
+                BlockHeader::arbitrary(&mut quickcheck::Gen::new(1));
+                MAX_HEADERS_RESULTS + 1
+            ],
+        };
+        assert!(matches!(
+            HeadersPayload::from_bytes(&headers.to_bytes().unwrap()),
+            Err(BTCP2PError::TooManyHeaders(2001))
+        ));
+
+        let getheaders = GetHeadersPayload {
+            version: 70016,
+            locator: vec![[0; 32]; MAX_LOCATOR_SIZE + 1],
+            stop_hash: [0; 32],
+        };
+        assert!(matches!(
+            GetHeadersPayload::from_bytes(&getheaders.to_bytes().unwrap()),
+            Err(BTCP2PError::TooManyLocatorHashes(102))
+        ));
+    }
+
+    #[quickcheck]
+    fn test_to_bytes(header: BlockHeader) -> TestResult {
+        let bytes = header.to_bytes().unwrap();
+        if bytes.len() != BLOCK_HEADER_SIZE {
+            return TestResult::failed();
+        }
+        TestResult::from_bool(header == BlockHeader::from_bytes(&bytes).unwrap())
+    }
+}
@@ -0,0 +1,399 @@
+use super::{
+    block::BlockHeader,
+    errors::{BTCP2PError, Result},
+    hash::hash_from_hex,
+    network::Network,
+};
+
+/// Merkle root of the genesis block shared by mainnet, testnet3, regtest and signet
+const GENESIS_MERKLE_ROOT: [u8; 32] =
+    hash_from_hex("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b");
+
+/// Two weeks, the time the retarget interval is expected to take
+const POW_TARGET_TIMESPAN: u32 = 14 * 24 * 60 * 60;
+
+/// Ten minutes, the expected time between two blocks
+const POW_TARGET_SPACING: u32 = 10 * 60;
+
+/// ChainParams holds the consensus and networking parameters of a chain
+/// https://github.com/bitcoin/bitcoin/blob/master/src/kernel/chainparams.cpp
+///
+/// Custom networks can build their own parameters from an existing set, as `Network::signet` does for
+/// custom signets: `ChainParams { network, ..ChainParams::signet() }`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainParams {
+    /// The network the parameters belong to, which defines the magic bytes.
+    pub network: Network,
+
+    /// The port nodes listen on by default.
+    pub default_port: u16,
+
+    /// DNS seeds used to discover nodes.
+    pub dns_seeds: &'static [&'static str],
+
+    /// The first block header of the chain.
+    pub genesis_header: BlockHeader,
+
+    /// The highest target a block hash may have, in byte order as hashes.
+    pub pow_limit: [u8; 32],
+
+    /// The time the retarget interval is expected to take, in seconds.
+    pub pow_target_timespan: u32,
+
+    /// The expected time between two blocks, in seconds.
+    pub pow_target_spacing: u32,
+
+    /// Whether a block more than twice the target spacing after its parent may use the minimum difficulty.
+    pub pow_allow_min_difficulty_blocks: bool,
+
+    /// Whether the difficulty never changes.
+    pub pow_no_retargeting: bool,
+
+    /// Whether the BIP94 rules (testnet4) apply.
+    pub enforce_bip94: bool,
+
+    /// Height from which the coinbase must start with the block height (BIP34).
+    pub bip34_height: u32,
+
+    /// Height from which OP_CHECKLOCKTIMEVERIFY is enforced (BIP65).
+    pub bip65_height: u32,
+
+    /// Height from which strict DER signatures are enforced (BIP66).
+    pub bip66_height: u32,
+
+    /// Height from which relative lock-times are enforced (BIP68, BIP112 and BIP113).
+    pub csv_height: u32,
+
+    /// Height from which segregated witness is enforced (BIP141, BIP143 and BIP147).
+    pub segwit_height: u32,
+
+    /// Known block hashes at given heights, in byte order.
+    pub checkpoints: &'static [(u32, [u8; 32])],
+}
+
+impl ChainParams {
+    pub fn mainnet() -> Self {
+        Self {
+            network: Network::MainNet,
+            default_port: 8333,
+            dns_seeds: &[
+                "seed.bitcoin.sipa.be",
+                "dnsseed.bluematt.me",
+                "dnsseed.bitcoin.dashjr-list-of-p2p-nodes.us",
+                "seed.bitcoinstats.com",
+                "seed.bitcoin.jonasschnelli.ch",
+                "seed.btc.petertodd.net",
+                "seed.bitcoin.sprovoost.nl",
+                "dnsseed.emzy.de",
+                "seed.bitcoin.wiz.biz",
+                "seed.mainnet.achownodes.xyz",
+            ],
+            genesis_header: BlockHeader {
+                version: 1,
+                prev_blockhash: [0u8; 32],
+                merkle_root: GENESIS_MERKLE_ROOT,
+                time: 1231006505,
+                bits: 0x1d00ffff,
+                nonce: 2083236893,
+            },
+            pow_limit: hash_from_hex(
+                "00000000ffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+            ),
+            pow_target_timespan: POW_TARGET_TIMESPAN,
+            pow_target_spacing: POW_TARGET_SPACING,
+            pow_allow_min_difficulty_blocks: false,
+            pow_no_retargeting: false,
+            enforce_bip94: false,
+            bip34_height: 227931,
+            bip65_height: 388381,
+            bip66_height: 363725,
+            csv_height: 419328,
+            segwit_height: 481824,
+            checkpoints: &MAINNET_CHECKPOINTS,
+        }
+    }
+
+    pub fn testnet() -> Self {
+        Self {
+            network: Network::TestNet,
+            default_port: 18333,
+            dns_seeds: &[
+                "testnet-seed.bitcoin.jonasschnelli.ch",
+                "seed.tbtc.petertodd.net",
+                "seed.testnet.bitcoin.sprovoost.nl",
+                "testnet-seed.bluematt.me",
+                "seed.testnet.achownodes.xyz",
+            ],
+            genesis_header: BlockHeader {
+                version: 1,
+                prev_blockhash: [0u8; 32],
+                merkle_root: GENESIS_MERKLE_ROOT,
+                time: 1296688602,
+                bits: 0x1d00ffff,
+                nonce: 414098458,
+            },
+            pow_limit: hash_from_hex(
+                "00000000ffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+            ),
+            pow_target_timespan: POW_TARGET_TIMESPAN,
+            pow_target_spacing: POW_TARGET_SPACING,
+            pow_allow_min_difficulty_blocks: true,
+            pow_no_retargeting: false,
+            enforce_bip94: false,
+            bip34_height: 21111,
+            bip65_height: 581885,
+            bip66_height: 330776,
+            csv_height: 770112,
+            segwit_height: 834624,
+            checkpoints: &TESTNET_CHECKPOINTS,
+        }
+    }
+
+    pub fn testnet4() -> Self {
+        Self {
+            network: Network::TestNet4,
+            default_port: 48333,
+            dns_seeds: &[
+                "seed.testnet4.bitcoin.sprovoost.nl",
+                "seed.testnet4.wiz.biz",
+            ],
+            genesis_header: BlockHeader {
+                version: 1,
+                prev_blockhash: [0u8; 32],
+                merkle_root: hash_from_hex(
+                    "7aa0a7ae1e223414cb807e40cd57e667b718e42aaf9306db9102fe28912b7b4e",
+                ),
+                time: 1714777860,
+                bits: 0x1d00ffff,
+                nonce: 393743547,
+            },
+            pow_limit: hash_from_hex(
+                "00000000ffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+            ),
+            pow_target_timespan: POW_TARGET_TIMESPAN,
+            pow_target_spacing: POW_TARGET_SPACING,
+            pow_allow_min_difficulty_blocks: true,
+            pow_no_retargeting: false,
+            enforce_bip94: true,
+            bip34_height: 1,
+            bip65_height: 1,
+            bip66_height: 1,
+            csv_height: 1,
+            segwit_height: 1,
+            checkpoints: &[],
+        }
+    }
+
+    pub fn signet() -> Self {
+        Self {
+            network: Network::Signet,
+            default_port: 38333,
+            dns_seeds: &[
+                "seed.signet.bitcoin.sprovoost.nl",
+                "seed.signet.achownodes.xyz",
+            ],
+            genesis_header: BlockHeader {
+                version: 1,
+                prev_blockhash: [0u8; 32],
+                merkle_root: GENESIS_MERKLE_ROOT,
+                time: 1598918400,
+                bits: 0x1e0377ae,
+                nonce: 52613770,
+            },
+            pow_limit: hash_from_hex(
+                "00000377ae000000000000000000000000000000000000000000000000000000",
+            ),
+            pow_target_timespan: POW_TARGET_TIMESPAN,
+            pow_target_spacing: POW_TARGET_SPACING,
+            pow_allow_min_difficulty_blocks: false,
+            pow_no_retargeting: false,
+            enforce_bip94: false,
+            bip34_height: 1,
+            bip65_height: 1,
+            bip66_height: 1,
+            csv_height: 1,
+            segwit_height: 1,
+            checkpoints: &[],
+        }
+    }
+
+    pub fn regtest() -> Self {
+        Self {
+            network: Network::RegTest,
+            default_port: 18444,
+            dns_seeds: &[],
+            genesis_header: BlockHeader {
+                version: 1,
+                prev_blockhash: [0u8; 32],
+                merkle_root: GENESIS_MERKLE_ROOT,
+                time: 1296688602,
+                bits: 0x207fffff,
+                nonce: 2,
+            },
+            pow_limit: hash_from_hex(
+                "7fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+            ),
+            pow_target_timespan: POW_TARGET_TIMESPAN,
+            pow_target_spacing: POW_TARGET_SPACING,
+            pow_allow_min_difficulty_blocks: true,
+            pow_no_retargeting: true,
+            enforce_bip94: false,
+            bip34_height: 1,
+            bip65_height: 1,
+            bip66_height: 1,
+            csv_height: 1,
+            segwit_height: 0,
+            checkpoints: &[],
+        }
+    }
+
+    /// retarget_interval is the number of blocks between two difficulty adjustments
+    pub fn retarget_interval(&self) -> u32 {
+        self.pow_target_timespan / self.pow_target_spacing
+    }
+
+    /// genesis_hash returns the hash of the genesis block in byte order
+    pub fn genesis_hash(&self) -> [u8; 32] {
+        self.genesis_header.hash()
+    }
+
+    /// checkpoint returns the expected block hash at a height, if there is a checkpoint there
+    pub fn checkpoint(&self, height: u32) -> Option<[u8; 32]> {
+        self.checkpoints
+            .iter()
+            .find(|(checkpoint_height, _)| *checkpoint_height == height)
+            .map(|(_, hash)| *hash)
+    }
+}
+
+impl TryFrom<Network> for ChainParams {
+    type Error = BTCP2PError;
+
+    /// Custom networks are only known by their magic bytes, their parameters must be built by the caller
+    fn try_from(network: Network) -> Result<Self> {
+        match network {
+            Network::MainNet => Ok(Self::mainnet()),
+            Network::TestNet => Ok(Self::testnet()),
+            Network::TestNet4 => Ok(Self::testnet4()),
+            Network::Signet => Ok(Self::signet()),
+            Network::RegTest => Ok(Self::regtest()),
+            Network::Custom(_) => Err(BTCP2PError::UnknowNetwork),
+        }
+    }
+}
+
+const MAINNET_CHECKPOINTS: [(u32, [u8; 32]); 13] = [
+    (
+        11111,
+        hash_from_hex("0000000069e244f73d78e8fd29ba2fd2ed618bd6fa2ee92559f542fdb26e7c1d"),
+    ),
+    (
+        33333,
+        hash_from_hex("000000002dd5588a74784eaa7ab0507a18ad16a236e7b1ce69f00d7ddfb5d0a6"),
+    ),
+    (
+        74000,
+        hash_from_hex("0000000000573993a3c9e41ce34471c079dcf5f52a0e824a81e7f953b8661a20"),
+    ),
+    (
+        105000,
+        hash_from_hex("00000000000291ce28027faea320c8d2b054b2e0fe44a773f3eefb151d6bdc97"),
+    ),
+    (
+        134444,
+        hash_from_hex("00000000000005b12ffd4cd315cd34ffd4a594f430ac814c91184a0d42d2b0fe"),
+    ),
+    (
+        168000,
+        hash_from_hex("000000000000099e61ea72015e79632f216fe6cb33d7899acb35b75c8303b763"),
+    ),
+    (
+        193000,
+        hash_from_hex("000000000000059f452a5f7340de6682a977387c17010ff6e6c3bd83ca8b1317"),
+    ),
+    (
+        210000,
+        hash_from_hex("000000000000048b95347e83192f69cf0366076336c639f9b7228e9ba171342e"),
+    ),
+    (
+        216116,
+        hash_from_hex("00000000000001b4f4b433e81ee46494af945cf96014816a4e2370f11b23df4e"),
+    ),
+    (
+        225430,
+        hash_from_hex("00000000000001c108384350f74090433e7fcf79a606b8e797f065b130575932"),
+    ),
+    (
+        250000,
+        hash_from_hex("000000000000003887df1f29024b06fc2200b55f8af8f35453d7be294df2d214"),
+    ),
+    (
+        279000,
+        hash_from_hex("0000000000000001ae8c72a0b0c301f67e3afca10e819efa9041e458e9bd7e40"),
+    ),
+    (
+        295000,
+        hash_from_hex("00000000000000004d9b4ef50f0f9d686fd69db2e03af35a100370c64632a983"),
+    ),
+];
+
+const TESTNET_CHECKPOINTS: [(u32, [u8; 32]); 1] = [(
+    546,
+    hash_from_hex("000000002a936ca763904c3c35fce2f3556c559c0214345d31b1bcebf76acb70"),
+)];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_genesis_hash() {
+        for (params, hash) in [
+            (
+                ChainParams::mainnet(),
+                "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+            ),
+            (
+                ChainParams::testnet(),
+                "000000000933ea01ad0ee984209779baaec3ced90fa3f408719526f8d77f4943",
+            ),
+            (
+                ChainParams::testnet4(),
+                "00000000da84f2bafbbc53dee25a72ae507ff4914b867c565be350b0da8bf043",
+            ),
+            (
+                ChainParams::signet(),
+                "00000008819873e925422c1ff0f99f7cc9bbb232af63a077a480a3633bee1ef6",
+            ),
+            (
+                ChainParams::regtest(),
+                "0f9188f13cb7b2c71f2a335e3a4fc328bf5beb436012afca590b1a11466e2206",
+            ),
+        ] {
+            assert_eq!(params.genesis_hash(), hash_from_hex(hash));
+            assert_eq!(params.network.genesis_hash(), Some(hash_from_hex(hash)));
+        }
+    }
+
+    #[test]
+    fn test_from_network() {
+        let custom = Network::Custom([0xde, 0xad, 0xbe, 0xef]);
+        assert!(ChainParams::try_from(custom).is_err());
+        assert_eq!(
+            ChainParams::try_from(Network::MainNet).unwrap(),
+            ChainParams::mainnet()
+        );
+    }
+
+    #[test]
+    fn test_retarget_interval() {
+        assert_eq!(ChainParams::mainnet().retarget_interval(), 2016);
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let params = ChainParams::mainnet();
+        assert!(params.checkpoint(11111).is_some());
+        assert!(params.checkpoint(11112).is_none());
+    }
+}
@@ -10,6 +10,28 @@ pub enum Command {
     VerAck,
     Ping,
     Pong,
+    WtxidRelay,
+    SendAddrV2,
+    SendTxRcncl,
+    ReqRecon,
+    Sketch,
+    ReqSketchExt,
+    ReconcilDiff,
+    SendPackages,
+    AncPkgInfo,
+    GetPkgTxns,
+    PkgTxns,
+    Addr,
+    AddrV2,
+    GetAddr,
+    SendHeaders,
+    SendCmpct,
+    FeeFilter,
+    GetHeaders,
+    Headers,
+    Inv,
+    GetData,
+    NotFound,
 }
 
 impl Command {
@@ -23,6 +45,28 @@ impl Command {
             Command::VerAck => "verack".to_string(),
             Command::Ping => "ping".to_string(),
             Command::Pong => "pong".to_string(),
+            Command::WtxidRelay => "wtxidrelay".to_string(),
+            Command::SendAddrV2 => "sendaddrv2".to_string(),
+            Command::SendTxRcncl => "sendtxrcncl".to_string(),
+            Command::ReqRecon => "reqrecon".to_string(),
+            Command::Sketch => "sketch".to_string(),
+            Command::ReqSketchExt => "reqsketchext".to_string(),
+            Command::ReconcilDiff => "reconcildiff".to_string(),
+            Command::SendPackages => "sendpackages".to_string(),
+            Command::AncPkgInfo => "ancpkginfo".to_string(),
+            Command::GetPkgTxns => "getpkgtxns".to_string(),
+            Command::PkgTxns => "pkgtxns".to_string(),
+            Command::Addr => "addr".to_string(),
+            Command::AddrV2 => "addrv2".to_string(),
+            Command::GetAddr => "getaddr".to_string(),
+            Command::SendHeaders => "sendheaders".to_string(),
+            Command::SendCmpct => "sendcmpct".to_string(),
+            Command::FeeFilter => "feefilter".to_string(),
+            Command::GetHeaders => "getheaders".to_string(),
+            Command::Headers => "headers".to_string(),
+            Command::Inv => "inv".to_string(),
+            Command::GetData => "getdata".to_string(),
+            Command::NotFound => "notfound".to_string(),
         };
 
         // padding with null bytes
@@ -41,6 +85,28 @@ impl Command {
             "verack" => Self::VerAck,
             "ping" => Self::Ping,
             "pong" => Self::Pong,
+            "wtxidrelay" => Self::WtxidRelay,
+            "sendaddrv2" => Self::SendAddrV2,
+            "sendtxrcncl" => Self::SendTxRcncl,
+            "reqrecon" => Self::ReqRecon,
+            "sketch" => Self::Sketch,
+            "reqsketchext" => Self::ReqSketchExt,
+            "reconcildiff" => Self::ReconcilDiff,
+            "sendpackages" => Self::SendPackages,
+            "ancpkginfo" => Self::AncPkgInfo,
+            "getpkgtxns" => Self::GetPkgTxns,
+            "pkgtxns" => Self::PkgTxns,
+            "addr" => Self::Addr,
+            "addrv2" => Self::AddrV2,
+            "getaddr" => Self::GetAddr,
+            "sendheaders" => Self::SendHeaders,
+            "sendcmpct" => Self::SendCmpct,
+            "feefilter" => Self::FeeFilter,
+            "getheaders" => Self::GetHeaders,
+            "headers" => Self::Headers,
+            "inv" => Self::Inv,
+            "getdata" => Self::GetData,
+            "notfound" => Self::NotFound,
             _ => return Err(BTCP2PError::InvalidCommand),
         })
     }
@@ -54,11 +120,33 @@ mod tests {
 
     impl Arbitrary for Command {
         fn arbitrary(g: &mut quickcheck::Gen) -> Self {
-            match u8::arbitrary(g) % 4 {
+            match u8::arbitrary(g) % 26 {
                 0 => Self::Version,
                 1 => Self::VerAck,
                 2 => Self::Ping,
                 3 => Self::Pong,
+                4 => Self::SendTxRcncl,
+                5 => Self::ReqRecon,
+                6 => Self::Sketch,
+                7 => Self::ReqSketchExt,
+                8 => Self::ReconcilDiff,
+                9 => Self::SendPackages,
+                10 => Self::AncPkgInfo,
+                11 => Self::GetPkgTxns,
+                12 => Self::WtxidRelay,
+                13 => Self::SendAddrV2,
+                14 => Self::Addr,
+                15 => Self::AddrV2,
+                16 => Self::GetAddr,
+                17 => Self::SendHeaders,
+                18 => Self::SendCmpct,
+                19 => Self::FeeFilter,
+                20 => Self::GetHeaders,
+                21 => Self::Headers,
+                22 => Self::Inv,
+                23 => Self::PkgTxns,
+                24 => Self::GetData,
+                25 => Self::NotFound,
                 _ => unreachable!(),
             }
         }
@@ -89,6 +177,10 @@ mod tests {
             Command::from_bytes("pong".as_bytes()).unwrap(),
             Command::Pong
         );
+        assert_eq!(
+            Command::from_bytes("reqsketchext".as_bytes()).unwrap(),
+            Command::ReqSketchExt
+        );
         assert_eq!(
             Command::from_bytes("version\0\0\0\0".as_bytes()).unwrap(),
             Command::Version
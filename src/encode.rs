@@ -0,0 +1,216 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Read, Write};
+
+use super::errors::{BTCP2PError, Result};
+
+/// write_compact_size writes a CompactSize unsigned integer
+/// https://developer.bitcoin.org/reference/transactions.html#compactsize-unsigned-integers
+pub(crate) fn write_compact_size<W: Write>(writer: &mut W, n: u64) -> Result<()> {
+    match n {
+        0..=0xfc => writer.write_u8(n as u8)?,
+        0xfd..=0xffff => {
+            writer.write_u8(0xfd)?;
+            writer.write_u16::<LittleEndian>(n as u16)?;
+        }
+        0x10000..=0xffff_ffff => {
+            writer.write_u8(0xfe)?;
+            writer.write_u32::<LittleEndian>(n as u32)?;
+        }
+        _ => {
+            writer.write_u8(0xff)?;
+            writer.write_u64::<LittleEndian>(n)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// read_compact_size reads a CompactSize unsigned integer
+/// non-canonical encodings are rejected, as Bitcoin Core does
+pub(crate) fn read_compact_size<R: Read>(reader: &mut R) -> Result<u64> {
+    let (n, min) = match reader.read_u8()? {
+        0xfd => (reader.read_u16::<LittleEndian>()? as u64, 0xfd),
+        0xfe => (reader.read_u32::<LittleEndian>()? as u64, 0x10000),
+        0xff => (reader.read_u64::<LittleEndian>()?, 0x1_0000_0000),
+        n => (n as u64, 0),
+    };
+
+    if n < min {
+        return Err(BTCP2PError::NonCanonicalCompactSize);
+    }
+
+    Ok(n)
+}
+
+/// read_vec_len reads a CompactSize used as the length of a vector
+/// the length is checked against the bytes left so a bogus length can't trigger a huge allocation
+pub(crate) fn read_vec_len(bytes: &mut &[u8], item_size: usize) -> Result<usize> {
+    let len = read_compact_size(bytes)?;
+
+    if len.saturating_mul(item_size as u64) > bytes.len() as u64 {
+        return Err(BTCP2PError::PayloadTooLarge);
+    }
+
+    Ok(len as usize)
+}
+
+/// write_hashes writes a CompactSize prefixed vector of 32 byte hashes
+pub(crate) fn write_hashes<W: Write>(writer: &mut W, hashes: &[[u8; 32]]) -> Result<()> {
+    write_compact_size(writer, hashes.len() as u64)?;
+    for hash in hashes {
+        writer.write_all(hash)?;
+    }
+    Ok(())
+}
+
+/// read_hashes reads a CompactSize prefixed vector of 32 byte hashes
+pub(crate) fn read_hashes(bytes: &mut &[u8]) -> Result<Vec<[u8; 32]>> {
+    let len = read_vec_len(bytes, 32)?;
+    let mut hashes = Vec::with_capacity(len);
+    for _ in 0..len {
+        let mut hash = [0u8; 32];
+        bytes.read_exact(&mut hash)?;
+        hashes.push(hash);
+    }
+    Ok(hashes)
+}
+
+/// Alphabet of base32, lowercase as in onion and I2P names
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Alphabet of the base64 variant used by I2P, with `-` and `~` instead of `+` and `/`
+const I2P_BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-~";
+
+/// base32_encode encodes bytes in lowercase base32 without padding
+pub(crate) fn base32_encode(bytes: &[u8]) -> String {
+    encode_bits(bytes, 5, BASE32_ALPHABET)
+}
+
+/// base32_decode decodes base32 without padding, in either case
+pub(crate) fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    decode_bits(&s.to_ascii_lowercase(), 5, BASE32_ALPHABET)
+}
+
+/// i2p_base64_encode encodes bytes in the base64 of I2P with padding
+#[cfg(test)]
+pub(crate) fn i2p_base64_encode(bytes: &[u8]) -> String {
+    let mut s = encode_bits(bytes, 6, I2P_BASE64_ALPHABET);
+    while !s.len().is_multiple_of(4) {
+        s.push('=');
+    }
+    s
+}
+
+/// i2p_base64_decode decodes the base64 of I2P, with or without padding
+pub(crate) fn i2p_base64_decode(s: &str) -> Option<Vec<u8>> {
+    decode_bits(s.trim_end_matches('='), 6, I2P_BASE64_ALPHABET)
+}
+
+fn encode_bits(bytes: &[u8], bits: u32, alphabet: &[u8]) -> String {
+    let mut s = String::new();
+    let (mut acc, mut acc_bits) = (0u32, 0u32);
+    for byte in bytes {
+        acc = (acc << 8) | *byte as u32;
+        acc_bits += 8;
+        while acc_bits >= bits {
+            acc_bits -= bits;
+            s.push(alphabet[((acc >> acc_bits) & ((1 << bits) - 1)) as usize] as char);
+        }
+    }
+    if acc_bits > 0 {
+        s.push(alphabet[((acc << (bits - acc_bits)) & ((1 << bits) - 1)) as usize] as char);
+    }
+    s
+}
+
+fn decode_bits(s: &str, bits: u32, alphabet: &[u8]) -> Option<Vec<u8>> {
+    let mut bytes = vec![];
+    let (mut acc, mut acc_bits) = (0u32, 0u32);
+    for c in s.bytes() {
+        let value = alphabet.iter().position(|a| *a == c)? as u32;
+        acc = ((acc << bits) | value) & 0xffff;
+        acc_bits += bits;
+        if acc_bits >= 8 {
+            acc_bits -= 8;
+            bytes.push((acc >> acc_bits) as u8);
+        }
+    }
+    // the leftover bits are padding and must be zero
+    if acc & ((1 << acc_bits) - 1) != 0 {
+        return None;
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::TestResult;
+    use quickcheck_macros::quickcheck;
+
+    #[quickcheck]
+    fn test_compact_size(n: u64) -> TestResult {
+        let mut buffer = vec![];
+        write_compact_size(&mut buffer, n).unwrap();
+        let n2 = read_compact_size(&mut &buffer[..]).unwrap();
+        TestResult::from_bool(n == n2)
+    }
+
+    #[quickcheck]
+    fn test_hashes(hashes: Vec<u64>) -> TestResult {
+        let hashes = hashes
+            .into_iter()
+            .map(|n| [n as u8; 32])
+            .collect::<Vec<_>>();
+
+        let mut buffer = vec![];
+        write_hashes(&mut buffer, &hashes).unwrap();
+        let hashes2 = read_hashes(&mut &buffer[..]).unwrap();
+        TestResult::from_bool(hashes == hashes2)
+    }
+
+    #[quickcheck]
+    fn test_base32(bytes: Vec<u8>) -> TestResult {
+        let s = base32_encode(&bytes);
+        TestResult::from_bool(base32_decode(&s) == Some(bytes))
+    }
+
+    #[quickcheck]
+    fn test_base64(bytes: Vec<u8>) -> TestResult {
+        let s = i2p_base64_encode(&bytes);
+        TestResult::from_bool(i2p_base64_decode(&s) == Some(bytes))
+    }
+
+    #[test]
+    fn test_base_vectors() {
+        // https://www.rfc-editor.org/rfc/rfc4648#section-10
+        assert_eq!(base32_encode(b"foobar"), "mzxw6ytboi");
+        assert_eq!(i2p_base64_encode(b"fooba"), "Zm9vYmE=");
+        assert_eq!(i2p_base64_decode("Zm9vYg==").unwrap(), b"foob");
+        assert!(base32_decode("mzxw6ytb0").is_none());
+    }
+
+    #[test]
+    fn test_compact_size_len() {
+        for (n, len) in [
+            (0, 1),
+            (0xfc, 1),
+            (0xfd, 3),
+            (0xffff, 3),
+            (0x10000, 5),
+            (0xffff_ffff, 5),
+            (0x1_0000_0000, 9),
+        ] {
+            let mut buffer = vec![];
+            write_compact_size(&mut buffer, n).unwrap();
+            assert_eq!(buffer.len(), len);
+        }
+    }
+
+    #[test]
+    fn test_non_canonical_compact_size() {
+        assert!(read_compact_size(&mut &[0xfd, 0x10, 0x00][..]).is_err());
+        assert!(read_compact_size(&mut &[0xfe, 0xff, 0xff, 0x00, 0x00][..]).is_err());
+    }
+}
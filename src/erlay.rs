@@ -0,0 +1,671 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+};
+
+use super::{
+    command::Command,
+    encode::{read_vec_len, write_compact_size},
+    errors::{BTCP2PError, Result},
+    hash::{siphash24, tagged_hash},
+    inventory::{InvPayload, InvType, Inventory},
+    minisketch::{Minisketch, MAX_SKETCH_CAPACITY},
+};
+
+/// Transaction reconciliation protocol version announced in sendtxrcncl
+/// https://github.com/bitcoin/bips/blob/master/bip-0330.mediawiki
+pub const TXRECONCILIATION_VERSION: u32 = 1;
+
+/// Default false positive coefficient used to size sketches, 0.25 as in Bitcoin Core
+pub const DEFAULT_Q: f64 = 0.25;
+
+/// Fixed point scale of the q coefficient sent in reqrecon
+const Q_PRECISION: f64 = 32767.0;
+
+/// Tag of the hash used to derive the short ID salt from both peers salts
+const RECON_SALT_TAG: &str = "Tx Relay Salting";
+
+/// SendTxRcnclPayload represents the payload of a sendtxrcncl message
+/// Sent before verack to signal support for transaction reconciliation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendTxRcnclPayload {
+    /// The reconciliation protocol version supported by the transmitting node.
+    pub version: u32,
+
+    /// The salt contributed by the transmitting node to compute short transaction IDs.
+    pub salt: u64,
+}
+
+impl SendTxRcnclPayload {
+    /// to_bytes converts the payload to bytes
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut buffer = vec![];
+        buffer.write_u32::<LittleEndian>(self.version)?;
+        buffer.write_u64::<LittleEndian>(self.salt)?;
+        Ok(buffer)
+    }
+
+    /// from_bytes converts bytes to a payload
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self> {
+        Ok(Self {
+            version: bytes.read_u32::<LittleEndian>()?,
+            salt: bytes.read_u64::<LittleEndian>()?,
+        })
+    }
+}
+
+/// ReqReconPayload represents the payload of a reqrecon message
+/// Sent by the reconciliation initiator to request a sketch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReqReconPayload {
+    /// The size of the reconciliation set of the transmitting node.
+    pub set_size: u16,
+
+    /// The q coefficient used to estimate the set difference, as a fixed point value scaled by 32767.
+    pub q: u16,
+}
+
+impl ReqReconPayload {
+    /// to_bytes converts the payload to bytes
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut buffer = vec![];
+        buffer.write_u16::<LittleEndian>(self.set_size)?;
+        buffer.write_u16::<LittleEndian>(self.q)?;
+        Ok(buffer)
+    }
+
+    /// from_bytes converts bytes to a payload
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self> {
+        Ok(Self {
+            set_size: bytes.read_u16::<LittleEndian>()?,
+            q: bytes.read_u16::<LittleEndian>()?,
+        })
+    }
+}
+
+/// SketchPayload represents the payload of a sketch message
+/// Contains a serialized minisketch of the short IDs in the reconciliation set of the responder
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SketchPayload {
+    pub sketch: Vec<u8>,
+}
+
+impl SketchPayload {
+    /// to_bytes converts the payload to bytes
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut buffer = vec![];
+        write_compact_size(&mut buffer, self.sketch.len() as u64)?;
+        buffer.write_all(&self.sketch)?;
+        Ok(buffer)
+    }
+
+    /// from_bytes converts bytes to a payload
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self> {
+        let len = read_vec_len(&mut bytes, 1)?;
+        let mut sketch = vec![0u8; len];
+        bytes.read_exact(&mut sketch)?;
+        Ok(Self { sketch })
+    }
+}
+
+/// ReconcilDiffPayload represents the payload of a reconcildiff message
+/// Sent by the reconciliation initiator once it decoded the set difference, or gave up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReconcilDiffPayload {
+    /// Whether the set difference was decoded. If not, both sides fall back to flooding.
+    pub success: bool,
+
+    /// Short IDs of the transactions the initiator is missing.
+    pub ask_shortids: Vec<u32>,
+}
+
+impl ReconcilDiffPayload {
+    /// to_bytes converts the payload to bytes
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut buffer = vec![];
+        buffer.write_u8(self.success.into())?;
+        write_compact_size(&mut buffer, self.ask_shortids.len() as u64)?;
+        for short_id in &self.ask_shortids {
+            buffer.write_u32::<LittleEndian>(*short_id)?;
+        }
+        Ok(buffer)
+    }
+
+    /// from_bytes converts bytes to a payload
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self> {
+        let success = bytes.read_u8()? != 0x00;
+        let len = read_vec_len(&mut bytes, 4)?;
+        let ask_shortids = (0..len)
+            .map(|_| bytes.read_u32::<LittleEndian>())
+            .collect::<std::io::Result<_>>()?;
+
+        Ok(Self {
+            success,
+            ask_shortids,
+        })
+    }
+}
+
+/// ReconciliationOutcome is the result of processing a sketch received from the responder
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReconciliationOutcome {
+    /// The difference was decoded.
+    /// `announce` holds the wtxids the responder is missing, `diff` must be sent back to the responder.
+    Decoded {
+        announce: Vec<[u8; 32]>,
+        diff: ReconcilDiffPayload,
+    },
+
+    /// The sketch was too small, a reqsketchext message should be sent to get an extended one.
+    NeedExtension,
+
+    /// The difference could not be decoded even after extension.
+    /// `diff` must be sent back to the responder and `flood` announced the usual way.
+    Failed {
+        flood: Vec<[u8; 32]>,
+        diff: ReconcilDiffPayload,
+    },
+}
+
+impl ReconciliationOutcome {
+    /// inv returns the inv announcing the transactions the responder is missing, or those to flood
+    /// no inv is sent while an extension is pending
+    pub fn inv(&self) -> Option<InvPayload> {
+        match self {
+            ReconciliationOutcome::Decoded { announce, .. } => {
+                Some(InvPayload::wtx(announce.clone()))
+            }
+            ReconciliationOutcome::NeedExtension => None,
+            ReconciliationOutcome::Failed { flood, .. } => Some(InvPayload::wtx(flood.clone())),
+        }
+    }
+}
+
+/// ReconciliationSet holds the transactions waiting to be reconciled with a single peer
+///
+/// Both peers exchange salts in sendtxrcncl before verack, the combined salt keys the short IDs,
+/// so the same transaction has a different short ID with each peer.
+#[derive(Debug, Clone)]
+pub struct ReconciliationSet {
+    k0: u64,
+    k1: u64,
+    transactions: HashMap<u32, [u8; 32]>,
+    /// Snapshot of the set sent in the last sketch, kept to answer reqsketchext
+    snapshot: Option<(usize, HashMap<u32, [u8; 32]>)>,
+    /// Round started as the initiator, sketches are only accepted while one is in progress
+    round: Option<Round>,
+}
+
+/// Round is the stage of a reconciliation round started by the initiator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Round {
+    /// reqrecon was sent, the first sketch is awaited
+    Requested,
+
+    /// The first sketch, of the given capacity, could not be decoded and reqsketchext was sent.
+    Extended(usize),
+}
+
+impl ReconciliationSet {
+    /// new creates a reconciliation set for a peer that announced its salt with sendtxrcncl
+    pub fn new(local_salt: u64, remote: &SendTxRcnclPayload) -> Result<Self> {
+        if remote.version < TXRECONCILIATION_VERSION {
+            return Err(BTCP2PError::UnsupportedReconciliationVersion(
+                remote.version,
+            ));
+        }
+
+        // salts are concatenated in ascending order so both sides derive the same key
+        let (low, high) = if local_salt <= remote.salt {
+            (local_salt, remote.salt)
+        } else {
+            (remote.salt, local_salt)
+        };
+
+        let mut data = [0u8; 16];
+        data[..8].copy_from_slice(&low.to_le_bytes());
+        data[8..].copy_from_slice(&high.to_le_bytes());
+        let key = tagged_hash(RECON_SALT_TAG, &data);
+
+        Ok(Self {
+            k0: u64::from_le_bytes(key[..8].try_into()?),
+            k1: u64::from_le_bytes(key[8..16].try_into()?),
+            transactions: HashMap::new(),
+            snapshot: None,
+            round: None,
+        })
+    }
+
+    /// short_id computes the 32 bit short ID of a transaction from its wtxid
+    /// zero is never returned since it is not a valid sketch element
+    pub fn short_id(&self, wtxid: &[u8; 32]) -> u32 {
+        let hash = siphash24(self.k0, self.k1, wtxid);
+        1 + (hash % 0xffff_ffff) as u32
+    }
+
+    /// add queues a transaction for the next reconciliation round
+    pub fn add(&mut self, wtxid: [u8; 32]) {
+        self.transactions.insert(self.short_id(&wtxid), wtxid);
+    }
+
+    /// add_inventory queues a transaction announced by wtxid, as in the inv messages flooded to other peers
+    /// returns false for other entries, only transactions relayed by wtxid can be reconciled (BIP330)
+    pub fn add_inventory(&mut self, inventory: &Inventory) -> bool {
+        if inventory.inv_type != InvType::WTx {
+            return false;
+        }
+        self.add(inventory.hash);
+        true
+    }
+
+    /// remove drops a transaction, for instance once it was announced to the peer
+    pub fn remove(&mut self, wtxid: &[u8; 32]) {
+        self.transactions.remove(&self.short_id(wtxid));
+    }
+
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    /// get returns the wtxid of a short ID requested by the peer in a reconcildiff message
+    pub fn get(&self, short_id: u32) -> Option<&[u8; 32]> {
+        self.transactions.get(&short_id).or_else(|| {
+            self.snapshot
+                .as_ref()
+                .and_then(|(_, transactions)| transactions.get(&short_id))
+        })
+    }
+
+    /// request starts a reconciliation round as the initiator
+    pub fn request(&mut self, q: f64) -> ReqReconPayload {
+        self.round = Some(Round::Requested);
+
+        ReqReconPayload {
+            set_size: self.len().min(u16::MAX as usize) as u16,
+            q: (q * Q_PRECISION) as u16,
+        }
+    }
+
+    /// respond answers a reqrecon as the responder
+    /// the set is moved to a snapshot until the initiator sends reconcildiff
+    /// the capacity is estimated from the request of the peer, up to MAX_SKETCH_CAPACITY
+    pub fn respond(&mut self, request: &ReqReconPayload) -> SketchPayload {
+        let q = request.q as f64 / Q_PRECISION;
+        let local = self.len();
+        let remote = request.set_size as usize;
+        let capacity = (local.abs_diff(remote) + (q * local.min(remote) as f64) as usize + 1)
+            .min(MAX_SKETCH_CAPACITY);
+
+        let transactions = std::mem::take(&mut self.transactions);
+        let sketch = Self::sketch(transactions.keys(), capacity);
+        self.snapshot = Some((capacity, transactions));
+
+        SketchPayload {
+            sketch: sketch.to_bytes(),
+        }
+    }
+
+    /// respond_extension answers a reqsketchext with a sketch of twice the capacity of the snapshot
+    /// up to MAX_SKETCH_CAPACITY
+    pub fn respond_extension(&self) -> Option<SketchPayload> {
+        let (capacity, transactions) = self.snapshot.as_ref()?;
+        let sketch = Self::sketch(transactions.keys(), (capacity * 2).min(MAX_SKETCH_CAPACITY));
+
+        Some(SketchPayload {
+            sketch: sketch.to_bytes(),
+        })
+    }
+
+    /// finish ends the round as the responder once reconcildiff is received
+    /// returns the inv announcing the transactions requested by the initiator, or the whole snapshot to flood
+    /// if reconciliation failed
+    pub fn finish(&mut self, diff: &ReconcilDiffPayload) -> InvPayload {
+        let Some((_, transactions)) = self.snapshot.take() else {
+            return InvPayload::wtx([]);
+        };
+
+        if diff.success {
+            InvPayload::wtx(
+                diff.ask_shortids
+                    .iter()
+                    .filter_map(|short_id| transactions.get(short_id).copied()),
+            )
+        } else {
+            InvPayload::wtx(transactions.into_values())
+        }
+    }
+
+    /// process_sketch decodes the sketch sent by the responder as the initiator
+    /// sketches are only accepted in answer to a request, the first one up to MAX_SKETCH_CAPACITY since the
+    /// responder sizes it from its own set, and an extension up to twice the capacity of the first one
+    /// an empty or a larger sketch is rejected, the set is kept
+    pub fn process_sketch(&mut self, payload: &SketchPayload) -> Result<ReconciliationOutcome> {
+        let max_capacity = match self.round {
+            None => return Err(BTCP2PError::UnexpectedMessage(Command::Sketch)),
+            Some(Round::Requested) => MAX_SKETCH_CAPACITY,
+            Some(Round::Extended(capacity)) => capacity * 2,
+        };
+
+        let remote = Minisketch::from_bytes(&payload.sketch)?;
+        if remote.capacity() == 0 || remote.capacity() > max_capacity {
+            return Err(BTCP2PError::InvalidSketch);
+        }
+        let mut sketch = Self::sketch(self.transactions.keys(), remote.capacity());
+        sketch.merge(&remote);
+
+        let Some(difference) = sketch.decode() else {
+            if let Some(Round::Extended(_)) = self.round {
+                self.round = None;
+                return Ok(ReconciliationOutcome::Failed {
+                    flood: self.transactions.drain().map(|(_, wtxid)| wtxid).collect(),
+                    diff: ReconcilDiffPayload {
+                        success: false,
+                        ask_shortids: vec![],
+                    },
+                });
+            }
+
+            self.round = Some(Round::Extended(remote.capacity()));
+            return Ok(ReconciliationOutcome::NeedExtension);
+        };
+
+        let (announce, ask_shortids): (Vec<_>, Vec<_>) = difference
+            .into_iter()
+            .partition(|short_id| self.transactions.contains_key(short_id));
+
+        let announce = announce
+            .iter()
+            .filter_map(|short_id| self.transactions.remove(short_id))
+            .collect();
+
+        // whatever was not part of the difference is known by both sides
+        self.transactions.clear();
+        self.round = None;
+
+        Ok(ReconciliationOutcome::Decoded {
+            announce,
+            diff: ReconcilDiffPayload {
+                success: true,
+                ask_shortids,
+            },
+        })
+    }
+
+    fn sketch<'a>(short_ids: impl Iterator<Item = &'a u32>, capacity: usize) -> Minisketch {
+        let mut sketch = Minisketch::new(capacity);
+        for short_id in short_ids {
+            sketch.add(*short_id);
+        }
+        sketch
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::{Arbitrary, TestResult};
+    use quickcheck_macros::quickcheck;
+
+    impl Arbitrary for SendTxRcnclPayload {
+        fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+            Self {
+                version: u32::arbitrary(g),
+                salt: u64::arbitrary(g),
+            }
+        }
+    }
+
+    impl Arbitrary for ReqReconPayload {
+        fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+            Self {
+                set_size: u16::arbitrary(g),
+                q: u16::arbitrary(g),
+            }
+        }
+    }
+
+    impl Arbitrary for SketchPayload {
+        fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+            Self {
+                sketch: Vec::<u8>::arbitrary(g),
+            }
+        }
+    }
+
+    impl Arbitrary for ReconcilDiffPayload {
+        fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+            Self {
+                success: bool::arbitrary(g),
+                ask_shortids: Vec::<u32>::arbitrary(g),
+            }
+        }
+    }
+
+    fn wtxid(n: u32) -> [u8; 32] {
+        let mut wtxid = [0u8; 32];
+        wtxid[..4].copy_from_slice(&n.to_le_bytes());
+        wtxid
+    }
+
+    fn sets(
+        alice: impl Iterator<Item = u32>,
+        bob: impl Iterator<Item = u32>,
+    ) -> (ReconciliationSet, ReconciliationSet) {
+        let alice_salt = SendTxRcnclPayload {
+            version: TXRECONCILIATION_VERSION,
+            salt: 1,
+        };
+        let bob_salt = SendTxRcnclPayload {
+            version: TXRECONCILIATION_VERSION,
+            salt: 2,
+        };
+
+        let mut alice_set = ReconciliationSet::new(alice_salt.salt, &bob_salt).unwrap();
+        let mut bob_set = ReconciliationSet::new(bob_salt.salt, &alice_salt).unwrap();
+        alice.for_each(|n| alice_set.add(wtxid(n)));
+        bob.for_each(|n| bob_set.add(wtxid(n)));
+
+        (alice_set, bob_set)
+    }
+
+    #[quickcheck]
+    fn test_sendtxrcncl_to_bytes(payload: SendTxRcnclPayload) -> TestResult {
+        let bytes = payload.to_bytes().unwrap();
+        TestResult::from_bool(payload == SendTxRcnclPayload::from_bytes(&bytes).unwrap())
+    }
+
+    #[quickcheck]
+    fn test_reqrecon_to_bytes(payload: ReqReconPayload) -> TestResult {
+        let bytes = payload.to_bytes().unwrap();
+        TestResult::from_bool(payload == ReqReconPayload::from_bytes(&bytes).unwrap())
+    }
+
+    #[quickcheck]
+    fn test_sketch_to_bytes(payload: SketchPayload) -> TestResult {
+        let bytes = payload.to_bytes().unwrap();
+        TestResult::from_bool(payload == SketchPayload::from_bytes(&bytes).unwrap())
+    }
+
+    #[quickcheck]
+    fn test_reconcildiff_to_bytes(payload: ReconcilDiffPayload) -> TestResult {
+        let bytes = payload.to_bytes().unwrap();
+        TestResult::from_bool(payload == ReconcilDiffPayload::from_bytes(&bytes).unwrap())
+    }
+
+    #[test]
+    fn test_short_id_is_symmetric() {
+        let (alice, bob) = sets(0..0, 0..0);
+        assert_eq!(alice.short_id(&wtxid(42)), bob.short_id(&wtxid(42)));
+        assert_ne!(alice.short_id(&wtxid(42)), alice.short_id(&wtxid(43)));
+    }
+
+    #[test]
+    fn test_add_inventory() {
+        let (mut alice, _) = sets(0..0, 0..0);
+        assert!(alice.add_inventory(&Inventory {
+            inv_type: InvType::WTx,
+            hash: wtxid(1),
+        }));
+        // transactions announced by txid are flooded
+        assert!(!alice.add_inventory(&Inventory {
+            inv_type: InvType::Tx,
+            hash: wtxid(2),
+        }));
+        assert_eq!(alice.len(), 1);
+        assert_eq!(alice.get(alice.short_id(&wtxid(1))), Some(&wtxid(1)));
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let remote = SendTxRcnclPayload {
+            version: 0,
+            salt: 1,
+        };
+        assert!(ReconciliationSet::new(2, &remote).is_err());
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let (mut alice, mut bob) = sets(0..100, 3..105);
+
+        let request = alice.request(DEFAULT_Q);
+        let sketch = bob.respond(&request);
+        let outcome = alice.process_sketch(&sketch).unwrap();
+
+        // the transactions bob is missing are announced by wtxid, as when flooding
+        let mut announce = outcome.inv().unwrap().inventory;
+        announce.sort_by_key(|entry| entry.hash);
+        assert_eq!(announce, InvPayload::wtx((0..3).map(wtxid)).inventory);
+
+        let ReconciliationOutcome::Decoded { diff, .. } = outcome else {
+            panic!("expected the difference to be decoded");
+        };
+        let mut requested = bob.finish(&diff).inventory;
+        requested.sort_by_key(|entry| entry.hash);
+        assert_eq!(requested, InvPayload::wtx((100..105).map(wtxid)).inventory);
+        assert!(alice.is_empty());
+    }
+
+    #[test]
+    fn test_empty_sketch() {
+        let (mut alice, _) = sets(0..10, 0..0);
+        alice.request(DEFAULT_Q);
+        let sketch = SketchPayload { sketch: vec![] };
+        assert!(matches!(
+            alice.process_sketch(&sketch),
+            Err(BTCP2PError::InvalidSketch)
+        ));
+        assert_eq!(alice.len(), 10);
+    }
+
+    #[test]
+    fn test_sketch_capacity_is_bounded() {
+        let (mut alice, mut bob) = sets(0..0, 0..10);
+        let request = ReqReconPayload {
+            set_size: u16::MAX,
+            q: u16::MAX,
+        };
+        let sketch = bob.respond(&request);
+        assert_eq!(sketch.sketch.len(), MAX_SKETCH_CAPACITY * 4);
+        let extension = bob.respond_extension().unwrap();
+        assert_eq!(extension.sketch.len(), MAX_SKETCH_CAPACITY * 4);
+
+        alice.request(DEFAULT_Q);
+        let sketch = SketchPayload {
+            sketch: vec![0; (MAX_SKETCH_CAPACITY + 1) * 4],
+        };
+        assert!(matches!(
+            alice.process_sketch(&sketch),
+            Err(BTCP2PError::InvalidSketch)
+        ));
+    }
+
+    #[test]
+    fn test_unrequested_sketch() {
+        let (mut alice, mut bob) = sets(0..10, 5..15);
+        let sketch = bob.respond(&alice.request(DEFAULT_Q));
+        alice.process_sketch(&sketch).unwrap();
+
+        // the round ended with the first sketch, another one is not expected
+        assert!(matches!(
+            alice.process_sketch(&sketch),
+            Err(BTCP2PError::UnexpectedMessage(Command::Sketch))
+        ));
+    }
+
+    #[test]
+    fn test_extension_capacity_is_bounded() {
+        let (mut alice, mut bob) = sets(0..50, 50..100);
+        let sketch = bob.respond(&alice.request(0.1));
+        assert_eq!(
+            alice.process_sketch(&sketch).unwrap(),
+            ReconciliationOutcome::NeedExtension
+        );
+
+        // the extension may only double the capacity of the first sketch
+        let capacity = sketch.sketch.len() / 4;
+        let sketch = SketchPayload {
+            sketch: vec![0; (capacity * 2 + 1) * 4],
+        };
+        assert!(matches!(
+            alice.process_sketch(&sketch),
+            Err(BTCP2PError::InvalidSketch)
+        ));
+        assert_eq!(alice.len(), 50);
+    }
+
+    #[test]
+    fn test_reconcile_with_extension() {
+        let (mut alice, mut bob) = sets(0..40, 4..44);
+
+        // the estimation expects 4 differences out of 8, so the first sketch is too small
+        let request = alice.request(0.1);
+        let sketch = bob.respond(&request);
+        assert_eq!(
+            alice.process_sketch(&sketch).unwrap(),
+            ReconciliationOutcome::NeedExtension
+        );
+
+        // the extension has twice the capacity, which fits the difference
+        let sketch = bob.respond_extension().unwrap();
+        let outcome = alice.process_sketch(&sketch).unwrap();
+        let mut announce = outcome.inv().unwrap().inventory;
+        announce.sort_by_key(|entry| entry.hash);
+        assert_eq!(announce, InvPayload::wtx((0..4).map(wtxid)).inventory);
+
+        let ReconciliationOutcome::Decoded { diff, .. } = outcome else {
+            panic!("expected the extended sketch to be decoded");
+        };
+        let mut requested = bob.finish(&diff).inventory;
+        requested.sort_by_key(|entry| entry.hash);
+        assert_eq!(requested, InvPayload::wtx((40..44).map(wtxid)).inventory);
+        assert!(alice.is_empty());
+    }
+
+    #[test]
+    fn test_extension_failure_falls_back_to_flooding() {
+        let (mut alice, mut bob) = sets(0..50, 50..100);
+
+        // the estimation expects a small difference, so the first sketch is too small
+        let request = alice.request(0.1);
+        let sketch = bob.respond(&request);
+        assert_eq!(
+            alice.process_sketch(&sketch).unwrap(),
+            ReconciliationOutcome::NeedExtension
+        );
+
+        // even the extension is too small, both sides flood their whole set
+        let sketch = bob.respond_extension().unwrap();
+        let outcome = alice.process_sketch(&sketch).unwrap();
+        assert_eq!(outcome.inv().unwrap().inventory.len(), 50);
+        let ReconciliationOutcome::Failed { diff, .. } = outcome else {
+            panic!("unexpected outcome {:?}", outcome);
+        };
+        assert_eq!(bob.finish(&diff).inventory.len(), 50);
+    }
+}
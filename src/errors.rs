@@ -1,8 +1,10 @@
 use thiserror::Error;
 
+use super::{command::Command, network::Network};
+
 pub type Result<T> = std::result::Result<T, BTCP2PError>;
 
-///! BTCP2PError represents an error in the BTC proto
+/// BTCP2PError represents an error in the BTC proto
 #[derive(Debug, Error)]
 pub enum BTCP2PError {
     #[error("Unknown network")]
@@ -28,4 +30,187 @@ pub enum BTCP2PError {
 
     #[error("Failed to decode command")]
     DecodeCommandError(#[from] std::string::FromUtf8Error),
+
+    #[error("Non canonical compact size")]
+    NonCanonicalCompactSize,
+
+    #[error("Invalid sketch")]
+    InvalidSketch,
+
+    #[error("Unsupported reconciliation version {0}")]
+    UnsupportedReconciliationVersion(u32),
+
+    #[error("Invalid transaction")]
+    InvalidTransaction,
+
+    #[error("Invalid package size {0}")]
+    InvalidPackageSize(usize),
+
+    #[error("Unexpected {0:?} message")]
+    UnexpectedMessage(Command),
+
+    #[error("Message from network {0:?}")]
+    NetworkMismatch(Network),
+
+    #[error("Obsolete protocol version {0}")]
+    ObsoleteVersion(i32),
+
+    #[error("User agent of {0} bytes is too long")]
+    UserAgentTooLong(usize),
+
+    #[error("Handshake timed out")]
+    HandshakeTimeout,
+
+    #[error("Connection closed")]
+    ConnectionClosed,
+
+    #[error("Connected to ourselves")]
+    SelfConnection,
+
+    #[error("Ping timed out")]
+    PingTimeout,
+
+    #[error("Invalid subnet {0}")]
+    InvalidSubnet(String),
+
+    #[error("Address {0} is banned")]
+    Banned(std::net::IpAddr),
+
+    #[error("Invalid address of network {0}")]
+    InvalidAddress(u8),
+
+    #[error("Too many addresses {0}")]
+    TooManyAddresses(usize),
+
+    #[error("Unsupported address file version {0}")]
+    UnsupportedFormatVersion(u8),
+
+    #[error("Invalid DNS message")]
+    InvalidDnsMessage,
+
+    #[error("Invalid SOCKS5 proxy response")]
+    InvalidProxyResponse,
+
+    #[error("SOCKS5 proxy authentication failed")]
+    ProxyAuthenticationFailed,
+
+    #[error("SOCKS5 proxy failed with reply {0}")]
+    ProxyError(u8),
+
+    #[error("Invalid proxy destination {0}")]
+    InvalidProxyDestination(String),
+
+    #[error("A proxy is required to connect to {0}")]
+    ProxyRequired(String),
+
+    #[error("I2P SAM bridge error {0}")]
+    SamError(String),
+
+    #[error("No transport for addresses of network {0}")]
+    UnreachableNetwork(u8),
+
+    #[error("Tor control error {0}")]
+    TorControlError(String),
+
+    #[error("Port mapping error {0}")]
+    PortMappingError(String),
+
+    #[error("Too many headers {0}")]
+    TooManyHeaders(usize),
+
+    #[error("Too many inventory entries {0}")]
+    TooManyInventory(usize),
+
+    #[error("Too many locator hashes {0}")]
+    TooManyLocatorHashes(usize),
+
+    #[error("Header hash does not meet its target")]
+    InvalidProofOfWork,
+
+    #[error("Header does not match the checkpoint at height {0}")]
+    CheckpointMismatch(u32),
+
+    #[error("Headers do not form a chain")]
+    NonContinuousHeaders,
+
+    #[error("Header does not connect to a known header")]
+    UnconnectedHeader,
+
+    #[error("Too many headers messages which do not connect {0}")]
+    TooManyUnconnectingHeaders(u32),
+
+    #[error("Header at height {0} has unexpected difficulty bits")]
+    BadDifficultyBits(u32),
+
+    #[error("Header at height {0} is too far before its parent, as in a timewarp attack")]
+    TimewarpAttack(u32),
+
+    #[error("Header at height {0} forks from the chain before the last checkpoint")]
+    ForkBeforeCheckpoint(u32),
+
+    #[error("Header at height {0} is not after the median time of its ancestors")]
+    HeaderTimeTooOld(u32),
+
+    #[error("Header at height {0} is too far in the future")]
+    HeaderTimeTooNew(u32),
+}
+
+impl BTCP2PError {
+    /// penalty returns the misbehavior score of a peer which caused this error
+    /// errors which are not the fault of the peer, or are likely honest mistakes, are not penalized
+    /// a wrong network or an oversized message only end the connection, as in Bitcoin Core, since banning
+    /// would also hit honest nodes sharing the address or misconfigured ones
+    pub fn penalty(&self) -> u32 {
+        match self {
+            BTCP2PError::InvalidHeaderSize
+            | BTCP2PError::InvalidProofOfWork
+            | BTCP2PError::CheckpointMismatch(_)
+            | BTCP2PError::BadDifficultyBits(_)
+            | BTCP2PError::TimewarpAttack(_)
+            | BTCP2PError::ForkBeforeCheckpoint(_)
+            | BTCP2PError::HeaderTimeTooOld(_) => 100,
+            BTCP2PError::DecodeError(_)
+            | BTCP2PError::DecodeCommandError(_)
+            | BTCP2PError::NonCanonicalCompactSize
+            | BTCP2PError::InvalidSketch
+            | BTCP2PError::InvalidTransaction
+            | BTCP2PError::InvalidPackageSize(_)
+            | BTCP2PError::InvalidAddress(_) => 50,
+            BTCP2PError::InvalidChecksum
+            | BTCP2PError::UnexpectedMessage(_)
+            | BTCP2PError::TooManyAddresses(_)
+            | BTCP2PError::TooManyHeaders(_)
+            | BTCP2PError::TooManyInventory(_)
+            | BTCP2PError::TooManyLocatorHashes(_)
+            | BTCP2PError::NonContinuousHeaders
+            | BTCP2PError::TooManyUnconnectingHeaders(_) => 20,
+            BTCP2PError::UnknowNetwork
+            | BTCP2PError::NetworkMismatch(_)
+            | BTCP2PError::PayloadTooLarge
+            | BTCP2PError::BufferIOError(_)
+            | BTCP2PError::InvalidCommand
+            | BTCP2PError::UnsupportedReconciliationVersion(_)
+            | BTCP2PError::ObsoleteVersion(_)
+            | BTCP2PError::UserAgentTooLong(_)
+            | BTCP2PError::HandshakeTimeout
+            | BTCP2PError::ConnectionClosed
+            | BTCP2PError::SelfConnection
+            | BTCP2PError::PingTimeout
+            | BTCP2PError::InvalidSubnet(_)
+            | BTCP2PError::Banned(_)
+            | BTCP2PError::UnsupportedFormatVersion(_)
+            | BTCP2PError::InvalidDnsMessage
+            | BTCP2PError::InvalidProxyResponse
+            | BTCP2PError::ProxyAuthenticationFailed
+            | BTCP2PError::ProxyError(_)
+            | BTCP2PError::InvalidProxyDestination(_)
+            | BTCP2PError::ProxyRequired(_)
+            | BTCP2PError::SamError(_)
+            | BTCP2PError::UnreachableNetwork(_)
+            | BTCP2PError::TorControlError(_)
+            | BTCP2PError::PortMappingError(_)
+            | BTCP2PError::UnconnectedHeader
+            | BTCP2PError::HeaderTimeTooNew(_) => 0,
+        }
+    }
 }
@@ -0,0 +1,205 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use super::{
+    erlay::SendTxRcnclPayload, errors::Result, handshake::PeerInfo, package::SendPackagesPayload,
+    payload::Payload, service_flags::ServiceFlags,
+};
+
+/// Compact block relay version announced in sendcmpct, the only one Bitcoin Core still supports
+/// https://github.com/bitcoin/bips/blob/master/bip-0152.mediawiki
+pub const CMPCTBLOCKS_VERSION: u64 = 2;
+
+/// SendCmpctPayload represents the payload of a sendcmpct message
+/// Sent after verack to signal support for compact blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendCmpctPayload {
+    /// Whether new blocks should be announced with cmpctblock messages, high bandwidth mode.
+    pub announce: bool,
+
+    /// The compact block relay version of the transmitting node.
+    pub version: u64,
+}
+
+impl SendCmpctPayload {
+    /// to_bytes converts the payload to bytes
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut buffer = vec![];
+        buffer.write_u8(self.announce.into())?;
+        buffer.write_u64::<LittleEndian>(self.version)?;
+        Ok(buffer)
+    }
+
+    /// from_bytes converts bytes to a payload
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self> {
+        Ok(Self {
+            announce: bytes.read_u8()? != 0,
+            version: bytes.read_u64::<LittleEndian>()?,
+        })
+    }
+}
+
+/// PeerFeatures summarizes what a peer supports, from its version and the messages it sent
+///
+/// Features negotiated before verack come from the handshake, sendheaders, sendcmpct and feefilter are
+/// received afterwards so the summary is updated as they arrive.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerFeatures {
+    /// The protocol version of the peer.
+    pub version: i32,
+
+    /// The services supported by the peer.
+    pub services: ServiceFlags,
+
+    /// Whether the peer wants transactions to be relayed, from its version.
+    pub relay: bool,
+
+    /// Whether the peer sent wtxidrelay (BIP339).
+    pub wtxid_relay: bool,
+
+    /// Whether the peer sent sendaddrv2 (BIP155).
+    pub addr_v2: bool,
+
+    /// The sendtxrcncl of the peer, if both sides sent one (BIP330).
+    pub tx_reconciliation: Option<SendTxRcnclPayload>,
+
+    /// The sendpackages of the peer, if both sides sent one (BIP331).
+    pub packages: Option<SendPackagesPayload>,
+
+    /// Whether the peer wants new blocks announced with headers (BIP130).
+    pub send_headers: bool,
+
+    /// Whether the peer announced the compact block version we speak with sendcmpct (BIP152).
+    pub compact_blocks: bool,
+
+    /// Whether the peer wants new blocks pushed as compact blocks of the version we speak, high bandwidth mode.
+    pub compact_blocks_high_bandwidth: bool,
+
+    /// The minimum fee rate of transactions to relay to the peer in satoshis per kilovbyte (BIP133).
+    pub fee_filter: Option<u64>,
+}
+
+impl PeerFeatures {
+    /// new starts the summary from what was negotiated during the handshake
+    pub fn new(info: &PeerInfo) -> Self {
+        Self {
+            version: info.version,
+            services: info.services,
+            relay: info.relay,
+            wtxid_relay: info.wtxid_relay,
+            addr_v2: info.addr_v2,
+            tx_reconciliation: info.tx_reconciliation,
+            packages: info.packages,
+            send_headers: false,
+            compact_blocks: false,
+            compact_blocks_high_bandwidth: false,
+            fee_filter: None,
+        }
+    }
+
+    /// receive updates the summary with a message of the peer, returns true if a feature changed
+    pub fn receive(&mut self, payload: &Payload) -> bool {
+        let before = self.clone();
+        match payload {
+            Payload::SendHeaders => self.send_headers = true,
+            // other versions are ignored, as Bitcoin Core does
+            Payload::SendCmpct(payload) if payload.version == CMPCTBLOCKS_VERSION => {
+                self.compact_blocks = true;
+                self.compact_blocks_high_bandwidth = payload.announce;
+            }
+            Payload::FeeFilter(fee_rate) => self.fee_filter = Some(*fee_rate),
+            _ => return false,
+        }
+        *self != before
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::Arbitrary;
+
+    impl Arbitrary for SendCmpctPayload {
+        fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+            Self {
+                announce: bool::arbitrary(g),
+                version: u64::arbitrary(g),
+            }
+        }
+    }
+
+    #[test]
+    fn test_sendcmpct_bytes() {
+        let payload = SendCmpctPayload {
+            announce: true,
+            version: CMPCTBLOCKS_VERSION,
+        };
+        let bytes = payload.to_bytes().unwrap();
+        assert_eq!(bytes, [1, 2, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(SendCmpctPayload::from_bytes(&bytes).unwrap(), payload);
+        assert!(SendCmpctPayload::from_bytes(&bytes[..8]).is_err());
+    }
+
+    #[test]
+    fn test_peer_features() {
+        let info = PeerInfo {
+            version: 70016,
+            services: ServiceFlags::NODE_NETWORK,
+            user_agent: "/test:0.1.0/".to_string(),
+            start_height: 42,
+            relay: true,
+            time_offset: 0,
+            addr_v2: true,
+            wtxid_relay: false,
+            reconciliation_salt: None,
+            tx_reconciliation: None,
+            packages: None,
+        };
+        let mut features = PeerFeatures::new(&info);
+        assert!(features.addr_v2);
+        assert!(!features.send_headers);
+        assert!(!features.compact_blocks);
+
+        assert!(features.receive(&Payload::SendHeaders));
+        assert!(!features.receive(&Payload::SendHeaders));
+        assert!(!features.receive(&Payload::Ping(1)));
+
+        // other versions are ignored, lower and higher ones alike
+        for version in [1, CMPCTBLOCKS_VERSION + 1] {
+            let sendcmpct = SendCmpctPayload {
+                announce: true,
+                version,
+            };
+            assert!(!features.receive(&Payload::SendCmpct(sendcmpct)));
+            assert!(!features.compact_blocks);
+            assert!(!features.compact_blocks_high_bandwidth);
+        }
+
+        let sendcmpct = SendCmpctPayload {
+            announce: false,
+            version: CMPCTBLOCKS_VERSION,
+        };
+        assert!(features.receive(&Payload::SendCmpct(sendcmpct)));
+        assert!(features.compact_blocks);
+        assert!(!features.compact_blocks_high_bandwidth);
+
+        // another version announced later does not undo it
+        let sendcmpct_v1 = SendCmpctPayload {
+            announce: true,
+            version: 1,
+        };
+        assert!(!features.receive(&Payload::SendCmpct(sendcmpct_v1)));
+        assert!(features.compact_blocks);
+        assert!(!features.compact_blocks_high_bandwidth);
+
+        // peers switch to high bandwidth mode with another sendcmpct
+        let sendcmpct = SendCmpctPayload {
+            announce: true,
+            ..sendcmpct
+        };
+        assert!(features.receive(&Payload::SendCmpct(sendcmpct)));
+        assert!(features.compact_blocks_high_bandwidth);
+
+        assert!(features.receive(&Payload::FeeFilter(1000)));
+        assert_eq!(features.fee_filter, Some(1000));
+    }
+}
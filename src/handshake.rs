@@ -0,0 +1,767 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use super::{
+    command::Command,
+    erlay::{ReconciliationSet, SendTxRcnclPayload},
+    errors::{BTCP2PError, Result},
+    message::Message,
+    network::Network,
+    package::SendPackagesPayload,
+    payload::{Payload, VersionPayload},
+    service_flags::ServiceFlags,
+    sync::lock,
+    time_offsets::unix_time,
+    MIN_PEER_PROTO_VERSION, WTXID_RELAY_VERSION,
+};
+
+/// Default time to wait for each stage of the handshake
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// NonceRegistry tracks the version nonces of our outbound handshakes in progress
+///
+/// Clones share the same registry. An inbound version carrying one of these nonces means we connected to ourselves,
+/// the nonce is then marked so the outbound side of the connection reports it too.
+#[derive(Debug, Clone, Default)]
+pub struct NonceRegistry(Arc<Mutex<HashMap<u64, bool>>>);
+
+impl NonceRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// insert records a nonce we sent, a nonce of 0 is ignored
+    pub fn insert(&self, nonce: u64) {
+        if nonce != 0 {
+            self.nonces().insert(nonce, false);
+        }
+    }
+
+    /// remove forgets a nonce we sent
+    pub fn remove(&self, nonce: u64) {
+        self.nonces().remove(&nonce);
+    }
+
+    /// contains checks if a nonce was sent by us
+    pub fn contains(&self, nonce: u64) -> bool {
+        nonce != 0 && self.nonces().contains_key(&nonce)
+    }
+
+    /// mark_self_connection records that a nonce we sent came back on an inbound connection
+    pub fn mark_self_connection(&self, nonce: u64) {
+        if let Some(matched) = self.nonces().get_mut(&nonce) {
+            *matched = true;
+        }
+    }
+
+    /// is_self_connection checks if a nonce we sent came back on an inbound connection
+    pub fn is_self_connection(&self, nonce: u64) -> bool {
+        self.nonces().get(&nonce).copied().unwrap_or(false)
+    }
+
+    fn nonces(&self) -> std::sync::MutexGuard<'_, HashMap<u64, bool>> {
+        lock(&self.0)
+    }
+}
+
+/// HandshakeConfig holds what we announce to peers during the handshake
+#[derive(Debug, Clone)]
+pub struct HandshakeConfig {
+    /// The network messages must belong to.
+    pub network: Network,
+
+    /// Our version message.
+    pub version: VersionPayload,
+
+    /// Whether to send wtxidrelay (BIP339) when the peer supports it.
+    pub wtxid_relay: bool,
+
+    /// Whether to send sendaddrv2 (BIP155).
+    pub addr_v2: bool,
+
+    /// Our sendtxrcncl (BIP330), sent when both sides relay transactions and negotiate wtxidrelay.
+    pub tx_reconciliation: Option<SendTxRcnclPayload>,
+
+    /// Our sendpackages (BIP331), sent when both sides negotiate wtxidrelay.
+    pub packages: Option<SendPackagesPayload>,
+
+    /// Time to wait for the version message of the peer.
+    pub version_timeout: Duration,
+
+    /// Time to wait for the verack message of the peer once versions are exchanged.
+    pub verack_timeout: Duration,
+
+    /// Nonces of our outbound handshakes, shared by every handshake of a node.
+    pub nonces: NonceRegistry,
+}
+
+impl HandshakeConfig {
+    pub fn new(network: Network, version: VersionPayload) -> Self {
+        Self {
+            network,
+            version,
+            wtxid_relay: true,
+            addr_v2: true,
+            tx_reconciliation: None,
+            packages: None,
+            version_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            verack_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            nonces: NonceRegistry::new(),
+        }
+    }
+}
+
+/// PeerInfo holds what was learned about a peer during the handshake
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerInfo {
+    /// The protocol version used with the peer, the lowest of both versions.
+    pub version: i32,
+
+    /// The services supported by the peer.
+    pub services: ServiceFlags,
+
+    /// The user agent of the peer.
+    pub user_agent: String,
+
+    /// The height of the best chain of the peer when it connected.
+    pub start_height: i32,
+
+    /// Whether the peer wants transactions to be relayed.
+    pub relay: bool,
+
+    /// The clock of the peer minus ours in seconds, when its version was received.
+    pub time_offset: i64,
+
+    /// Whether the peer sent sendaddrv2, so it wants addresses in addrv2 messages (BIP155).
+    pub addr_v2: bool,
+
+    /// Whether the peer sent wtxidrelay, so it announces transactions by wtxid (BIP339).
+    pub wtxid_relay: bool,
+
+    /// The salt of the sendtxrcncl we sent, if we sent one (BIP330).
+    pub reconciliation_salt: Option<u64>,
+
+    /// The sendtxrcncl of the peer, kept only if we sent ours (BIP330).
+    pub tx_reconciliation: Option<SendTxRcnclPayload>,
+
+    /// The sendpackages of the peer, kept only if we sent ours (BIP331).
+    pub packages: Option<SendPackagesPayload>,
+}
+
+impl PeerInfo {
+    /// reconciliation_set creates the reconciliation set of the peer if both sides sent sendtxrcncl
+    pub fn reconciliation_set(&self) -> Result<Option<ReconciliationSet>> {
+        match (self.reconciliation_salt, &self.tx_reconciliation) {
+            (Some(salt), Some(remote)) => ReconciliationSet::new(salt, remote).map(Some),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// HandshakeState represents the stage of a handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeState {
+    AwaitingVersion,
+    AwaitingVerack,
+    Complete,
+}
+
+/// Handshake drives the version/verack exchange without doing any IO
+/// https://developer.bitcoin.org/devguide/p2p_network.html#connecting-to-peers
+///
+/// Messages received from the peer are given to `receive` and messages to send are taken from `poll_transmit`.
+/// The caller is responsible for calling `handle_timeout` once the instant returned by `timeout` is reached.
+#[derive(Debug)]
+pub struct Handshake {
+    config: HandshakeConfig,
+    inbound: bool,
+    state: HandshakeState,
+    deadline: Instant,
+    outbox: VecDeque<Message>,
+    peer_info: Option<PeerInfo>,
+    /// Whether we sent sendpackages, the one of the peer is ignored otherwise.
+    sent_packages: bool,
+}
+
+impl Handshake {
+    /// outbound starts a handshake on a connection we opened, our version is sent first
+    pub fn outbound(config: HandshakeConfig, now: Instant) -> Self {
+        let mut handshake = Self::new(config, false, now);
+        handshake
+            .config
+            .nonces
+            .insert(handshake.config.version.nonce);
+        handshake.send_version();
+        handshake
+    }
+
+    /// inbound starts a handshake on a connection the peer opened, we wait for its version first
+    pub fn inbound(config: HandshakeConfig, now: Instant) -> Self {
+        Self::new(config, true, now)
+    }
+
+    fn new(config: HandshakeConfig, inbound: bool, now: Instant) -> Self {
+        Self {
+            deadline: now + config.version_timeout,
+            config,
+            inbound,
+            state: HandshakeState::AwaitingVersion,
+            outbox: VecDeque::new(),
+            peer_info: None,
+            sent_packages: false,
+        }
+    }
+
+    /// network returns the network messages must belong to
+    pub fn network(&self) -> Network {
+        self.config.network
+    }
+
+    /// is_inbound checks if the peer opened the connection
+    pub fn is_inbound(&self) -> bool {
+        self.inbound
+    }
+
+    pub fn state(&self) -> HandshakeState {
+        self.state
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.state == HandshakeState::Complete
+    }
+
+    /// peer_info returns what was negotiated with the peer once the handshake is complete
+    pub fn peer_info(&self) -> Option<&PeerInfo> {
+        self.peer_info.as_ref().filter(|_| self.is_complete())
+    }
+
+    /// poll_transmit returns the next message to send to the peer
+    pub fn poll_transmit(&mut self) -> Option<Message> {
+        self.outbox.pop_front()
+    }
+
+    /// timeout returns when the current stage times out, if the handshake is not complete
+    pub fn timeout(&self) -> Option<Instant> {
+        (!self.is_complete()).then_some(self.deadline)
+    }
+
+    /// handle_timeout fails the handshake if the current stage took too long
+    pub fn handle_timeout(&mut self, now: Instant) -> Result<()> {
+        match self.timeout() {
+            Some(deadline) if now >= deadline => Err(BTCP2PError::HandshakeTimeout),
+            _ => Ok(()),
+        }
+    }
+
+    /// receive processes a message from the peer
+    /// once the handshake is complete, messages not part of it are handed back
+    pub fn receive(&mut self, message: Message, now: Instant) -> Result<Option<Message>> {
+        if message.network != self.config.network {
+            return Err(BTCP2PError::NetworkMismatch(message.network));
+        }
+
+        match (self.state, &message.payload) {
+            (HandshakeState::AwaitingVersion, Payload::Version(version)) => {
+                self.receive_version(version, now)?;
+                Ok(None)
+            }
+            (HandshakeState::AwaitingVersion, _) | (_, Payload::Version(_)) => {
+                Err(BTCP2PError::UnexpectedMessage(message.command))
+            }
+            (HandshakeState::AwaitingVerack, Payload::VerAck) => {
+                self.state = HandshakeState::Complete;
+                self.forget_nonce();
+                Ok(None)
+            }
+            (HandshakeState::AwaitingVerack, Payload::SendAddrV2) => {
+                if let Some(info) = &mut self.peer_info {
+                    info.addr_v2 = true;
+                }
+                Ok(None)
+            }
+            (HandshakeState::AwaitingVerack, Payload::WtxidRelay) => {
+                if let Some(info) = &mut self.peer_info {
+                    info.wtxid_relay = true;
+                }
+                Ok(None)
+            }
+            (HandshakeState::AwaitingVerack, Payload::SendTxRcncl(payload)) => {
+                if let Some(info) = &mut self.peer_info {
+                    if info.reconciliation_salt.is_some() {
+                        info.tx_reconciliation = Some(*payload);
+                    }
+                }
+                Ok(None)
+            }
+            (HandshakeState::AwaitingVerack, Payload::SendPackages(payload)) => {
+                if let Some(info) = &mut self.peer_info {
+                    if self.sent_packages {
+                        info.packages = Some(*payload);
+                    }
+                }
+                Ok(None)
+            }
+            // other messages before verack are ignored, as Bitcoin Core does
+            (HandshakeState::AwaitingVerack, _) => Ok(None),
+            // features can only be negotiated before verack
+            (HandshakeState::Complete, Payload::VerAck)
+            | (HandshakeState::Complete, Payload::WtxidRelay)
+            | (HandshakeState::Complete, Payload::SendAddrV2)
+            | (HandshakeState::Complete, Payload::SendTxRcncl(_))
+            | (HandshakeState::Complete, Payload::SendPackages(_)) => {
+                Err(BTCP2PError::UnexpectedMessage(message.command))
+            }
+            (HandshakeState::Complete, _) => Ok(Some(message)),
+        }
+    }
+
+    fn receive_version(&mut self, version: &VersionPayload, now: Instant) -> Result<()> {
+        if version.version < MIN_PEER_PROTO_VERSION {
+            return Err(BTCP2PError::ObsoleteVersion(version.version));
+        }
+        if self.inbound && self.config.nonces.contains(version.nonce) {
+            self.config.nonces.mark_self_connection(version.nonce);
+            return Err(BTCP2PError::SelfConnection);
+        }
+
+        let common_version = version.version.min(self.config.version.version);
+
+        if self.inbound {
+            self.send_version();
+        }
+        let wtxid_relay = self.config.wtxid_relay && common_version >= WTXID_RELAY_VERSION;
+        if wtxid_relay {
+            self.send(Command::WtxidRelay, Payload::WtxidRelay);
+        }
+        if self.config.addr_v2 {
+            self.send(Command::SendAddrV2, Payload::SendAddrV2);
+        }
+        // reconciliation and packages are announced by wtxid, so they need wtxidrelay
+        let tx_reconciliation = self
+            .config
+            .tx_reconciliation
+            .filter(|_| wtxid_relay && self.config.version.relay && version.relay);
+        if let Some(payload) = tx_reconciliation {
+            self.send(Command::SendTxRcncl, Payload::SendTxRcncl(payload));
+        }
+        if let Some(payload) = self.config.packages.filter(|_| wtxid_relay) {
+            self.send(Command::SendPackages, Payload::SendPackages(payload));
+            self.sent_packages = true;
+        }
+        self.send(Command::VerAck, Payload::VerAck);
+
+        self.peer_info = Some(PeerInfo {
+            version: common_version,
+            services: version.services,
+            user_agent: version.user_agent.clone(),
+            start_height: version.start_height,
+            relay: version.relay,
+            time_offset: version.timestamp.saturating_sub(unix_time()),
+            addr_v2: false,
+            wtxid_relay: false,
+            reconciliation_salt: tx_reconciliation.map(|payload| payload.salt),
+            tx_reconciliation: None,
+            packages: None,
+        });
+        self.state = HandshakeState::AwaitingVerack;
+        self.deadline = now + self.config.verack_timeout;
+
+        Ok(())
+    }
+
+    /// is_self_connection checks if our outbound version came back on one of our inbound connections
+    /// the other side closes the connection, so the handshake only fails with a closed connection
+    pub fn is_self_connection(&self) -> bool {
+        !self.inbound
+            && self
+                .config
+                .nonces
+                .is_self_connection(self.config.version.nonce)
+    }
+
+    /// forget_nonce removes our nonce from the registry once it can no longer match an inbound version
+    fn forget_nonce(&self) {
+        if !self.inbound {
+            self.config.nonces.remove(self.config.version.nonce);
+        }
+    }
+
+    fn send_version(&mut self) {
+        let version = Payload::Version(self.config.version.clone());
+        self.send(Command::Version, version);
+    }
+
+    fn send(&mut self, command: Command, payload: Payload) {
+        self.outbox
+            .push_back(Message::new(self.config.network, command, payload));
+    }
+}
+
+impl Drop for Handshake {
+    fn drop(&mut self) {
+        if !self.is_complete() {
+            self.forget_nonce();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::Arbitrary;
+
+    fn config(version: i32) -> HandshakeConfig {
+        let mut payload = VersionPayload::arbitrary(&mut quickcheck::Gen::new(10));
+        payload.version = version;
+        payload.user_agent = "/test:0.1.0/".to_string();
+        HandshakeConfig::new(Network::RegTest, payload)
+    }
+
+    fn message(command: Command, payload: Payload) -> Message {
+        Message::new(Network::RegTest, command, payload)
+    }
+
+    fn transmitted(handshake: &mut Handshake) -> Vec<Command> {
+        std::iter::from_fn(|| handshake.poll_transmit())
+            .map(|message| message.command)
+            .collect()
+    }
+
+    #[test]
+    fn test_outbound() {
+        let now = Instant::now();
+        let mut handshake = Handshake::outbound(config(70016), now);
+        assert_eq!(transmitted(&mut handshake), vec![Command::Version]);
+
+        let peer = config(70015).version;
+        handshake
+            .receive(
+                message(Command::Version, Payload::Version(peer.clone())),
+                now,
+            )
+            .unwrap();
+        // the peer does not support wtxidrelay
+        assert_eq!(
+            transmitted(&mut handshake),
+            vec![Command::SendAddrV2, Command::VerAck]
+        );
+        assert_eq!(handshake.state(), HandshakeState::AwaitingVerack);
+        assert!(handshake.peer_info().is_none());
+
+        handshake
+            .receive(message(Command::SendAddrV2, Payload::SendAddrV2), now)
+            .unwrap();
+        handshake
+            .receive(message(Command::VerAck, Payload::VerAck), now)
+            .unwrap();
+        assert!(handshake.is_complete());
+
+        let info = handshake.peer_info().unwrap();
+        assert_eq!(info.version, 70015);
+        assert_eq!(info.services, peer.services);
+        assert_eq!(info.user_agent, "/test:0.1.0/");
+        assert_eq!(info.start_height, peer.start_height);
+        assert_eq!(info.relay, peer.relay);
+    }
+
+    #[test]
+    fn test_inbound() {
+        let now = Instant::now();
+        let mut handshake = Handshake::inbound(config(70016), now);
+        assert!(handshake.poll_transmit().is_none());
+
+        handshake
+            .receive(
+                message(Command::Version, Payload::Version(config(70016).version)),
+                now,
+            )
+            .unwrap();
+        assert_eq!(
+            transmitted(&mut handshake),
+            vec![
+                Command::Version,
+                Command::WtxidRelay,
+                Command::SendAddrV2,
+                Command::VerAck
+            ]
+        );
+
+        handshake
+            .receive(message(Command::VerAck, Payload::VerAck), now)
+            .unwrap();
+        assert!(handshake.is_complete());
+
+        let ping = message(Command::Ping, Payload::Ping(42));
+        assert_eq!(handshake.receive(ping.clone(), now).unwrap(), Some(ping));
+    }
+
+    #[test]
+    fn test_version_must_come_first() {
+        let now = Instant::now();
+        let mut handshake = Handshake::outbound(config(70016), now);
+        assert!(handshake
+            .receive(message(Command::VerAck, Payload::VerAck), now)
+            .is_err());
+    }
+
+    #[test]
+    fn test_features_after_verack() {
+        let now = Instant::now();
+        let mut handshake = Handshake::outbound(config(70016), now);
+        handshake
+            .receive(
+                message(Command::Version, Payload::Version(config(70016).version)),
+                now,
+            )
+            .unwrap();
+        handshake
+            .receive(message(Command::VerAck, Payload::VerAck), now)
+            .unwrap();
+
+        assert!(handshake
+            .receive(message(Command::WtxidRelay, Payload::WtxidRelay), now)
+            .is_err());
+        assert!(handshake
+            .receive(
+                message(Command::Version, Payload::Version(config(70016).version)),
+                now
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_obsolete_version() {
+        let now = Instant::now();
+        let mut handshake = Handshake::outbound(config(70016), now);
+        assert!(handshake
+            .receive(
+                message(Command::Version, Payload::Version(config(209).version)),
+                now
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_network_mismatch() {
+        let now = Instant::now();
+        let mut handshake = Handshake::outbound(config(70016), now);
+        let version = Message::new(
+            Network::MainNet,
+            Command::Version,
+            Payload::Version(config(70016).version),
+        );
+        assert!(handshake.receive(version, now).is_err());
+
+        // any 4 bytes decode to a network, so messages with unknown magic bytes are rejected here
+        let magic = [0xde, 0xad, 0xbe, 0xef];
+        for (ours, theirs) in [
+            (Network::RegTest, Network::Custom(magic)),
+            (
+                Network::Custom(magic),
+                Network::Custom([0x0a, 0x03, 0xcf, 0x41]),
+            ),
+            (Network::Custom(magic), Network::Signet),
+        ] {
+            let mut handshake =
+                Handshake::outbound(HandshakeConfig::new(ours, config(70016).version), now);
+            let version = Message::new(
+                theirs,
+                Command::Version,
+                Payload::Version(config(70016).version),
+            );
+            let bytes = version.to_bytes().unwrap();
+            let version = Message::from_bytes(&bytes).unwrap();
+            assert!(matches!(
+                handshake.receive(version, now),
+                Err(BTCP2PError::NetworkMismatch(network)) if network == theirs
+            ));
+        }
+    }
+
+    #[test]
+    fn test_negotiate_reconciliation_and_packages() {
+        let now = Instant::now();
+        let mut ours = config(70016);
+        ours.version.relay = true;
+        ours.tx_reconciliation = Some(SendTxRcnclPayload {
+            version: crate::TXRECONCILIATION_VERSION,
+            salt: 1,
+        });
+        ours.packages = Some(SendPackagesPayload {
+            versions: crate::ANCESTOR_PACKAGE_VERSION,
+        });
+        let mut theirs = ours.clone();
+        theirs.nonces = NonceRegistry::new();
+        theirs.tx_reconciliation = Some(SendTxRcnclPayload {
+            version: crate::TXRECONCILIATION_VERSION,
+            salt: 2,
+        });
+
+        let mut outbound = Handshake::outbound(ours, now);
+        let mut inbound = Handshake::inbound(theirs, now);
+        while !outbound.is_complete() || !inbound.is_complete() {
+            let mut exchanged = false;
+            while let Some(message) = outbound.poll_transmit() {
+                inbound.receive(message, now).unwrap();
+                exchanged = true;
+            }
+            while let Some(message) = inbound.poll_transmit() {
+                outbound.receive(message, now).unwrap();
+                exchanged = true;
+            }
+            assert!(exchanged);
+        }
+
+        let ours = outbound.peer_info().unwrap();
+        let theirs = inbound.peer_info().unwrap();
+        assert_eq!(ours.reconciliation_salt, Some(1));
+        assert_eq!(ours.tx_reconciliation.map(|payload| payload.salt), Some(2));
+        assert_eq!(
+            theirs.tx_reconciliation.map(|payload| payload.salt),
+            Some(1)
+        );
+        assert!(ours
+            .packages
+            .unwrap()
+            .supports(crate::ANCESTOR_PACKAGE_VERSION));
+        assert!(theirs.packages.is_some());
+
+        // both sides derive the same short IDs
+        let wtxid = [7; 32];
+        let (ours, theirs) = (
+            ours.reconciliation_set().unwrap().unwrap(),
+            theirs.reconciliation_set().unwrap().unwrap(),
+        );
+        assert_eq!(ours.short_id(&wtxid), theirs.short_id(&wtxid));
+
+        // they can only be negotiated before verack
+        let sendpackages = message(
+            Command::SendPackages,
+            Payload::SendPackages(SendPackagesPayload { versions: 1 }),
+        );
+        assert!(outbound.receive(sendpackages, now).is_err());
+    }
+
+    #[test]
+    fn test_reconciliation_needs_both_sides() {
+        let now = Instant::now();
+        let mut handshake = Handshake::outbound(config(70016), now);
+        let mut peer = config(70016).version;
+        peer.relay = true;
+        handshake
+            .receive(message(Command::Version, Payload::Version(peer)), now)
+            .unwrap();
+        assert!(!transmitted(&mut handshake).contains(&Command::SendTxRcncl));
+
+        // the sendtxrcncl and sendpackages of the peer are ignored since we did not send ours
+        let sendtxrcncl = SendTxRcnclPayload {
+            version: crate::TXRECONCILIATION_VERSION,
+            salt: 2,
+        };
+        let sendpackages = SendPackagesPayload { versions: 1 };
+        for message in [
+            message(Command::SendTxRcncl, Payload::SendTxRcncl(sendtxrcncl)),
+            message(Command::SendPackages, Payload::SendPackages(sendpackages)),
+            message(Command::VerAck, Payload::VerAck),
+        ] {
+            handshake.receive(message, now).unwrap();
+        }
+        let info = handshake.peer_info().unwrap();
+        assert_eq!(info.tx_reconciliation, None);
+        assert_eq!(info.packages, None);
+        assert!(info.reconciliation_set().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_self_connection() {
+        let now = Instant::now();
+        let nonces = NonceRegistry::new();
+
+        let mut ours = config(70016);
+        ours.version.nonce = 42;
+        ours.nonces = nonces.clone();
+        let outbound = Handshake::outbound(ours.clone(), now);
+        assert!(nonces.contains(42));
+        assert!(!outbound.is_self_connection());
+
+        // the connection we opened arrives on our own listener, the outbound side learns about it
+        let mut inbound = Handshake::inbound(ours.clone(), now);
+        let version = message(Command::Version, Payload::Version(ours.version.clone()));
+        assert!(matches!(
+            inbound.receive(version.clone(), now),
+            Err(BTCP2PError::SelfConnection)
+        ));
+        assert!(outbound.is_self_connection());
+        assert!(!inbound.is_self_connection());
+
+        // other peers may use any nonce
+        let mut other = config(70016);
+        other.version.nonce = 43;
+        let mut inbound = Handshake::inbound(ours.clone(), now);
+        assert!(inbound
+            .receive(
+                message(Command::Version, Payload::Version(other.version)),
+                now
+            )
+            .is_ok());
+
+        drop(outbound);
+        assert!(!nonces.contains(42));
+    }
+
+    #[test]
+    fn test_nonce_forgotten_on_completion() {
+        let now = Instant::now();
+        let mut ours = config(70016);
+        ours.version.nonce = 42;
+        let nonces = ours.nonces.clone();
+
+        let mut handshake = Handshake::outbound(ours, now);
+        handshake
+            .receive(
+                message(Command::Version, Payload::Version(config(70016).version)),
+                now,
+            )
+            .unwrap();
+        assert!(nonces.contains(42));
+        handshake
+            .receive(message(Command::VerAck, Payload::VerAck), now)
+            .unwrap();
+        assert!(!nonces.contains(42));
+    }
+
+    #[test]
+    fn test_timeouts() {
+        let now = Instant::now();
+        let mut config = config(70016);
+        config.version_timeout = Duration::from_secs(5);
+        config.verack_timeout = Duration::from_secs(10);
+
+        let mut handshake = Handshake::outbound(config.clone(), now);
+        assert_eq!(handshake.timeout(), Some(now + Duration::from_secs(5)));
+        assert!(handshake.handle_timeout(now).is_ok());
+        assert!(handshake
+            .handle_timeout(now + Duration::from_secs(5))
+            .is_err());
+
+        let later = now + Duration::from_secs(3);
+        handshake
+            .receive(
+                message(Command::Version, Payload::Version(config.version)),
+                later,
+            )
+            .unwrap();
+        assert_eq!(handshake.timeout(), Some(later + Duration::from_secs(10)));
+
+        handshake
+            .receive(message(Command::VerAck, Payload::VerAck), later)
+            .unwrap();
+        assert_eq!(handshake.timeout(), None);
+        assert!(handshake
+            .handle_timeout(later + Duration::from_secs(60))
+            .is_ok());
+    }
+}
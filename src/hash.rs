@@ -0,0 +1,287 @@
+use sha2::{Digest, Sha256};
+
+/// sha256d hashes the data twice with SHA-256, as used for checksums, txids and block hashes
+pub(crate) fn sha256d(data: &[u8]) -> [u8; 32] {
+    let hash = Sha256::digest(data);
+    Sha256::digest(hash).into()
+}
+
+/// sha256 hashes the data once with SHA-256
+pub(crate) fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+/// hash_from_hex parses a hash in the usual display order, which is the reverse of its byte order
+/// panics on invalid input, it is meant to build constants at compile time
+pub(crate) const fn hash_from_hex(hex: &str) -> [u8; 32] {
+    const fn nibble(c: u8) -> u8 {
+        match c {
+            b'0'..=b'9' => c - b'0',
+            b'a'..=b'f' => c - b'a' + 10,
+            b'A'..=b'F' => c - b'A' + 10,
+            _ => panic!("invalid hex character"),
+        }
+    }
+
+    let hex = hex.as_bytes();
+    assert!(hex.len() == 64, "a hash is 64 hex characters long");
+
+    let mut hash = [0u8; 32];
+    let mut i = 0;
+    while i < 32 {
+        hash[31 - i] = (nibble(hex[2 * i]) << 4) | nibble(hex[2 * i + 1]);
+        i += 1;
+    }
+    hash
+}
+
+/// tagged_hash computes a BIP340 tagged hash: SHA256(SHA256(tag) || SHA256(tag) || data)
+/// https://github.com/bitcoin/bips/blob/master/bip-0340.mediawiki#design
+pub(crate) fn tagged_hash(tag: &str, data: &[u8]) -> [u8; 32] {
+    let tag_hash = Sha256::digest(tag.as_bytes());
+
+    let mut hasher = Sha256::new();
+    hasher.update(tag_hash);
+    hasher.update(tag_hash);
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+/// siphash24 computes SipHash-2-4 of the data with the 128 bit key (k0, k1)
+/// https://www.aumasson.jp/siphash/siphash.pdf
+pub(crate) fn siphash24(k0: u64, k1: u64, data: &[u8]) -> u64 {
+    let mut v = [
+        k0 ^ 0x736f6d6570736575,
+        k1 ^ 0x646f72616e646f6d,
+        k0 ^ 0x6c7967656e657261,
+        k1 ^ 0x7465646279746573,
+    ];
+
+    fn round(v: &mut [u64; 4]) {
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13) ^ v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16) ^ v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21) ^ v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17) ^ v[2];
+        v[2] = v[2].rotate_left(32);
+    }
+
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        let m = u64::from_le_bytes(chunk.try_into().expect("chunk of 8 bytes"));
+        v[3] ^= m;
+        round(&mut v);
+        round(&mut v);
+        v[0] ^= m;
+    }
+
+    // last block holds the remaining bytes and the message length in its top byte
+    let mut last = [0u8; 8];
+    let rest = chunks.remainder();
+    last[..rest.len()].copy_from_slice(rest);
+    let m = u64::from_le_bytes(last) | ((data.len() as u64) << 56);
+    v[3] ^= m;
+    round(&mut v);
+    round(&mut v);
+    v[0] ^= m;
+
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        round(&mut v);
+    }
+
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+/// hmac_sha256 computes HMAC-SHA256 of the data with a key, https://www.rfc-editor.org/rfc/rfc2104
+pub(crate) fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    const BLOCK_SIZE: usize = 64;
+
+    let mut block = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block[..32].copy_from_slice(&sha256(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = Sha256::new();
+    inner.update(block.map(|b| b ^ 0x36));
+    inner.update(data);
+
+    let mut outer = Sha256::new();
+    outer.update(block.map(|b| b ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().into()
+}
+
+/// Round constants of Keccak-f[1600]
+const KECCAK_ROUND_CONSTANTS: [u64; 24] = [
+    0x0000000000000001,
+    0x0000000000008082,
+    0x800000000000808a,
+    0x8000000080008000,
+    0x000000000000808b,
+    0x0000000080000001,
+    0x8000000080008081,
+    0x8000000000008009,
+    0x000000000000008a,
+    0x0000000000000088,
+    0x0000000080008009,
+    0x000000008000000a,
+    0x000000008000808b,
+    0x800000000000008b,
+    0x8000000000008089,
+    0x8000000000008003,
+    0x8000000000008002,
+    0x8000000000000080,
+    0x000000000000800a,
+    0x800000008000000a,
+    0x8000000080008081,
+    0x8000000000008080,
+    0x0000000080000001,
+    0x8000000080008008,
+];
+
+/// Rotations and lane order of the rho and pi steps of Keccak-f[1600]
+const KECCAK_ROTATIONS: [u32; 24] = [
+    1, 3, 6, 10, 15, 21, 28, 36, 45, 55, 2, 14, 27, 41, 56, 8, 25, 43, 62, 18, 39, 61, 20, 44,
+];
+const KECCAK_LANES: [usize; 24] = [
+    10, 7, 11, 17, 18, 3, 5, 16, 8, 21, 24, 4, 15, 23, 19, 13, 12, 2, 20, 14, 22, 9, 6, 1,
+];
+
+fn keccak_f(state: &mut [u64; 25]) {
+    for round_constant in KECCAK_ROUND_CONSTANTS {
+        // theta
+        let mut columns = [0u64; 5];
+        for (i, column) in columns.iter_mut().enumerate() {
+            *column = state[i] ^ state[i + 5] ^ state[i + 10] ^ state[i + 15] ^ state[i + 20];
+        }
+        for i in 0..5 {
+            let t = columns[(i + 4) % 5] ^ columns[(i + 1) % 5].rotate_left(1);
+            for j in (0..25).step_by(5) {
+                state[j + i] ^= t;
+            }
+        }
+
+        // rho and pi
+        let mut t = state[1];
+        for (lane, rotation) in KECCAK_LANES.iter().zip(KECCAK_ROTATIONS) {
+            let next = state[*lane];
+            state[*lane] = t.rotate_left(rotation);
+            t = next;
+        }
+
+        // chi
+        for j in (0..25).step_by(5) {
+            let row = [
+                state[j],
+                state[j + 1],
+                state[j + 2],
+                state[j + 3],
+                state[j + 4],
+            ];
+            for i in 0..5 {
+                state[j + i] ^= !row[(i + 1) % 5] & row[(i + 2) % 5];
+            }
+        }
+
+        // iota
+        state[0] ^= round_constant;
+    }
+}
+
+/// sha3_256 computes SHA3-256 of the data, as used by the checksum of onion addresses
+/// https://nvlpubs.nist.gov/nistpubs/FIPS/NIST.FIPS.202.pdf
+pub(crate) fn sha3_256(data: &[u8]) -> [u8; 32] {
+    const RATE: usize = 136;
+
+    let mut padded = data.to_vec();
+    padded.push(0x06);
+    padded.resize(padded.len().div_ceil(RATE) * RATE, 0);
+    *padded.last_mut().expect("padded data is not empty") |= 0x80;
+
+    let mut state = [0u64; 25];
+    for block in padded.chunks_exact(RATE) {
+        for (lane, bytes) in state.iter_mut().zip(block.chunks_exact(8)) {
+            *lane ^= u64::from_le_bytes(bytes.try_into().expect("chunk of 8 bytes"));
+        }
+        keccak_f(&mut state);
+    }
+
+    let mut hash = [0u8; 32];
+    for (bytes, lane) in hash.chunks_exact_mut(8).zip(state) {
+        bytes.copy_from_slice(&lane.to_le_bytes());
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sha256d() {
+        // checksum of an empty payload, as seen in every verack message
+        assert_eq!(sha256d(&[])[..4], [0x5d, 0xf6, 0xe0, 0xe2]);
+    }
+
+    #[test]
+    fn test_hash_from_hex() {
+        let hash =
+            hash_from_hex("00000000000000000000000000000000000000000000000000000000000001ff");
+        assert_eq!(hash[0], 0xff);
+        assert_eq!(hash[1], 0x01);
+        assert_eq!(hash[31], 0x00);
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_hmac_sha256() {
+        // test case 2 of RFC 4231
+        assert_eq!(
+            hmac_sha256(b"Jefe", b"what do ya want for nothing?").to_vec(),
+            hex("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843")
+        );
+    }
+
+    #[test]
+    fn test_sha3_256() {
+        assert_eq!(
+            sha3_256(b"").to_vec(),
+            hex("a7ffc6f8bf1ed76651c14756a061d662f580ff4de43b49fa82d80a4b80f8434a")
+        );
+        assert_eq!(
+            sha3_256(b"abc").to_vec(),
+            hex("3a985da74fe225b2045c172d6bd390bd855f086e3e9d525b46bfe24511431532")
+        );
+        // a message longer than a block
+        assert_eq!(
+            sha3_256(&[0xa3; 200]).to_vec(),
+            hex("79f38adec5c20307a98ef76e8324afbfd46cfd81b22e3973c65fa1bd9de31787")
+        );
+    }
+
+    #[test]
+    fn test_siphash24() {
+        // test vectors from the SipHash reference implementation
+        let k0 = u64::from_le_bytes([0, 1, 2, 3, 4, 5, 6, 7]);
+        let k1 = u64::from_le_bytes([8, 9, 10, 11, 12, 13, 14, 15]);
+        let data = (0u8..15).collect::<Vec<_>>();
+
+        assert_eq!(siphash24(k0, k1, &data[..0]), 0x726fdb47dd0e0e31);
+        assert_eq!(siphash24(k0, k1, &data[..1]), 0x74f839c593dc67fd);
+        assert_eq!(siphash24(k0, k1, &data[..8]), 0x93f5f5799a932462);
+        assert_eq!(siphash24(k0, k1, &data[..15]), 0xa129ca6149be45e5);
+    }
+}
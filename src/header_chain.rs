@@ -0,0 +1,793 @@
+use std::collections::HashMap;
+
+use super::{
+    block::BlockHeader,
+    chain_params::ChainParams,
+    errors::{BTCP2PError, Result},
+    pow::{check_proof_of_work, retarget, U256},
+};
+
+#[cfg(test)]
+use super::time_offsets::unix_time;
+
+/// Number of hashes of the locator taken one by one from the tip before the steps double
+const LOCATOR_DENSE_HASHES: usize = 10;
+
+/// How far before its parent the first header of a retarget interval may be under BIP94
+const MAX_TIMEWARP: u32 = 600;
+
+/// Number of headers whose median time a header must be after
+const MEDIAN_TIME_SPAN: usize = 11;
+
+/// How far after the adjusted time a header may be, headers further ahead are not stored yet
+pub const MAX_FUTURE_BLOCK_TIME: i64 = 2 * 60 * 60;
+
+/// Number of headers off the best chain kept by `HeaderSync`, branches with the least work are pruned first
+pub const MAX_STALE_HEADERS: usize = 10_000;
+
+/// HeaderEntry is a validated header with its position in the tree of headers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeaderEntry {
+    pub header: BlockHeader,
+
+    /// The hash of the header in byte order.
+    pub hash: [u8; 32],
+
+    pub height: u32,
+
+    /// The total work of the chain ending with this header.
+    pub chainwork: U256,
+}
+
+/// ChainChange lists the headers leaving and joining the best chain when it changes
+///
+/// A header extending the best chain only connects itself. A branch with more work disconnects the headers of
+/// the best chain after the fork, then connects its own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainChange {
+    /// Headers removed from the best chain, from the old tip down to the fork.
+    pub disconnected: Vec<HeaderEntry>,
+
+    /// Headers added to the best chain, from the fork up to the new tip.
+    pub connected: Vec<HeaderEntry>,
+}
+
+impl ChainChange {
+    /// is_reorg checks if headers of the best chain were replaced
+    pub fn is_reorg(&self) -> bool {
+        !self.disconnected.is_empty()
+    }
+}
+
+/// HeaderChain stores validated headers and follows the chain with the most work
+///
+/// Every header connecting to a stored header is kept, so a branch can become the best chain once it has more
+/// work. Headers must have valid proof of work and match the checkpoints of the chain parameters, and branches
+/// may not fork from the chain before the last stored checkpoint. `prune_stale` bounds the headers kept off the
+/// best chain.
+#[derive(Debug, Clone)]
+pub struct HeaderChain {
+    params: ChainParams,
+    entries: HashMap<[u8; 32], HeaderEntry>,
+    /// Hashes of the best chain by height.
+    active: Vec<[u8; 32]>,
+}
+
+impl HeaderChain {
+    /// new creates a chain holding the genesis header of the chain parameters
+    pub fn new(params: ChainParams) -> Self {
+        let header = params.genesis_header;
+        let genesis = HeaderEntry {
+            header,
+            hash: header.hash(),
+            height: 0,
+            chainwork: header.work(),
+        };
+
+        Self {
+            params,
+            entries: HashMap::from([(genesis.hash, genesis)]),
+            active: vec![genesis.hash],
+        }
+    }
+
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    /// tip returns the last header of the best chain
+    pub fn tip(&self) -> &HeaderEntry {
+        let hash = self.active.last().expect("chain holds the genesis header");
+        &self.entries[hash]
+    }
+
+    /// height returns the height of the best chain
+    pub fn height(&self) -> u32 {
+        self.tip().height
+    }
+
+    /// len returns the number of stored headers, including those of other branches
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains(&self, hash: &[u8; 32]) -> bool {
+        self.entries.contains_key(hash)
+    }
+
+    /// get returns a stored header by hash
+    pub fn get(&self, hash: &[u8; 32]) -> Option<&HeaderEntry> {
+        self.entries.get(hash)
+    }
+
+    /// get_by_height returns the header of the best chain at a height
+    pub fn get_by_height(&self, height: u32) -> Option<&HeaderEntry> {
+        self.active
+            .get(height as usize)
+            .map(|hash| &self.entries[hash])
+    }
+
+    /// is_active checks if a header is part of the best chain
+    pub fn is_active(&self, hash: &[u8; 32]) -> bool {
+        self.entries
+            .get(hash)
+            .is_some_and(|entry| self.active.get(entry.height as usize) == Some(hash))
+    }
+
+    /// ancestor returns the header at a height of the chain ending with a stored header
+    pub fn ancestor(&self, entry: &HeaderEntry, height: u32) -> Option<&HeaderEntry> {
+        if height > entry.height {
+            return None;
+        }
+
+        let mut current = self.entries.get(&entry.hash)?;
+        while !self.is_active(&current.hash) {
+            if current.height == height {
+                return Some(current);
+            }
+            current = self.entries.get(&current.header.prev_blockhash)?;
+        }
+        self.get_by_height(height)
+    }
+
+    /// next_work_required returns the bits expected of a header with the given time following prev
+    ///
+    /// The target changes every retarget interval. Networks allowing minimum difficulty blocks accept the pow
+    /// limit for a header more than twice the target spacing after its parent, and otherwise expect the bits of
+    /// the last header which did not use that exception (testnet3 and testnet4). Under BIP94 (testnet4) the new
+    /// target is scaled from the first header of the interval, which never uses the exception.
+    /// Signet blocks also carry a solution to the signet challenge, which is not part of the header.
+    pub fn next_work_required(&self, prev: &HeaderEntry, time: u32) -> u32 {
+        let params = &self.params;
+        let interval = params.retarget_interval();
+        let pow_limit = U256::from_le_bytes(params.pow_limit).to_compact();
+
+        if !(prev.height + 1).is_multiple_of(interval) {
+            if !params.pow_allow_min_difficulty_blocks {
+                return prev.header.bits;
+            }
+            if time
+                > prev
+                    .header
+                    .time
+                    .saturating_add(params.pow_target_spacing * 2)
+            {
+                return pow_limit;
+            }
+
+            let mut entry = prev;
+            while !entry.height.is_multiple_of(interval) && entry.header.bits == pow_limit {
+                match self.entries.get(&entry.header.prev_blockhash) {
+                    Some(parent) => entry = parent,
+                    None => break,
+                }
+            }
+            return entry.header.bits;
+        }
+
+        let first = self
+            .ancestor(prev, prev.height + 1 - interval)
+            .expect("ancestors of stored headers are stored");
+        let bits = if params.enforce_bip94 {
+            first.header.bits
+        } else {
+            prev.header.bits
+        };
+        retarget(
+            bits,
+            prev.header.time as i64 - first.header.time as i64,
+            params,
+        )
+    }
+
+    /// locator returns the block locator of the best chain, to ask peers for the headers following it
+    pub fn locator(&self) -> Vec<[u8; 32]> {
+        self.locator_from(&self.tip().hash)
+    }
+
+    /// locator_from returns a block locator starting at a stored header, which may be on another branch
+    /// hashes are taken one by one from the start, then with steps doubling back to genesis
+    pub fn locator_from(&self, hash: &[u8; 32]) -> Vec<[u8; 32]> {
+        let mut locator = vec![];
+
+        // walk a branch back to the best chain, one header at a time
+        let mut entry = self.entries.get(hash);
+        while let Some(current) = entry.filter(|current| !self.is_active(&current.hash)) {
+            locator.push(current.hash);
+            entry = self.entries.get(&current.header.prev_blockhash);
+        }
+        let Some(fork) = entry else {
+            return vec![self.active[0]];
+        };
+
+        let mut height = fork.height as usize;
+        let mut step = 1;
+        loop {
+            locator.push(self.active[height]);
+            if height == 0 {
+                break;
+            }
+            if locator.len() >= LOCATOR_DENSE_HASHES {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+        }
+        locator
+    }
+
+    /// change_from returns how the best chain changed since its tip was a stored header
+    /// None is returned if that header is still the tip or is not stored
+    pub fn change_from(&self, old_tip: &[u8; 32]) -> Option<ChainChange> {
+        let mut disconnected = vec![];
+        let mut entry = self.entries.get(old_tip)?;
+        while !self.is_active(&entry.hash) {
+            disconnected.push(*entry);
+            entry = &self.entries[&entry.header.prev_blockhash];
+        }
+
+        let connected: Vec<HeaderEntry> = self.active[entry.height as usize + 1..]
+            .iter()
+            .map(|hash| self.entries[hash])
+            .collect();
+        if disconnected.is_empty() && connected.is_empty() {
+            return None;
+        }
+        Some(ChainChange {
+            disconnected,
+            connected,
+        })
+    }
+
+    /// accept validates a header and stores it, returns the change of the best chain if it extends or replaces it
+    /// a header already stored is accepted again without changes
+    /// the time of the header is checked against the adjusted time, the Unix time corrected by peer offsets
+    pub fn accept(
+        &mut self,
+        header: BlockHeader,
+        adjusted_time: i64,
+    ) -> Result<Option<ChainChange>> {
+        let hash = header.hash();
+        if self.entries.contains_key(&hash) {
+            return Ok(None);
+        }
+        let prev = *self
+            .entries
+            .get(&header.prev_blockhash)
+            .ok_or(BTCP2PError::UnconnectedHeader)?;
+
+        check_proof_of_work(&hash, header.bits, &self.params.pow_limit)?;
+        let height = prev.height + 1;
+        if header.bits != self.next_work_required(&prev, header.time) {
+            return Err(BTCP2PError::BadDifficultyBits(height));
+        }
+        if self
+            .last_checkpoint_height()
+            .is_some_and(|checkpoint| height < checkpoint)
+        {
+            return Err(BTCP2PError::ForkBeforeCheckpoint(height));
+        }
+        if header.time <= self.median_time_past(&prev) {
+            return Err(BTCP2PError::HeaderTimeTooOld(height));
+        }
+        if self.params.enforce_bip94
+            && height.is_multiple_of(self.params.retarget_interval())
+            && header.time < prev.header.time.saturating_sub(MAX_TIMEWARP)
+        {
+            return Err(BTCP2PError::TimewarpAttack(height));
+        }
+        if i64::from(header.time) > adjusted_time + MAX_FUTURE_BLOCK_TIME {
+            return Err(BTCP2PError::HeaderTimeTooNew(height));
+        }
+        if self
+            .params
+            .checkpoint(height)
+            .is_some_and(|checkpoint| checkpoint != hash)
+        {
+            return Err(BTCP2PError::CheckpointMismatch(height));
+        }
+
+        let entry = HeaderEntry {
+            header,
+            hash,
+            height,
+            chainwork: prev.chainwork + header.work(),
+        };
+        self.entries.insert(hash, entry);
+
+        if entry.chainwork <= self.tip().chainwork {
+            return Ok(None);
+        }
+        let old_tip = self.tip().hash;
+        self.activate(entry);
+        Ok(self.change_from(&old_tip))
+    }
+
+    /// prune_stale drops the branches off the best chain with the least work until at most max headers are left
+    /// off it, returns the number of headers dropped
+    pub fn prune_stale(&mut self, max: usize) -> usize {
+        let stale = self.entries.len() - self.active.len();
+        if stale <= max {
+            return 0;
+        }
+
+        let mut children: HashMap<[u8; 32], usize> = HashMap::new();
+        for entry in self.entries.values() {
+            *children.entry(entry.header.prev_blockhash).or_default() += 1;
+        }
+        let mut tips: Vec<HeaderEntry> = self
+            .entries
+            .values()
+            .filter(|entry| !children.contains_key(&entry.hash) && !self.is_active(&entry.hash))
+            .copied()
+            .collect();
+        tips.sort_unstable_by_key(|entry| entry.chainwork);
+
+        let mut pruned = 0;
+        for tip in tips {
+            if stale - pruned <= max {
+                break;
+            }
+
+            // a branch is dropped back to the best chain or to a header other branches build on
+            let mut hash = tip.hash;
+            loop {
+                let entry = self
+                    .entries
+                    .remove(&hash)
+                    .expect("branch headers are stored");
+                pruned += 1;
+                hash = entry.header.prev_blockhash;
+                let count = children
+                    .get_mut(&hash)
+                    .expect("parents count their children");
+                *count -= 1;
+                if *count > 0 || self.is_active(&hash) {
+                    break;
+                }
+            }
+        }
+        pruned
+    }
+
+    /// last_checkpoint_height returns the height of the last checkpoint among the stored headers
+    fn last_checkpoint_height(&self) -> Option<u32> {
+        self.params
+            .checkpoints
+            .iter()
+            .rev()
+            .find(|(_, hash)| self.entries.contains_key(hash))
+            .map(|(height, _)| *height)
+    }
+
+    /// median_time_past returns the median time of a header and its ancestors, up to eleven headers
+    pub fn median_time_past(&self, entry: &HeaderEntry) -> u32 {
+        let mut times = Vec::with_capacity(MEDIAN_TIME_SPAN);
+        let mut current = Some(entry);
+        while let Some(entry) = current.filter(|_| times.len() < MEDIAN_TIME_SPAN) {
+            times.push(entry.header.time);
+            current = self.entries.get(&entry.header.prev_blockhash);
+        }
+        times.sort_unstable();
+        times[times.len() / 2]
+    }
+
+    /// activate makes the chain ending with a header the best chain
+    fn activate(&mut self, tip: HeaderEntry) {
+        let mut branch = vec![];
+        let mut entry = tip;
+        while !self.is_active(&entry.hash) {
+            branch.push(entry.hash);
+            entry = self.entries[&entry.header.prev_blockhash];
+        }
+
+        self.active.truncate(entry.height as usize + 1);
+        self.active.extend(branch.into_iter().rev());
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// mine builds a header on top of another with the given time and bits, grinding the nonce
+    pub(crate) fn mine(prev: &BlockHeader, time: u32, bits: u32) -> BlockHeader {
+        let target = U256::from_compact(bits).unwrap();
+        let mut header = BlockHeader {
+            version: 0x20000000,
+            prev_blockhash: prev.hash(),
+            merkle_root: rand::random(),
+            time,
+            bits,
+            nonce: 0,
+        };
+        while U256::from_le_bytes(header.hash()) > target {
+            header.nonce += 1;
+        }
+        header
+    }
+
+    /// extend mines count headers on top of another, ten minutes apart
+    pub(crate) fn extend(prev: &BlockHeader, count: usize) -> Vec<BlockHeader> {
+        let mut headers: Vec<BlockHeader> = vec![];
+        for _ in 0..count {
+            let last = headers.last().unwrap_or(prev);
+            headers.push(mine(last, last.time + 600, last.bits));
+        }
+        headers
+    }
+
+    #[test]
+    fn test_accept() {
+        let params = ChainParams::regtest();
+        let mut chain = HeaderChain::new(params.clone());
+        assert_eq!(chain.height(), 0);
+        assert_eq!(chain.tip().hash, params.genesis_hash());
+
+        let headers = extend(&params.genesis_header, 3);
+        for header in &headers {
+            assert!(chain.accept(*header, unix_time()).unwrap().is_some());
+        }
+        assert!(chain.accept(headers[0], unix_time()).unwrap().is_none());
+        assert_eq!(chain.height(), 3);
+        assert_eq!(chain.tip().hash, headers[2].hash());
+        assert_eq!(chain.tip().chainwork, U256::from(8));
+        assert_eq!(chain.get_by_height(1).unwrap().header, headers[0]);
+
+        // headers must connect
+        let orphan = extend(&headers[2], 2)[1];
+        assert!(matches!(
+            chain.accept(orphan, unix_time()),
+            Err(BTCP2PError::UnconnectedHeader)
+        ));
+
+        // and meet their target
+        let mut invalid = extend(&headers[2], 1)[0];
+        while U256::from_le_bytes(invalid.hash()) <= U256::from_compact(invalid.bits).unwrap() {
+            invalid.nonce += 1;
+        }
+        assert!(matches!(
+            chain.accept(invalid, unix_time()),
+            Err(BTCP2PError::InvalidProofOfWork)
+        ));
+        assert_eq!(chain.len(), 4);
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let mut params = ChainParams::regtest();
+        params.checkpoints = &[(1, [7; 32])];
+        let mut chain = HeaderChain::new(params.clone());
+        let header = extend(&params.genesis_header, 1)[0];
+        assert!(matches!(
+            chain.accept(header, unix_time()),
+            Err(BTCP2PError::CheckpointMismatch(1))
+        ));
+    }
+
+    #[test]
+    fn test_header_time() {
+        let params = ChainParams::regtest();
+        let mut chain = HeaderChain::new(params.clone());
+        let tip = accept_all(&mut chain, &extend(&params.genesis_header, 4));
+        let now = unix_time();
+
+        // headers must be after the median time of the last eleven headers
+        let median = chain.median_time_past(&tip);
+        assert_eq!(median, params.genesis_header.time + 2 * 600);
+        let old = mine(&tip.header, median, tip.header.bits);
+        assert!(matches!(
+            chain.accept(old, now),
+            Err(BTCP2PError::HeaderTimeTooOld(5))
+        ));
+        assert!(chain
+            .accept(mine(&tip.header, median + 1, tip.header.bits), now)
+            .unwrap()
+            .is_some());
+
+        // and at most two hours after the adjusted time, later headers are not stored
+        let time = (now + MAX_FUTURE_BLOCK_TIME) as u32;
+        let future = mine(&tip.header, time + 1, tip.header.bits);
+        let err = chain.accept(future, now).unwrap_err();
+        assert!(matches!(err, BTCP2PError::HeaderTimeTooNew(5)));
+        assert_eq!(err.penalty(), 0);
+        assert!(!chain.contains(&future.hash()));
+        assert!(chain.accept(future, now + 1).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_fork_before_checkpoint() {
+        let mut params = ChainParams::regtest();
+        let main = extend(&params.genesis_header, 3);
+        params.checkpoints = Box::leak(Box::new([(2, main[1].hash())]));
+        let mut chain = HeaderChain::new(params.clone());
+
+        // before the checkpoint is reached, branches may fork anywhere
+        let early = extend(&params.genesis_header, 1)[0];
+        accept_all(&mut chain, &[main[0], early]);
+        accept_all(&mut chain, &main[1..]);
+
+        let fork = extend(&params.genesis_header, 1)[0];
+        let err = chain.accept(fork, unix_time()).unwrap_err();
+        assert!(matches!(err, BTCP2PError::ForkBeforeCheckpoint(1)));
+        assert_eq!(err.penalty(), 100);
+        assert!(chain.accept(extend(&main[1], 1)[0], unix_time()).is_ok());
+    }
+
+    #[test]
+    fn test_prune_stale() {
+        let params = ChainParams::regtest();
+        let mut chain = HeaderChain::new(params.clone());
+        let main = extend(&params.genesis_header, 4);
+        let short = extend(&main[0], 1);
+        let long = extend(&main[0], 3);
+        let twig = extend(&long[0], 1);
+        accept_all(&mut chain, &main);
+        accept_all(&mut chain, &short);
+        accept_all(&mut chain, &long);
+        accept_all(&mut chain, &twig);
+        assert_eq!(chain.len(), 10);
+
+        assert_eq!(chain.prune_stale(5), 0);
+
+        // the branch with the least work goes first
+        assert_eq!(chain.prune_stale(4), 1);
+        assert!(!chain.contains(&short[0].hash()));
+
+        // a branch is dropped down to the header the other branch builds on
+        assert_eq!(chain.prune_stale(3), 1);
+        assert!(!chain.contains(&twig[0].hash()));
+        assert_eq!(chain.prune_stale(0), 3);
+        assert_eq!(chain.len(), main.len() + 1);
+        assert_eq!(chain.tip().hash, main[3].hash());
+    }
+
+    #[test]
+    fn test_most_work() {
+        let params = ChainParams::regtest();
+        let mut chain = HeaderChain::new(params.clone());
+        let main = extend(&params.genesis_header, 3);
+        let fork = extend(&main[0], 3);
+
+        for header in &main {
+            chain.accept(*header, unix_time()).unwrap();
+        }
+        // a branch with as much work does not replace the best chain
+        assert!(chain.accept(fork[0], unix_time()).unwrap().is_none());
+        assert!(chain.accept(fork[1], unix_time()).unwrap().is_none());
+        assert_eq!(chain.tip().hash, main[2].hash());
+        assert!(!chain.is_active(&fork[1].hash()));
+
+        assert!(chain.accept(fork[2], unix_time()).unwrap().is_some());
+        assert_eq!(chain.height(), 4);
+        assert_eq!(chain.tip().hash, fork[2].hash());
+        assert!(chain.is_active(&main[0].hash()));
+        assert!(!chain.is_active(&main[1].hash()));
+        assert_eq!(chain.get_by_height(2).unwrap().hash, fork[0].hash());
+        assert_eq!(chain.len(), 7);
+    }
+
+    #[test]
+    fn test_reorg() {
+        let params = ChainParams::regtest();
+        let mut chain = HeaderChain::new(params.clone());
+        let main = extend(&params.genesis_header, 3);
+        let fork = extend(&main[0], 3);
+        let hashes =
+            |entries: &[HeaderEntry]| entries.iter().map(|entry| entry.hash).collect::<Vec<_>>();
+
+        // extending the best chain connects the header alone
+        let change = chain.accept(main[0], unix_time()).unwrap().unwrap();
+        assert!(!change.is_reorg());
+        assert_eq!(hashes(&change.connected), vec![main[0].hash()]);
+        chain.accept(main[1], unix_time()).unwrap();
+        chain.accept(main[2], unix_time()).unwrap();
+        chain.accept(fork[0], unix_time()).unwrap();
+        chain.accept(fork[1], unix_time()).unwrap();
+
+        let change = chain.accept(fork[2], unix_time()).unwrap().unwrap();
+        assert!(change.is_reorg());
+        assert_eq!(
+            hashes(&change.disconnected),
+            vec![main[2].hash(), main[1].hash()]
+        );
+        assert_eq!(
+            hashes(&change.connected),
+            vec![fork[0].hash(), fork[1].hash(), fork[2].hash()]
+        );
+        assert_eq!(change.connected[0].height, 2);
+
+        // the change since any earlier tip can be asked for
+        assert_eq!(chain.change_from(&main[2].hash()), Some(change));
+        assert_eq!(chain.change_from(&fork[2].hash()), None);
+        assert_eq!(chain.change_from(&[7; 32]), None);
+        let change = chain.change_from(&main[0].hash()).unwrap();
+        assert!(!change.is_reorg());
+        assert_eq!(change.connected.len(), 3);
+    }
+
+    /// retargeting returns regtest parameters which retarget every four headers
+    fn retargeting() -> ChainParams {
+        ChainParams {
+            pow_target_timespan: 4 * 600,
+            pow_no_retargeting: false,
+            pow_allow_min_difficulty_blocks: false,
+            ..ChainParams::regtest()
+        }
+    }
+
+    /// accept_all adds headers to a chain, returns the last entry
+    fn accept_all(chain: &mut HeaderChain, headers: &[BlockHeader]) -> HeaderEntry {
+        for header in headers {
+            chain.accept(*header, unix_time()).unwrap();
+        }
+        *chain.tip()
+    }
+
+    #[test]
+    fn test_retarget() {
+        let params = retargeting();
+        let mut chain = HeaderChain::new(params.clone());
+        let tip = accept_all(&mut chain, &extend(&params.genesis_header, 3));
+
+        // blocks came every ten minutes but the interval spans three of them, so the target shrinks
+        let time = tip.header.time + 600;
+        let bits = chain.next_work_required(&tip, time);
+        assert_eq!(bits, retarget(tip.header.bits, 1800, &params));
+        assert_ne!(bits, tip.header.bits);
+
+        let stale = mine(&tip.header, time, tip.header.bits);
+        assert!(matches!(
+            chain.accept(stale, unix_time()),
+            Err(BTCP2PError::BadDifficultyBits(4))
+        ));
+        let header = mine(&tip.header, time, bits);
+        assert!(chain.accept(header, unix_time()).unwrap().is_some());
+
+        // the bits stay the same within the interval
+        let next = mine(&header, time + 600, params.genesis_header.bits);
+        assert!(matches!(
+            chain.accept(next, unix_time()),
+            Err(BTCP2PError::BadDifficultyBits(5))
+        ));
+        assert!(chain
+            .accept(extend(&header, 1)[0], unix_time())
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn test_min_difficulty_blocks() {
+        let params = ChainParams {
+            pow_allow_min_difficulty_blocks: true,
+            ..retargeting()
+        };
+        let limit = params.genesis_header.bits;
+        let mut chain = HeaderChain::new(params.clone());
+        let tip = accept_all(&mut chain, &extend(&params.genesis_header, 3));
+        let bits = chain.next_work_required(&tip, tip.header.time + 600);
+        let tip = accept_all(
+            &mut chain,
+            &[mine(&tip.header, tip.header.time + 600, bits)],
+        );
+
+        // a header twenty minutes after its parent may use the minimum difficulty
+        let slow = mine(&tip.header, tip.header.time + 1201, limit);
+        let tip = accept_all(&mut chain, &[slow]);
+        assert_eq!(chain.height(), 5);
+
+        // the following headers go back to the difficulty of the interval
+        assert_eq!(chain.next_work_required(&tip, tip.header.time + 600), bits);
+        let easy = mine(&tip.header, tip.header.time + 600, limit);
+        assert!(matches!(
+            chain.accept(easy, unix_time()),
+            Err(BTCP2PError::BadDifficultyBits(6))
+        ));
+    }
+
+    #[test]
+    fn test_bip94() {
+        let params = ChainParams {
+            pow_allow_min_difficulty_blocks: true,
+            enforce_bip94: true,
+            ..retargeting()
+        };
+        let limit = params.genesis_header.bits;
+        let mut chain = HeaderChain::new(params.clone());
+        let tip = accept_all(&mut chain, &extend(&params.genesis_header, 3));
+        let bits = chain.next_work_required(&tip, tip.header.time + 600);
+        let first = mine(&tip.header, tip.header.time + 600, bits);
+        let mut headers = extend(&first, 2);
+        headers.insert(0, first);
+        let tip = accept_all(&mut chain, &headers);
+
+        // the last header of the interval uses the minimum difficulty
+        let tip = accept_all(
+            &mut chain,
+            &[mine(&tip.header, tip.header.time + 1201, limit)],
+        );
+        assert_eq!(chain.height(), 7);
+
+        // the next target is scaled from the first header of the interval, not from the last one
+        let time = tip.header.time + 600;
+        let expected = retarget(bits, (tip.header.time - first.time) as i64, &params);
+        assert_eq!(chain.next_work_required(&tip, time), expected);
+
+        // the first header of an interval may not go back in time by more than ten minutes
+        let early = tip.header.time - 601;
+        let warped = mine(&tip.header, early, chain.next_work_required(&tip, early));
+        assert!(matches!(
+            chain.accept(warped, unix_time()),
+            Err(BTCP2PError::TimewarpAttack(8))
+        ));
+        assert!(chain
+            .accept(mine(&tip.header, time, expected), unix_time())
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn test_ancestor() {
+        let params = ChainParams::regtest();
+        let mut chain = HeaderChain::new(params.clone());
+        let main = extend(&params.genesis_header, 3);
+        let fork = extend(&main[0], 2);
+        accept_all(&mut chain, &main);
+        accept_all(&mut chain, &fork);
+
+        let fork_tip = *chain.get(&fork[1].hash()).unwrap();
+        assert_eq!(chain.ancestor(&fork_tip, 2).unwrap().hash, fork[0].hash());
+        assert_eq!(chain.ancestor(&fork_tip, 1).unwrap().hash, main[0].hash());
+        assert!(chain.ancestor(&fork_tip, 4).is_none());
+    }
+
+    #[test]
+    fn test_locator() {
+        let params = ChainParams::regtest();
+        let mut chain = HeaderChain::new(params.clone());
+        let headers = extend(&params.genesis_header, 30);
+        for header in &headers {
+            chain.accept(*header, unix_time()).unwrap();
+        }
+
+        let heights: Vec<u32> = chain
+            .locator()
+            .iter()
+            .map(|hash| chain.get(hash).unwrap().height)
+            .collect();
+        assert_eq!(
+            heights,
+            vec![30, 29, 28, 27, 26, 25, 24, 23, 22, 21, 19, 15, 7, 0]
+        );
+
+        // a locator from a branch starts with the branch
+        let fork = extend(&headers[27], 1)[0];
+        chain.accept(fork, unix_time()).unwrap();
+        let locator = chain.locator_from(&fork.hash());
+        assert_eq!(locator[0], fork.hash());
+        assert_eq!(locator[1], headers[27].hash());
+    }
+}
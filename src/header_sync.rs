@@ -0,0 +1,435 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use super::{
+    block::{GetHeadersPayload, HeadersPayload, MAX_HEADERS_RESULTS},
+    chain_params::ChainParams,
+    command::Command,
+    errors::{BTCP2PError, Result},
+    header_chain::{ChainChange, HeaderChain, MAX_STALE_HEADERS},
+    message::Message,
+    payload::Payload,
+    time_offsets::TimeOffsets,
+    PROTOCOL_VERSION,
+};
+
+/// Time a peer has to answer a getheaders before it is considered stalling
+pub const DEFAULT_HEADERS_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+
+/// Number of headers messages in a row which do not connect a peer may send, as in Bitcoin Core
+pub const MAX_UNCONNECTING_HEADERS: u32 = 10;
+
+/// SyncPeer is the sync state of a peer
+#[derive(Debug, Clone, Default)]
+struct SyncPeer {
+    /// Deadline of the getheaders the peer has not answered yet.
+    pending: Option<Instant>,
+
+    /// Headers messages in a row which did not connect to a known header.
+    unconnecting: u32,
+}
+
+/// HeaderSync downloads the best header chain from peers with getheaders
+///
+/// It is a state machine without IO: peers are added as they connect, their messages are handed to `receive`
+/// and the messages to send are taken from `poll_transmit`. Each peer is asked for the headers following our
+/// best chain, and asked again while it returns full headers messages. Block announcements, by inv or by
+/// headers for peers which sent sendheaders, are followed by asking for the headers that connect them. Peers
+/// which do not answer in time are returned by `handle_timeout`. Changes of the best chain, including reorgs,
+/// are taken from `poll_event`. Header times are checked against the adjusted time of the clock offsets, which
+/// should be shared with the `PeerConfig` of the peers.
+#[derive(Debug)]
+pub struct HeaderSync {
+    chain: HeaderChain,
+    peers: HashMap<SocketAddr, SyncPeer>,
+    transmit: VecDeque<(SocketAddr, Message)>,
+    events: VecDeque<ChainChange>,
+    timeout: Duration,
+    time_offsets: TimeOffsets,
+}
+
+impl HeaderSync {
+    /// new starts a sync from the genesis header of the chain parameters
+    pub fn new(params: ChainParams) -> Self {
+        Self::with_chain(HeaderChain::new(params))
+    }
+
+    /// with_chain resumes a sync from stored headers
+    pub fn with_chain(chain: HeaderChain) -> Self {
+        Self {
+            chain,
+            peers: HashMap::new(),
+            transmit: VecDeque::new(),
+            events: VecDeque::new(),
+            timeout: DEFAULT_HEADERS_TIMEOUT,
+            time_offsets: TimeOffsets::default(),
+        }
+    }
+
+    /// with_timeout sets the time peers have to answer a getheaders
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// with_time_offsets sets the clock offsets of peers header times are checked against
+    pub fn with_time_offsets(mut self, time_offsets: TimeOffsets) -> Self {
+        self.time_offsets = time_offsets;
+        self
+    }
+
+    pub fn chain(&self) -> &HeaderChain {
+        &self.chain
+    }
+
+    /// add_peer starts syncing from a peer which completed its handshake
+    pub fn add_peer(&mut self, addr: SocketAddr, now: Instant) {
+        self.peers.insert(addr, SyncPeer::default());
+        let locator = self.chain.locator();
+        self.get_headers(addr, locator, now);
+    }
+
+    /// remove_peer stops syncing from a peer, such as one which disconnected
+    pub fn remove_peer(&mut self, addr: &SocketAddr) {
+        self.peers.remove(addr);
+        self.transmit.retain(|(to, _)| to != addr);
+    }
+
+    /// receive processes a message of a peer, returns true if the best chain changed
+    /// errors are the misbehavior of the peer, scored by `BTCP2PError::penalty`
+    pub fn receive(&mut self, addr: SocketAddr, message: &Message, now: Instant) -> Result<bool> {
+        if !self.peers.contains_key(&addr) {
+            return Ok(false);
+        }
+
+        match &message.payload {
+            Payload::Headers(payload) => self.receive_headers(addr, payload, now),
+            Payload::Inv(payload) => {
+                let unknown = payload
+                    .inventory
+                    .iter()
+                    .any(|entry| entry.inv_type.is_block() && !self.chain.contains(&entry.hash));
+                if unknown && self.peers[&addr].pending.is_none() {
+                    let locator = self.chain.locator();
+                    self.get_headers(addr, locator, now);
+                }
+                Ok(false)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn receive_headers(
+        &mut self,
+        addr: SocketAddr,
+        payload: &HeadersPayload,
+        now: Instant,
+    ) -> Result<bool> {
+        let Some(peer) = self.peers.get_mut(&addr) else {
+            return Ok(false);
+        };
+        peer.pending = None;
+        let Some(first) = payload.headers.first() else {
+            return Ok(false);
+        };
+
+        // announcements may not connect when we are behind, the missing headers are asked for
+        // unless the peer keeps sending headers which never connect
+        if !self.chain.contains(&first.prev_blockhash) {
+            peer.unconnecting += 1;
+            if peer.unconnecting > MAX_UNCONNECTING_HEADERS {
+                return Err(BTCP2PError::TooManyUnconnectingHeaders(peer.unconnecting));
+            }
+            let locator = self.chain.locator();
+            self.get_headers(addr, locator, now);
+            return Ok(false);
+        }
+        peer.unconnecting = 0;
+        if payload
+            .headers
+            .windows(2)
+            .any(|pair| pair[1].prev_blockhash != pair[0].hash())
+        {
+            return Err(BTCP2PError::NonContinuousHeaders);
+        }
+
+        // headers accepted before an invalid one are kept, so their change is reported either way
+        let old_tip = self.chain.tip().hash;
+        let adjusted_time = self.time_offsets.adjusted_time();
+        let accepted = payload
+            .headers
+            .iter()
+            .try_for_each(|header| self.chain.accept(*header, adjusted_time).map(drop));
+        let change = self.chain.change_from(&old_tip);
+        let changed = change.is_some();
+        self.events.extend(change);
+        accepted?;
+
+        // a full message means the peer has more headers
+        if payload.headers.len() == MAX_HEADERS_RESULTS {
+            let last = payload.headers[MAX_HEADERS_RESULTS - 1].hash();
+            let locator = self.chain.locator_from(&last);
+            self.get_headers(addr, locator, now);
+        }
+        self.chain.prune_stale(MAX_STALE_HEADERS);
+        Ok(changed)
+    }
+
+    /// get_headers queues a getheaders for a peer and waits for its answer
+    fn get_headers(&mut self, addr: SocketAddr, locator: Vec<[u8; 32]>, now: Instant) {
+        let Some(peer) = self.peers.get_mut(&addr) else {
+            return;
+        };
+        peer.pending = Some(now + self.timeout);
+
+        let payload = GetHeadersPayload {
+            version: PROTOCOL_VERSION as u32,
+            locator,
+            stop_hash: [0; 32],
+        };
+        let message = Message::new(
+            self.chain.params().network,
+            Command::GetHeaders,
+            Payload::GetHeaders(payload),
+        );
+        self.transmit.push_back((addr, message));
+    }
+
+    /// poll_transmit returns the next message to send and the peer to send it to
+    pub fn poll_transmit(&mut self) -> Option<(SocketAddr, Message)> {
+        self.transmit.pop_front()
+    }
+
+    /// poll_event returns the next change of the best chain, one for each headers message which changed it
+    pub fn poll_event(&mut self) -> Option<ChainChange> {
+        self.events.pop_front()
+    }
+
+    /// timeout returns the earliest deadline of the getheaders waiting for an answer
+    pub fn timeout(&self) -> Option<Instant> {
+        self.peers.values().filter_map(|peer| peer.pending).min()
+    }
+
+    /// handle_timeout removes the peers which did not answer a getheaders in time and returns them
+    pub fn handle_timeout(&mut self, now: Instant) -> Vec<SocketAddr> {
+        let stalled: Vec<SocketAddr> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| peer.pending.is_some_and(|deadline| now >= deadline))
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in &stalled {
+            self.remove_peer(addr);
+        }
+        stalled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        block::BlockHeader,
+        header_chain::tests::extend,
+        inventory::{InvPayload, InvType, Inventory},
+        network::Network,
+        pow::U256,
+    };
+
+    fn headers(headers: &[BlockHeader]) -> Message {
+        Message::new(
+            Network::RegTest,
+            Command::Headers,
+            Payload::Headers(HeadersPayload {
+                headers: headers.to_vec(),
+            }),
+        )
+    }
+
+    /// locator returns the locator of the getheaders queued for a peer
+    fn locator(sync: &mut HeaderSync, peer: SocketAddr) -> Vec<[u8; 32]> {
+        let (to, message) = sync.poll_transmit().expect("getheaders");
+        assert_eq!(to, peer);
+        let Payload::GetHeaders(payload) = message.payload else {
+            panic!("expected getheaders");
+        };
+        payload.locator
+    }
+
+    #[test]
+    fn test_sync() {
+        let params = ChainParams::regtest();
+        let peer: SocketAddr = "127.0.0.1:18444".parse().unwrap();
+        let now = Instant::now();
+        let mut sync = HeaderSync::new(params.clone());
+
+        sync.add_peer(peer, now);
+        assert_eq!(locator(&mut sync, peer), vec![params.genesis_hash()]);
+        assert_eq!(sync.timeout(), Some(now + DEFAULT_HEADERS_TIMEOUT));
+
+        // a full message is followed by another getheaders
+        let chain = extend(&params.genesis_header, MAX_HEADERS_RESULTS + 5);
+        assert!(sync
+            .receive(peer, &headers(&chain[..MAX_HEADERS_RESULTS]), now)
+            .unwrap());
+        assert_eq!(sync.chain().height(), MAX_HEADERS_RESULTS as u32);
+        assert_eq!(
+            locator(&mut sync, peer)[0],
+            chain[MAX_HEADERS_RESULTS - 1].hash()
+        );
+
+        assert!(sync
+            .receive(peer, &headers(&chain[MAX_HEADERS_RESULTS..]), now)
+            .unwrap());
+        assert_eq!(sync.chain().tip().hash, chain.last().unwrap().hash());
+        assert!(sync.poll_transmit().is_none());
+        assert_eq!(sync.timeout(), None);
+
+        // an announcement which connects extends the chain
+        let next = extend(chain.last().unwrap(), 3);
+        assert!(sync.receive(peer, &headers(&next[..1]), now).unwrap());
+        assert!(sync.poll_transmit().is_none());
+
+        // one which does not is followed by a getheaders for the missing headers
+        assert!(!sync.receive(peer, &headers(&next[2..]), now).unwrap());
+        assert_eq!(locator(&mut sync, peer)[0], next[0].hash());
+        assert!(sync.receive(peer, &headers(&next[1..]), now).unwrap());
+        assert_eq!(sync.chain().tip().hash, next[2].hash());
+    }
+
+    #[test]
+    fn test_inv_announcement() {
+        let params = ChainParams::regtest();
+        let peer: SocketAddr = "127.0.0.1:18444".parse().unwrap();
+        let now = Instant::now();
+        let mut sync = HeaderSync::new(params.clone());
+        sync.add_peer(peer, now);
+        locator(&mut sync, peer);
+        sync.receive(peer, &headers(&[]), now).unwrap();
+
+        let inv = |hash| {
+            Message::new(
+                Network::RegTest,
+                Command::Inv,
+                Payload::Inv(InvPayload {
+                    inventory: vec![Inventory {
+                        inv_type: InvType::Block,
+                        hash,
+                    }],
+                }),
+            )
+        };
+        sync.receive(peer, &inv(params.genesis_hash()), now)
+            .unwrap();
+        assert!(sync.poll_transmit().is_none());
+
+        let block = extend(&params.genesis_header, 1)[0];
+        sync.receive(peer, &inv(block.hash()), now).unwrap();
+        assert_eq!(locator(&mut sync, peer), vec![params.genesis_hash()]);
+        assert!(sync.receive(peer, &headers(&[block]), now).unwrap());
+    }
+
+    #[test]
+    fn test_invalid_headers() {
+        let params = ChainParams::regtest();
+        let peer: SocketAddr = "127.0.0.1:18444".parse().unwrap();
+        let now = Instant::now();
+        let mut sync = HeaderSync::new(params.clone());
+        sync.add_peer(peer, now);
+
+        let mut chain = extend(&params.genesis_header, 3);
+        chain.swap(1, 2);
+        let err = sync.receive(peer, &headers(&chain), now).unwrap_err();
+        assert!(matches!(err, BTCP2PError::NonContinuousHeaders));
+        assert_eq!(err.penalty(), 20);
+    }
+
+    #[test]
+    fn test_unconnecting_headers() {
+        let params = ChainParams::regtest();
+        let peer: SocketAddr = "127.0.0.1:18444".parse().unwrap();
+        let now = Instant::now();
+        let mut sync = HeaderSync::new(params.clone());
+        sync.add_peer(peer, now);
+        locator(&mut sync, peer);
+
+        // each announcement which does not connect is answered with a getheaders
+        let chain = extend(&params.genesis_header, 3);
+        for _ in 0..MAX_UNCONNECTING_HEADERS {
+            assert!(!sync.receive(peer, &headers(&chain[2..]), now).unwrap());
+            assert_eq!(locator(&mut sync, peer), vec![params.genesis_hash()]);
+        }
+
+        // headers which connect reset the count
+        assert!(sync.receive(peer, &headers(&chain[..1]), now).unwrap());
+        for _ in 0..MAX_UNCONNECTING_HEADERS {
+            sync.receive(peer, &headers(&chain[2..]), now).unwrap();
+        }
+
+        let err = sync.receive(peer, &headers(&chain[2..]), now).unwrap_err();
+        assert!(matches!(
+            err,
+            BTCP2PError::TooManyUnconnectingHeaders(count) if count == MAX_UNCONNECTING_HEADERS + 1
+        ));
+        assert_eq!(err.penalty(), 20);
+    }
+
+    #[test]
+    fn test_reorg_events() {
+        let params = ChainParams::regtest();
+        let peer: SocketAddr = "127.0.0.1:18444".parse().unwrap();
+        let now = Instant::now();
+        let mut sync = HeaderSync::new(params.clone());
+        sync.add_peer(peer, now);
+        locator(&mut sync, peer);
+
+        let main = extend(&params.genesis_header, 3);
+        assert!(sync.receive(peer, &headers(&main), now).unwrap());
+        let change = sync.poll_event().unwrap();
+        assert!(!change.is_reorg());
+        assert_eq!(change.connected.len(), 3);
+        assert!(sync.poll_event().is_none());
+
+        // a heavier branch is reported once, and so are the headers accepted before an invalid one
+        let mut fork = extend(&main[0], 4);
+        while U256::from_le_bytes(fork[3].hash()) <= U256::from_compact(fork[3].bits).unwrap() {
+            fork[3].nonce += 1;
+        }
+        let err = sync.receive(peer, &headers(&fork), now).unwrap_err();
+        assert!(matches!(err, BTCP2PError::InvalidProofOfWork));
+        let change = sync.poll_event().unwrap();
+        assert_eq!(change.disconnected[0].hash, main[2].hash());
+        assert_eq!(change.disconnected[1].hash, main[1].hash());
+        assert_eq!(change.connected.last().unwrap().hash, fork[2].hash());
+        assert!(sync.poll_event().is_none());
+    }
+
+    #[test]
+    fn test_stalling_peer() {
+        let params = ChainParams::regtest();
+        let peers: [SocketAddr; 2] = [
+            "127.0.0.1:18444".parse().unwrap(),
+            "127.0.0.2:18444".parse().unwrap(),
+        ];
+        let now = Instant::now();
+        let mut sync = HeaderSync::new(params).with_timeout(Duration::from_secs(10));
+        sync.add_peer(peers[0], now);
+        sync.add_peer(peers[1], now + Duration::from_secs(5));
+        sync.receive(peers[1], &headers(&[]), now).unwrap();
+
+        assert!(sync.handle_timeout(now + Duration::from_secs(9)).is_empty());
+        assert_eq!(
+            sync.handle_timeout(now + Duration::from_secs(10)),
+            vec![peers[0]]
+        );
+        assert_eq!(sync.timeout(), None);
+
+        // messages of removed peers are ignored, and none is left for them
+        assert!(!sync.receive(peers[0], &headers(&[]), now).unwrap());
+        assert_eq!(sync.poll_transmit().unwrap().0, peers[1]);
+        assert!(sync.poll_transmit().is_none());
+    }
+}
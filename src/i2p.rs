@@ -0,0 +1,432 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use super::{
+    address::Address,
+    encode::{base32_decode, base32_encode, i2p_base64_decode},
+    errors::{BTCP2PError, Result},
+    hash::sha256,
+};
+
+/// Version of the SAM protocol we speak, https://geti2p.net/en/docs/api/samv3
+const SAM_VERSION: &str = "3.1";
+
+/// Signature type of generated destinations, EdDSA-SHA512-Ed25519 as used by Bitcoin Core
+const SIGNATURE_TYPE: u8 = 7;
+
+/// Longest line expected from the SAM bridge, a reply holding a private key is about 900 characters
+const MAX_SAM_LINE_LEN: usize = 65536;
+
+/// Suffix of the names of I2P destinations
+const B32_SUFFIX: &str = ".b32.i2p";
+
+/// b32_name returns the `.b32.i2p` name of the hash of a destination
+pub fn b32_name(hash: &[u8; 32]) -> String {
+    format!("{}{}", base32_encode(hash), B32_SUFFIX)
+}
+
+/// parse_b32_name returns the hash of the destination of a `.b32.i2p` name
+pub fn parse_b32_name(name: &str) -> Option<[u8; 32]> {
+    let encoded = name.strip_suffix(B32_SUFFIX)?;
+    base32_decode(encoded)?.try_into().ok()
+}
+
+/// destination_hash returns the hash of a destination in the base64 of I2P, which identifies it in addrv2
+fn destination_hash(destination: &str) -> Result<[u8; 32]> {
+    let bytes = i2p_base64_decode(destination)
+        .ok_or_else(|| BTCP2PError::SamError(format!("invalid destination {}", destination)))?;
+    Ok(sha256(&bytes))
+}
+
+struct SessionInner {
+    sam: SocketAddr,
+    id: String,
+    hash: [u8; 32],
+    /// The session lives as long as its control connection is open.
+    _control: TcpStream,
+}
+
+/// I2pSession is a session of a SAM v3 bridge, such as the one of i2pd or the Java router
+///
+/// The private key of our destination is kept in a file, so our I2P address survives restarts. Each
+/// connection, outbound with `connect` or inbound with `accept`, is a stream of its own to the bridge.
+/// I2P has no ports, peers reached over I2P use port 0 as in Bitcoin Core.
+/// Clones share the session, which is closed once every clone is dropped.
+#[derive(Clone)]
+pub struct I2pSession(Arc<SessionInner>);
+
+impl fmt::Debug for I2pSession {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("I2pSession")
+            .field("sam", &self.0.sam)
+            .field("id", &self.0.id)
+            .field("name", &self.name())
+            .finish()
+    }
+}
+
+impl I2pSession {
+    /// create opens a session with the destination of the key file, generating one if the file does not exist
+    pub async fn create<P: AsRef<Path>>(sam: SocketAddr, key_path: P) -> Result<Self> {
+        let mut control = hello(sam).await?;
+
+        let key_path = key_path.as_ref();
+        let private_key = match std::fs::read_to_string(key_path) {
+            Ok(key) => key.trim().to_string(),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                generate_key(&mut control, key_path).await?
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        let id = format!("{:010x}", rand::random::<u64>() & 0xff_ffff_ffff);
+        request(
+            &mut control,
+            &format!(
+                "SESSION CREATE STYLE=STREAM ID={} DESTINATION={} SIGNATURE_TYPE={}",
+                id, private_key, SIGNATURE_TYPE
+            ),
+            "SESSION STATUS",
+        )
+        .await?;
+
+        let reply = request(&mut control, "NAMING LOOKUP NAME=ME", "NAMING REPLY").await?;
+        let hash = destination_hash(value(&reply, "VALUE")?)?;
+
+        Ok(Self(Arc::new(SessionInner {
+            sam,
+            id,
+            hash,
+            _control: control,
+        })))
+    }
+
+    /// address returns our address as announced in addrv2 messages
+    pub fn address(&self) -> Address {
+        Address::I2p(self.0.hash)
+    }
+
+    /// name returns our `.b32.i2p` name
+    pub fn name(&self) -> String {
+        b32_name(&self.0.hash)
+    }
+
+    /// connect opens a stream to the destination with a hash
+    pub async fn connect(&self, hash: &[u8; 32]) -> Result<TcpStream> {
+        let mut stream = hello(self.0.sam).await?;
+
+        // the bridge may not know the full destination of a name yet
+        let reply = request(
+            &mut stream,
+            &format!("NAMING LOOKUP NAME={}", b32_name(hash)),
+            "NAMING REPLY",
+        )
+        .await?;
+        let destination = value(&reply, "VALUE")?.to_string();
+
+        request(
+            &mut stream,
+            &format!(
+                "STREAM CONNECT ID={} DESTINATION={} SILENT=false",
+                self.0.id, destination
+            ),
+            "STREAM STATUS",
+        )
+        .await?;
+
+        Ok(stream)
+    }
+
+    /// accept waits for a stream from another destination, returns the stream and the hash of its destination
+    pub async fn accept(&self) -> Result<(TcpStream, [u8; 32])> {
+        let mut stream = hello(self.0.sam).await?;
+        request(
+            &mut stream,
+            &format!("STREAM ACCEPT ID={} SILENT=false", self.0.id),
+            "STREAM STATUS",
+        )
+        .await?;
+
+        // the destination of the peer comes first, then the data of the stream
+        let line = read_line(&mut stream).await?;
+        let destination = line.split_whitespace().next().unwrap_or_default();
+        let hash = destination_hash(destination)?;
+
+        Ok((stream, hash))
+    }
+}
+
+/// hello connects to the bridge and negotiates the protocol version
+async fn hello(sam: SocketAddr) -> Result<TcpStream> {
+    let mut stream = TcpStream::connect(sam).await?;
+    request(
+        &mut stream,
+        &format!("HELLO VERSION MIN={} MAX={}", SAM_VERSION, SAM_VERSION),
+        "HELLO REPLY",
+    )
+    .await?;
+    Ok(stream)
+}
+
+/// generate_key asks the bridge for a new destination and saves its private key
+async fn generate_key(control: &mut TcpStream, key_path: &Path) -> Result<String> {
+    let reply = request(
+        control,
+        &format!("DEST GENERATE SIGNATURE_TYPE={}", SIGNATURE_TYPE),
+        "DEST REPLY",
+    )
+    .await?;
+    let private_key = value(&reply, "PRIV")?.to_string();
+
+    let tmp = PathBuf::from(format!("{}.new", key_path.display()));
+    std::fs::write(&tmp, &private_key)?;
+    std::fs::rename(&tmp, key_path)?;
+
+    Ok(private_key)
+}
+
+/// request sends a command and reads its reply, which must start with the expected words
+/// a reply with a RESULT other than OK is an error
+async fn request(
+    stream: &mut TcpStream,
+    command: &str,
+    expected: &str,
+) -> Result<HashMap<String, String>> {
+    stream
+        .write_all(format!("{}\n", command).as_bytes())
+        .await?;
+    let line = read_line(stream).await?;
+
+    let Some(rest) = line.strip_prefix(expected) else {
+        return Err(BTCP2PError::SamError(format!("unexpected reply {}", line)));
+    };
+    let reply = rest
+        .split_whitespace()
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect::<HashMap<_, _>>();
+
+    match reply.get("RESULT").map(String::as_str) {
+        None | Some("OK") => Ok(reply),
+        Some(result) => Err(BTCP2PError::SamError(result.to_string())),
+    }
+}
+
+fn value<'a>(reply: &'a HashMap<String, String>, key: &str) -> Result<&'a str> {
+    reply
+        .get(key)
+        .map(String::as_str)
+        .ok_or_else(|| BTCP2PError::SamError(format!("missing {}", key)))
+}
+
+/// read_line reads a line from the bridge without reading past it, the stream data may follow
+async fn read_line(stream: &mut TcpStream) -> Result<String> {
+    let mut line = vec![];
+    loop {
+        match stream.read_u8().await? {
+            b'\n' => break,
+            byte => line.push(byte),
+        }
+        if line.len() > MAX_SAM_LINE_LEN {
+            return Err(BTCP2PError::SamError("reply too long".to_string()));
+        }
+    }
+    Ok(String::from_utf8(line)?.trim_end_matches('\r').to_string())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::encode::i2p_base64_encode;
+    use std::{
+        sync::Mutex,
+        time::{Duration, Instant},
+    };
+    use tokio::net::TcpListener;
+
+    type Accepts = Arc<Mutex<HashMap<String, Vec<TcpStream>>>>;
+
+    /// fake_sam starts a SAM bridge whose destinations are only reachable from each other
+    /// a private key is its public destination followed by `.priv`
+    pub(crate) async fn fake_sam() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let sessions = Arc::new(Mutex::new(HashMap::<String, String>::new()));
+        let accepts: Accepts = Arc::default();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, sessions.clone(), accepts.clone()));
+            }
+        });
+
+        addr
+    }
+
+    async fn serve(
+        mut stream: TcpStream,
+        sessions: Arc<Mutex<HashMap<String, String>>>,
+        accepts: Accepts,
+    ) {
+        let mut session = None;
+        while let Ok(line) = read_line(&mut stream).await {
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            let args = words
+                .iter()
+                .filter_map(|pair| pair.split_once('='))
+                .collect::<HashMap<_, _>>();
+
+            let reply = match (words[0], words[1]) {
+                ("HELLO", _) => "HELLO REPLY RESULT=OK VERSION=3.1".to_string(),
+                ("DEST", "GENERATE") => {
+                    let public = i2p_base64_encode(&rand::random::<[u8; 32]>());
+                    format!("DEST REPLY PUB={} PRIV={}.priv", public, public)
+                }
+                ("SESSION", "CREATE") => {
+                    let public = args["DESTINATION"].trim_end_matches(".priv").to_string();
+                    sessions
+                        .lock()
+                        .unwrap()
+                        .insert(args["ID"].to_string(), public.clone());
+                    session = Some(public);
+                    "SESSION STATUS RESULT=OK".to_string()
+                }
+                ("NAMING", "LOOKUP") if args["NAME"] == "ME" => {
+                    format!(
+                        "NAMING REPLY RESULT=OK NAME=ME VALUE={}",
+                        session.clone().unwrap()
+                    )
+                }
+                ("NAMING", "LOOKUP") => {
+                    let hash = parse_b32_name(args["NAME"]).unwrap();
+                    let sessions = sessions.lock().unwrap().clone();
+                    match sessions
+                        .values()
+                        .find(|public| destination_hash(public).unwrap() == hash)
+                    {
+                        Some(public) => format!("NAMING REPLY RESULT=OK VALUE={}", public),
+                        None => "NAMING REPLY RESULT=KEY_NOT_FOUND".to_string(),
+                    }
+                }
+                ("STREAM", "ACCEPT") => {
+                    let public = sessions.lock().unwrap()[args["ID"]].clone();
+                    stream
+                        .write_all(b"STREAM STATUS RESULT=OK\n")
+                        .await
+                        .unwrap();
+                    accepts
+                        .lock()
+                        .unwrap()
+                        .entry(public)
+                        .or_default()
+                        .push(stream);
+                    return;
+                }
+                ("STREAM", "CONNECT") => {
+                    let from = sessions.lock().unwrap()[args["ID"]].clone();
+                    let Some(mut accepted) = wait_accept(&accepts, args["DESTINATION"]).await
+                    else {
+                        let reply = "STREAM STATUS RESULT=CANT_REACH_PEER\n";
+                        stream.write_all(reply.as_bytes()).await.unwrap();
+                        continue;
+                    };
+                    stream
+                        .write_all(b"STREAM STATUS RESULT=OK\n")
+                        .await
+                        .unwrap();
+                    accepted
+                        .write_all(format!("{} FROM_PORT=0 TO_PORT=0\n", from).as_bytes())
+                        .await
+                        .unwrap();
+                    let _ = tokio::io::copy_bidirectional(&mut stream, &mut accepted).await;
+                    return;
+                }
+                _ => "ERROR RESULT=I2P_ERROR".to_string(),
+            };
+            stream
+                .write_all(format!("{}\n", reply).as_bytes())
+                .await
+                .unwrap();
+        }
+    }
+
+    /// wait_accept waits a little for the destination to accept a stream
+    async fn wait_accept(accepts: &Accepts, destination: &str) -> Option<TcpStream> {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(1) {
+            let accepted = accepts
+                .lock()
+                .unwrap()
+                .get_mut(destination)
+                .and_then(Vec::pop);
+            if accepted.is_some() {
+                return accepted;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        None
+    }
+
+    pub(crate) fn key_path() -> PathBuf {
+        std::env::temp_dir().join(format!("i2p-{:x}.key", rand::random::<u64>()))
+    }
+
+    #[test]
+    fn test_b32_name() {
+        let hash = [7u8; 32];
+        let name = b32_name(&hash);
+        assert_eq!(name.len(), 52 + B32_SUFFIX.len());
+        assert_eq!(parse_b32_name(&name), Some(hash));
+        assert_eq!(parse_b32_name("example.i2p"), None);
+    }
+
+    #[tokio::test]
+    async fn test_persisted_destination() {
+        let sam = fake_sam().await;
+        let path = key_path();
+
+        let session = I2pSession::create(sam, &path).await.unwrap();
+        let session2 = I2pSession::create(sam, &path).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // the destination is kept across sessions
+        assert_eq!(session.address(), session2.address());
+        assert_eq!(parse_b32_name(&session.name()), Some(session.0.hash));
+    }
+
+    #[tokio::test]
+    async fn test_stream() {
+        let sam = fake_sam().await;
+        let (path, path2) = (key_path(), key_path());
+        let server = I2pSession::create(sam, &path).await.unwrap();
+        let client = I2pSession::create(sam, &path2).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&path2).unwrap();
+
+        let accept = tokio::spawn({
+            let server = server.clone();
+            async move { server.accept().await }
+        });
+        let mut stream = client.connect(&server.0.hash).await.unwrap();
+        let (mut accepted, from) = accept.await.unwrap().unwrap();
+        assert_eq!(from, client.0.hash);
+
+        stream.write_all(b"ping").await.unwrap();
+        let mut buffer = [0u8; 4];
+        accepted.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"ping");
+
+        // destinations nobody accepts streams for can't be reached
+        let result = client.connect(&[1u8; 32]).await;
+        assert!(matches!(result, Err(BTCP2PError::SamError(_))));
+    }
+}
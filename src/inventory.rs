@@ -0,0 +1,175 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Read, Write};
+
+use super::{
+    encode::{read_vec_len, write_compact_size},
+    errors::{BTCP2PError, Result},
+};
+
+/// Maximum number of entries in an inv message, as in Bitcoin Core
+pub const MAX_INV_SIZE: usize = 50000;
+
+/// Flag of the inventory types asking for witness data (BIP144)
+const MSG_WITNESS_FLAG: u32 = 1 << 30;
+
+/// InvType is the kind of object an inventory entry refers to
+/// https://developer.bitcoin.org/reference/p2p_networking.html#data-messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InvType {
+    Error,
+    Tx,
+    Block,
+    FilteredBlock,
+    CmpctBlock,
+    /// A transaction identified by its wtxid (BIP339).
+    WTx,
+    /// A package identified by its package ID, in notfound answers to getpkgtxns (BIP331).
+    PkgTxns,
+    /// The ancestor package of a transaction identified by its wtxid, requested with getdata (BIP331).
+    AncPkgInfo,
+    WitnessTx,
+    WitnessBlock,
+    /// A type we don't know, kept as received.
+    Unknown(u32),
+}
+
+impl InvType {
+    pub fn to_u32(self) -> u32 {
+        match self {
+            InvType::Error => 0,
+            InvType::Tx => 1,
+            InvType::Block => 2,
+            InvType::FilteredBlock => 3,
+            InvType::CmpctBlock => 4,
+            InvType::WTx => 5,
+            InvType::PkgTxns => 6,
+            InvType::AncPkgInfo => 7,
+            InvType::WitnessTx => 1 | MSG_WITNESS_FLAG,
+            InvType::WitnessBlock => 2 | MSG_WITNESS_FLAG,
+            InvType::Unknown(value) => value,
+        }
+    }
+
+    /// is_block checks if the entry refers to a block, in any form
+    pub fn is_block(self) -> bool {
+        matches!(
+            self,
+            InvType::Block | InvType::FilteredBlock | InvType::CmpctBlock | InvType::WitnessBlock
+        )
+    }
+}
+
+impl From<u32> for InvType {
+    fn from(value: u32) -> Self {
+        match value {
+            0 => InvType::Error,
+            1 => InvType::Tx,
+            2 => InvType::Block,
+            3 => InvType::FilteredBlock,
+            4 => InvType::CmpctBlock,
+            5 => InvType::WTx,
+            6 => InvType::PkgTxns,
+            7 => InvType::AncPkgInfo,
+            value if value == 1 | MSG_WITNESS_FLAG => InvType::WitnessTx,
+            value if value == 2 | MSG_WITNESS_FLAG => InvType::WitnessBlock,
+            value => InvType::Unknown(value),
+        }
+    }
+}
+
+/// Inventory is an entry of inv, getdata and notfound messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Inventory {
+    pub inv_type: InvType,
+
+    /// The hash of the object in byte order.
+    pub hash: [u8; 32],
+}
+
+/// InvPayload represents the payload of an inv, getdata or notfound message
+/// https://developer.bitcoin.org/reference/p2p_networking.html#inv
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvPayload {
+    pub inventory: Vec<Inventory>,
+}
+
+impl InvPayload {
+    /// wtx builds the payload announcing transactions by wtxid (BIP339)
+    pub fn wtx(wtxids: impl IntoIterator<Item = [u8; 32]>) -> Self {
+        Self {
+            inventory: wtxids
+                .into_iter()
+                .map(|hash| Inventory {
+                    inv_type: InvType::WTx,
+                    hash,
+                })
+                .collect(),
+        }
+    }
+
+    /// to_bytes converts the payload to bytes
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut buffer = vec![];
+        write_compact_size(&mut buffer, self.inventory.len() as u64)?;
+        for entry in &self.inventory {
+            buffer.write_u32::<LittleEndian>(entry.inv_type.to_u32())?;
+            buffer.write_all(&entry.hash)?;
+        }
+        Ok(buffer)
+    }
+
+    /// from_bytes converts bytes to a payload
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self> {
+        let len = read_vec_len(&mut bytes, 4 + 32)?;
+        if len > MAX_INV_SIZE {
+            return Err(BTCP2PError::TooManyInventory(len));
+        }
+
+        let inventory = (0..len)
+            .map(|_| {
+                let inv_type = bytes.read_u32::<LittleEndian>()?.into();
+                let mut hash = [0u8; 32];
+                bytes.read_exact(&mut hash)?;
+                Ok(Inventory { inv_type, hash })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { inventory })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::{Arbitrary, TestResult};
+    use quickcheck_macros::quickcheck;
+
+    impl Arbitrary for InvPayload {
+        fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+            let len = usize::arbitrary(g) % 16;
+            Self {
+                inventory: (0..len)
+                    .map(|_| Inventory {
+                        inv_type: u32::arbitrary(g).into(),
+                        hash: [u8::arbitrary(g); 32],
+                    })
+                    .collect(),
+            }
+        }
+    }
+
+    #[quickcheck]
+    fn test_to_bytes(payload: InvPayload) -> TestResult {
+        let bytes = payload.to_bytes().unwrap();
+        TestResult::from_bool(payload == InvPayload::from_bytes(&bytes).unwrap())
+    }
+
+    #[test]
+    fn test_inv_type() {
+        assert_eq!(InvType::from(0x40000002), InvType::WitnessBlock);
+        assert_eq!(InvType::WitnessTx.to_u32(), 0x40000001);
+        assert_eq!(InvType::from(7), InvType::AncPkgInfo);
+        assert_eq!(InvType::from(8), InvType::Unknown(8));
+        assert!(InvType::CmpctBlock.is_block());
+        assert!(!InvType::WTx.is_block());
+    }
+}
@@ -4,27 +4,113 @@
 //!
 //! This crate provides a pure Rust implementation of the Bitcoin protocol.
 
+mod address;
+mod addrman;
+mod ban;
+mod bandwidth;
+mod block;
+mod chain_params;
 mod command;
+mod encode;
+mod erlay;
 mod errors;
+mod features;
+mod handshake;
+mod hash;
+mod header_chain;
+mod header_sync;
+mod i2p;
+mod inventory;
+mod listener;
+mod local_addresses;
 mod message;
+mod minisketch;
+mod nat;
 mod network;
+mod package;
 mod payload;
+mod peer;
+mod peer_manager;
+mod ping;
+mod pow;
+mod proxy;
+mod seeds;
+mod service_flags;
+mod sync;
+mod time_offsets;
+mod tor;
+mod transaction;
 
+pub use address::{AddrPayload, AddrV2Payload, Address, NetAddress, MAX_ADDR_TO_SEND};
+pub use addrman::{AddrInfo, AddrMan, GOSSIP_TIME_PENALTY};
+pub use ban::{Ban, BanManager, Misbehavior, Subnet, DEFAULT_BAN_DURATION, DEFAULT_BAN_THRESHOLD};
+pub use bandwidth::{
+    Bandwidth, BandwidthLimits, TokenBucket, HISTORICAL_BLOCK_AGE, UPLOAD_TARGET_CYCLE,
+};
+pub use block::{
+    BlockHeader, GetHeadersPayload, HeadersPayload, BLOCK_HEADER_SIZE, MAX_HEADERS_RESULTS,
+    MAX_LOCATOR_SIZE,
+};
+pub use chain_params::ChainParams;
 pub use command::Command;
+pub use erlay::{
+    ReconcilDiffPayload, ReconciliationOutcome, ReconciliationSet, ReqReconPayload,
+    SendTxRcnclPayload, SketchPayload, DEFAULT_Q, TXRECONCILIATION_VERSION,
+};
 pub use errors::{BTCP2PError, Result};
+pub use features::{PeerFeatures, SendCmpctPayload, CMPCTBLOCKS_VERSION};
+pub use handshake::{
+    Handshake, HandshakeConfig, HandshakeState, NonceRegistry, PeerInfo, DEFAULT_HANDSHAKE_TIMEOUT,
+};
+pub use header_chain::{
+    ChainChange, HeaderChain, HeaderEntry, MAX_FUTURE_BLOCK_TIME, MAX_STALE_HEADERS,
+};
+pub use header_sync::{HeaderSync, DEFAULT_HEADERS_TIMEOUT, MAX_UNCONNECTING_HEADERS};
+pub use i2p::{b32_name, parse_b32_name, I2pSession};
+pub use inventory::{InvPayload, InvType, Inventory, MAX_INV_SIZE};
+pub use listener::{Listener, DEFAULT_MAX_INBOUND};
+pub use local_addresses::LocalAddresses;
 pub use message::Message;
-pub use network::Network;
-pub use payload::{Payload, ServiceFlags, VersionPayload};
+pub use minisketch::{Minisketch, MAX_SKETCH_CAPACITY};
+pub use nat::{
+    default_gateway, MappingProtocol, PortMapper, PortMapping, DEFAULT_MAPPING_LIFETIME,
+    NAT_PMP_PORT,
+};
+pub use network::{Network, DEFAULT_SIGNET_CHALLENGE};
+pub use package::{
+    package_id, AncPkgInfoPayload, GetPkgTxnsPayload, PkgTxnsPayload, SendPackagesPayload,
+    ANCESTOR_PACKAGE_VERSION, MAX_PACKAGE_COUNT,
+};
+pub use payload::{Payload, VersionPayload, MAX_SUBVERSION_LENGTH};
+pub use peer::{Peer, PeerConfig};
+pub use peer_manager::{
+    netgroup, AddressSource, PeerEvent, PeerManager, PeerManagerConfig, DEFAULT_RETRY_INTERVAL,
+    DEFAULT_TARGET_OUTBOUND,
+};
+pub use ping::{Latency, PingScheduler, DEFAULT_PING_INTERVAL, DEFAULT_PING_TIMEOUT};
+pub use pow::{check_proof_of_work, U256};
+pub use proxy::{Credentials, Destination, Proxy};
+pub use seeds::{DnsResolver, Resolver, SeedResolver, SystemResolver, DEFAULT_DNS_TIMEOUT};
+pub use service_flags::ServiceFlags;
+pub use time_offsets::{TimeOffsets, DEFAULT_MAX_TIME_ADJUSTMENT, MAX_TIME_OFFSET_SAMPLES};
+pub use tor::{onion_name, parse_onion_name, OnionService, TorControl};
+pub use transaction::{OutPoint, Transaction, TxIn, TxOut};
 
 /// Protocol version for the BTC proto
 /// https://developer.bitcoin.org/reference/p2p_networking.html#protocol-versions
 ///
 /// The table below lists some notable versions of the P2P network protocol, with the most recent versions listed first.
 /// (If you know of a protocol version that implemented a major change but which is not listed here, please open an issue.)
-const PROTOCOL_VERSION: i32 = 70015;
+const PROTOCOL_VERSION: i32 = 70016;
 
-/// Message format for the BTC proto:
-/// https://developer.bitcoin.org/reference/p2p_networking.html#message-headers
+/// Protocol version from which wtxidrelay is negotiated (BIP339)
+const WTXID_RELAY_VERSION: i32 = 70016;
+
+/// Oldest protocol version peers may use, as in Bitcoin Core
+const MIN_PEER_PROTO_VERSION: i32 = 31800;
+
+// Message format for the BTC proto:
+// https://developer.bitcoin.org/reference/p2p_networking.html#message-headers
 
 /// Max size for the start string in the message header
 const START_STRING_SIZE: usize = 4;
@@ -44,6 +130,9 @@ const HEADER_SIZE: usize = START_STRING_SIZE + COMMAND_NAME_SIZE + PAYLOAD_LEN_S
 // 32 MB
 const MAX_PAYLOAD_SIZE: usize = 32 * 1024 * 1024;
 
+/// Maximum payload length of a message read from a peer, MAX_PROTOCOL_MESSAGE_LENGTH of Bitcoin Core
+const MAX_PROTOCOL_MESSAGE_LENGTH: usize = 4_000_000;
+
 /// Position of the start string in the message header
 const HEADER_START_STRING_RANGE: std::ops::Range<usize> = 0..4;
 
@@ -0,0 +1,236 @@
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::{mpsc, OwnedSemaphorePermit, Semaphore},
+    task::JoinHandle,
+};
+
+use super::{
+    errors::{BTCP2PError, Result},
+    handshake::Handshake,
+    peer::{Peer, PeerConfig},
+};
+
+/// Default number of inbound connections, as in Bitcoin Core (125 connections minus 11 outbound)
+pub const DEFAULT_MAX_INBOUND: usize = 114;
+
+/// Number of negotiated peers waiting to be accepted by the application
+const ACCEPTED_CHANNEL_SIZE: usize = 16;
+
+/// Time to wait after a failed accept, such as when we ran out of file descriptors, before accepting again
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// Listener accepts inbound connections and answers their handshake
+///
+/// Connections are accepted in a background task and each handshake runs in its own task, so a slow peer
+/// does not delay the others. Connections beyond the inbound cap are closed right away, connections whose
+/// handshake fails are dropped. The slot of a peer is released when it is dropped.
+#[derive(Debug)]
+pub struct Listener {
+    local_addr: SocketAddr,
+    slots: Arc<Semaphore>,
+    max_inbound: usize,
+    receiver: mpsc::Receiver<Peer>,
+    task: JoinHandle<()>,
+}
+
+impl Listener {
+    /// bind listens on an address, peers are negotiated with the given config
+    pub async fn bind<A: ToSocketAddrs>(
+        addr: A,
+        config: PeerConfig,
+        max_inbound: usize,
+    ) -> Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let slots = Arc::new(Semaphore::new(max_inbound));
+        let (sender, receiver) = mpsc::channel(ACCEPTED_CHANNEL_SIZE);
+
+        let task = tokio::spawn(accept_loop(listener, config, slots.clone(), sender));
+
+        Ok(Self {
+            local_addr,
+            slots,
+            max_inbound,
+            receiver,
+            task,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// inbound_count returns the number of connections holding a slot, including handshakes in progress
+    pub fn inbound_count(&self) -> usize {
+        self.max_inbound - self.slots.available_permits()
+    }
+
+    /// accept waits for the next peer which completed the handshake
+    pub async fn accept(&mut self) -> Result<Peer> {
+        self.receiver
+            .recv()
+            .await
+            .ok_or(BTCP2PError::ConnectionClosed)
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn accept_loop(
+    listener: TcpListener,
+    config: PeerConfig,
+    slots: Arc<Semaphore>,
+    sender: mpsc::Sender<Peer>,
+) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                tracing::warn!(%err, "failed to accept a connection");
+                tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                continue;
+            }
+        };
+
+        // banned addresses and connections above the cap are closed as the stream is dropped
+        if config.bans.is_banned(addr.ip(), Instant::now()) {
+            continue;
+        }
+        let Ok(slot) = slots.clone().try_acquire_owned() else {
+            continue;
+        };
+
+        tokio::spawn(negotiate(
+            stream,
+            addr,
+            config.clone(),
+            slot,
+            sender.clone(),
+        ));
+    }
+}
+
+async fn negotiate(
+    stream: TcpStream,
+    addr: SocketAddr,
+    config: PeerConfig,
+    slot: OwnedSemaphorePermit,
+    sender: mpsc::Sender<Peer>,
+) {
+    let Ok(local) = stream.local_addr() else {
+        return;
+    };
+
+    // failures are scored by the ban manager of the config, a self connection is reported on the outbound side
+    let handshake = Handshake::inbound(config.handshake_config(addr, local), Instant::now());
+    match Peer::start(stream, addr, handshake, &config).await {
+        Ok(peer) => {
+            let _ = sender.send(peer.with_slot(slot)).await;
+        }
+        Err(err) => tracing::debug!(%addr, %err, "inbound handshake failed"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{network::Network, service_flags::ServiceFlags};
+
+    fn config() -> PeerConfig {
+        let mut config = PeerConfig::new(Network::RegTest);
+        config.services = ServiceFlags::NODE_NETWORK;
+        config.start_height = 7;
+        config
+    }
+
+    #[tokio::test]
+    async fn test_accept() {
+        let mut listener = Listener::bind("127.0.0.1:0", config(), DEFAULT_MAX_INBOUND)
+            .await
+            .unwrap();
+        let addr = listener.local_addr();
+
+        let config = PeerConfig::new(Network::RegTest);
+        let (outbound, inbound) = tokio::join!(Peer::connect(addr, &config), listener.accept());
+        let (outbound, inbound) = (outbound.unwrap(), inbound.unwrap());
+
+        assert!(inbound.is_inbound());
+        assert!(!outbound.is_inbound());
+        assert_eq!(outbound.info().start_height, 7);
+        assert_eq!(outbound.info().services, ServiceFlags::NODE_NETWORK);
+        assert_eq!(listener.inbound_count(), 1);
+
+        drop(inbound);
+        assert_eq!(listener.inbound_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_max_inbound() {
+        let mut listener = Listener::bind("127.0.0.1:0", config(), 1).await.unwrap();
+        let addr = listener.local_addr();
+
+        let mut config = PeerConfig::new(Network::RegTest);
+        config.handshake_timeout = Duration::from_secs(5);
+
+        let (first, inbound) = tokio::join!(Peer::connect(addr, &config), listener.accept());
+        first.unwrap();
+        let inbound = inbound.unwrap();
+
+        // the second connection is closed before its handshake
+        assert!(Peer::connect(addr, &config).await.is_err());
+
+        // the slot is released once the first peer is dropped
+        drop(inbound);
+        let (second, inbound) = tokio::join!(Peer::connect(addr, &config), listener.accept());
+        second.unwrap();
+        inbound.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_self_connection() {
+        let config = config();
+        let listener = Listener::bind("127.0.0.1:0", config.clone(), DEFAULT_MAX_INBOUND)
+            .await
+            .unwrap();
+
+        // the listener shares the nonces of our outbound handshakes through the config
+        let result = Peer::connect(listener.local_addr(), &config).await;
+        assert!(matches!(result, Err(BTCP2PError::SelfConnection)));
+        assert!(!config
+            .bans
+            .is_banned(listener.local_addr().ip(), Instant::now()));
+    }
+
+    #[tokio::test]
+    async fn test_banned() {
+        let config = config();
+        let listener = Listener::bind("127.0.0.1:0", config.clone(), DEFAULT_MAX_INBOUND)
+            .await
+            .unwrap();
+        let addr = listener.local_addr();
+        config.bans.ban(
+            "127.0.0.0/8".parse().unwrap(),
+            Duration::from_secs(60),
+            Instant::now(),
+        );
+
+        // we refuse to dial banned addresses
+        assert!(matches!(
+            Peer::connect(addr, &config).await,
+            Err(BTCP2PError::Banned(_))
+        ));
+
+        // and the listener closes their connections
+        let result = Peer::connect(addr, &PeerConfig::new(Network::RegTest)).await;
+        assert!(matches!(result, Err(BTCP2PError::ConnectionClosed)));
+    }
+}
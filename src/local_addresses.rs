@@ -0,0 +1,151 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use super::{
+    address::{Address, NetAddress},
+    service_flags::ServiceFlags,
+    sync::lock,
+    tor::OnionService,
+};
+
+/// LocalAddresses holds the addresses other nodes can reach us at, clones share the same addresses
+///
+/// Addresses are added as they are discovered, such as the external address of a port mapping or an onion
+/// service, then announced in version messages and advertised to peers after the handshake.
+#[derive(Debug, Clone, Default)]
+pub struct LocalAddresses(Arc<Mutex<Vec<(Address, u16)>>>);
+
+impl LocalAddresses {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<(Address, u16)>> {
+        lock(&self.0)
+    }
+
+    /// add registers an address we are reachable at, adding an address twice has no effect
+    pub fn add(&self, address: Address, port: u16) {
+        let mut addresses = self.lock();
+        if !addresses.contains(&(address.clone(), port)) {
+            addresses.push((address, port));
+        }
+    }
+
+    /// add_onion registers the address of an onion service, which is advertised to peers reached over Tor
+    pub fn add_onion(&self, service: &OnionService) {
+        self.add(service.address(), service.port());
+    }
+
+    /// remove forgets an address, returns false if it was not registered
+    pub fn remove(&self, address: &Address, port: u16) -> bool {
+        let mut addresses = self.lock();
+        let len = addresses.len();
+        addresses.retain(|(other, other_port)| (other, *other_port) != (address, port));
+        addresses.len() != len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    /// addresses returns the registered addresses in the order they were added
+    pub fn addresses(&self) -> Vec<(Address, u16)> {
+        self.lock().clone()
+    }
+
+    /// best_ip returns the first IP address, the one announced in version messages
+    pub fn best_ip(&self) -> Option<SocketAddr> {
+        self.lock()
+            .iter()
+            .find_map(|(address, port)| address.ip().map(|ip| SocketAddr::new(ip, *port)))
+    }
+
+    /// net_addresses returns the addresses to advertise to peers, seen at the given Unix time
+    pub fn net_addresses(&self, services: ServiceFlags, time: u32) -> Vec<NetAddress> {
+        self.lock()
+            .iter()
+            .map(|(address, port)| NetAddress {
+                time,
+                services,
+                address: address.clone(),
+                port: *port,
+            })
+            .collect()
+    }
+
+    /// net_address returns the first address a peer can reach, to advertise to it at the given Unix time
+    pub fn net_address<F>(
+        &self,
+        reachable: F,
+        services: ServiceFlags,
+        time: u32,
+    ) -> Option<NetAddress>
+    where
+        F: Fn(&Address) -> bool,
+    {
+        self.lock()
+            .iter()
+            .find(|(address, _)| reachable(address))
+            .map(|(address, port)| NetAddress {
+                time,
+                services,
+                address: address.clone(),
+                port: *port,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_local_addresses() {
+        let addresses = LocalAddresses::new();
+        assert!(addresses.is_empty());
+        assert_eq!(addresses.best_ip(), None);
+
+        let onion = Address::TorV3([7; 32]);
+        let ip = Address::Ipv4(Ipv4Addr::new(203, 0, 113, 5));
+        addresses.add(onion.clone(), 8333);
+        assert_eq!(addresses.best_ip(), None);
+
+        // clones share the addresses
+        addresses.clone().add(ip.clone(), 8333);
+        addresses.add(ip.clone(), 8333);
+        assert_eq!(
+            addresses.addresses(),
+            vec![(onion.clone(), 8333), (ip.clone(), 8333)]
+        );
+        assert_eq!(
+            addresses.best_ip(),
+            Some("203.0.113.5:8333".parse().unwrap())
+        );
+
+        let advertised = addresses.net_addresses(ServiceFlags::NODE_NETWORK, 42);
+        assert_eq!(advertised.len(), 2);
+        assert_eq!(advertised[1].address, ip);
+        assert_eq!(advertised[1].time, 42);
+        assert_eq!(advertised[1].services, ServiceFlags::NODE_NETWORK);
+
+        let onion_only = |address: &Address| matches!(address, Address::TorV3(_));
+        let advertised = addresses.net_address(onion_only, ServiceFlags::NODE_NETWORK, 42);
+        assert_eq!(
+            advertised.map(|address| address.address),
+            Some(onion.clone())
+        );
+        let i2p_only = |address: &Address| matches!(address, Address::I2p(_));
+        assert_eq!(
+            addresses.net_address(i2p_only, ServiceFlags::NODE_NETWORK, 42),
+            None
+        );
+
+        assert!(addresses.remove(&ip, 8333));
+        assert!(!addresses.remove(&ip, 8333));
+        assert_eq!(addresses.addresses(), vec![(onion, 8333)]);
+    }
+}
@@ -1,10 +1,10 @@
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use sha2::{Digest, Sha256};
 use std::io::Write;
 
 use super::{
     command::Command,
     errors::{BTCP2PError, Result},
+    hash::sha256d,
     network::Network,
     payload::Payload,
     CHECKSUM_SIZE, HEADER_CHECKSUM_RANGE, HEADER_COMMAND_NAME_RANGE, HEADER_PAYLOAD_LEN_RANGE,
@@ -84,7 +84,7 @@ impl Message {
         // payload char[..]
         let payload_bytes = &bytes[HEADER_CHECKSUM_RANGE.end..];
 
-        if checksum_value != Message::checksum(&payload_bytes) {
+        if checksum_value != Message::checksum(payload_bytes) {
             return Err(BTCP2PError::InvalidChecksum);
         }
 
@@ -99,13 +99,7 @@ impl Message {
 
     /// Calculates the checksum of the payload
     fn checksum(data: &[u8]) -> [u8; 4] {
-        let mut hasher = Sha256::new();
-        hasher.update(data);
-        let hash = hasher.finalize();
-
-        let mut hasher = Sha256::new();
-        hasher.update(hash);
-        let hash = hasher.finalize();
+        let hash = sha256d(data);
 
         let mut buffer = [0u8; CHECKSUM_SIZE];
         buffer.clone_from_slice(&hash[..CHECKSUM_SIZE]);
@@ -116,7 +110,11 @@ impl Message {
 
 #[cfg(test)]
 mod tests {
-    use crate::VersionPayload;
+    use crate::{
+        AddrPayload, AddrV2Payload, AncPkgInfoPayload, GetHeadersPayload, GetPkgTxnsPayload,
+        HeadersPayload, InvPayload, PkgTxnsPayload, ReconcilDiffPayload, ReqReconPayload,
+        SendCmpctPayload, SendPackagesPayload, SendTxRcnclPayload, SketchPayload, VersionPayload,
+    };
 
     use super::*;
     use quickcheck::{Arbitrary, TestResult};
@@ -131,6 +129,28 @@ mod tests {
                 Command::VerAck => Payload::VerAck,
                 Command::Ping => Payload::Ping(u64::arbitrary(g)),
                 Command::Pong => Payload::Pong(u64::arbitrary(g)),
+                Command::WtxidRelay => Payload::WtxidRelay,
+                Command::SendAddrV2 => Payload::SendAddrV2,
+                Command::SendTxRcncl => Payload::SendTxRcncl(SendTxRcnclPayload::arbitrary(g)),
+                Command::ReqRecon => Payload::ReqRecon(ReqReconPayload::arbitrary(g)),
+                Command::Sketch => Payload::Sketch(SketchPayload::arbitrary(g)),
+                Command::ReqSketchExt => Payload::ReqSketchExt,
+                Command::ReconcilDiff => Payload::ReconcilDiff(ReconcilDiffPayload::arbitrary(g)),
+                Command::SendPackages => Payload::SendPackages(SendPackagesPayload::arbitrary(g)),
+                Command::AncPkgInfo => Payload::AncPkgInfo(AncPkgInfoPayload::arbitrary(g)),
+                Command::GetPkgTxns => Payload::GetPkgTxns(GetPkgTxnsPayload::arbitrary(g)),
+                Command::PkgTxns => Payload::PkgTxns(PkgTxnsPayload::arbitrary(g)),
+                Command::Addr => Payload::Addr(AddrPayload::arbitrary(g)),
+                Command::AddrV2 => Payload::AddrV2(AddrV2Payload::arbitrary(g)),
+                Command::GetAddr => Payload::GetAddr,
+                Command::SendHeaders => Payload::SendHeaders,
+                Command::SendCmpct => Payload::SendCmpct(SendCmpctPayload::arbitrary(g)),
+                Command::FeeFilter => Payload::FeeFilter(u64::arbitrary(g)),
+                Command::GetHeaders => Payload::GetHeaders(GetHeadersPayload::arbitrary(g)),
+                Command::Headers => Payload::Headers(HeadersPayload::arbitrary(g)),
+                Command::Inv => Payload::Inv(InvPayload::arbitrary(g)),
+                Command::GetData => Payload::GetData(InvPayload::arbitrary(g)),
+                Command::NotFound => Payload::NotFound(InvPayload::arbitrary(g)),
             };
 
             Self {
@@ -0,0 +1,400 @@
+use super::errors::{BTCP2PError, Result};
+
+/// Reduction polynomial of GF(2^32): x^32 + x^7 + x^3 + x^2 + 1, the one used by libminisketch
+const FIELD_MODULUS: u32 = 0x8d;
+
+/// Size in bytes of a serialized field element
+const ELEMENT_SIZE: usize = 4;
+
+/// Largest capacity of a sketch sent or received
+/// Decoding is quadratic in the capacity, so peers can't be allowed to pick any: a sketch of this capacity
+/// decodes in milliseconds, larger differences fall back to flooding.
+pub const MAX_SKETCH_CAPACITY: usize = 64;
+
+/// Minisketch is a PinSketch set sketch over GF(2^32), as used by BIP330 to reconcile short transaction IDs
+/// https://github.com/sipa/minisketch
+///
+/// A sketch of capacity c stores the odd power sums s1, s3, ..., s(2c-1) of its elements.
+/// Merging two sketches yields the sketch of the symmetric difference of their sets,
+/// which can be decoded as long as it has no more than c elements.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Minisketch {
+    syndromes: Vec<u32>,
+}
+
+impl Minisketch {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            syndromes: vec![0; capacity],
+        }
+    }
+
+    /// capacity is the maximum number of differences the sketch can decode
+    pub fn capacity(&self) -> usize {
+        self.syndromes.len()
+    }
+
+    /// add toggles an element in the sketch, adding it twice removes it
+    /// zero is not a valid element and is ignored
+    pub fn add(&mut self, element: u32) {
+        if element == 0 {
+            return;
+        }
+
+        let squared = gf_mul(element, element);
+        let mut power = element;
+
+        for syndrome in self.syndromes.iter_mut() {
+            *syndrome ^= power;
+            power = gf_mul(power, squared);
+        }
+    }
+
+    /// merge combines another sketch into this one, the result is the sketch of the symmetric difference
+    /// the capacity of the result is the smallest of both capacities
+    pub fn merge(&mut self, other: &Minisketch) {
+        self.syndromes.truncate(other.capacity());
+
+        for (syndrome, other) in self.syndromes.iter_mut().zip(&other.syndromes) {
+            *syndrome ^= other;
+        }
+    }
+
+    /// decode recovers the elements of the sketch
+    /// returns None if the sketch holds more elements than its capacity
+    pub fn decode(&self) -> Option<Vec<u32>> {
+        // even power sums are free in characteristic 2: s(2i) = s(i)^2
+        let mut sums = vec![0u32; 2 * self.capacity()];
+        for (i, syndrome) in self.syndromes.iter().enumerate() {
+            sums[2 * i] = *syndrome;
+        }
+        for i in (1..sums.len()).step_by(2) {
+            sums[i] = gf_mul(sums[i / 2], sums[i / 2]);
+        }
+
+        // the connection polynomial has the inverses of the elements as roots,
+        // its reverse has the elements themselves as roots
+        let mut locator = berlekamp_massey(&sums);
+        if locator.len() - 1 > self.capacity() || *locator.last()? == 0 {
+            return None;
+        }
+        locator.reverse();
+
+        let mut elements = find_roots(&locator)?;
+        elements.sort_unstable();
+
+        Some(elements)
+    }
+
+    /// to_bytes serializes the sketch as little endian field elements
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.syndromes
+            .iter()
+            .flat_map(|syndrome| syndrome.to_le_bytes())
+            .collect()
+    }
+
+    /// from_bytes deserializes a sketch, the capacity is derived from the length
+    /// and may not exceed MAX_SKETCH_CAPACITY
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if !bytes.len().is_multiple_of(ELEMENT_SIZE)
+            || bytes.len() > MAX_SKETCH_CAPACITY * ELEMENT_SIZE
+        {
+            return Err(BTCP2PError::InvalidSketch);
+        }
+
+        let syndromes = bytes
+            .chunks_exact(ELEMENT_SIZE)
+            .map(|chunk| u32::from_le_bytes(chunk.try_into().expect("chunk of 4 bytes")))
+            .collect();
+
+        Ok(Self { syndromes })
+    }
+}
+
+/// gf_mul multiplies two elements of GF(2^32)
+fn gf_mul(mut a: u32, mut b: u32) -> u32 {
+    let mut result = 0;
+
+    while b != 0 {
+        if b & 1 == 1 {
+            result ^= a;
+        }
+        b >>= 1;
+
+        let carry = a >> 31;
+        a <<= 1;
+        if carry == 1 {
+            a ^= FIELD_MODULUS;
+        }
+    }
+
+    result
+}
+
+/// gf_inv inverts a non zero element of GF(2^32) as a^(2^32 - 2)
+fn gf_inv(a: u32) -> u32 {
+    let mut result = 1;
+    let mut square = a;
+
+    // 2^32 - 2 has every bit set except the lowest one
+    for _ in 1..32 {
+        square = gf_mul(square, square);
+        result = gf_mul(result, square);
+    }
+
+    result
+}
+
+/// berlekamp_massey finds the shortest linear recurrence generating the power sums
+/// the result has its constant term first and its length is the degree plus one
+fn berlekamp_massey(sums: &[u32]) -> Vec<u32> {
+    let mut current = vec![1u32];
+    let mut previous = vec![1u32];
+    let mut degree = 0;
+    let mut shift = 1;
+    let mut previous_discrepancy = 1;
+
+    for n in 0..sums.len() {
+        let mut discrepancy = sums[n];
+        for i in 1..=degree {
+            discrepancy ^= gf_mul(current[i], sums[n - i]);
+        }
+
+        if discrepancy == 0 {
+            shift += 1;
+            continue;
+        }
+
+        let factor = gf_mul(discrepancy, gf_inv(previous_discrepancy));
+        let mut next = current.clone();
+        if next.len() < previous.len() + shift {
+            next.resize(previous.len() + shift, 0);
+        }
+        for (i, coefficient) in previous.iter().enumerate() {
+            next[i + shift] ^= gf_mul(factor, *coefficient);
+        }
+
+        if 2 * degree <= n {
+            degree = n + 1 - degree;
+            previous = std::mem::replace(&mut current, next);
+            previous_discrepancy = discrepancy;
+            shift = 1;
+        } else {
+            current = next;
+            shift += 1;
+        }
+    }
+
+    current.resize(degree + 1, 0);
+    current
+}
+
+/// find_roots returns the roots of a monic polynomial if it has exactly as many distinct roots as its degree
+fn find_roots(poly: &[u32]) -> Option<Vec<u32>> {
+    let poly = poly_normalize(poly.to_vec());
+
+    // a polynomial splits into distinct linear factors iff it divides x^(2^32) - x
+    let mut frobenius = vec![0, 1];
+    for _ in 0..32 {
+        frobenius = poly_mul_mod(&frobenius, &frobenius, &poly);
+    }
+    if frobenius != poly_rem(&[0, 1], &poly) {
+        return None;
+    }
+
+    let mut roots = vec![];
+    split_roots(poly, 0, &mut roots);
+
+    Some(roots)
+}
+
+/// split_roots splits a polynomial with distinct roots using the trace map Tr(beta * x),
+/// trying every element of the polynomial basis as beta, which separates any two distinct roots
+fn split_roots(poly: Vec<u32>, basis: u32, roots: &mut Vec<u32>) {
+    match poly.len() {
+        0 | 1 => return,
+        2 => {
+            roots.push(gf_mul(poly[0], gf_inv(poly[1])));
+            return;
+        }
+        _ => {}
+    }
+
+    for bit in basis..32 {
+        let linear = poly_rem(&[0, 1 << bit], &poly);
+        let mut trace = linear.clone();
+        let mut power = linear;
+        for _ in 1..32 {
+            power = poly_mul_mod(&power, &power, &poly);
+            trace = poly_add(&trace, &power);
+        }
+
+        let factor = poly_gcd(poly.clone(), trace);
+        if factor.len() > 1 && factor.len() < poly.len() {
+            let cofactor = poly_div(&poly, &factor);
+            split_roots(factor, bit + 1, roots);
+            split_roots(cofactor, bit + 1, roots);
+            return;
+        }
+    }
+}
+
+fn poly_normalize(mut poly: Vec<u32>) -> Vec<u32> {
+    while poly.last() == Some(&0) {
+        poly.pop();
+    }
+    poly
+}
+
+fn poly_add(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut result = vec![0; a.len().max(b.len())];
+    for (i, coefficient) in a.iter().enumerate() {
+        result[i] ^= coefficient;
+    }
+    for (i, coefficient) in b.iter().enumerate() {
+        result[i] ^= coefficient;
+    }
+    poly_normalize(result)
+}
+
+fn poly_mul_mod(a: &[u32], b: &[u32], modulus: &[u32]) -> Vec<u32> {
+    if a.is_empty() || b.is_empty() {
+        return vec![];
+    }
+
+    let mut result = vec![0; a.len() + b.len() - 1];
+    for (i, x) in a.iter().enumerate() {
+        for (j, y) in b.iter().enumerate() {
+            result[i + j] ^= gf_mul(*x, *y);
+        }
+    }
+    poly_rem(&result, modulus)
+}
+
+/// poly_div_rem divides a by b, returning the quotient and the remainder
+fn poly_div_rem(a: &[u32], b: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    let mut remainder = poly_normalize(a.to_vec());
+    let divisor = b.len() - 1;
+
+    if remainder.len() < b.len() {
+        return (vec![], remainder);
+    }
+
+    let lead_inv = gf_inv(b[divisor]);
+    let mut quotient = vec![0; remainder.len() - divisor];
+
+    for i in (divisor..remainder.len()).rev() {
+        let factor = gf_mul(remainder[i], lead_inv);
+        if factor == 0 {
+            continue;
+        }
+        quotient[i - divisor] = factor;
+        for (j, coefficient) in b.iter().enumerate() {
+            remainder[i - divisor + j] ^= gf_mul(factor, *coefficient);
+        }
+    }
+
+    (poly_normalize(quotient), poly_normalize(remainder))
+}
+
+fn poly_rem(a: &[u32], b: &[u32]) -> Vec<u32> {
+    poly_div_rem(a, b).1
+}
+
+fn poly_div(a: &[u32], b: &[u32]) -> Vec<u32> {
+    poly_div_rem(a, b).0
+}
+
+fn poly_gcd(mut a: Vec<u32>, mut b: Vec<u32>) -> Vec<u32> {
+    while !b.is_empty() {
+        let remainder = poly_rem(&a, &b);
+        a = std::mem::replace(&mut b, remainder);
+    }
+    a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::TestResult;
+    use quickcheck_macros::quickcheck;
+    use std::collections::BTreeSet;
+
+    #[quickcheck]
+    fn test_gf_inv(a: u32) -> TestResult {
+        if a == 0 {
+            return TestResult::discard();
+        }
+        TestResult::from_bool(gf_mul(a, gf_inv(a)) == 1)
+    }
+
+    #[quickcheck]
+    fn test_decode(elements: BTreeSet<u32>) -> TestResult {
+        let elements = elements
+            .into_iter()
+            .filter(|e| *e != 0)
+            .take(32)
+            .collect::<Vec<_>>();
+
+        let mut sketch = Minisketch::new(elements.len() + 2);
+        for element in &elements {
+            sketch.add(*element);
+        }
+
+        TestResult::from_bool(sketch.decode() == Some(elements))
+    }
+
+    #[quickcheck]
+    fn test_to_bytes(elements: Vec<u32>) -> TestResult {
+        let mut sketch = Minisketch::new(8);
+        for element in elements {
+            sketch.add(element);
+        }
+
+        let sketch2 = Minisketch::from_bytes(&sketch.to_bytes()).unwrap();
+        TestResult::from_bool(sketch == sketch2)
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut alice = Minisketch::new(4);
+        let mut bob = Minisketch::new(4);
+
+        for element in 1..100 {
+            alice.add(element);
+            bob.add(element);
+        }
+        alice.add(1000);
+        alice.add(2000);
+        bob.add(3000);
+
+        alice.merge(&bob);
+        assert_eq!(alice.decode(), Some(vec![1000, 2000, 3000]));
+    }
+
+    #[test]
+    fn test_decode_over_capacity() {
+        let mut sketch = Minisketch::new(3);
+        for element in 1..=10u32 {
+            sketch.add(element.wrapping_mul(0x9e3779b9));
+        }
+        assert_eq!(sketch.decode(), None);
+    }
+
+    #[test]
+    fn test_empty_sketch() {
+        assert_eq!(Minisketch::new(5).decode(), Some(vec![]));
+    }
+
+    #[test]
+    fn test_from_bytes_invalid_len() {
+        assert!(Minisketch::from_bytes(&[0u8; 5]).is_err());
+        let bytes = vec![0u8; MAX_SKETCH_CAPACITY * ELEMENT_SIZE];
+        assert_eq!(
+            Minisketch::from_bytes(&bytes).unwrap().capacity(),
+            MAX_SKETCH_CAPACITY
+        );
+        assert!(Minisketch::from_bytes(&[bytes, vec![0; ELEMENT_SIZE]].concat()).is_err());
+    }
+}
@@ -0,0 +1,589 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{net::UdpSocket, task::JoinHandle};
+
+use super::{
+    errors::{BTCP2PError, Result},
+    local_addresses::LocalAddresses,
+    sync::lock,
+};
+
+/// Port the gateway serves NAT-PMP and PCP on
+pub const NAT_PMP_PORT: u16 = 5351;
+
+/// Lifetime requested for mappings, they are renewed at half their lifetime
+pub const DEFAULT_MAPPING_LIFETIME: Duration = Duration::from_secs(20 * 60);
+
+/// Versions of the protocols, a NAT-PMP gateway answers PCP requests with version 0
+const NAT_PMP_VERSION: u8 = 0;
+const PCP_VERSION: u8 = 2;
+
+/// Opcodes of the requests, replies set the high bit
+const NAT_PMP_OP_EXTERNAL_ADDRESS: u8 = 0;
+const NAT_PMP_OP_MAP_TCP: u8 = 2;
+const PCP_OP_MAP: u8 = 1;
+const RESPONSE_BIT: u8 = 0x80;
+
+/// Sizes of the messages, https://www.rfc-editor.org/rfc/rfc6886 and https://www.rfc-editor.org/rfc/rfc6887
+const NAT_PMP_EXTERNAL_ADDRESS_SIZE: usize = 12;
+const NAT_PMP_MAP_SIZE: usize = 16;
+const PCP_MAP_SIZE: usize = 60;
+
+/// IANA protocol number of TCP
+const PROTOCOL_TCP: u8 = 6;
+
+/// Time to wait for the first reply, doubled on each retransmission
+const INITIAL_RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(250);
+const MAX_ATTEMPTS: u32 = 4;
+
+/// Time to wait before retrying a failed renewal
+const RENEW_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// MappingProtocol is the protocol a port mapping was made with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappingProtocol {
+    /// Port Control Protocol, https://www.rfc-editor.org/rfc/rfc6887
+    Pcp,
+    /// NAT Port Mapping Protocol, https://www.rfc-editor.org/rfc/rfc6886
+    NatPmp,
+}
+
+/// PortMapping is a TCP port of the gateway forwarded to a port of this host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortMapping {
+    pub protocol: MappingProtocol,
+    pub gateway: SocketAddr,
+    pub internal_port: u16,
+    /// The address other nodes can reach the internal port at.
+    pub external: SocketAddr,
+    /// The lifetime granted by the gateway.
+    pub lifetime: Duration,
+    /// Nonce of PCP requests, renewals and releases of a mapping reuse it.
+    nonce: [u8; 12],
+}
+
+impl PortMapping {
+    /// request asks the gateway to forward a TCP port to internal_port, with PCP then NAT-PMP
+    /// the gateway is usually the default gateway on NAT_PMP_PORT
+    pub async fn request(
+        gateway: SocketAddr,
+        internal_port: u16,
+        lifetime: Duration,
+    ) -> Result<Self> {
+        let socket = UdpSocket::bind(unspecified(gateway)).await?;
+        socket.connect(gateway).await?;
+
+        let nonce = rand::random();
+        match pcp_map(&socket, nonce, internal_port, None, lifetime).await? {
+            Some((external, lifetime)) => Ok(Self {
+                protocol: MappingProtocol::Pcp,
+                gateway,
+                internal_port,
+                external,
+                lifetime,
+                nonce,
+            }),
+            None => {
+                let (port, lifetime) =
+                    nat_pmp_map(&socket, internal_port, internal_port, lifetime).await?;
+                let ip = nat_pmp_external_address(&socket).await?;
+                Ok(Self {
+                    protocol: MappingProtocol::NatPmp,
+                    gateway,
+                    internal_port,
+                    external: SocketAddr::new(ip.into(), port),
+                    lifetime,
+                    nonce,
+                })
+            }
+        }
+    }
+
+    /// renew extends the lease of the mapping, the gateway may assign another external address
+    pub async fn renew(&self, lifetime: Duration) -> Result<Self> {
+        let socket = UdpSocket::bind(unspecified(self.gateway)).await?;
+        socket.connect(self.gateway).await?;
+
+        let (external, lifetime) = match self.protocol {
+            MappingProtocol::Pcp => pcp_map(
+                &socket,
+                self.nonce,
+                self.internal_port,
+                Some(self.external),
+                lifetime,
+            )
+            .await?
+            .ok_or_else(|| {
+                BTCP2PError::PortMappingError("gateway stopped supporting PCP".to_string())
+            })?,
+            MappingProtocol::NatPmp => {
+                let (port, lifetime) =
+                    nat_pmp_map(&socket, self.internal_port, self.external.port(), lifetime)
+                        .await?;
+                let ip = nat_pmp_external_address(&socket).await?;
+                (SocketAddr::new(ip.into(), port), lifetime)
+            }
+        };
+
+        Ok(Self {
+            external,
+            lifetime,
+            ..self.clone()
+        })
+    }
+
+    /// release asks the gateway to remove the mapping
+    pub async fn release(&self) -> Result<()> {
+        let socket = UdpSocket::bind(unspecified(self.gateway)).await?;
+        socket.connect(self.gateway).await?;
+
+        match self.protocol {
+            MappingProtocol::Pcp => {
+                pcp_map(
+                    &socket,
+                    self.nonce,
+                    self.internal_port,
+                    Some(self.external),
+                    Duration::ZERO,
+                )
+                .await?;
+            }
+            MappingProtocol::NatPmp => {
+                nat_pmp_map(&socket, self.internal_port, 0, Duration::ZERO).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// PortMapper keeps a port mapping alive and registers its external address as a local address
+///
+/// The mapping is renewed at half its lifetime, and the local address follows the external address assigned by
+/// the gateway. `shutdown` releases the mapping, dropping the mapper only stops renewing it.
+#[derive(Debug)]
+pub struct PortMapper {
+    mapping: Arc<Mutex<PortMapping>>,
+    local_addresses: LocalAddresses,
+    renewer: JoinHandle<()>,
+}
+
+impl PortMapper {
+    /// start maps internal_port on the gateway and adds the external address to the local addresses
+    pub async fn start(
+        gateway: SocketAddr,
+        internal_port: u16,
+        lifetime: Duration,
+        local_addresses: LocalAddresses,
+    ) -> Result<Self> {
+        let mapping = PortMapping::request(gateway, internal_port, lifetime).await?;
+        add_external(&local_addresses, &mapping);
+
+        let mapping = Arc::new(Mutex::new(mapping));
+        let renewer = tokio::spawn(renew_loop(
+            mapping.clone(),
+            lifetime,
+            local_addresses.clone(),
+        ));
+
+        Ok(Self {
+            mapping,
+            local_addresses,
+            renewer,
+        })
+    }
+
+    /// mapping returns the current mapping
+    pub fn mapping(&self) -> PortMapping {
+        lock(&self.mapping).clone()
+    }
+
+    /// shutdown stops renewing the mapping, releases it and removes its external address
+    pub async fn shutdown(self) -> Result<()> {
+        self.renewer.abort();
+        let mapping = self.mapping();
+        remove_external(&self.local_addresses, &mapping);
+        mapping.release().await
+    }
+}
+
+impl Drop for PortMapper {
+    fn drop(&mut self) {
+        self.renewer.abort();
+    }
+}
+
+async fn renew_loop(
+    mapping: Arc<Mutex<PortMapping>>,
+    lifetime: Duration,
+    local_addresses: LocalAddresses,
+) {
+    let current = || lock(&mapping).clone();
+    let mut expiry = Instant::now() + current().lifetime;
+    let mut next_renewal = Instant::now() + current().lifetime / 2;
+
+    loop {
+        tokio::time::sleep_until(next_renewal.into()).await;
+
+        let old = current();
+        match old.renew(lifetime).await {
+            Ok(new) => {
+                if new.external != old.external {
+                    remove_external(&local_addresses, &old);
+                }
+                add_external(&local_addresses, &new);
+                expiry = Instant::now() + new.lifetime;
+                next_renewal = Instant::now() + new.lifetime / 2;
+                *lock(&mapping) = new;
+            }
+            Err(_) => {
+                // the address is not announced once the lease is over, renewals keep being attempted
+                if Instant::now() >= expiry {
+                    remove_external(&local_addresses, &old);
+                }
+                next_renewal = Instant::now() + RENEW_RETRY_INTERVAL;
+            }
+        }
+    }
+}
+
+fn add_external(local_addresses: &LocalAddresses, mapping: &PortMapping) {
+    local_addresses.add(mapping.external.ip().into(), mapping.external.port());
+}
+
+fn remove_external(local_addresses: &LocalAddresses, mapping: &PortMapping) {
+    local_addresses.remove(&mapping.external.ip().into(), mapping.external.port());
+}
+
+/// unspecified returns the unspecified address of the family of addr
+fn unspecified(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+        SocketAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+    }
+}
+
+/// transact sends a request until a reply accepted by parse is received, retransmitting with backoff
+async fn transact<T>(
+    socket: &UdpSocket,
+    request: &[u8],
+    parse: impl Fn(&[u8]) -> Option<Result<T>>,
+) -> Result<T> {
+    let mut timeout = INITIAL_RETRANSMIT_TIMEOUT;
+    let mut buffer = [0u8; 1100];
+
+    for _ in 0..MAX_ATTEMPTS {
+        socket.send(request).await?;
+        let deadline = tokio::time::Instant::now() + timeout;
+
+        // replies that do not match the request are ignored
+        while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv(&mut buffer)).await {
+            if let Some(result) = parse(&buffer[..received?]) {
+                return result;
+            }
+        }
+        timeout *= 2;
+    }
+
+    Err(BTCP2PError::PortMappingError(
+        "gateway did not answer".to_string(),
+    ))
+}
+
+/// pcp_map sends a PCP MAP request, returns None if the gateway only speaks NAT-PMP
+async fn pcp_map(
+    socket: &UdpSocket,
+    nonce: [u8; 12],
+    internal_port: u16,
+    suggested: Option<SocketAddr>,
+    lifetime: Duration,
+) -> Result<Option<(SocketAddr, Duration)>> {
+    let client_ip = match socket.local_addr()?.ip() {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    };
+    let (suggested_port, suggested_ip) = match suggested {
+        Some(addr) => (addr.port(), pcp_ip(addr.ip())),
+        None => (internal_port, Ipv6Addr::UNSPECIFIED),
+    };
+
+    let mut request = Vec::with_capacity(PCP_MAP_SIZE);
+    request.extend([PCP_VERSION, PCP_OP_MAP, 0, 0]);
+    request.extend((lifetime.as_secs() as u32).to_be_bytes());
+    request.extend(client_ip.octets());
+    request.extend(nonce);
+    request.extend([PROTOCOL_TCP, 0, 0, 0]);
+    request.extend(internal_port.to_be_bytes());
+    request.extend(suggested_port.to_be_bytes());
+    request.extend(suggested_ip.octets());
+
+    transact(socket, &request, |reply| {
+        if reply.len() >= 4 && reply[0] == NAT_PMP_VERSION {
+            return Some(Ok(None));
+        }
+        if reply.len() < PCP_MAP_SIZE
+            || reply[0] != PCP_VERSION
+            || reply[1] != PCP_OP_MAP | RESPONSE_BIT
+            || reply[24..36] != nonce
+        {
+            return None;
+        }
+        if reply[3] != 0 {
+            return Some(Err(BTCP2PError::PortMappingError(format!(
+                "PCP result code {}",
+                reply[3]
+            ))));
+        }
+
+        let lifetime = u32::from_be_bytes(reply[4..8].try_into().unwrap());
+        let port = u16::from_be_bytes(reply[42..44].try_into().unwrap());
+        let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&reply[44..60]).unwrap());
+        Some(Ok(Some((
+            SocketAddr::new(ip.to_canonical(), port),
+            Duration::from_secs(lifetime.into()),
+        ))))
+    })
+    .await
+}
+
+/// pcp_ip returns an IP address as written in PCP messages, IPv4 addresses are mapped
+fn pcp_ip(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// nat_pmp_result checks the header of a NAT-PMP reply to the given opcode
+fn nat_pmp_result(reply: &[u8], opcode: u8, size: usize) -> Option<Result<()>> {
+    if reply.len() < size || reply[0] != NAT_PMP_VERSION || reply[1] != opcode | RESPONSE_BIT {
+        return None;
+    }
+    match u16::from_be_bytes([reply[2], reply[3]]) {
+        0 => Some(Ok(())),
+        code => Some(Err(BTCP2PError::PortMappingError(format!(
+            "NAT-PMP result code {}",
+            code
+        )))),
+    }
+}
+
+/// nat_pmp_map sends a NAT-PMP request to map a TCP port, returns the external port and the granted lifetime
+async fn nat_pmp_map(
+    socket: &UdpSocket,
+    internal_port: u16,
+    suggested_port: u16,
+    lifetime: Duration,
+) -> Result<(u16, Duration)> {
+    let mut request = vec![NAT_PMP_VERSION, NAT_PMP_OP_MAP_TCP, 0, 0];
+    request.extend(internal_port.to_be_bytes());
+    request.extend(suggested_port.to_be_bytes());
+    request.extend((lifetime.as_secs() as u32).to_be_bytes());
+
+    transact(socket, &request, |reply| {
+        Some(
+            nat_pmp_result(reply, NAT_PMP_OP_MAP_TCP, NAT_PMP_MAP_SIZE)?.map(|()| {
+                let port = u16::from_be_bytes([reply[10], reply[11]]);
+                let lifetime = u32::from_be_bytes(reply[12..16].try_into().unwrap());
+                (port, Duration::from_secs(lifetime.into()))
+            }),
+        )
+    })
+    .await
+}
+
+/// nat_pmp_external_address asks a NAT-PMP gateway for its external IPv4 address
+async fn nat_pmp_external_address(socket: &UdpSocket) -> Result<Ipv4Addr> {
+    transact(
+        socket,
+        &[NAT_PMP_VERSION, NAT_PMP_OP_EXTERNAL_ADDRESS],
+        |reply| {
+            Some(
+                nat_pmp_result(
+                    reply,
+                    NAT_PMP_OP_EXTERNAL_ADDRESS,
+                    NAT_PMP_EXTERNAL_ADDRESS_SIZE,
+                )?
+                .map(|()| Ipv4Addr::new(reply[8], reply[9], reply[10], reply[11])),
+            )
+        },
+    )
+    .await
+}
+
+/// default_gateway returns the IPv4 default gateway from the routing table, only available on Linux
+pub fn default_gateway() -> Result<Ipv4Addr> {
+    let table = std::fs::read_to_string("/proc/net/route")?;
+    parse_route_table(&table)
+        .ok_or_else(|| BTCP2PError::PortMappingError("no default gateway".to_string()))
+}
+
+/// parse_route_table finds the gateway of the default route in the format of `/proc/net/route`
+fn parse_route_table(table: &str) -> Option<Ipv4Addr> {
+    table.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.get(1) != Some(&"00000000") {
+            return None;
+        }
+        // addresses are written in hex in host byte order
+        let gateway = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+        Some(Ipv4Addr::from(gateway.to_le_bytes())).filter(|ip| !ip.is_unspecified())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// FakeGateway is a stand-in NAT gateway, mappings are granted the requested lifetime
+    #[derive(Debug, Clone, Copy)]
+    struct FakeGateway {
+        pcp: bool,
+        external_ip: Ipv4Addr,
+        port_offset: u16,
+    }
+
+    impl FakeGateway {
+        /// start serves the gateway, returns its address and the requested lifetimes
+        async fn start(self) -> (SocketAddr, Arc<Mutex<Vec<u32>>>) {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let addr = socket.local_addr().unwrap();
+            let lifetimes = Arc::new(Mutex::new(vec![]));
+            let requested = lifetimes.clone();
+
+            tokio::spawn(async move {
+                let mut buffer = [0u8; 1100];
+                while let Ok((len, from)) = socket.recv_from(&mut buffer).await {
+                    if let Some(reply) = self.reply(&buffer[..len], &requested) {
+                        socket.send_to(&reply, from).await.unwrap();
+                    }
+                }
+            });
+            (addr, lifetimes)
+        }
+
+        fn reply(&self, request: &[u8], lifetimes: &Mutex<Vec<u32>>) -> Option<Vec<u8>> {
+            let mut reply = vec![];
+            match (request[0], request[1]) {
+                (PCP_VERSION, PCP_OP_MAP) if self.pcp => {
+                    let lifetime = u32::from_be_bytes(request[4..8].try_into().unwrap());
+                    lifetimes.lock().unwrap().push(lifetime);
+                    let internal_port = u16::from_be_bytes([request[40], request[41]]);
+
+                    reply.extend([PCP_VERSION, PCP_OP_MAP | RESPONSE_BIT, 0, 0]);
+                    reply.extend(lifetime.to_be_bytes());
+                    reply.extend([0; 16]);
+                    reply.extend(&request[24..40]);
+                    reply.extend(&request[40..42]);
+                    reply.extend((internal_port + self.port_offset).to_be_bytes());
+                    reply.extend(self.external_ip.to_ipv6_mapped().octets());
+                }
+                (PCP_VERSION, _) => reply.extend([NAT_PMP_VERSION, RESPONSE_BIT, 0, 1]),
+                (NAT_PMP_VERSION, NAT_PMP_OP_EXTERNAL_ADDRESS) => {
+                    reply.extend([NAT_PMP_VERSION, RESPONSE_BIT, 0, 0, 0, 0, 0, 0]);
+                    reply.extend(self.external_ip.octets());
+                }
+                (NAT_PMP_VERSION, NAT_PMP_OP_MAP_TCP) => {
+                    let internal_port = u16::from_be_bytes([request[4], request[5]]);
+                    let lifetime = u32::from_be_bytes(request[8..12].try_into().unwrap());
+                    lifetimes.lock().unwrap().push(lifetime);
+
+                    reply.extend([NAT_PMP_VERSION, NAT_PMP_OP_MAP_TCP | RESPONSE_BIT, 0, 0]);
+                    reply.extend([0; 4]);
+                    reply.extend(internal_port.to_be_bytes());
+                    reply.extend((internal_port + self.port_offset).to_be_bytes());
+                    reply.extend(lifetime.to_be_bytes());
+                }
+                _ => return None,
+            }
+            Some(reply)
+        }
+    }
+
+    fn gateway(pcp: bool) -> FakeGateway {
+        FakeGateway {
+            pcp,
+            external_ip: Ipv4Addr::new(203, 0, 113, 7),
+            port_offset: 1000,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pcp_mapping() {
+        let (addr, lifetimes) = gateway(true).start().await;
+
+        let mapping = PortMapping::request(addr, 8333, DEFAULT_MAPPING_LIFETIME)
+            .await
+            .unwrap();
+        assert_eq!(mapping.protocol, MappingProtocol::Pcp);
+        assert_eq!(mapping.external, "203.0.113.7:9333".parse().unwrap());
+        assert_eq!(mapping.lifetime, DEFAULT_MAPPING_LIFETIME);
+
+        mapping.release().await.unwrap();
+        assert_eq!(*lifetimes.lock().unwrap(), vec![1200, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_nat_pmp_fallback() {
+        let (addr, lifetimes) = gateway(false).start().await;
+
+        let mapping = PortMapping::request(addr, 8333, Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert_eq!(mapping.protocol, MappingProtocol::NatPmp);
+        assert_eq!(mapping.external, "203.0.113.7:9333".parse().unwrap());
+        assert_eq!(mapping.lifetime, Duration::from_secs(60));
+
+        let renewed = mapping.renew(Duration::from_secs(60)).await.unwrap();
+        assert_eq!(renewed, mapping);
+        assert_eq!(*lifetimes.lock().unwrap(), vec![60, 60]);
+    }
+
+    #[tokio::test]
+    async fn test_no_gateway() {
+        // nothing answers on a bound socket that never reads
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let err =
+            PortMapping::request(silent.local_addr().unwrap(), 8333, DEFAULT_MAPPING_LIFETIME)
+                .await
+                .unwrap_err();
+        assert!(matches!(err, BTCP2PError::PortMappingError(_)));
+    }
+
+    #[tokio::test]
+    async fn test_port_mapper() {
+        let (addr, lifetimes) = gateway(true).start().await;
+        let local_addresses = LocalAddresses::new();
+
+        // the gateway grants two seconds, so the mapping is renewed after one
+        let mapper = PortMapper::start(addr, 8333, Duration::from_secs(2), local_addresses.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            local_addresses.best_ip(),
+            Some("203.0.113.7:9333".parse().unwrap())
+        );
+
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(*lifetimes.lock().unwrap(), vec![2, 2]);
+
+        mapper.shutdown().await.unwrap();
+        assert!(local_addresses.is_empty());
+        assert_eq!(*lifetimes.lock().unwrap(), vec![2, 2, 0]);
+    }
+
+    #[test]
+    fn test_parse_route_table() {
+        let table =
+            "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+                     eth0\t0000A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0\n\
+                     eth0\t00000000\t0100A8C0\t0003\t0\t0\t0\t00000000\t0\t0\t0\n";
+        assert_eq!(
+            parse_route_table(table),
+            Some(Ipv4Addr::new(192, 168, 0, 1))
+        );
+        assert_eq!(parse_route_table(table.lines().next().unwrap()), None);
+    }
+}
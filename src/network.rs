@@ -1,4 +1,20 @@
-use super::errors::{BTCP2PError, Result};
+use super::{
+    chain_params::ChainParams,
+    encode::write_compact_size,
+    errors::{BTCP2PError, Result},
+    hash::sha256d,
+    START_STRING_SIZE,
+};
+
+/// Challenge script of the default signet, a 1-of-2 multisig
+/// https://github.com/bitcoin/bips/blob/master/bip-0325.mediawiki
+pub const DEFAULT_SIGNET_CHALLENGE: [u8; 71] = [
+    0x51, 0x21, 0x03, 0xad, 0x5e, 0x0e, 0xda, 0xd1, 0x8c, 0xb1, 0xf0, 0xfc, 0x0d, 0x28, 0xa3, 0xd4,
+    0xf1, 0xf3, 0xe4, 0x45, 0x64, 0x03, 0x37, 0x48, 0x9a, 0xbb, 0x10, 0x40, 0x4f, 0x2d, 0x1e, 0x08,
+    0x6b, 0xe4, 0x30, 0x21, 0x03, 0x59, 0xef, 0x50, 0x21, 0x96, 0x4f, 0xe2, 0x2d, 0x6f, 0x8e, 0x05,
+    0xb2, 0x46, 0x3c, 0x95, 0x40, 0xce, 0x96, 0x88, 0x3f, 0xe3, 0xb2, 0x78, 0x76, 0x0f, 0x04, 0x8f,
+    0x51, 0x89, 0xf2, 0xe6, 0xc4, 0x52, 0xae,
+];
 
 /// Represents the network to which a message belongs.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -7,13 +23,25 @@ pub enum Network {
     /// Default Port 8333
     MainNet,
 
-    /// Testnet
+    /// Testnet (testnet3)
     /// Default Port 18333
     TestNet,
 
+    /// Testnet4, the replacement of testnet3 defined by BIP94
+    /// Default Port 48333
+    TestNet4,
+
     /// Regtest
     /// Default Port 18444
     RegTest,
+
+    /// Default signet
+    /// Default Port 38333
+    Signet,
+
+    /// Any other network identified only by its magic bytes,
+    /// such as custom signets (see `Network::signet`) or private test networks
+    Custom([u8; START_STRING_SIZE]),
 }
 
 impl Network {
@@ -21,18 +49,66 @@ impl Network {
         match network {
             Network::MainNet => [0xf9, 0xbe, 0xb4, 0xd9],
             Network::TestNet => [0x0b, 0x11, 0x09, 0x07],
+            Network::TestNet4 => [0x1c, 0x16, 0x3f, 0x28],
             Network::RegTest => [0xfa, 0xbf, 0xb5, 0xda],
+            Network::Signet => [0x0a, 0x03, 0xcf, 0x40],
+            Network::Custom(magic) => magic,
         }
     }
 
+    /// from_bytes maps magic bytes to a network
+    /// magic bytes not matching a well known network are returned as `Network::Custom`
     pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
         match bytes {
             [0xf9, 0xbe, 0xb4, 0xd9] => Ok(Self::MainNet),
             [0x0b, 0x11, 0x09, 0x07] => Ok(Self::TestNet),
+            [0x1c, 0x16, 0x3f, 0x28] => Ok(Self::TestNet4),
             [0xfa, 0xbf, 0xb5, 0xda] => Ok(Self::RegTest),
+            [0x0a, 0x03, 0xcf, 0x40] => Ok(Self::Signet),
+            [a, b, c, d] => Ok(Self::Custom([*a, *b, *c, *d])),
             _ => Err(BTCP2PError::UnknowNetwork),
         }
     }
+
+    /// signet returns the chain parameters of the signet with the given challenge script
+    /// the magic bytes are the first 4 bytes of the double SHA256 of the serialized challenge,
+    /// the other parameters are those of the default signet, without its DNS seeds
+    pub fn signet(challenge: &[u8]) -> Result<ChainParams> {
+        let mut buffer = Vec::with_capacity(challenge.len() + 9);
+        write_compact_size(&mut buffer, challenge.len() as u64)?;
+        buffer.extend(challenge);
+
+        let network = Network::from_bytes(&sha256d(&buffer)[..START_STRING_SIZE])?;
+        if network == Network::Signet {
+            return Ok(ChainParams::signet());
+        }
+        Ok(ChainParams {
+            network,
+            dns_seeds: &[],
+            ..ChainParams::signet()
+        })
+    }
+
+    /// params returns the chain parameters of a well known network
+    /// custom networks have none, those of custom signets are built by `Network::signet`
+    pub fn params(&self) -> Option<ChainParams> {
+        ChainParams::try_from(*self).ok()
+    }
+
+    /// default_port returns the port nodes of a well known network listen on by default
+    pub fn default_port(&self) -> Option<u16> {
+        self.params().map(|params| params.default_port)
+    }
+
+    /// dns_seeds returns the DNS seeds used to discover nodes of a well known network
+    pub fn dns_seeds(&self) -> Option<&'static [&'static str]> {
+        self.params().map(|params| params.dns_seeds)
+    }
+
+    /// genesis_hash returns the hash of the genesis block of a well known network in byte order
+    pub fn genesis_hash(&self) -> Option<[u8; 32]> {
+        self.params().map(|params| params.genesis_hash())
+    }
 }
 
 #[cfg(test)]
@@ -43,10 +119,13 @@ mod tests {
 
     impl Arbitrary for Network {
         fn arbitrary(g: &mut quickcheck::Gen) -> Self {
-            match u8::arbitrary(g) % 3 {
+            match u8::arbitrary(g) % 6 {
                 0 => Self::MainNet,
                 1 => Self::TestNet,
                 2 => Self::RegTest,
+                3 => Self::Signet,
+                4 => Self::TestNet4,
+                5 => Self::from_bytes(&u32::arbitrary(g).to_le_bytes()).unwrap(),
                 _ => unreachable!(),
             }
         }
@@ -74,8 +153,52 @@ mod tests {
             Network::RegTest
         );
         assert_eq!(
-            Network::from_bytes(&[0xde, 0xad, 0xbe, 0xef]).is_err(),
-            true,
+            Network::from_bytes(&[0x1c, 0x16, 0x3f, 0x28]).unwrap(),
+            Network::TestNet4
         );
+        assert_eq!(
+            Network::from_bytes(&[0x0a, 0x03, 0xcf, 0x40]).unwrap(),
+            Network::Signet
+        );
+        assert_eq!(
+            Network::from_bytes(&[0xde, 0xad, 0xbe, 0xef]).unwrap(),
+            Network::Custom([0xde, 0xad, 0xbe, 0xef])
+        );
+        assert!(Network::from_bytes(&[0xde, 0xad, 0xbe]).is_err());
+    }
+
+    #[test]
+    fn test_signet() {
+        assert_eq!(
+            Network::signet(&DEFAULT_SIGNET_CHALLENGE).unwrap(),
+            ChainParams::signet()
+        );
+
+        // custom signets share the genesis and difficulty rules of the default one
+        let params = Network::signet(&[0x51]).unwrap();
+        assert!(matches!(params.network, Network::Custom(_)));
+        assert_eq!(params.genesis_hash(), ChainParams::signet().genesis_hash());
+        assert_eq!(params.pow_limit, ChainParams::signet().pow_limit);
+        assert_eq!(params.default_port, 38333);
+        assert!(params.dns_seeds.is_empty());
+    }
+
+    #[test]
+    fn test_testnet4() {
+        let network = Network::TestNet4;
+        assert_eq!(network.default_port(), Some(48333));
+        assert_eq!(network.dns_seeds().unwrap().len(), 2);
+        assert_eq!(network.genesis_hash().unwrap()[31], 0x00);
+        assert_eq!(network.genesis_hash().unwrap()[0], 0x43);
+    }
+
+    #[test]
+    fn test_custom_params() {
+        // the parameters of a custom network are not guessed, custom signets build theirs
+        let network = Network::signet(&[0x51]).unwrap().network;
+        assert_eq!(network.params(), None);
+        assert_eq!(network.default_port(), None);
+        assert_eq!(network.dns_seeds(), None);
+        assert_eq!(network.genesis_hash(), None);
     }
 }
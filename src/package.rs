@@ -0,0 +1,309 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use sha2::{Digest, Sha256};
+
+use super::{
+    encode::{read_hashes, read_vec_len, write_compact_size, write_hashes},
+    errors::{BTCP2PError, Result},
+    inventory::{InvType, Inventory},
+    transaction::{Transaction, MIN_TRANSACTION_SIZE},
+};
+
+/// Package relay version bit for ancestor packages
+/// https://github.com/bitcoin/bips/blob/master/bip-0331.mediawiki
+pub const ANCESTOR_PACKAGE_VERSION: u64 = 0x1;
+
+/// Maximum number of transactions in a package, as in Bitcoin Core
+pub const MAX_PACKAGE_COUNT: usize = 25;
+
+/// SendPackagesPayload represents the payload of a sendpackages message
+/// Sent before verack to announce the package relay versions supported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendPackagesPayload {
+    /// Bitfield of the package relay versions supported by the transmitting node.
+    pub versions: u64,
+}
+
+impl SendPackagesPayload {
+    /// to_bytes converts the payload to bytes
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut buffer = vec![];
+        buffer.write_u64::<LittleEndian>(self.versions)?;
+        Ok(buffer)
+    }
+
+    /// from_bytes converts bytes to a payload
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self> {
+        Ok(Self {
+            versions: bytes.read_u64::<LittleEndian>()?,
+        })
+    }
+
+    /// supports checks whether a package relay version is announced
+    pub fn supports(&self, version: u64) -> bool {
+        self.versions & version == version
+    }
+}
+
+/// AncPkgInfoPayload represents the payload of an ancpkginfo message
+/// Lists the wtxids of the unconfirmed ancestors of a transaction, the transaction itself last
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AncPkgInfoPayload {
+    pub wtxids: Vec<[u8; 32]>,
+}
+
+impl AncPkgInfoPayload {
+    /// to_bytes converts the payload to bytes
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut buffer = vec![];
+        write_hashes(&mut buffer, &self.wtxids)?;
+        Ok(buffer)
+    }
+
+    /// from_bytes converts bytes to a payload
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self> {
+        Ok(Self {
+            wtxids: read_hashes(&mut bytes)?,
+        })
+    }
+
+    /// package_id returns the package ID of the ancestor package
+    pub fn package_id(&self) -> Result<[u8; 32]> {
+        package_id(&self.wtxids)
+    }
+
+    /// request returns the getdata entry asking for the ancestor package of a transaction by wtxid
+    /// a peer without the package answers with a notfound of the same entry
+    pub fn request(wtxid: [u8; 32]) -> Inventory {
+        Inventory {
+            inv_type: InvType::AncPkgInfo,
+            hash: wtxid,
+        }
+    }
+}
+
+/// GetPkgTxnsPayload represents the payload of a getpkgtxns message
+/// Requests the transactions of a package by wtxid, answered with pkgtxns
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GetPkgTxnsPayload {
+    pub wtxids: Vec<[u8; 32]>,
+}
+
+impl GetPkgTxnsPayload {
+    /// to_bytes converts the payload to bytes
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut buffer = vec![];
+        write_hashes(&mut buffer, &self.wtxids)?;
+        Ok(buffer)
+    }
+
+    /// from_bytes converts bytes to a payload
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self> {
+        Ok(Self {
+            wtxids: read_hashes(&mut bytes)?,
+        })
+    }
+
+    /// package_id returns the package ID of the requested transactions
+    pub fn package_id(&self) -> Result<[u8; 32]> {
+        package_id(&self.wtxids)
+    }
+
+    /// not_found returns the notfound entry answering the request when some transactions are unknown
+    pub fn not_found(&self) -> Result<Inventory> {
+        Ok(Inventory {
+            inv_type: InvType::PkgTxns,
+            hash: self.package_id()?,
+        })
+    }
+}
+
+/// PkgTxnsPayload represents the payload of a pkgtxns message
+/// Answers a getpkgtxns with the transactions of a package, such as a parent and its child
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PkgTxnsPayload {
+    pub transactions: Vec<Transaction>,
+}
+
+impl PkgTxnsPayload {
+    /// to_bytes converts the payload to bytes
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut buffer = vec![];
+        write_compact_size(&mut buffer, self.transactions.len() as u64)?;
+        for transaction in &self.transactions {
+            buffer.extend(transaction.to_bytes()?);
+        }
+        Ok(buffer)
+    }
+
+    /// from_bytes converts bytes to a payload
+    /// packages of more than MAX_PACKAGE_COUNT transactions are rejected
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self> {
+        let len = read_vec_len(&mut bytes, MIN_TRANSACTION_SIZE)?;
+        if len > MAX_PACKAGE_COUNT {
+            return Err(BTCP2PError::InvalidPackageSize(len));
+        }
+
+        let transactions = (0..len)
+            .map(|_| Transaction::read(&mut bytes))
+            .collect::<Result<_>>()?;
+        Ok(Self { transactions })
+    }
+
+    /// package_id returns the package ID of the transactions, from their wtxids
+    pub fn package_id(&self) -> Result<[u8; 32]> {
+        let wtxids: Vec<[u8; 32]> = self.transactions.iter().map(Transaction::wtxid).collect();
+        package_id(&wtxids)
+    }
+}
+
+/// package_id computes the ID of a package: the SHA256 of its wtxids sorted in byte order
+/// the ID does not depend on the order in which the transactions are listed
+pub fn package_id(wtxids: &[[u8; 32]]) -> Result<[u8; 32]> {
+    if wtxids.is_empty() || wtxids.len() > MAX_PACKAGE_COUNT {
+        return Err(BTCP2PError::InvalidPackageSize(wtxids.len()));
+    }
+
+    let mut sorted = wtxids.to_vec();
+    sorted.sort_unstable();
+
+    let mut hasher = Sha256::new();
+    for wtxid in sorted {
+        hasher.update(wtxid);
+    }
+
+    Ok(hasher.finalize().into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::{Arbitrary, TestResult};
+    use quickcheck_macros::quickcheck;
+
+    fn arbitrary_wtxids(g: &mut quickcheck::Gen) -> Vec<[u8; 32]> {
+        Vec::<u8>::arbitrary(g)
+            .into_iter()
+            .map(|n| [n; 32])
+            .collect()
+    }
+
+    impl Arbitrary for SendPackagesPayload {
+        fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+            Self {
+                versions: u64::arbitrary(g),
+            }
+        }
+    }
+
+    impl Arbitrary for AncPkgInfoPayload {
+        fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+            Self {
+                wtxids: arbitrary_wtxids(g),
+            }
+        }
+    }
+
+    impl Arbitrary for GetPkgTxnsPayload {
+        fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+            Self {
+                wtxids: arbitrary_wtxids(g),
+            }
+        }
+    }
+
+    impl Arbitrary for PkgTxnsPayload {
+        fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+            Self {
+                transactions: (0..usize::arbitrary(g) % 4)
+                    .map(|_| Transaction::arbitrary(g))
+                    .collect(),
+            }
+        }
+    }
+
+    #[quickcheck]
+    fn test_sendpackages_to_bytes(payload: SendPackagesPayload) -> TestResult {
+        let bytes = payload.to_bytes().unwrap();
+        TestResult::from_bool(payload == SendPackagesPayload::from_bytes(&bytes).unwrap())
+    }
+
+    #[quickcheck]
+    fn test_ancpkginfo_to_bytes(payload: AncPkgInfoPayload) -> TestResult {
+        let bytes = payload.to_bytes().unwrap();
+        TestResult::from_bool(payload == AncPkgInfoPayload::from_bytes(&bytes).unwrap())
+    }
+
+    #[quickcheck]
+    fn test_getpkgtxns_to_bytes(payload: GetPkgTxnsPayload) -> TestResult {
+        let bytes = payload.to_bytes().unwrap();
+        TestResult::from_bool(payload == GetPkgTxnsPayload::from_bytes(&bytes).unwrap())
+    }
+
+    #[quickcheck]
+    fn test_pkgtxns_to_bytes(payload: PkgTxnsPayload) -> TestResult {
+        let bytes = payload.to_bytes().unwrap();
+        TestResult::from_bool(payload == PkgTxnsPayload::from_bytes(&bytes).unwrap())
+    }
+
+    #[test]
+    fn test_pkgtxns() {
+        let mut g = quickcheck::Gen::new(10);
+        let parent = Transaction::arbitrary(&mut g);
+        let child = Transaction::arbitrary(&mut g);
+        let payload = PkgTxnsPayload {
+            transactions: vec![parent.clone(), child.clone()],
+        };
+        assert_eq!(
+            payload.package_id().unwrap(),
+            package_id(&[child.wtxid(), parent.wtxid()]).unwrap()
+        );
+
+        let payload = PkgTxnsPayload {
+            transactions: vec![parent; MAX_PACKAGE_COUNT + 1],
+        };
+        assert!(matches!(
+            PkgTxnsPayload::from_bytes(&payload.to_bytes().unwrap()),
+            Err(BTCP2PError::InvalidPackageSize(26))
+        ));
+    }
+
+    #[test]
+    fn test_package_id_is_order_independent() {
+        let parent = [0x02; 32];
+        let child = [0x01; 32];
+
+        let id = package_id(&[parent, child]).unwrap();
+        assert_eq!(id, package_id(&[child, parent]).unwrap());
+        assert_ne!(id, package_id(&[parent]).unwrap());
+    }
+
+    #[test]
+    fn test_inventory() {
+        let child = [0x01; 32];
+        let request = AncPkgInfoPayload::request(child);
+        assert_eq!(request.inv_type.to_u32(), 7);
+        assert_eq!(request.hash, child);
+
+        let payload = GetPkgTxnsPayload {
+            wtxids: vec![[0x02; 32], child],
+        };
+        let not_found = payload.not_found().unwrap();
+        assert_eq!(not_found.inv_type.to_u32(), 6);
+        assert_eq!(not_found.hash, payload.package_id().unwrap());
+    }
+
+    #[test]
+    fn test_package_id_size() {
+        assert!(package_id(&[]).is_err());
+        assert!(package_id(&[[0u8; 32]; MAX_PACKAGE_COUNT + 1]).is_err());
+    }
+
+    #[test]
+    fn test_supports() {
+        let payload = SendPackagesPayload {
+            versions: ANCESTOR_PACKAGE_VERSION,
+        };
+        assert!(payload.supports(ANCESTOR_PACKAGE_VERSION));
+        assert!(!payload.supports(0x2));
+    }
+}
@@ -2,10 +2,25 @@ use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::{
     io::{Read, Write},
     net::SocketAddr,
-    time::SystemTime,
 };
 
-use super::{command::Command, errors::Result, PROTOCOL_VERSION};
+use super::{
+    address::{AddrPayload, AddrV2Payload},
+    block::{GetHeadersPayload, HeadersPayload},
+    command::Command,
+    encode::{read_vec_len, write_compact_size},
+    erlay::{ReconcilDiffPayload, ReqReconPayload, SendTxRcnclPayload, SketchPayload},
+    errors::{BTCP2PError, Result},
+    features::SendCmpctPayload,
+    inventory::InvPayload,
+    package::{AncPkgInfoPayload, GetPkgTxnsPayload, PkgTxnsPayload, SendPackagesPayload},
+    service_flags::ServiceFlags,
+    time_offsets::unix_time,
+    PROTOCOL_VERSION,
+};
+
+/// Maximum length of the user agent of a version message in bytes, as in Bitcoin Core
+pub const MAX_SUBVERSION_LENGTH: usize = 256;
 
 /// Payload represents the payload of a message
 /// The inner type encapsulates all the different payloads
@@ -15,6 +30,29 @@ pub enum Payload {
     VerAck,
     Ping(u64),
     Pong(u64),
+    WtxidRelay,
+    SendAddrV2,
+    SendTxRcncl(SendTxRcnclPayload),
+    ReqRecon(ReqReconPayload),
+    Sketch(SketchPayload),
+    ReqSketchExt,
+    ReconcilDiff(ReconcilDiffPayload),
+    SendPackages(SendPackagesPayload),
+    AncPkgInfo(AncPkgInfoPayload),
+    GetPkgTxns(GetPkgTxnsPayload),
+    PkgTxns(PkgTxnsPayload),
+    Addr(AddrPayload),
+    AddrV2(AddrV2Payload),
+    GetAddr,
+    SendHeaders,
+    SendCmpct(SendCmpctPayload),
+    /// The minimum fee rate in satoshis per kilovbyte of transactions to relay.
+    FeeFilter(u64),
+    GetHeaders(GetHeadersPayload),
+    Headers(HeadersPayload),
+    Inv(InvPayload),
+    GetData(InvPayload),
+    NotFound(InvPayload),
     Empty,
 }
 
@@ -26,6 +64,28 @@ impl Payload {
             Payload::VerAck => Ok(vec![]),
             Payload::Ping(nonce) => Ok(nonce.to_le_bytes().to_vec()),
             Payload::Pong(nonce) => Ok(nonce.to_le_bytes().to_vec()),
+            Payload::WtxidRelay => Ok(vec![]),
+            Payload::SendAddrV2 => Ok(vec![]),
+            Payload::SendTxRcncl(payload) => payload.to_bytes(),
+            Payload::ReqRecon(payload) => payload.to_bytes(),
+            Payload::Sketch(payload) => payload.to_bytes(),
+            Payload::ReqSketchExt => Ok(vec![]),
+            Payload::ReconcilDiff(payload) => payload.to_bytes(),
+            Payload::SendPackages(payload) => payload.to_bytes(),
+            Payload::AncPkgInfo(payload) => payload.to_bytes(),
+            Payload::GetPkgTxns(payload) => payload.to_bytes(),
+            Payload::PkgTxns(payload) => payload.to_bytes(),
+            Payload::Addr(payload) => payload.to_bytes(),
+            Payload::AddrV2(payload) => payload.to_bytes(),
+            Payload::GetAddr => Ok(vec![]),
+            Payload::SendHeaders => Ok(vec![]),
+            Payload::SendCmpct(payload) => payload.to_bytes(),
+            Payload::FeeFilter(fee_rate) => Ok(fee_rate.to_le_bytes().to_vec()),
+            Payload::GetHeaders(payload) => payload.to_bytes(),
+            Payload::Headers(payload) => payload.to_bytes(),
+            Payload::Inv(payload) => payload.to_bytes(),
+            Payload::GetData(payload) => payload.to_bytes(),
+            Payload::NotFound(payload) => payload.to_bytes(),
             Payload::Empty => Ok(vec![]),
         }
     }
@@ -34,57 +94,42 @@ impl Payload {
     /// the command is needed to determine the payload type
     pub fn from_bytes(command: &Command, bytes: &[u8]) -> Result<Self> {
         match command {
-            Command::Version => Ok(Payload::Version(VersionPayload::from_bytes(&bytes)?)),
+            Command::Version => Ok(Payload::Version(VersionPayload::from_bytes(bytes)?)),
             Command::VerAck => Ok(Payload::VerAck),
             Command::Ping => Ok(Payload::Ping(u64::from_le_bytes(bytes.try_into()?))),
             Command::Pong => Ok(Payload::Pong(u64::from_le_bytes(bytes.try_into()?))),
+            Command::WtxidRelay => Ok(Payload::WtxidRelay),
+            Command::SendAddrV2 => Ok(Payload::SendAddrV2),
+            Command::SendTxRcncl => {
+                Ok(Payload::SendTxRcncl(SendTxRcnclPayload::from_bytes(bytes)?))
+            }
+            Command::ReqRecon => Ok(Payload::ReqRecon(ReqReconPayload::from_bytes(bytes)?)),
+            Command::Sketch => Ok(Payload::Sketch(SketchPayload::from_bytes(bytes)?)),
+            Command::ReqSketchExt => Ok(Payload::ReqSketchExt),
+            Command::ReconcilDiff => Ok(Payload::ReconcilDiff(ReconcilDiffPayload::from_bytes(
+                bytes,
+            )?)),
+            Command::SendPackages => Ok(Payload::SendPackages(SendPackagesPayload::from_bytes(
+                bytes,
+            )?)),
+            Command::AncPkgInfo => Ok(Payload::AncPkgInfo(AncPkgInfoPayload::from_bytes(bytes)?)),
+            Command::GetPkgTxns => Ok(Payload::GetPkgTxns(GetPkgTxnsPayload::from_bytes(bytes)?)),
+            Command::PkgTxns => Ok(Payload::PkgTxns(PkgTxnsPayload::from_bytes(bytes)?)),
+            Command::Addr => Ok(Payload::Addr(AddrPayload::from_bytes(bytes)?)),
+            Command::AddrV2 => Ok(Payload::AddrV2(AddrV2Payload::from_bytes(bytes)?)),
+            Command::GetAddr => Ok(Payload::GetAddr),
+            Command::SendHeaders => Ok(Payload::SendHeaders),
+            Command::SendCmpct => Ok(Payload::SendCmpct(SendCmpctPayload::from_bytes(bytes)?)),
+            Command::FeeFilter => Ok(Payload::FeeFilter(u64::from_le_bytes(bytes.try_into()?))),
+            Command::GetHeaders => Ok(Payload::GetHeaders(GetHeadersPayload::from_bytes(bytes)?)),
+            Command::Headers => Ok(Payload::Headers(HeadersPayload::from_bytes(bytes)?)),
+            Command::Inv => Ok(Payload::Inv(InvPayload::from_bytes(bytes)?)),
+            Command::GetData => Ok(Payload::GetData(InvPayload::from_bytes(bytes)?)),
+            Command::NotFound => Ok(Payload::NotFound(InvPayload::from_bytes(bytes)?)),
         }
     }
 }
 
-/// ServiceFlags represents the service flags of a node
-/// https://developer.bitcoin.org/reference/p2p_networking.html#version
-pub struct ServiceFlags(u64);
-
-impl ServiceFlags {
-    /// This node is not a full node. It may not be able to provide any data except for the transactions it originates.
-    pub const UNNAMED: ServiceFlags = ServiceFlags(0);
-
-    /// This is a full node and can be asked for full blocks. It should implement all protocol features available in its self-reported protocol version
-    pub const NODE_NETWORK: ServiceFlags = ServiceFlags(0x1);
-
-    /// This is a full node capable of responding to the getutxo protocol request. This is not supported by any currently-maintained Bitcoin node.
-    pub const NODE_GETUTXO: ServiceFlags = ServiceFlags(0x2);
-
-    /// This is a full node capable and willing to handle bloom-filtered connections.
-    pub const NODE_BLOOM: ServiceFlags = ServiceFlags(0x4);
-
-    /// This is a full node that can be asked for blocks and transactions including witness data.
-    pub const NODE_WITNESS: ServiceFlags = ServiceFlags(0x8);
-
-    /// This is a full node that supports Xtreme Thinblocks. This is not supported by any currently-maintained Bitcoin node.
-    pub const NODE_XTHIN: ServiceFlags = ServiceFlags(0x10);
-
-    /// This is the same as NODE_NETWORK but the node has at least the last 288 blocks (last 2 days).
-    pub const NODE_NETWORK_LIMITED: ServiceFlags = ServiceFlags(0x0400);
-
-    /// Gets the integer representation of this ServiceFlags
-    pub fn to_u64(self) -> u64 {
-        self.0
-    }
-
-    /// Gets the ServiceFlags from an integer representation
-    pub fn from_u64(n: u64) -> Self {
-        ServiceFlags(n)
-    }
-}
-
-impl From<u64> for ServiceFlags {
-    fn from(n: u64) -> Self {
-        ServiceFlags(n)
-    }
-}
-
 /// VersionPayload represents the payload of a version message
 /// https://developer.bitcoin.org/reference/p2p_networking.html#version
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -93,13 +138,13 @@ pub struct VersionPayload {
     pub version: i32,
 
     /// The services supported by the transmitting node encoded as a bitfield.
-    pub services: u64,
+    pub services: ServiceFlags,
 
     /// The current Unix epoch time according to the transmitting node’s clock.
     pub timestamp: i64,
 
     /// The services supported by the receiving node as perceived by the transmitting node. Same format as the ‘services’ field above.
-    pub addr_recv_serv: u64,
+    pub addr_recv_serv: ServiceFlags,
 
     /// The IPv6 address of the receiving node as perceived by the transmitting node in big endian byte order.
     pub addr_recv: [u8; 16],
@@ -108,7 +153,7 @@ pub struct VersionPayload {
     pub addr_recv_port: u16,
 
     /// Added inprotocol version 106. The services supported by the transmitting node. Should be identical to the ‘services’ field above.
-    pub addr_trans_serv: u64,
+    pub addr_trans_serv: ServiceFlags,
 
     /// Added inprotocol version 106. The IPv6 address of the transmitting node in big endian byte order.
     pub addr_trans: [u8; 16],
@@ -134,6 +179,8 @@ pub struct VersionPayload {
 }
 
 impl VersionPayload {
+    /// build creates the payload of a version message, see `VersionPayload::new`
+    #[allow(clippy::too_many_arguments)]
     pub fn build(
         services: ServiceFlags,
         addr_recv_serv: ServiceFlags,
@@ -144,10 +191,31 @@ impl VersionPayload {
         start_height: i32,
         relay: bool,
     ) -> Payload {
-        let timestamp = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .expect("get timestamp since unix epoch")
-            .as_secs() as i64;
+        Payload::Version(VersionPayload::new(
+            services,
+            addr_recv_serv,
+            addr_recv_socket,
+            addr_trans_serv,
+            addr_trans_socket,
+            nonce,
+            start_height,
+            relay,
+        ))
+    }
+
+    /// new creates a version payload with the current time, our protocol version and user agent
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        services: ServiceFlags,
+        addr_recv_serv: ServiceFlags,
+        addr_recv_socket: SocketAddr,
+        addr_trans_serv: ServiceFlags,
+        addr_trans_socket: SocketAddr,
+        nonce: u64,
+        start_height: i32,
+        relay: bool,
+    ) -> Self {
+        let timestamp = unix_time();
 
         let (addr_recv, addr_recv_port) =
             VersionPayload::socket_to_octets_and_port(addr_recv_socket);
@@ -160,37 +228,37 @@ impl VersionPayload {
 
         let user_agent = format!("/{}:{}/", CARGO_PKG_NAME, CARGO_PKG_VERSION).to_string();
 
-        Payload::Version(VersionPayload {
+        VersionPayload {
             version: PROTOCOL_VERSION,
-            services: services.to_u64(),
+            services,
             timestamp,
-            addr_recv_serv: addr_recv_serv.to_u64(),
+            addr_recv_serv,
             addr_recv,
             addr_recv_port,
-            addr_trans_serv: addr_trans_serv.to_u64(),
+            addr_trans_serv,
             addr_trans,
             addr_trans_port,
             user_agent,
             nonce,
             start_height,
             relay,
-        })
+        }
     }
 
     /// to_bytes converts the payload to bytes
     pub fn to_bytes(&self) -> Result<Vec<u8>> {
         let mut buffer: Vec<u8> = vec![];
         buffer.write_i32::<LittleEndian>(self.version)?;
-        buffer.write_u64::<LittleEndian>(self.services)?;
+        buffer.write_u64::<LittleEndian>(self.services.to_u64())?;
         buffer.write_i64::<LittleEndian>(self.timestamp)?;
-        buffer.write_u64::<LittleEndian>(self.addr_recv_serv)?;
-        buffer.write_u128::<BigEndian>(u128::from_ne_bytes(self.addr_recv))?;
+        buffer.write_u64::<LittleEndian>(self.addr_recv_serv.to_u64())?;
+        buffer.write_all(&self.addr_recv)?;
         buffer.write_u16::<BigEndian>(self.addr_recv_port)?;
-        buffer.write_u64::<LittleEndian>(self.addr_trans_serv)?;
-        buffer.write_u128::<BigEndian>(u128::from_ne_bytes(self.addr_trans))?;
+        buffer.write_u64::<LittleEndian>(self.addr_trans_serv.to_u64())?;
+        buffer.write_all(&self.addr_trans)?;
         buffer.write_u16::<BigEndian>(self.addr_trans_port)?;
         buffer.write_u64::<LittleEndian>(self.nonce)?;
-        buffer.write_u8(self.user_agent.len() as u8)?;
+        write_compact_size(&mut buffer, self.user_agent.len() as u64)?;
         buffer.write_all(self.user_agent.as_bytes())?;
         buffer.write_i32::<LittleEndian>(self.start_height)?;
         buffer.write_u8(self.relay.into())?;
@@ -201,24 +269,35 @@ impl VersionPayload {
     pub fn from_bytes(mut bytes: &[u8]) -> Result<Self> {
         let version_payload = VersionPayload {
             version: bytes.read_i32::<LittleEndian>()?,
-            services: bytes.read_u64::<LittleEndian>()?,
+            services: bytes.read_u64::<LittleEndian>()?.into(),
             timestamp: bytes.read_i64::<LittleEndian>()?,
-            addr_recv_serv: bytes.read_u64::<LittleEndian>()?,
-            addr_recv: bytes.read_u128::<BigEndian>()?.to_ne_bytes(),
+            addr_recv_serv: bytes.read_u64::<LittleEndian>()?.into(),
+            addr_recv: {
+                let mut addr = [0u8; 16];
+                bytes.read_exact(&mut addr)?;
+                addr
+            },
             addr_recv_port: bytes.read_u16::<BigEndian>()?,
-            addr_trans_serv: bytes.read_u64::<LittleEndian>()?,
-            addr_trans: bytes.read_u128::<BigEndian>()?.to_ne_bytes(),
+            addr_trans_serv: bytes.read_u64::<LittleEndian>()?.into(),
+            addr_trans: {
+                let mut addr = [0u8; 16];
+                bytes.read_exact(&mut addr)?;
+                addr
+            },
             addr_trans_port: bytes.read_u16::<BigEndian>()?,
             nonce: bytes.read_u64::<LittleEndian>()?,
             user_agent: {
-                let mut tmp_bytes = vec![0u8; 0];
-                let user_agent_len = bytes.read_u8()?;
-                let user_agent_bytes = vec![0u8; user_agent_len as usize];
-                bytes.read_exact(&mut tmp_bytes)?;
+                let user_agent_len = read_vec_len(&mut bytes, 1)?;
+                if user_agent_len > MAX_SUBVERSION_LENGTH {
+                    return Err(BTCP2PError::UserAgentTooLong(user_agent_len));
+                }
+                let mut user_agent_bytes = vec![0u8; user_agent_len];
+                bytes.read_exact(&mut user_agent_bytes)?;
                 String::from_utf8(user_agent_bytes)?
             },
             start_height: bytes.read_i32::<LittleEndian>()?,
-            relay: bytes.read_u8()? != 0x00,
+            // the relay field is optional, a missing one means relay
+            relay: bytes.read_u8().map_or(true, |relay| relay != 0x00),
         };
 
         Ok(version_payload)
@@ -262,12 +341,12 @@ mod tests {
         fn arbitrary(g: &mut quickcheck::Gen) -> VersionPayload {
             VersionPayload {
                 version: i32::arbitrary(g),
-                services: u64::arbitrary(g),
+                services: ServiceFlags::arbitrary(g),
                 timestamp: i64::arbitrary(g),
-                addr_recv_serv: u64::arbitrary(g),
+                addr_recv_serv: ServiceFlags::arbitrary(g),
                 addr_recv: [u8::arbitrary(g); 16],
                 addr_recv_port: u16::arbitrary(g),
-                addr_trans_serv: u64::arbitrary(g),
+                addr_trans_serv: ServiceFlags::arbitrary(g),
                 addr_trans: [u8::arbitrary(g); 16],
                 addr_trans_port: u16::arbitrary(g),
                 nonce: u64::arbitrary(g),
@@ -278,6 +357,34 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_version_addresses_in_network_order() {
+        let version = VersionPayload::new(
+            ServiceFlags::UNNAMED,
+            ServiceFlags::UNNAMED,
+            "1.2.3.4:8333".parse().unwrap(),
+            ServiceFlags::UNNAMED,
+            "[2001:db8::1]:18333".parse().unwrap(),
+            0,
+            0,
+            true,
+        );
+        let bytes = version.to_bytes().unwrap();
+
+        // version, services, timestamp and services of the receiver come first
+        let addr_recv = &bytes[28..46];
+        assert_eq!(
+            &addr_recv[..16],
+            &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 1, 2, 3, 4]
+        );
+        assert_eq!(&addr_recv[16..], &8333u16.to_be_bytes());
+        let addr_trans = &bytes[54..72];
+        assert_eq!(&addr_trans[..4], &[0x20, 0x01, 0x0d, 0xb8]);
+        assert_eq!(&addr_trans[16..], &18333u16.to_be_bytes());
+
+        assert_eq!(VersionPayload::from_bytes(&bytes).unwrap(), version);
+    }
+
     #[quickcheck]
     fn payload_from_bytes(payload: Payload) {
         let mut nonce = 0;
@@ -313,4 +420,51 @@ mod tests {
         let bytes = version_payload.to_bytes().unwrap();
         let _ = VersionPayload::from_bytes(&bytes).unwrap();
     }
+
+    #[quickcheck]
+    fn version_user_agent_from_bytes(user_agent: String) -> TestResult {
+        if user_agent.len() > MAX_SUBVERSION_LENGTH {
+            return TestResult::discard();
+        }
+        let version_payload = VersionPayload {
+            user_agent,
+            ..VersionPayload::arbitrary(&mut quickcheck::Gen::new(10))
+        };
+
+        let bytes = version_payload.to_bytes().unwrap();
+        let result = VersionPayload::from_bytes(&bytes).unwrap();
+        TestResult::from_bool(result == version_payload)
+    }
+
+    #[test]
+    fn version_user_agent_too_long() {
+        let version_payload = VersionPayload {
+            user_agent: "a".repeat(MAX_SUBVERSION_LENGTH),
+            ..VersionPayload::arbitrary(&mut quickcheck::Gen::new(10))
+        };
+        let bytes = version_payload.to_bytes().unwrap();
+        assert_eq!(VersionPayload::from_bytes(&bytes).unwrap(), version_payload);
+
+        let version_payload = VersionPayload {
+            user_agent: "a".repeat(MAX_SUBVERSION_LENGTH + 1),
+            ..version_payload
+        };
+        let bytes = version_payload.to_bytes().unwrap();
+        assert!(matches!(
+            VersionPayload::from_bytes(&bytes),
+            Err(BTCP2PError::UserAgentTooLong(257))
+        ));
+    }
+
+    #[test]
+    fn version_without_relay() {
+        let version_payload = VersionPayload {
+            relay: true,
+            ..VersionPayload::arbitrary(&mut quickcheck::Gen::new(10))
+        };
+
+        let bytes = version_payload.to_bytes().unwrap();
+        let result = VersionPayload::from_bytes(&bytes[..bytes.len() - 1]).unwrap();
+        assert_eq!(result, version_payload);
+    }
 }
@@ -0,0 +1,1095 @@
+use std::{
+    net::{Ipv6Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    sync::{mpsc, watch, OwnedSemaphorePermit},
+    task::JoinHandle,
+};
+
+use super::{
+    address::{AddrPayload, AddrV2Payload, Address, NetAddress},
+    ban::{BanManager, Misbehavior},
+    bandwidth::{Bandwidth, Throttle},
+    command::Command,
+    erlay::{SendTxRcnclPayload, TXRECONCILIATION_VERSION},
+    errors::{BTCP2PError, Result},
+    features::PeerFeatures,
+    handshake::{Handshake, HandshakeConfig, NonceRegistry, PeerInfo, DEFAULT_HANDSHAKE_TIMEOUT},
+    i2p::{b32_name, I2pSession},
+    local_addresses::LocalAddresses,
+    message::Message,
+    network::Network,
+    package::SendPackagesPayload,
+    payload::{Payload, VersionPayload},
+    ping::{Latency, PingScheduler, DEFAULT_PING_INTERVAL, DEFAULT_PING_TIMEOUT},
+    proxy::{Destination, Proxy},
+    service_flags::ServiceFlags,
+    time_offsets::{unix_time, TimeOffsets},
+    tor::onion_name,
+    HEADER_PAYLOAD_LEN_RANGE, HEADER_SIZE, MAX_PROTOCOL_MESSAGE_LENGTH,
+};
+
+/// Number of messages buffered in each direction between a peer and its tasks
+const PEER_CHANNEL_SIZE: usize = 64;
+
+/// PeerConfig holds what we announce to peers and how long we wait for them
+#[derive(Debug, Clone)]
+pub struct PeerConfig {
+    /// The network to connect to.
+    pub network: Network,
+
+    /// The services we support.
+    pub services: ServiceFlags,
+
+    /// The height of our best chain.
+    pub start_height: i32,
+
+    /// Whether we want transactions to be relayed to us.
+    pub relay: bool,
+
+    /// Whether to negotiate wtxid based transaction relay (BIP339).
+    pub wtxid_relay: bool,
+
+    /// Whether to negotiate addrv2 messages (BIP155).
+    pub addr_v2: bool,
+
+    /// Whether to negotiate transaction reconciliation (BIP330), with a fresh salt for each connection.
+    pub tx_reconciliation: bool,
+
+    /// The package relay versions to announce with sendpackages (BIP331), if any.
+    pub packages: Option<SendPackagesPayload>,
+
+    /// Time to wait for each stage of the handshake.
+    pub handshake_timeout: Duration,
+
+    /// Time between pings sent to measure the latency of the peer.
+    pub ping_interval: Duration,
+
+    /// Time to wait for a pong before disconnecting the peer.
+    pub ping_timeout: Duration,
+
+    /// Nonces of our outbound handshakes, clones of the config share them.
+    pub nonces: NonceRegistry,
+
+    /// Misbehavior scores and bans, clones of the config share them.
+    pub bans: BanManager,
+
+    /// The SOCKS5 proxy outbound connections go through, if any.
+    pub proxy: Option<Proxy>,
+
+    /// The I2P session used to reach I2P addresses, if any.
+    pub i2p: Option<I2pSession>,
+
+    /// The addresses we are reachable at, announced in version messages and advertised after the handshake.
+    pub local_addresses: LocalAddresses,
+
+    /// Rate limits and upload target, clones of the config share the global limits.
+    pub bandwidth: Bandwidth,
+
+    /// Clock offsets of outbound peers, clones of the config share them.
+    pub time_offsets: TimeOffsets,
+}
+
+impl PeerConfig {
+    pub fn new(network: Network) -> Self {
+        Self {
+            network,
+            services: ServiceFlags::UNNAMED,
+            start_height: 0,
+            relay: true,
+            wtxid_relay: true,
+            addr_v2: true,
+            tx_reconciliation: false,
+            packages: None,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            ping_interval: DEFAULT_PING_INTERVAL,
+            ping_timeout: DEFAULT_PING_TIMEOUT,
+            nonces: NonceRegistry::new(),
+            bans: BanManager::default(),
+            proxy: None,
+            i2p: None,
+            local_addresses: LocalAddresses::new(),
+            bandwidth: Bandwidth::default(),
+            time_offsets: TimeOffsets::default(),
+        }
+    }
+
+    /// handshake_config builds the handshake config of a connection, with a fresh version message
+    /// our local address is not announced when connections go through a proxy, otherwise the best local address
+    /// is announced instead of the address of the socket, an unspecified local address is kept as is
+    pub fn handshake_config(&self, remote: SocketAddr, local: SocketAddr) -> HandshakeConfig {
+        let local = if self.proxy.is_some() {
+            SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0)
+        } else if local.ip().is_unspecified() {
+            local
+        } else {
+            self.local_addresses.best_ip().unwrap_or(local)
+        };
+        let version = VersionPayload::new(
+            self.services,
+            ServiceFlags::UNNAMED,
+            remote,
+            self.services,
+            local,
+            rand::random(),
+            self.start_height,
+            self.relay,
+        );
+
+        HandshakeConfig {
+            wtxid_relay: self.wtxid_relay,
+            addr_v2: self.addr_v2,
+            tx_reconciliation: self.tx_reconciliation.then(|| SendTxRcnclPayload {
+                version: TXRECONCILIATION_VERSION,
+                salt: rand::random(),
+            }),
+            packages: self.packages,
+            version_timeout: self.handshake_timeout,
+            verack_timeout: self.handshake_timeout,
+            nonces: self.nonces.clone(),
+            ..HandshakeConfig::new(self.network, version)
+        }
+    }
+}
+
+/// Peer is a connection to a node which completed the handshake
+///
+/// Reading and writing happen in background tasks. Pings from the peer are answered automatically, and the peer
+/// is pinged at an interval to measure its latency, answering pongs are not delivered. Every other message is
+/// delivered by `recv`. The connection is closed when the peer is dropped, or when a ping is not answered in time.
+#[derive(Debug)]
+pub struct Peer {
+    addr: SocketAddr,
+    inbound: bool,
+    info: PeerInfo,
+    network: Network,
+    sender: mpsc::Sender<Message>,
+    receiver: mpsc::Receiver<Result<Message>>,
+    latency: watch::Receiver<Latency>,
+    features: watch::Receiver<PeerFeatures>,
+    bans: BanManager,
+    /// Misbehavior score of the connection, shared with the reader task.
+    misbehavior: Misbehavior,
+    reader: JoinHandle<()>,
+    /// The host name the peer was reached by, for peers connected by name.
+    host: Option<String>,
+    /// Connection slot held until the peer is dropped, for peers accepted by a listener.
+    _slot: Option<OwnedSemaphorePermit>,
+}
+
+impl Peer {
+    /// connect opens a TCP connection to a node, through the proxy of the config if any, and performs the handshake
+    pub async fn connect(addr: SocketAddr, config: &PeerConfig) -> Result<Self> {
+        if config.bans.is_banned(addr.ip(), Instant::now()) {
+            return Err(BTCP2PError::Banned(addr.ip()));
+        }
+
+        let stream = match &config.proxy {
+            Some(proxy) => proxy.connect(&Destination::Addr(addr)).await?,
+            None => TcpStream::connect(addr).await?,
+        };
+        let local = stream.local_addr()?;
+        let handshake = Handshake::outbound(config.handshake_config(addr, local), Instant::now());
+
+        Peer::start(stream, addr, handshake, config).await
+    }
+
+    /// connect_host connects to a node by host name through the proxy of the config, such as a `.onion` host
+    /// the name is resolved by the proxy, the address of the peer is unspecified and it is never banned
+    pub async fn connect_host(host: &str, port: u16, config: &PeerConfig) -> Result<Self> {
+        let Some(proxy) = &config.proxy else {
+            return Err(BTCP2PError::ProxyRequired(host.to_string()));
+        };
+
+        let stream = proxy
+            .connect(&Destination::Host(host.to_string(), port))
+            .await?;
+        Peer::start_named(stream, host.to_string(), port, false, config).await
+    }
+
+    /// connect_address connects to an address of an addr or addrv2 message
+    /// onion addresses are reached through the proxy of the config, I2P addresses through its I2P session
+    pub async fn connect_address(
+        address: &Address,
+        port: u16,
+        config: &PeerConfig,
+    ) -> Result<Self> {
+        match address {
+            Address::Ipv4(ip) => Peer::connect(SocketAddr::new((*ip).into(), port), config).await,
+            Address::Ipv6(ip) | Address::Cjdns(ip) => {
+                Peer::connect(SocketAddr::new((*ip).into(), port), config).await
+            }
+            Address::TorV3(pubkey) => Peer::connect_host(&onion_name(pubkey), port, config).await,
+            Address::I2p(hash) => {
+                let name = b32_name(hash);
+                let Some(session) = &config.i2p else {
+                    return Err(BTCP2PError::ProxyRequired(name));
+                };
+                let stream = session.connect(hash).await?;
+                Peer::start_named(stream, name, 0, false, config).await
+            }
+            _ => Err(BTCP2PError::UnreachableNetwork(address.network_id())),
+        }
+    }
+
+    /// accept_i2p waits for an inbound stream of an I2P session and answers its handshake
+    pub async fn accept_i2p(session: &I2pSession, config: &PeerConfig) -> Result<Self> {
+        let (stream, hash) = session.accept().await?;
+        Peer::start_named(stream, b32_name(&hash), 0, true, config).await
+    }
+
+    /// start_named starts a peer known by name, its address is unspecified and our address is not announced
+    async fn start_named(
+        stream: TcpStream,
+        host: String,
+        port: u16,
+        inbound: bool,
+        config: &PeerConfig,
+    ) -> Result<Self> {
+        let addr = SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port);
+        let local = SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0);
+        let handshake_config = config.handshake_config(addr, local);
+        let handshake = if inbound {
+            Handshake::inbound(handshake_config, Instant::now())
+        } else {
+            Handshake::outbound(handshake_config, Instant::now())
+        };
+
+        Peer::open(stream, addr, Some(host), handshake, config).await
+    }
+
+    /// start performs the handshake over an established stream, then spawns the reader and writer tasks
+    /// protocol errors of the peer are scored by the ban manager of the config
+    pub async fn start<S>(
+        stream: S,
+        addr: SocketAddr,
+        handshake: Handshake,
+        config: &PeerConfig,
+    ) -> Result<Self>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        Peer::open(stream, addr, None, handshake, config).await
+    }
+
+    /// open starts a peer reached by address, or by name when host is set
+    async fn open<S>(
+        stream: S,
+        addr: SocketAddr,
+        host: Option<String>,
+        mut handshake: Handshake,
+        config: &PeerConfig,
+    ) -> Result<Self>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let inbound = handshake.is_inbound();
+        let misbehavior = Misbehavior::new();
+        let (mut read_half, mut write_half) = tokio::io::split(stream);
+
+        // when we connected to ourselves, the inbound side closed the connection
+        let network = negotiate(&mut handshake, &mut read_half, &mut write_half)
+            .await
+            .map_err(|err| {
+                if handshake.is_self_connection() {
+                    BTCP2PError::SelfConnection
+                } else {
+                    err
+                }
+            })
+            .inspect_err(|err| {
+                config
+                    .bans
+                    .report(addr.ip(), &misbehavior, err, Instant::now());
+            })?;
+
+        let info = handshake
+            .peer_info()
+            .cloned()
+            .expect("complete handshake has peer info");
+
+        // only outbound peers with an IP address are trusted with our clock, as Bitcoin Core does
+        if !inbound && !addr.ip().is_unspecified() {
+            config.time_offsets.add(addr.ip(), info.time_offset);
+        }
+
+        let (sender, outgoing) = mpsc::channel(PEER_CHANNEL_SIZE);
+        let (incoming, receiver) = mpsc::channel(PEER_CHANNEL_SIZE);
+        let (latency_sender, latency) = watch::channel(Latency::default());
+        let (features_sender, features) = watch::channel(PeerFeatures::new(&info));
+
+        let now = Instant::now();
+        let writer = tokio::spawn(write_loop(
+            write_half,
+            outgoing,
+            Throttle::upload(&config.bandwidth, now),
+        ));
+        let pings = PingScheduler::new(config.ping_interval, config.ping_timeout, now);
+        let reader = tokio::spawn(read_loop(
+            read_half,
+            handshake,
+            pings,
+            Throttle::download(&config.bandwidth, now),
+            Channels {
+                addr,
+                bans: config.bans.clone(),
+                misbehavior: misbehavior.clone(),
+                outgoing: sender.clone(),
+                incoming,
+                latency: latency_sender,
+                features: features_sender,
+                writer,
+            },
+        ));
+
+        let peer = Self {
+            addr,
+            inbound,
+            info,
+            network,
+            sender,
+            receiver,
+            latency,
+            features,
+            bans: config.bans.clone(),
+            misbehavior,
+            reader,
+            host,
+            _slot: None,
+        };
+
+        // a single address is advertised, on the network the peer reaches us by, as Bitcoin Core does:
+        // advertising both our onion address and our IP address to a peer would link them
+        let proxied = config.proxy.is_some();
+        let address = config.local_addresses.net_address(
+            |address| peer.reaches(address, proxied),
+            config.services,
+            unix_time() as u32,
+        );
+        peer.advertise(address.into_iter().collect()).await?;
+        Ok(peer)
+    }
+
+    /// reaches checks if a local address is on the network of the peer
+    /// named peers are on the onion or I2P network of their host, peers reached through a proxy or inbound from
+    /// a loopback address, as Tor forwards onion service connections, are on the onion or I2P network
+    fn reaches(&self, address: &Address, proxied: bool) -> bool {
+        match &self.host {
+            Some(host) if host.ends_with(".b32.i2p") => matches!(address, Address::I2p(_)),
+            Some(_) => matches!(address, Address::TorV3(_)),
+            None if proxied || (self.inbound && self.addr.ip().is_loopback()) => {
+                matches!(address, Address::TorV3(_) | Address::I2p(_))
+            }
+            None => address.ip().is_some(),
+        }
+    }
+
+    /// advertise sends addresses to the peer, in an addrv2 message if it asked for them
+    /// only IP addresses can be sent to peers that did not, nothing is sent if no address is left
+    pub async fn advertise(&self, mut addresses: Vec<NetAddress>) -> Result<()> {
+        if !self.info.addr_v2 {
+            addresses.retain(|address| address.address.ip().is_some());
+        }
+        if addresses.is_empty() {
+            return Ok(());
+        }
+
+        let (command, payload) = if self.info.addr_v2 {
+            (
+                Command::AddrV2,
+                Payload::AddrV2(AddrV2Payload { addresses }),
+            )
+        } else {
+            (Command::Addr, Payload::Addr(AddrPayload { addresses }))
+        };
+        self.send(Message::new(self.network, command, payload))
+            .await
+    }
+
+    /// with_slot ties a connection slot to the lifetime of the peer
+    pub(crate) fn with_slot(mut self, slot: OwnedSemaphorePermit) -> Self {
+        self._slot = Some(slot);
+        self
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// host returns the host name of a peer known by name, such as `.onion` and `.b32.i2p` peers
+    pub fn host(&self) -> Option<&str> {
+        self.host.as_deref()
+    }
+
+    /// is_inbound checks if the peer opened the connection
+    pub fn is_inbound(&self) -> bool {
+        self.inbound
+    }
+
+    /// info returns what was negotiated during the handshake
+    pub fn info(&self) -> &PeerInfo {
+        &self.info
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    /// features returns what the peer supports, updated as its sendheaders, sendcmpct and feefilter arrive
+    pub fn features(&self) -> PeerFeatures {
+        self.features.borrow().clone()
+    }
+
+    /// latency returns the round trip times measured with pings
+    pub fn latency(&self) -> Latency {
+        *self.latency.borrow()
+    }
+
+    /// misbehaving adds a penalty to the score of the peer, the peer is disconnected once its score reaches the
+    /// threshold, its address is also banned unless it is a local or unspecified address
+    pub fn misbehaving(&self, penalty: u32) -> bool {
+        let disconnect =
+            self.bans
+                .misbehaving(self.addr.ip(), &self.misbehavior, penalty, Instant::now());
+        if disconnect {
+            self.reader.abort();
+        }
+        disconnect
+    }
+
+    /// send queues a message to the peer
+    pub async fn send(&self, message: Message) -> Result<()> {
+        self.sender
+            .send(message)
+            .await
+            .map_err(|_| BTCP2PError::ConnectionClosed)
+    }
+
+    /// recv waits for the next message from the peer
+    /// the error that closed the connection, if any, is the last item before None
+    pub async fn recv(&mut self) -> Option<Result<Message>> {
+        self.receiver.recv().await
+    }
+}
+
+impl Drop for Peer {
+    fn drop(&mut self) {
+        // the reader stops the writer as it exits
+        self.reader.abort();
+    }
+}
+
+/// Channels connects the reader task to the peer and the writer task
+struct Channels {
+    addr: SocketAddr,
+    bans: BanManager,
+    misbehavior: Misbehavior,
+    outgoing: mpsc::Sender<Message>,
+    incoming: mpsc::Sender<Result<Message>>,
+    latency: watch::Sender<Latency>,
+    features: watch::Sender<PeerFeatures>,
+    writer: JoinHandle<()>,
+}
+
+impl Channels {
+    /// fail scores the error that ends the connection and hands it to the peer
+    async fn fail(&self, err: BTCP2PError) {
+        self.bans
+            .report(self.addr.ip(), &self.misbehavior, &err, Instant::now());
+        let _ = self.incoming.send(Err(err)).await;
+    }
+}
+
+impl Drop for Channels {
+    fn drop(&mut self) {
+        // the connection is closed once both halves of the stream are dropped
+        self.writer.abort();
+    }
+}
+
+async fn read_loop<R>(
+    mut reader: R,
+    mut handshake: Handshake,
+    mut pings: PingScheduler,
+    mut throttle: Throttle,
+    channels: Channels,
+) where
+    R: AsyncRead + Unpin,
+{
+    let network = handshake.network();
+
+    loop {
+        // reading is not cancel safe, so the same read is polled until a message is complete
+        let read = read_sized_message(&mut reader);
+        tokio::pin!(read);
+
+        let result = loop {
+            tokio::select! {
+                result = &mut read => break result,
+                _ = tokio::time::sleep_until(pings.timeout().into()) => {
+                    let now = Instant::now();
+                    if let Err(err) = pings.handle_timeout(now) {
+                        channels.fail(err).await;
+                        return;
+                    }
+                    if let Some(nonce) = pings.poll_ping(now) {
+                        let ping = Message::new(network, Command::Ping, Payload::Ping(nonce));
+                        if channels.outgoing.send(ping).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        };
+
+        let result = match result {
+            Ok((message, size)) => {
+                // the next message is not read until the tokens of this one are available
+                tokio::time::sleep(throttle.delay(size, Instant::now())).await;
+                handshake.receive(message, Instant::now())
+            }
+            Err(err) => Err(err),
+        };
+
+        let message = match result {
+            Ok(Some(message)) => message,
+            Ok(None) => continue,
+            Err(err) => {
+                channels.fail(err).await;
+                return;
+            }
+        };
+
+        match message.payload {
+            Payload::Ping(nonce) => {
+                let pong = Message::new(network, Command::Pong, Payload::Pong(nonce));
+                if channels.outgoing.send(pong).await.is_err() {
+                    return;
+                }
+            }
+            Payload::Pong(nonce) if pings.receive_pong(nonce, Instant::now()) => {
+                channels.latency.send_replace(pings.latency());
+            }
+            _ => {
+                channels
+                    .features
+                    .send_if_modified(|features| features.receive(&message.payload));
+                if channels.incoming.send(Ok(message)).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// negotiate runs a handshake until it completes, returns the network of the peer
+async fn negotiate<R, W>(
+    handshake: &mut Handshake,
+    reader: &mut R,
+    writer: &mut W,
+) -> Result<Network>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        while let Some(message) = handshake.poll_transmit() {
+            write_message(writer, &message).await?;
+        }
+
+        let Some(deadline) = handshake.timeout() else {
+            return Ok(handshake.network());
+        };
+
+        let message = tokio::time::timeout_at(deadline.into(), read_message(reader))
+            .await
+            .map_err(|_| BTCP2PError::HandshakeTimeout)??;
+        handshake.receive(message, Instant::now())?;
+    }
+}
+
+async fn write_loop<W>(mut writer: W, mut outgoing: mpsc::Receiver<Message>, mut throttle: Throttle)
+where
+    W: AsyncWrite + Unpin,
+{
+    while let Some(message) = outgoing.recv().await {
+        let Ok(bytes) = message.to_bytes() else {
+            return;
+        };
+        tokio::time::sleep(throttle.delay(bytes.len(), Instant::now())).await;
+        if writer.write_all(&bytes).await.is_err() || writer.flush().await.is_err() {
+            return;
+        }
+    }
+
+    let _ = writer.shutdown().await;
+}
+
+/// read_message reads exactly one message from a stream
+/// messages with a command we don't know are skipped, as Bitcoin Core does
+pub(crate) async fn read_message<R>(reader: &mut R) -> Result<Message>
+where
+    R: AsyncRead + Unpin,
+{
+    read_sized_message(reader).await.map(|(message, _)| message)
+}
+
+/// read_sized_message reads exactly one message from a stream, with its size in bytes
+/// the payload buffer grows as bytes arrive, so a header announcing a large payload costs nothing by itself
+async fn read_sized_message<R>(reader: &mut R) -> Result<(Message, usize)>
+where
+    R: AsyncRead + Unpin,
+{
+    loop {
+        let mut buffer = vec![0u8; HEADER_SIZE];
+        read_exact(reader, &mut buffer).await?;
+
+        let payload_len = u32::from_le_bytes(buffer[HEADER_PAYLOAD_LEN_RANGE].try_into()?) as usize;
+        if payload_len > MAX_PROTOCOL_MESSAGE_LENGTH {
+            return Err(BTCP2PError::PayloadTooLarge);
+        }
+
+        (&mut *reader)
+            .take(payload_len as u64)
+            .read_to_end(&mut buffer)
+            .await
+            .map_err(read_error)?;
+        if buffer.len() < HEADER_SIZE + payload_len {
+            return Err(BTCP2PError::ConnectionClosed);
+        }
+
+        match Message::from_bytes(&buffer) {
+            Err(BTCP2PError::InvalidCommand) => continue,
+            result => return result.map(|message| (message, buffer.len())),
+        }
+    }
+}
+
+/// write_message writes a message to a stream
+pub(crate) async fn write_message<W>(writer: &mut W, message: &Message) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    writer.write_all(&message.to_bytes()?).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+async fn read_exact<R>(reader: &mut R, buffer: &mut [u8]) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    reader.read_exact(buffer).await.map_err(read_error)?;
+    Ok(())
+}
+
+/// read_error reports a stream that ended or was reset as a closed connection
+fn read_error(err: std::io::Error) -> BTCP2PError {
+    match err.kind() {
+        std::io::ErrorKind::UnexpectedEof | std::io::ErrorKind::ConnectionReset => {
+            BTCP2PError::ConnectionClosed
+        }
+        _ => err.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bandwidth::BandwidthLimits;
+    use tokio::net::TcpListener;
+
+    /// remote accepts a connection and completes the handshake as the node we connect to
+    async fn remote(listener: TcpListener) -> TcpStream {
+        let (mut stream, addr) = listener.accept().await.unwrap();
+        let local = stream.local_addr().unwrap();
+
+        let mut config = PeerConfig::new(Network::RegTest);
+        config.services = ServiceFlags::NODE_NETWORK | ServiceFlags::NODE_WITNESS;
+        config.start_height = 42;
+        let mut handshake =
+            Handshake::inbound(config.handshake_config(addr, local), Instant::now());
+
+        while !handshake.is_complete() {
+            let message = read_message(&mut stream).await.unwrap();
+            handshake.receive(message, Instant::now()).unwrap();
+            while let Some(message) = handshake.poll_transmit() {
+                write_message(&mut stream, &message).await.unwrap();
+            }
+        }
+
+        stream
+    }
+
+    /// read_skipping_pings reads the next message from a peer which is not a ping
+    async fn read_skipping_pings(stream: &mut TcpStream) -> Message {
+        loop {
+            let message = read_message(stream).await.unwrap();
+            if !matches!(message.payload, Payload::Ping(_)) {
+                return message;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_host() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (proxy, requests) =
+            crate::proxy::tests::stub_proxy(listener.local_addr().unwrap()).await;
+
+        let mut config = PeerConfig::new(Network::RegTest);
+        let result = Peer::connect_host("example.onion", 8333, &config).await;
+        assert!(matches!(result, Err(BTCP2PError::ProxyRequired(_))));
+
+        config.proxy = Some(Proxy::new(proxy));
+        let connect =
+            tokio::spawn(async move { Peer::connect_host("example.onion", 8333, &config).await });
+
+        // our local address is not announced through the proxy
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let Payload::Version(version) = read_message(&mut stream).await.unwrap().payload else {
+            panic!("expected a version");
+        };
+        assert_eq!((version.addr_trans, version.addr_trans_port), ([0; 16], 0));
+        drop(stream);
+        assert!(connect.await.unwrap().is_err());
+
+        let destination = requests.lock().unwrap()[0].destination.clone();
+        assert_eq!(
+            destination,
+            Destination::Host("example.onion".to_string(), 8333)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connect_through_proxy() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (proxy, _) = crate::proxy::tests::stub_proxy(listener.local_addr().unwrap()).await;
+        let remote = tokio::spawn(remote(listener));
+
+        let mut config = PeerConfig::new(Network::RegTest);
+        config.proxy = Some(Proxy::new(proxy));
+        config.local_addresses.add(Address::TorV3([7; 32]), 8333);
+        config
+            .local_addresses
+            .add(Address::Ipv4(std::net::Ipv4Addr::new(203, 0, 113, 7)), 8333);
+        let peer = Peer::connect_host("example.onion", 8333, &config)
+            .await
+            .unwrap();
+        let mut stream = remote.await.unwrap();
+
+        // only our onion address is advertised to an onion peer
+        let Payload::AddrV2(payload) = read_skipping_pings(&mut stream).await.payload else {
+            panic!("expected addrv2");
+        };
+        let addresses: Vec<_> = payload.addresses.iter().map(|a| &a.address).collect();
+        assert_eq!(addresses, vec![&Address::TorV3([7; 32])]);
+
+        assert_eq!(peer.host(), Some("example.onion"));
+        assert!(peer.addr().ip().is_unspecified());
+        assert_eq!(peer.info().start_height, 42);
+
+        // peers without an address are disconnected, but never banned
+        let mut peer = peer;
+        assert!(peer.misbehaving(100));
+        assert!(config.bans.bans(Instant::now()).is_empty());
+        while let Some(result) = peer.recv().await {
+            assert!(result.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_i2p() {
+        let sam = crate::i2p::tests::fake_sam().await;
+        let (path, path2) = (crate::i2p::tests::key_path(), crate::i2p::tests::key_path());
+        let server = I2pSession::create(sam, &path).await.unwrap();
+        let client = I2pSession::create(sam, &path2).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&path2).unwrap();
+
+        let mut config = PeerConfig::new(Network::RegTest);
+        let result = Peer::connect_address(&server.address(), 0, &config).await;
+        assert!(matches!(result, Err(BTCP2PError::ProxyRequired(_))));
+
+        config.i2p = Some(client.clone());
+        let address = server.address();
+        let server_config = PeerConfig::new(Network::RegTest);
+        let (outbound, inbound) = tokio::join!(
+            Peer::connect_address(&address, 0, &config),
+            Peer::accept_i2p(&server, &server_config)
+        );
+        let (outbound, inbound) = (outbound.unwrap(), inbound.unwrap());
+
+        assert_eq!(outbound.host(), Some(server.name().as_str()));
+        assert_eq!(inbound.host(), Some(client.name().as_str()));
+        assert!(inbound.is_inbound());
+        assert_eq!(inbound.addr().port(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let remote = tokio::spawn(remote(listener));
+
+        let config = PeerConfig::new(Network::RegTest);
+        let mut peer = Peer::connect(addr, &config).await.unwrap();
+        let mut stream = remote.await.unwrap();
+
+        assert_eq!(peer.addr(), addr);
+        assert_eq!(peer.info().start_height, 42);
+        assert!(peer.info().services.contains(ServiceFlags::NODE_WITNESS));
+
+        // the clock offset of outbound peers is recorded
+        assert_eq!(config.time_offsets.len(), 1);
+
+        // the peer is pinged right after the handshake
+        let Payload::Ping(nonce) = read_message(&mut stream).await.unwrap().payload else {
+            panic!("expected a ping");
+        };
+
+        // pings are answered without reaching the application
+        let ping = Message::new(Network::RegTest, Command::Ping, Payload::Ping(7));
+        write_message(&mut stream, &ping).await.unwrap();
+        let pong = read_message(&mut stream).await.unwrap();
+        assert_eq!(pong.payload, Payload::Pong(7));
+
+        // pongs are only consumed when they answer our ping
+        let pong = Message::new(Network::RegTest, Command::Pong, Payload::Pong(nonce ^ 1));
+        write_message(&mut stream, &pong).await.unwrap();
+        assert_eq!(peer.recv().await.unwrap().unwrap(), pong);
+
+        let pong = Message::new(Network::RegTest, Command::Pong, Payload::Pong(nonce));
+        write_message(&mut stream, &pong).await.unwrap();
+        let mut latency = peer.latency.clone();
+        latency
+            .wait_for(|latency| latency.samples == 1)
+            .await
+            .unwrap();
+        assert!(peer.latency().last.is_some());
+
+        // features sent after the handshake are summarized and still delivered
+        assert!(peer.features().wtxid_relay);
+        assert!(!peer.features().send_headers);
+        let fee_filter = Message::new(
+            Network::RegTest,
+            Command::FeeFilter,
+            Payload::FeeFilter(1000),
+        );
+        write_message(&mut stream, &fee_filter).await.unwrap();
+        assert_eq!(peer.recv().await.unwrap().unwrap(), fee_filter);
+        assert_eq!(peer.features().fee_filter, Some(1000));
+
+        let ping = Message::new(Network::RegTest, Command::Ping, Payload::Ping(9));
+        peer.send(ping.clone()).await.unwrap();
+        assert_eq!(read_message(&mut stream).await.unwrap(), ping);
+
+        drop(stream);
+        assert!(matches!(
+            peer.recv().await,
+            Some(Err(BTCP2PError::ConnectionClosed))
+        ));
+        assert!(peer.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_advertise_local_addresses() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let remote = tokio::spawn(remote(listener));
+
+        let external: SocketAddr = "203.0.113.7:9333".parse().unwrap();
+        let config = PeerConfig::new(Network::RegTest);
+        config.local_addresses.add(Address::TorV3([7; 32]), 8333);
+        config
+            .local_addresses
+            .add(external.ip().into(), external.port());
+
+        // the external address is announced instead of the address of the socket
+        let version = config.handshake_config(addr, addr).version;
+        assert_eq!(
+            version.addr_trans,
+            std::net::Ipv4Addr::new(203, 0, 113, 7)
+                .to_ipv6_mapped()
+                .octets()
+        );
+        assert_eq!(version.addr_trans_port, 9333);
+
+        let peer = Peer::connect(addr, &config).await.unwrap();
+        let mut stream = remote.await.unwrap();
+        assert!(peer.info().addr_v2);
+
+        // the peer is reached by IP, so only the external IP address is advertised, never the onion address
+        // which it would link to it, the ping may come first
+        let Payload::AddrV2(payload) = read_skipping_pings(&mut stream).await.payload else {
+            panic!("expected addrv2");
+        };
+        let addresses = payload.addresses;
+        assert_eq!(addresses.len(), 1);
+        assert_eq!(addresses[0].socket_addr(), Some(external));
+
+        // only IP addresses fit in addr messages
+        let mut peer = peer;
+        peer.info.addr_v2 = false;
+        peer.advertise(
+            config
+                .local_addresses
+                .net_addresses(ServiceFlags::UNNAMED, 0),
+        )
+        .await
+        .unwrap();
+        let Payload::Addr(payload) = read_skipping_pings(&mut stream).await.payload else {
+            panic!("expected addr");
+        };
+        assert_eq!(payload.addresses.len(), 1);
+        assert_eq!(payload.addresses[0].socket_addr(), Some(external));
+    }
+
+    #[tokio::test]
+    async fn test_upload_rate() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let remote = tokio::spawn(remote(listener));
+
+        // room for two pings of 32 bytes per second
+        let mut config = PeerConfig::new(Network::RegTest);
+        config.bandwidth = Bandwidth::new(BandwidthLimits {
+            peer_upload_rate: Some(64),
+            ..Default::default()
+        });
+        let peer = Peer::connect(addr, &config).await.unwrap();
+        let mut stream = remote.await.unwrap();
+
+        // with the ping sent after the handshake, the last ping waits for half a second of tokens
+        let start = Instant::now();
+        for nonce in 0..2 {
+            let ping = Message::new(Network::RegTest, Command::Ping, Payload::Ping(nonce));
+            peer.send(ping).await.unwrap();
+        }
+        for _ in 0..3 {
+            read_message(&mut stream).await.unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(400));
+        assert_eq!(config.bandwidth.uploaded(Instant::now()), 96);
+    }
+
+    #[tokio::test]
+    async fn test_ping_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let remote = tokio::spawn(remote(listener));
+
+        let mut config = PeerConfig::new(Network::RegTest);
+        config.ping_timeout = Duration::from_millis(50);
+        let mut peer = Peer::connect(addr, &config).await.unwrap();
+        let mut stream = remote.await.unwrap();
+
+        // the ping is never answered
+        assert!(matches!(
+            read_message(&mut stream).await.unwrap().payload,
+            Payload::Ping(_)
+        ));
+        assert!(matches!(
+            peer.recv().await,
+            Some(Err(BTCP2PError::PingTimeout))
+        ));
+
+        // the connection is closed even though the peer is still alive
+        assert!(matches!(
+            read_message(&mut stream).await,
+            Err(BTCP2PError::ConnectionClosed)
+        ));
+        assert!(peer
+            .send(Message::new(
+                Network::RegTest,
+                Command::Ping,
+                Payload::Ping(1)
+            ))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_misbehavior() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let remote = tokio::spawn(remote(listener));
+
+        let config = PeerConfig::new(Network::RegTest);
+        let mut peer = Peer::connect(addr, &config).await.unwrap();
+        let mut stream = remote.await.unwrap();
+
+        let ping = Message::new(Network::MainNet, Command::Ping, Payload::Ping(1));
+        write_message(&mut stream, &ping).await.unwrap();
+        assert!(matches!(
+            peer.recv().await,
+            Some(Err(BTCP2PError::NetworkMismatch(Network::MainNet)))
+        ));
+        // a wrong network ends the connection without a ban, penalties of the application add up
+        assert!(!config.bans.is_banned(addr.ip(), Instant::now()));
+        assert!(!peer.misbehaving(99));
+        assert!(peer.misbehaving(1));
+        // the peer is on a loopback address, so it is disconnected but not banned
+        assert!(!config.bans.is_banned(addr.ip(), Instant::now()));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // the remote accepts the connection but never answers
+        let remote = tokio::spawn(async move { listener.accept().await.unwrap() });
+
+        let mut config = PeerConfig::new(Network::RegTest);
+        config.handshake_timeout = Duration::from_millis(50);
+        let result = Peer::connect(addr, &config).await;
+        assert!(matches!(result, Err(BTCP2PError::HandshakeTimeout)));
+
+        drop(remote.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_read_message_skips_unknown_commands() {
+        let ping = Message::new(Network::RegTest, Command::Ping, Payload::Ping(1));
+        let mut unknown = Message::new(Network::RegTest, Command::VerAck, Payload::VerAck)
+            .to_bytes()
+            .unwrap();
+        unknown[4..16].copy_from_slice(b"unknown\0\0\0\0\0");
+        // the checksum of an empty payload does not depend on the command
+        let mut bytes = unknown;
+        bytes.extend(ping.to_bytes().unwrap());
+
+        let message = read_message(&mut &bytes[..]).await.unwrap();
+        assert_eq!(message, ping);
+    }
+
+    #[tokio::test]
+    async fn test_read_message_length() {
+        let ping = Message::new(Network::RegTest, Command::Ping, Payload::Ping(1));
+        let header = |payload_len: usize| {
+            let mut bytes = ping.to_bytes().unwrap();
+            bytes.truncate(HEADER_SIZE);
+            bytes[HEADER_PAYLOAD_LEN_RANGE].copy_from_slice(&(payload_len as u32).to_le_bytes());
+            bytes
+        };
+
+        // payloads longer than Bitcoin Core accepts are rejected from the header
+        let bytes = header(MAX_PROTOCOL_MESSAGE_LENGTH + 1);
+        assert!(matches!(
+            read_message(&mut &bytes[..]).await,
+            Err(BTCP2PError::PayloadTooLarge)
+        ));
+
+        // a connection closed before the announced payload arrives is reported as such
+        let mut bytes = header(MAX_PROTOCOL_MESSAGE_LENGTH);
+        bytes.extend([0; 100]);
+        assert!(matches!(
+            read_message(&mut &bytes[..]).await,
+            Err(BTCP2PError::ConnectionClosed)
+        ));
+    }
+}
@@ -0,0 +1,596 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::mpsc,
+    task::{JoinHandle, JoinSet},
+};
+
+use super::{
+    address::Address,
+    ban::Subnet,
+    errors::{BTCP2PError, Result},
+    handshake::PeerInfo,
+    message::Message,
+    peer::{Peer, PeerConfig},
+};
+
+/// Default number of outbound connections, as the full relay connections of Bitcoin Core
+pub const DEFAULT_TARGET_OUTBOUND: usize = 8;
+
+/// Default time to wait before asking the address source again when it had no address
+pub const DEFAULT_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Most addresses taken from the source at once, so a source of colliding addresses does not spin
+const MAX_SELECT_ATTEMPTS: usize = 64;
+
+/// Number of events waiting to be handled by the application
+const EVENT_CHANNEL_SIZE: usize = 256;
+
+/// Number of requests of the application waiting to be handled by the manager
+const REQUEST_CHANNEL_SIZE: usize = 256;
+
+/// Number of messages waiting to be sent to a peer, a peer too slow to keep up is disconnected
+const PEER_QUEUE_SIZE: usize = 64;
+
+/// AddressSource provides the addresses the peer manager connects to
+/// addresses are reached with `Peer::connect_address`, so onion and I2P addresses need a proxy or I2P session
+pub trait AddressSource: Send + 'static {
+    /// select returns an address and port to connect to, or None if there is none right now
+    fn select(&mut self) -> Option<(Address, u16)>;
+
+    /// connected is called once the handshake with an address completed
+    fn connected(&mut self, _address: &Address, _port: u16) {}
+
+    /// failed is called when connecting to an address failed
+    fn failed(&mut self, _address: &Address, _port: u16) {}
+}
+
+/// Addresses are tried once, in order
+impl AddressSource for VecDeque<(Address, u16)> {
+    fn select(&mut self) -> Option<(Address, u16)> {
+        self.pop_front()
+    }
+}
+
+/// Addresses are tried once, in order
+impl AddressSource for VecDeque<SocketAddr> {
+    fn select(&mut self) -> Option<(Address, u16)> {
+        self.pop_front().map(|addr| (addr.ip().into(), addr.port()))
+    }
+}
+
+/// netgroup returns the group of addresses likely run by the same operator, /16 for IPv4 and /32 for IPv6
+/// as Bitcoin Core does. Local addresses have no group, so a local test network can be used.
+pub fn netgroup(ip: IpAddr) -> Option<Subnet> {
+    let ip = ip.to_canonical();
+    if ip.is_loopback() || ip.is_unspecified() {
+        return None;
+    }
+
+    let prefix_len = if ip.is_ipv4() { 16 } else { 32 };
+    Subnet::new(ip, prefix_len).ok()
+}
+
+/// PeerManagerConfig holds the connections a peer manager maintains
+#[derive(Debug, Clone)]
+pub struct PeerManagerConfig {
+    /// The config of each connection.
+    pub peer: PeerConfig,
+
+    /// The number of outbound connections to keep.
+    pub target_outbound: usize,
+
+    /// Time to wait before asking the address source again when it had no address.
+    pub retry_interval: Duration,
+}
+
+impl PeerManagerConfig {
+    pub fn new(peer: PeerConfig) -> Self {
+        Self {
+            peer,
+            target_outbound: DEFAULT_TARGET_OUTBOUND,
+            retry_interval: DEFAULT_RETRY_INTERVAL,
+        }
+    }
+}
+
+/// PeerEvent is something that happened to a peer of the manager, peers are identified by the address and
+/// port they were selected with
+#[derive(Debug)]
+pub enum PeerEvent {
+    /// The handshake with the peer completed.
+    Connected {
+        address: Address,
+        port: u16,
+        info: PeerInfo,
+    },
+
+    /// The peer sent a message.
+    Message {
+        address: Address,
+        port: u16,
+        message: Message,
+    },
+
+    /// The peer disconnected, with the error that closed the connection if any.
+    Disconnected {
+        address: Address,
+        port: u16,
+        reason: Option<BTCP2PError>,
+    },
+
+    /// Connecting to the address failed.
+    ConnectFailed {
+        address: Address,
+        port: u16,
+        error: BTCP2PError,
+    },
+}
+
+#[derive(Debug)]
+enum Request {
+    Send((Address, u16), Message),
+    Broadcast(Message),
+    Disconnect((Address, u16)),
+}
+
+/// PeerManager keeps a number of outbound connections alive
+///
+/// Connections run in a background task. Failed and disconnected peers are replaced with addresses from the
+/// source, at most one peer is connected per netgroup of IP addresses. What happens to peers is reported by
+/// `next_event`.
+#[derive(Debug)]
+pub struct PeerManager {
+    requests: mpsc::Sender<Request>,
+    events: mpsc::Receiver<PeerEvent>,
+    task: JoinHandle<()>,
+}
+
+impl PeerManager {
+    /// start spawns the task connecting to peers
+    pub fn start<S: AddressSource>(config: PeerManagerConfig, source: S) -> Self {
+        let (requests, request_receiver) = mpsc::channel(REQUEST_CHANNEL_SIZE);
+        let (event_sender, events) = mpsc::channel(EVENT_CHANNEL_SIZE);
+
+        let manager = Manager {
+            config,
+            source,
+            events: event_sender,
+            peers: HashMap::new(),
+            connecting: HashSet::new(),
+        };
+        let task = tokio::spawn(manager.run(request_receiver));
+
+        Self {
+            requests,
+            events,
+            task,
+        }
+    }
+
+    /// next_event waits for the next event, None once the manager stopped
+    pub async fn next_event(&mut self) -> Option<PeerEvent> {
+        self.events.recv().await
+    }
+
+    /// send queues a message to a connected peer, waiting while the manager is busy
+    /// a peer whose queue of messages is full is disconnected
+    pub async fn send(&self, address: Address, port: u16, message: Message) -> Result<()> {
+        self.request(Request::Send((address, port), message)).await
+    }
+
+    /// broadcast queues a message to every connected peer, as send does
+    pub async fn broadcast(&self, message: Message) -> Result<()> {
+        self.request(Request::Broadcast(message)).await
+    }
+
+    /// disconnect closes the connection to a peer, it is replaced by another one
+    pub async fn disconnect(&self, address: Address, port: u16) -> Result<()> {
+        self.request(Request::Disconnect((address, port))).await
+    }
+
+    async fn request(&self, request: Request) -> Result<()> {
+        self.requests
+            .send(request)
+            .await
+            .map_err(|_| BTCP2PError::ConnectionClosed)
+    }
+}
+
+impl Drop for PeerManager {
+    fn drop(&mut self) {
+        // dropping the task drops every peer task it spawned
+        self.task.abort();
+    }
+}
+
+/// Connected holds a peer of the manager
+struct Connected {
+    netgroup: Option<Subnet>,
+    sender: mpsc::Sender<Message>,
+}
+
+struct Manager<S> {
+    config: PeerManagerConfig,
+    source: S,
+    events: mpsc::Sender<PeerEvent>,
+    peers: HashMap<(Address, u16), Connected>,
+    connecting: HashSet<(Address, u16)>,
+}
+
+impl<S: AddressSource> Manager<S> {
+    async fn run(mut self, mut requests: mpsc::Receiver<Request>) {
+        let mut connects = JoinSet::new();
+        let mut peer_tasks = JoinSet::new();
+        let mut retry = tokio::time::Instant::now();
+
+        loop {
+            if tokio::time::Instant::now() >= retry && !self.fill(&mut connects) {
+                retry = tokio::time::Instant::now() + self.config.retry_interval;
+            }
+
+            tokio::select! {
+                Some(result) = connects.join_next() => {
+                    let Ok((addr, result)) = result else { continue };
+                    self.connecting.remove(&addr);
+
+                    match result {
+                        Ok(peer) => {
+                            if !self.connected(addr, peer, &mut peer_tasks).await {
+                                return;
+                            }
+                        }
+                        Err(error) => {
+                            self.source.failed(&addr.0, addr.1);
+                            let (address, port) = addr;
+                            if !self.emit(PeerEvent::ConnectFailed { address, port, error }).await {
+                                return;
+                            }
+                        }
+                    }
+                }
+                Some(result) = peer_tasks.join_next() => {
+                    let Ok((addr, reason)) = result else { continue };
+                    self.peers.remove(&addr);
+                    let (address, port) = addr;
+                    if !self.emit(PeerEvent::Disconnected { address, port, reason }).await {
+                        return;
+                    }
+                }
+                request = requests.recv() => match request {
+                    Some(request) => self.handle(request),
+                    None => return,
+                },
+                _ = tokio::time::sleep_until(retry), if self.needs_peers() => {}
+            }
+        }
+    }
+
+    fn needs_peers(&self) -> bool {
+        self.peers.len() + self.connecting.len() < self.config.target_outbound
+    }
+
+    /// fill starts connections until the target is reached, returns false if the source ran out of addresses
+    fn fill(&mut self, connects: &mut JoinSet<((Address, u16), Result<Peer>)>) -> bool {
+        let mut attempts = 0;
+        while self.needs_peers() {
+            if attempts == MAX_SELECT_ATTEMPTS {
+                return false;
+            }
+            attempts += 1;
+
+            let Some(addr) = self.source.select() else {
+                return false;
+            };
+            if !self.is_candidate(&addr) {
+                continue;
+            }
+
+            self.connecting.insert(addr.clone());
+            let config = self.config.peer.clone();
+            connects.spawn(async move {
+                let result = Peer::connect_address(&addr.0, addr.1, &config).await;
+                (addr, result)
+            });
+        }
+
+        true
+    }
+
+    /// is_candidate checks if an address is not connected, not banned and not in the netgroup of a peer
+    /// only IP addresses can be banned and have a netgroup
+    fn is_candidate(&self, addr: &(Address, u16)) -> bool {
+        if self.peers.contains_key(addr) || self.connecting.contains(addr) {
+            return false;
+        }
+        let Some(ip) = addr.0.ip() else {
+            return true;
+        };
+        if self.config.peer.bans.is_banned(ip, Instant::now()) {
+            return false;
+        }
+
+        let Some(group) = netgroup(ip) else {
+            return true;
+        };
+        let taken = self.peers.values().any(|peer| peer.netgroup == Some(group))
+            || self
+                .connecting
+                .iter()
+                .any(|(address, _)| address.ip().and_then(netgroup) == Some(group));
+        !taken
+    }
+
+    async fn connected(
+        &mut self,
+        addr: (Address, u16),
+        peer: Peer,
+        peer_tasks: &mut JoinSet<((Address, u16), Option<BTCP2PError>)>,
+    ) -> bool {
+        let info = peer.info().clone();
+        let (sender, outgoing) = mpsc::channel(PEER_QUEUE_SIZE);
+
+        self.source.connected(&addr.0, addr.1);
+        self.peers.insert(
+            addr.clone(),
+            Connected {
+                netgroup: addr.0.ip().and_then(netgroup),
+                sender,
+            },
+        );
+        peer_tasks.spawn(drive(addr.clone(), peer, outgoing, self.events.clone()));
+
+        let (address, port) = addr;
+        self.emit(PeerEvent::Connected {
+            address,
+            port,
+            info,
+        })
+        .await
+    }
+
+    /// handle carries out a request of the application
+    /// peers whose queue is full are disconnected rather than buffering messages without bound
+    fn handle(&mut self, request: Request) {
+        match request {
+            Request::Send(addr, message) => {
+                if let Some(peer) = self.peers.get(&addr) {
+                    if let Err(mpsc::error::TrySendError::Full(_)) = peer.sender.try_send(message) {
+                        self.peers.remove(&addr);
+                    }
+                }
+            }
+            Request::Broadcast(message) => {
+                self.peers.retain(|_, peer| {
+                    !matches!(
+                        peer.sender.try_send(message.clone()),
+                        Err(mpsc::error::TrySendError::Full(_))
+                    )
+                });
+            }
+            Request::Disconnect(addr) => {
+                // the peer task stops once its sender is dropped, its end is reported as a disconnection
+                self.peers.remove(&addr);
+            }
+        }
+    }
+
+    /// emit reports an event, returns false if the application dropped the manager
+    async fn emit(&mut self, event: PeerEvent) -> bool {
+        self.events.send(event).await.is_ok()
+    }
+}
+
+/// drive forwards the messages of a peer to the application, and the messages of the application to the peer
+async fn drive(
+    addr: (Address, u16),
+    mut peer: Peer,
+    mut outgoing: mpsc::Receiver<Message>,
+    events: mpsc::Sender<PeerEvent>,
+) -> ((Address, u16), Option<BTCP2PError>) {
+    let mut reason = None;
+
+    loop {
+        tokio::select! {
+            received = peer.recv() => match received {
+                Some(Ok(message)) => {
+                    let (address, port) = addr.clone();
+                    let event = PeerEvent::Message { address, port, message };
+                    if events.send(event).await.is_err() {
+                        break;
+                    }
+                }
+                Some(Err(err)) => reason = Some(err),
+                None => break,
+            },
+            message = outgoing.recv() => match message {
+                Some(message) => {
+                    if let Err(err) = peer.send(message).await {
+                        reason = Some(err);
+                        break;
+                    }
+                }
+                None => break,
+            },
+        }
+    }
+
+    (addr, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        command::Command, listener::Listener, network::Network, payload::Payload,
+        DEFAULT_MAX_INBOUND,
+    };
+
+    fn addr(s: &str) -> (Address, u16) {
+        let addr: SocketAddr = s.parse().unwrap();
+        (addr.ip().into(), addr.port())
+    }
+
+    /// socket_addr returns the socket address of an IP peer of the manager
+    fn socket_addr(address: Address, port: u16) -> SocketAddr {
+        SocketAddr::new(address.ip().unwrap(), port)
+    }
+
+    #[test]
+    fn test_netgroup() {
+        let group = |s: &str| netgroup(s.parse().unwrap());
+
+        assert_eq!(group("1.2.3.4"), group("1.2.200.1"));
+        assert_ne!(group("1.2.3.4"), group("1.3.3.4"));
+        assert_eq!(group("::ffff:1.2.3.4"), group("1.2.9.9"));
+        assert_eq!(group("2001:db8:1::1"), group("2001:db8:2::1"));
+        assert_ne!(group("2001:db8::1"), group("2001:db9::1"));
+        assert_eq!(group("127.0.0.1"), None);
+    }
+
+    #[test]
+    fn test_netgroup_diversity() {
+        let (events, _) = mpsc::channel(1);
+        let mut manager = Manager {
+            config: PeerManagerConfig::new(PeerConfig::new(Network::RegTest)),
+            source: VecDeque::<SocketAddr>::new(),
+            events,
+            peers: HashMap::new(),
+            connecting: HashSet::from([addr("1.2.3.4:8333")]),
+        };
+
+        assert!(!manager.is_candidate(&addr("1.2.3.4:8333")));
+        assert!(!manager.is_candidate(&addr("1.2.7.7:8333")));
+        assert!(manager.is_candidate(&addr("1.3.7.7:8333")));
+
+        manager.connecting.clear();
+        manager.config.peer.bans.ban(
+            "1.3.0.0/16".parse().unwrap(),
+            Duration::from_secs(60),
+            Instant::now(),
+        );
+        assert!(!manager.is_candidate(&addr("1.3.7.7:8333")));
+    }
+
+    #[test]
+    fn test_slow_peer_disconnected() {
+        let (sender, _outgoing) = mpsc::channel(PEER_QUEUE_SIZE);
+        let peer = addr("1.2.3.4:8333");
+        let mut manager = Manager {
+            config: PeerManagerConfig::new(PeerConfig::new(Network::RegTest)),
+            source: VecDeque::<SocketAddr>::new(),
+            events: mpsc::channel(1).0,
+            peers: HashMap::from([(
+                peer.clone(),
+                Connected {
+                    netgroup: None,
+                    sender,
+                },
+            )]),
+            connecting: HashSet::new(),
+        };
+
+        // the peer does not read its queue, once it is full the peer is dropped
+        let ping = Message::new(Network::RegTest, Command::Ping, Payload::Ping(1));
+        for _ in 0..PEER_QUEUE_SIZE {
+            manager.handle(Request::Broadcast(ping.clone()));
+        }
+        assert!(manager.peers.contains_key(&peer));
+        manager.handle(Request::Send(peer.clone(), ping));
+        assert!(manager.peers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_connect_onion_address() {
+        let onion = (Address::TorV3([7; 32]), 8333);
+        let mut manager = Manager {
+            config: PeerManagerConfig::new(PeerConfig::new(Network::RegTest)),
+            source: VecDeque::from([onion.clone()]),
+            events: mpsc::channel(1).0,
+            peers: HashMap::new(),
+            connecting: HashSet::new(),
+        };
+
+        // onion addresses have no netgroup and are dialed through the proxy, which is missing
+        assert!(manager.is_candidate(&onion));
+        let mut connects = JoinSet::new();
+        manager.fill(&mut connects);
+        assert!(manager.connecting.contains(&onion));
+        let (addr, result) = connects.join_next().await.unwrap().unwrap();
+        assert_eq!(addr, onion);
+        assert!(matches!(result, Err(BTCP2PError::ProxyRequired(_))));
+    }
+
+    #[tokio::test]
+    async fn test_replace_peers() {
+        let mut listeners = Vec::new();
+        for _ in 0..3 {
+            let config = PeerConfig::new(Network::RegTest);
+            let listener = Listener::bind("127.0.0.1:0", config, DEFAULT_MAX_INBOUND)
+                .await
+                .unwrap();
+            listeners.push(listener);
+        }
+        let addrs = listeners
+            .iter()
+            .map(|listener| listener.local_addr())
+            .collect::<VecDeque<_>>();
+
+        let mut config = PeerManagerConfig::new(PeerConfig::new(Network::RegTest));
+        config.target_outbound = 2;
+        config.retry_interval = Duration::from_millis(10);
+        let mut manager = PeerManager::start(config, addrs.clone());
+
+        let mut connected = HashSet::new();
+        while connected.len() < 2 {
+            if let Some(PeerEvent::Connected { address, port, .. }) = manager.next_event().await {
+                connected.insert(socket_addr(address, port));
+            }
+        }
+
+        // the listeners keep the inbound side of each connection alive
+        let mut inbound = HashMap::new();
+        for listener in listeners.iter_mut() {
+            if connected.contains(&listener.local_addr()) {
+                let peer = listener.accept().await.unwrap();
+                inbound.insert(listener.local_addr(), peer);
+            }
+        }
+
+        let first = *connected.iter().next().unwrap();
+        let pong = Message::new(Network::RegTest, Command::Pong, Payload::Pong(5));
+        inbound[&first].send(pong.clone()).await.unwrap();
+        loop {
+            if let Some(PeerEvent::Message {
+                address,
+                port,
+                message,
+            }) = manager.next_event().await
+            {
+                assert_eq!((socket_addr(address, port), message), (first, pong));
+                break;
+            }
+        }
+
+        // the disconnected peer is replaced by the last address
+        inbound.remove(&first);
+        let mut disconnected = false;
+        loop {
+            match manager.next_event().await.unwrap() {
+                PeerEvent::Disconnected { address, port, .. } => {
+                    assert_eq!(socket_addr(address, port), first);
+                    disconnected = true;
+                }
+                PeerEvent::Connected { address, port, .. } => {
+                    assert!(disconnected);
+                    assert!(!connected.contains(&socket_addr(address, port)));
+                    break;
+                }
+                _ => {}
+            }
+        }
+    }
+}
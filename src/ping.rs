@@ -0,0 +1,201 @@
+use std::time::{Duration, Instant};
+
+use super::errors::{BTCP2PError, Result};
+
+/// Default time between pings, as in Bitcoin Core
+pub const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(2 * 60);
+
+/// Default time to wait for a pong, as in Bitcoin Core
+pub const DEFAULT_PING_TIMEOUT: Duration = Duration::from_secs(20 * 60);
+
+/// Weight of the newest round trip in the moving average, as a divisor (TCP uses 8 as well)
+const AVERAGE_WEIGHT: u32 = 8;
+
+/// Latency holds the round trip times measured with a peer
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Latency {
+    /// The last round trip time.
+    pub last: Option<Duration>,
+
+    /// The lowest round trip time.
+    pub min: Option<Duration>,
+
+    /// The exponential moving average of the round trip times.
+    pub average: Option<Duration>,
+
+    /// The number of pongs received.
+    pub samples: u64,
+}
+
+impl Latency {
+    /// record adds a round trip time
+    pub fn record(&mut self, rtt: Duration) {
+        self.last = Some(rtt);
+        self.min = Some(self.min.map_or(rtt, |min| min.min(rtt)));
+        self.average = Some(match self.average {
+            None => rtt,
+            Some(average) => average - average / AVERAGE_WEIGHT + rtt / AVERAGE_WEIGHT,
+        });
+        self.samples += 1;
+    }
+}
+
+/// PingScheduler decides when to ping a peer and measures the round trips, without doing any IO
+///
+/// The nonce to send is taken from `poll_ping`, pongs are given to `receive_pong`. The caller is responsible
+/// for calling `handle_timeout` once the instant returned by `timeout` is reached.
+#[derive(Debug, Clone)]
+pub struct PingScheduler {
+    interval: Duration,
+    ping_timeout: Duration,
+    next_ping: Instant,
+    pending: Option<(u64, Instant)>,
+    latency: Latency,
+}
+
+impl PingScheduler {
+    /// new creates a scheduler whose first ping is due right away
+    pub fn new(interval: Duration, ping_timeout: Duration, now: Instant) -> Self {
+        Self {
+            interval,
+            ping_timeout,
+            next_ping: now,
+            pending: None,
+            latency: Latency::default(),
+        }
+    }
+
+    pub fn latency(&self) -> Latency {
+        self.latency
+    }
+
+    /// pending returns the nonce of the ping waiting for a pong
+    pub fn pending(&self) -> Option<u64> {
+        self.pending.map(|(nonce, _)| nonce)
+    }
+
+    /// poll_ping returns the nonce of the ping to send, if one is due
+    /// a new ping is only sent once the previous one was answered
+    pub fn poll_ping(&mut self, now: Instant) -> Option<u64> {
+        if self.pending.is_some() || now < self.next_ping {
+            return None;
+        }
+
+        let nonce = rand::random::<u64>().max(1);
+        self.pending = Some((nonce, now));
+        self.next_ping = now + self.interval;
+        Some(nonce)
+    }
+
+    /// receive_pong matches a pong with the pending ping, returns whether it was ours
+    pub fn receive_pong(&mut self, nonce: u64, now: Instant) -> bool {
+        match self.pending {
+            Some((pending, sent)) if pending == nonce => {
+                self.latency.record(now.saturating_duration_since(sent));
+                self.pending = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// timeout returns when the scheduler needs to be polled again
+    pub fn timeout(&self) -> Instant {
+        match self.pending {
+            Some((_, sent)) => sent + self.ping_timeout,
+            None => self.next_ping,
+        }
+    }
+
+    /// handle_timeout fails if the pending ping was not answered in time
+    pub fn handle_timeout(&mut self, now: Instant) -> Result<()> {
+        match self.pending {
+            Some((_, sent)) if now >= sent + self.ping_timeout => Err(BTCP2PError::PingTimeout),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::TestResult;
+    use quickcheck_macros::quickcheck;
+
+    #[test]
+    fn test_schedule() {
+        let now = Instant::now();
+        let interval = Duration::from_secs(10);
+        let mut scheduler = PingScheduler::new(interval, Duration::from_secs(5), now);
+
+        let nonce = scheduler.poll_ping(now).unwrap();
+        assert_eq!(scheduler.pending(), Some(nonce));
+        assert_eq!(scheduler.poll_ping(now), None);
+        assert_eq!(scheduler.timeout(), now + Duration::from_secs(5));
+
+        // pongs of other pings are not ours
+        assert!(!scheduler.receive_pong(nonce.wrapping_add(1), now));
+
+        let later = now + Duration::from_millis(300);
+        assert!(scheduler.receive_pong(nonce, later));
+        assert_eq!(scheduler.latency().last, Some(Duration::from_millis(300)));
+        assert_eq!(scheduler.timeout(), now + interval);
+
+        // the next ping is due one interval after the previous one was sent
+        assert_eq!(scheduler.poll_ping(later), None);
+        assert!(scheduler.poll_ping(now + interval).is_some());
+    }
+
+    #[test]
+    fn test_timeout() {
+        let now = Instant::now();
+        let mut scheduler =
+            PingScheduler::new(Duration::from_secs(10), Duration::from_secs(5), now);
+        assert!(scheduler
+            .handle_timeout(now + Duration::from_secs(60))
+            .is_ok());
+
+        scheduler.poll_ping(now).unwrap();
+        assert!(scheduler
+            .handle_timeout(now + Duration::from_secs(4))
+            .is_ok());
+        assert!(matches!(
+            scheduler.handle_timeout(now + Duration::from_secs(5)),
+            Err(BTCP2PError::PingTimeout)
+        ));
+    }
+
+    #[test]
+    fn test_latency() {
+        let mut latency = Latency::default();
+        latency.record(Duration::from_millis(80));
+        latency.record(Duration::from_millis(40));
+        latency.record(Duration::from_millis(120));
+
+        assert_eq!(latency.last, Some(Duration::from_millis(120)));
+        assert_eq!(latency.min, Some(Duration::from_millis(40)));
+        // 80 -> 75 -> 80.625
+        assert_eq!(latency.average, Some(Duration::from_micros(80_625)));
+        assert_eq!(latency.samples, 3);
+    }
+
+    #[quickcheck]
+    fn test_average_is_bounded(rtts: Vec<u32>) -> TestResult {
+        let rtts = rtts
+            .into_iter()
+            .map(|ms| Duration::from_millis(ms as u64))
+            .collect::<Vec<_>>();
+        let mut latency = Latency::default();
+        rtts.iter().for_each(|rtt| latency.record(*rtt));
+
+        let bounded = match (latency.average, rtts.iter().min(), rtts.iter().max()) {
+            (Some(average), Some(min), Some(max)) => {
+                // each division may round down by a nanosecond
+                average + Duration::from_nanos(rtts.len() as u64) >= *min && average <= *max
+            }
+            (None, None, None) => true,
+            _ => false,
+        };
+        TestResult::from_bool(bounded)
+    }
+}
@@ -0,0 +1,408 @@
+use std::{
+    cmp::Ordering,
+    fmt,
+    ops::{Add, Div, Not, Shl, Shr, Sub},
+};
+
+use super::{
+    chain_params::ChainParams,
+    errors::{BTCP2PError, Result},
+};
+
+/// U256 is an unsigned 256 bit integer, for targets and chain work
+/// limbs are 64 bit words with the least significant first
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct U256([u64; 4]);
+
+impl U256 {
+    pub const ZERO: U256 = U256([0; 4]);
+    pub const ONE: U256 = U256([1, 0, 0, 0]);
+    pub const MAX: U256 = U256([u64::MAX; 4]);
+
+    /// from_le_bytes reads a number in little endian order, the byte order of hashes
+    pub fn from_le_bytes(bytes: [u8; 32]) -> Self {
+        let mut limbs = [0u64; 4];
+        for (limb, chunk) in limbs.iter_mut().zip(bytes.chunks_exact(8)) {
+            *limb = u64::from_le_bytes(chunk.try_into().expect("8 byte chunk"));
+        }
+        Self(limbs)
+    }
+
+    pub fn to_le_bytes(&self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        for (chunk, limb) in bytes.chunks_exact_mut(8).zip(self.0) {
+            chunk.copy_from_slice(&limb.to_le_bytes());
+        }
+        bytes
+    }
+
+    pub fn is_zero(&self) -> bool {
+        *self == Self::ZERO
+    }
+
+    /// bits returns the number of bits needed to represent the number
+    pub fn bits(&self) -> u32 {
+        for (i, limb) in self.0.iter().enumerate().rev() {
+            if *limb != 0 {
+                return 64 * i as u32 + 64 - limb.leading_zeros();
+            }
+        }
+        0
+    }
+
+    /// low_u64 returns the least significant 64 bits
+    pub fn low_u64(&self) -> u64 {
+        self.0[0]
+    }
+
+    fn bit(&self, index: u32) -> bool {
+        self.0[(index / 64) as usize] >> (index % 64) & 1 == 1
+    }
+
+    /// overflowing_add adds two numbers, returns whether the sum wrapped around
+    pub fn overflowing_add(self, other: Self) -> (Self, bool) {
+        let mut limbs = [0u64; 4];
+        let mut carry = false;
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            let (sum, first) = self.0[i].overflowing_add(other.0[i]);
+            let (sum, second) = sum.overflowing_add(carry.into());
+            *limb = sum;
+            carry = first || second;
+        }
+        (Self(limbs), carry)
+    }
+
+    /// overflowing_sub subtracts a number, returns whether the difference wrapped around
+    pub fn overflowing_sub(self, other: Self) -> (Self, bool) {
+        let mut limbs = [0u64; 4];
+        let mut borrow = false;
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            let (difference, first) = self.0[i].overflowing_sub(other.0[i]);
+            let (difference, second) = difference.overflowing_sub(borrow.into());
+            *limb = difference;
+            borrow = first || second;
+        }
+        (Self(limbs), borrow)
+    }
+
+    /// overflowing_mul_u64 multiplies by a 64 bit number, returns whether the product overflowed
+    pub fn overflowing_mul_u64(self, other: u64) -> (Self, bool) {
+        let mut limbs = [0u64; 4];
+        let mut carry = 0u128;
+        for (i, limb) in limbs.iter_mut().enumerate() {
+            let product = self.0[i] as u128 * other as u128 + carry;
+            *limb = product as u64;
+            carry = product >> 64;
+        }
+        (Self(limbs), carry != 0)
+    }
+
+    /// div_rem divides by a number which is not zero, returns the quotient and the remainder
+    pub fn div_rem(self, divisor: Self) -> (Self, Self) {
+        assert!(!divisor.is_zero(), "division by zero");
+
+        let mut quotient = Self::ZERO;
+        let mut remainder = Self::ZERO;
+        for index in (0..self.bits()).rev() {
+            remainder = remainder << 1;
+            if self.bit(index) {
+                remainder.0[0] |= 1;
+            }
+            if remainder >= divisor {
+                remainder = remainder - divisor;
+                quotient.0[(index / 64) as usize] |= 1 << (index % 64);
+            }
+        }
+        (quotient, remainder)
+    }
+
+    /// from_compact decodes the compact format of the bits of a header, None if it is negative or overflows
+    /// https://github.com/bitcoin/bitcoin/blob/master/src/arith_uint256.cpp
+    pub fn from_compact(compact: u32) -> Option<Self> {
+        let size = compact >> 24;
+        let mut word = compact & 0x007fffff;
+        let negative = word != 0 && compact & 0x00800000 != 0;
+        let overflow =
+            word != 0 && (size > 34 || (word > 0xff && size > 33) || (word > 0xffff && size > 32));
+        if negative || overflow {
+            return None;
+        }
+
+        Some(if size <= 3 {
+            word >>= 8 * (3 - size);
+            Self::from(word as u64)
+        } else {
+            Self::from(word as u64) << (8 * (size - 3))
+        })
+    }
+
+    /// to_compact encodes the number in the compact format of the bits of a header, dropping low bits
+    pub fn to_compact(&self) -> u32 {
+        let mut size = self.bits().div_ceil(8);
+        let mut compact = if size <= 3 {
+            (self.low_u64() << (8 * (3 - size))) as u32
+        } else {
+            (*self >> (8 * (size - 3))).low_u64() as u32
+        };
+
+        // the sign bit is set, move the mantissa a byte down
+        if compact & 0x00800000 != 0 {
+            compact >>= 8;
+            size += 1;
+        }
+        compact | (size << 24)
+    }
+
+    /// work returns the expected number of hashes to find a hash at most this target, 2^256 / (target + 1)
+    pub fn work(&self) -> Self {
+        // 2^256 / (target + 1) == ~target / (target + 1) + 1, which fits in 256 bits
+        if *self == Self::MAX {
+            return Self::ONE;
+        }
+        let (quotient, _) = (!*self).div_rem(*self + Self::ONE);
+        quotient + Self::ONE
+    }
+}
+
+impl From<u64> for U256 {
+    fn from(value: u64) -> Self {
+        Self([value, 0, 0, 0])
+    }
+}
+
+impl Ord for U256 {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.iter().rev().cmp(other.0.iter().rev())
+    }
+}
+
+impl PartialOrd for U256 {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Add for U256 {
+    type Output = U256;
+
+    fn add(self, other: Self) -> Self {
+        let (sum, overflow) = self.overflowing_add(other);
+        debug_assert!(!overflow, "U256 addition overflowed");
+        sum
+    }
+}
+
+impl Sub for U256 {
+    type Output = U256;
+
+    fn sub(self, other: Self) -> Self {
+        let (difference, overflow) = self.overflowing_sub(other);
+        debug_assert!(!overflow, "U256 subtraction overflowed");
+        difference
+    }
+}
+
+impl Div for U256 {
+    type Output = U256;
+
+    fn div(self, divisor: Self) -> Self {
+        self.div_rem(divisor).0
+    }
+}
+
+impl Not for U256 {
+    type Output = U256;
+
+    fn not(self) -> Self {
+        Self(self.0.map(|limb| !limb))
+    }
+}
+
+impl Shl<u32> for U256 {
+    type Output = U256;
+
+    fn shl(self, shift: u32) -> Self {
+        let mut limbs = [0u64; 4];
+        let (words, bits) = ((shift / 64) as usize, shift % 64);
+        for (i, limb) in limbs.iter_mut().enumerate().skip(words) {
+            *limb = self.0[i - words] << bits;
+            if bits > 0 && i > words {
+                *limb |= self.0[i - words - 1] >> (64 - bits);
+            }
+        }
+        Self(limbs)
+    }
+}
+
+impl Shr<u32> for U256 {
+    type Output = U256;
+
+    fn shr(self, shift: u32) -> Self {
+        let mut limbs = [0u64; 4];
+        let (words, bits) = ((shift / 64) as usize, shift % 64);
+        for (i, limb) in limbs
+            .iter_mut()
+            .enumerate()
+            .take(4usize.saturating_sub(words))
+        {
+            *limb = self.0[i + words] >> bits;
+            if bits > 0 && i + words < 3 {
+                *limb |= self.0[i + words + 1] << (64 - bits);
+            }
+        }
+        Self(limbs)
+    }
+}
+
+impl fmt::Debug for U256 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "U256(0x")?;
+        for limb in self.0.iter().rev() {
+            write!(f, "{:016x}", limb)?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// retarget computes the bits of the first header of a retarget interval, from the bits the target is scaled
+/// from and the time the previous interval took, clamped to a quarter and four times the expected timespan
+/// https://github.com/bitcoin/bitcoin/blob/master/src/pow.cpp
+pub fn retarget(bits: u32, actual_timespan: i64, params: &ChainParams) -> u32 {
+    if params.pow_no_retargeting {
+        return bits;
+    }
+
+    let timespan = params.pow_target_timespan as i64;
+    let actual_timespan = actual_timespan.clamp(timespan / 4, timespan * 4);
+    let pow_limit = U256::from_le_bytes(params.pow_limit);
+
+    // target * actual / timespan, dividing first so that large targets do not overflow
+    let target = U256::from_compact(bits).unwrap_or(pow_limit);
+    let (quotient, remainder) = target.div_rem(U256::from(timespan as u64));
+    let rounding =
+        (remainder.low_u64() as u128 * actual_timespan as u128 / timespan as u128) as u64;
+    let target = match quotient.overflowing_mul_u64(actual_timespan as u64) {
+        (product, false) => product.overflowing_add(U256::from(rounding)),
+        overflow => overflow,
+    };
+    match target {
+        (target, false) => target.min(pow_limit).to_compact(),
+        (_, true) => pow_limit.to_compact(),
+    }
+}
+
+/// check_proof_of_work checks that a block hash meets the target of its bits, and the target the pow limit
+pub fn check_proof_of_work(hash: &[u8; 32], bits: u32, pow_limit: &[u8; 32]) -> Result<()> {
+    let target = match U256::from_compact(bits) {
+        Some(target) if !target.is_zero() => target,
+        _ => return Err(BTCP2PError::InvalidProofOfWork),
+    };
+    if target > U256::from_le_bytes(*pow_limit) || U256::from_le_bytes(*hash) > target {
+        return Err(BTCP2PError::InvalidProofOfWork);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::hash_from_hex;
+
+    #[test]
+    fn test_compact() {
+        let limit = U256::from_compact(0x1d00ffff).unwrap();
+        assert_eq!(
+            limit.to_le_bytes(),
+            hash_from_hex("00000000ffff0000000000000000000000000000000000000000000000000000")
+        );
+        assert_eq!(limit.to_compact(), 0x1d00ffff);
+
+        // cases of arith_uint256_tests.cpp of Bitcoin Core
+        assert_eq!(U256::from_compact(0), Some(U256::ZERO));
+        assert_eq!(U256::from_compact(0x00123456), Some(U256::ZERO));
+        assert_eq!(U256::from_compact(0x01123456), Some(U256::from(0x12)));
+        assert_eq!(U256::from_compact(0x02123456), Some(U256::from(0x1234)));
+        assert_eq!(U256::from_compact(0x05009234), Some(U256::from(0x92340000)));
+        assert_eq!(U256::from(0x12).to_compact(), 0x01120000);
+        assert_eq!(U256::from(0x80).to_compact(), 0x02008000);
+        assert_eq!(U256::from(0x92340000).to_compact(), 0x05009234);
+        assert_eq!(U256::from_compact(0x04923456), None);
+        assert_eq!(U256::from_compact(0xff123456), None);
+        assert_eq!(
+            U256::from_compact(0x20123456).unwrap().to_compact(),
+            0x20123456
+        );
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let a = U256::from(u64::MAX);
+        let b = a + U256::ONE;
+        assert_eq!(b, U256::ONE << 64);
+        assert_eq!(b - U256::ONE, a);
+        assert_eq!(b >> 64, U256::ONE);
+        assert_eq!((U256::ONE << 200) >> 137, U256::ONE << 63);
+        assert_eq!(b.bits(), 65);
+        assert!(b > a);
+
+        let (product, overflow) = b.overflowing_mul_u64(3);
+        assert!(!overflow);
+        assert_eq!(product / U256::from(3), b);
+        assert_eq!(product.div_rem(U256::from(5)).1, U256::from(3));
+        assert!(U256::MAX.overflowing_mul_u64(2).1);
+        assert!(U256::MAX.overflowing_add(U256::ONE).1);
+    }
+
+    #[test]
+    fn test_work() {
+        // the work of the genesis block, 2^32 hashes for a difficulty of 1
+        let work = U256::from_compact(0x1d00ffff).unwrap().work();
+        assert_eq!(work, U256::from(0x100010001));
+        assert_eq!(U256::MAX.work(), U256::ONE);
+        assert_eq!(
+            U256::from_compact(0x207fffff).unwrap().work(),
+            U256::from(2)
+        );
+    }
+
+    #[test]
+    fn test_retarget() {
+        // cases of pow_tests.cpp of Bitcoin Core, from mainnet retargets
+        let params = ChainParams::mainnet();
+        assert_eq!(
+            retarget(0x1d00ffff, 1262152739 - 1261130161, &params),
+            0x1d00d86a
+        );
+        // the target never goes above the pow limit
+        assert_eq!(
+            retarget(0x1d00ffff, 1233061996 - 1231006505, &params),
+            0x1d00ffff
+        );
+        // nor changes more than four times
+        assert_eq!(
+            retarget(0x1c05a3f4, 1279297671 - 1279008237, &params),
+            0x1c0168fd
+        );
+        assert_eq!(
+            retarget(0x1c387f6f, 1269211443 - 1263163443, &params),
+            0x1d00e1fd
+        );
+
+        assert_eq!(retarget(0x207fffff, 1, &ChainParams::regtest()), 0x207fffff);
+    }
+
+    #[test]
+    fn test_check_proof_of_work() {
+        let limit =
+            hash_from_hex("00000000ffffffffffffffffffffffffffffffffffffffffffffffffffffffff");
+        let hash =
+            hash_from_hex("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f");
+        assert!(check_proof_of_work(&hash, 0x1d00ffff, &limit).is_ok());
+        assert!(check_proof_of_work(&hash, 0x1b00ffff, &limit).is_err());
+
+        // targets above the limit, negative or zero are invalid
+        assert!(check_proof_of_work(&hash, 0x1e00ffff, &limit).is_err());
+        assert!(check_proof_of_work(&hash, 0x1d80ffff, &limit).is_err());
+        assert!(check_proof_of_work(&hash, 0x1d000000, &limit).is_err());
+    }
+}
@@ -0,0 +1,362 @@
+use std::{fmt, net::SocketAddr};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use super::errors::{BTCP2PError, Result};
+
+/// SOCKS5 protocol constants, https://www.rfc-editor.org/rfc/rfc1928
+const SOCKS_VERSION: u8 = 0x05;
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xff;
+const COMMAND_CONNECT: u8 = 0x01;
+const ADDRESS_IPV4: u8 = 0x01;
+const ADDRESS_DOMAIN: u8 = 0x03;
+const ADDRESS_IPV6: u8 = 0x04;
+
+/// Username/password authentication, https://www.rfc-editor.org/rfc/rfc1929
+const AUTH_VERSION: u8 = 0x01;
+
+/// Destination is where a connection through a proxy goes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Destination {
+    Addr(SocketAddr),
+
+    /// A host name resolved by the proxy, such as a `.onion` address.
+    Host(String, u16),
+}
+
+impl Destination {
+    pub fn port(&self) -> u16 {
+        match self {
+            Destination::Addr(addr) => addr.port(),
+            Destination::Host(_, port) => *port,
+        }
+    }
+}
+
+impl From<SocketAddr> for Destination {
+    fn from(addr: SocketAddr) -> Self {
+        Destination::Addr(addr)
+    }
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Destination::Addr(addr) => write!(f, "{}", addr),
+            Destination::Host(host, port) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+/// Credentials authenticate to a proxy with a username and password
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Credentials {
+    /// random creates credentials which are unique to a connection
+    pub fn random() -> Self {
+        Self {
+            username: format!("{:016x}", rand::random::<u64>()),
+            password: format!("{:016x}", rand::random::<u64>()),
+        }
+    }
+}
+
+/// Proxy opens connections through a SOCKS5 proxy, such as the SOCKS port of Tor
+///
+/// With `randomize_credentials`, each connection authenticates with random credentials, which makes Tor
+/// use a separate circuit for each stream (stream isolation), as `-proxyrandomize` does in Bitcoin Core.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Proxy {
+    /// The address of the proxy.
+    pub addr: SocketAddr,
+
+    /// The credentials of every connection, used unless they are randomized.
+    pub credentials: Option<Credentials>,
+
+    /// Whether each connection authenticates with random credentials.
+    pub randomize_credentials: bool,
+}
+
+impl Proxy {
+    /// new creates a proxy isolating every connection
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            credentials: None,
+            randomize_credentials: true,
+        }
+    }
+
+    /// connect opens a connection to a destination through the proxy
+    pub async fn connect(&self, destination: &Destination) -> Result<TcpStream> {
+        let credentials = if self.randomize_credentials {
+            Some(Credentials::random())
+        } else {
+            self.credentials.clone()
+        };
+
+        let mut stream = TcpStream::connect(self.addr).await?;
+        authenticate(&mut stream, credentials.as_ref()).await?;
+        request_connect(&mut stream, destination).await?;
+        Ok(stream)
+    }
+}
+
+/// authenticate negotiates the authentication method and authenticates with the credentials, if any
+async fn authenticate(stream: &mut TcpStream, credentials: Option<&Credentials>) -> Result<()> {
+    let method = if credentials.is_some() {
+        METHOD_USERNAME_PASSWORD
+    } else {
+        METHOD_NO_AUTH
+    };
+    stream.write_all(&[SOCKS_VERSION, 1, method]).await?;
+
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != SOCKS_VERSION {
+        return Err(BTCP2PError::InvalidProxyResponse);
+    }
+
+    match (reply[1], credentials) {
+        (METHOD_NO_AUTH, None) => Ok(()),
+        (METHOD_USERNAME_PASSWORD, Some(credentials)) => {
+            let username = credentials.username.as_bytes();
+            let password = credentials.password.as_bytes();
+            if username.len() > 255 || password.len() > 255 {
+                return Err(BTCP2PError::ProxyAuthenticationFailed);
+            }
+
+            let mut request = vec![AUTH_VERSION, username.len() as u8];
+            request.extend(username);
+            request.push(password.len() as u8);
+            request.extend(password);
+            stream.write_all(&request).await?;
+
+            stream.read_exact(&mut reply).await?;
+            if reply[0] != AUTH_VERSION {
+                return Err(BTCP2PError::InvalidProxyResponse);
+            }
+            if reply[1] != 0 {
+                return Err(BTCP2PError::ProxyAuthenticationFailed);
+            }
+            Ok(())
+        }
+        (METHOD_NONE_ACCEPTABLE, _) => Err(BTCP2PError::ProxyAuthenticationFailed),
+        _ => Err(BTCP2PError::InvalidProxyResponse),
+    }
+}
+
+/// request_connect asks the proxy to connect to a destination
+async fn request_connect(stream: &mut TcpStream, destination: &Destination) -> Result<()> {
+    let mut request = vec![SOCKS_VERSION, COMMAND_CONNECT, 0];
+    match destination {
+        Destination::Addr(SocketAddr::V4(addr)) => {
+            request.push(ADDRESS_IPV4);
+            request.extend(addr.ip().octets());
+        }
+        Destination::Addr(SocketAddr::V6(addr)) => {
+            request.push(ADDRESS_IPV6);
+            request.extend(addr.ip().octets());
+        }
+        Destination::Host(host, _) => {
+            if host.is_empty() || host.len() > 255 {
+                return Err(BTCP2PError::InvalidProxyDestination(host.clone()));
+            }
+            request.push(ADDRESS_DOMAIN);
+            request.push(host.len() as u8);
+            request.extend(host.as_bytes());
+        }
+    }
+    request.extend(destination.port().to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != SOCKS_VERSION {
+        return Err(BTCP2PError::InvalidProxyResponse);
+    }
+    if reply[1] != 0 {
+        return Err(BTCP2PError::ProxyError(reply[1]));
+    }
+
+    // the address the proxy bound is not used
+    let len = match reply[3] {
+        ADDRESS_IPV4 => 4,
+        ADDRESS_IPV6 => 16,
+        ADDRESS_DOMAIN => stream.read_u8().await? as usize,
+        _ => return Err(BTCP2PError::InvalidProxyResponse),
+    };
+    let mut bound = vec![0u8; len + 2];
+    stream.read_exact(&mut bound).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpListener;
+
+    /// Request is what a stub proxy received
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub(crate) struct Request {
+        pub credentials: Option<Credentials>,
+        pub destination: Destination,
+    }
+
+    /// stub_proxy starts a SOCKS5 proxy which forwards every connection to a target, whatever its destination
+    /// requests are recorded, and connections to `refused:1` are refused
+    pub(crate) async fn stub_proxy(target: SocketAddr) -> (SocketAddr, Arc<Mutex<Vec<Request>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(vec![]));
+
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let recorded = recorded.clone();
+                tokio::spawn(async move {
+                    let request = serve(&mut stream).await.unwrap();
+                    let refused = request.destination == Destination::Host("refused".into(), 1);
+                    recorded.lock().unwrap().push(request);
+
+                    if refused {
+                        // connection refused
+                        stream
+                            .write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0])
+                            .await
+                            .unwrap();
+                        return;
+                    }
+                    stream
+                        .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+                        .await
+                        .unwrap();
+                    let mut upstream = TcpStream::connect(target).await.unwrap();
+                    let _ = tokio::io::copy_bidirectional(&mut stream, &mut upstream).await;
+                });
+            }
+        });
+
+        (addr, requests)
+    }
+
+    async fn serve(stream: &mut TcpStream) -> Result<Request> {
+        let mut greeting = [0u8; 2];
+        stream.read_exact(&mut greeting).await?;
+        let mut methods = vec![0u8; greeting[1] as usize];
+        stream.read_exact(&mut methods).await?;
+
+        let credentials = if methods.contains(&METHOD_USERNAME_PASSWORD) {
+            stream.write_all(&[5, METHOD_USERNAME_PASSWORD]).await?;
+            stream.read_u8().await?;
+            let mut username = vec![0u8; stream.read_u8().await? as usize];
+            stream.read_exact(&mut username).await?;
+            let mut password = vec![0u8; stream.read_u8().await? as usize];
+            stream.read_exact(&mut password).await?;
+            stream.write_all(&[1, 0]).await?;
+            Some(Credentials {
+                username: String::from_utf8(username)?,
+                password: String::from_utf8(password)?,
+            })
+        } else {
+            stream.write_all(&[5, METHOD_NO_AUTH]).await?;
+            None
+        };
+
+        let mut request = [0u8; 4];
+        stream.read_exact(&mut request).await?;
+        let destination = match request[3] {
+            ADDRESS_IPV4 => {
+                let mut ip = [0u8; 4];
+                stream.read_exact(&mut ip).await?;
+                Destination::Addr(SocketAddr::new(ip.into(), stream.read_u16().await?))
+            }
+            ADDRESS_IPV6 => {
+                let mut ip = [0u8; 16];
+                stream.read_exact(&mut ip).await?;
+                Destination::Addr(SocketAddr::new(ip.into(), stream.read_u16().await?))
+            }
+            _ => {
+                let mut host = vec![0u8; stream.read_u8().await? as usize];
+                stream.read_exact(&mut host).await?;
+                Destination::Host(String::from_utf8(host)?, stream.read_u16().await?)
+            }
+        };
+
+        Ok(Request {
+            credentials,
+            destination,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_connect() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (addr, requests) = stub_proxy(target.local_addr().unwrap()).await;
+
+        let mut proxy = Proxy::new(addr);
+        proxy.randomize_credentials = false;
+        let destination = Destination::Host("example.onion".to_string(), 8333);
+        let mut stream = proxy.connect(&destination).await.unwrap();
+
+        // the stream reaches the target
+        let (mut accepted, _) = target.accept().await.unwrap();
+        stream.write_all(b"ping").await.unwrap();
+        let mut buffer = [0u8; 4];
+        accepted.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"ping");
+
+        let destination2 = Destination::Addr("[2001:db8::1]:8333".parse().unwrap());
+        let _stream2 = proxy.connect(&destination2).await.unwrap();
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![
+                Request {
+                    credentials: None,
+                    destination
+                },
+                Request {
+                    credentials: None,
+                    destination: destination2
+                }
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stream_isolation() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (addr, requests) = stub_proxy(target.local_addr().unwrap()).await;
+
+        let proxy = Proxy::new(addr);
+        let destination = Destination::Addr("1.2.3.4:8333".parse().unwrap());
+        let _stream = proxy.connect(&destination).await.unwrap();
+        let _stream2 = proxy.connect(&destination).await.unwrap();
+
+        // each stream has its own credentials
+        let requests = requests.lock().unwrap();
+        assert!(requests[0].credentials.is_some());
+        assert_ne!(requests[0].credentials, requests[1].credentials);
+    }
+
+    #[tokio::test]
+    async fn test_refused() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (addr, _) = stub_proxy(target.local_addr().unwrap()).await;
+
+        let result = Proxy::new(addr)
+            .connect(&Destination::Host("refused".to_string(), 1))
+            .await;
+        assert!(matches!(result, Err(BTCP2PError::ProxyError(5))));
+    }
+}
@@ -0,0 +1,435 @@
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::{
+    collections::HashSet,
+    future::Future,
+    io::{self, Read},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{net::UdpSocket, task::JoinSet};
+
+use super::{
+    chain_params::ChainParams,
+    errors::{BTCP2PError, Result},
+    service_flags::ServiceFlags,
+};
+
+/// Default time to wait for the answer of a DNS server
+pub const DEFAULT_DNS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// DNS record types, https://www.rfc-editor.org/rfc/rfc1035#section-3.2.2
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+/// Largest DNS message received over UDP
+const MAX_DNS_MESSAGE_SIZE: usize = 4096;
+
+/// Resolver resolves a host name to socket addresses, so the way seeds are queried can be replaced
+pub trait Resolver: Send + Sync + 'static {
+    fn resolve(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> impl Future<Output = Result<Vec<SocketAddr>>> + Send;
+}
+
+/// SystemResolver resolves host names with the resolver of the operating system
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    async fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        Ok(tokio::net::lookup_host((host, port)).await?.collect())
+    }
+}
+
+/// DnsResolver queries the A and AAAA records of a host name from a DNS server over UDP
+#[derive(Debug, Clone, Copy)]
+pub struct DnsResolver {
+    pub server: SocketAddr,
+    pub timeout: Duration,
+}
+
+impl DnsResolver {
+    pub fn new(server: SocketAddr) -> Self {
+        Self {
+            server,
+            timeout: DEFAULT_DNS_TIMEOUT,
+        }
+    }
+
+    /// query returns the addresses of a record type of a host name
+    async fn query(&self, host: &str, record_type: u16) -> Result<Vec<IpAddr>> {
+        let bind: SocketAddr = if self.server.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let socket = UdpSocket::bind(bind).await?;
+        socket.connect(self.server).await?;
+
+        let id = rand::random();
+        socket.send(&dns_query(id, host, record_type)?).await?;
+
+        let mut buffer = vec![0u8; MAX_DNS_MESSAGE_SIZE];
+        loop {
+            let len = tokio::time::timeout(self.timeout, socket.recv(&mut buffer))
+                .await
+                .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+
+            // answers to other queries are ignored
+            if let Some(addrs) = dns_answers(&buffer[..len], id)? {
+                return Ok(addrs);
+            }
+        }
+    }
+}
+
+impl Resolver for DnsResolver {
+    async fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        let (v4, v6) = tokio::join!(self.query(host, TYPE_A), self.query(host, TYPE_AAAA));
+
+        // a host may only have addresses of one family
+        let addrs = match (v4, v6) {
+            (Err(err), Err(_)) => return Err(err),
+            (v4, v6) => v4
+                .unwrap_or_default()
+                .into_iter()
+                .chain(v6.unwrap_or_default()),
+        };
+        Ok(addrs.map(|ip| SocketAddr::new(ip, port)).collect())
+    }
+}
+
+/// dns_query builds a recursive query of a record type of a host name
+fn dns_query(id: u16, host: &str, record_type: u16) -> Result<Vec<u8>> {
+    let mut buffer = vec![];
+    buffer.write_u16::<BigEndian>(id)?;
+    // recursion desired
+    buffer.write_u16::<BigEndian>(0x0100)?;
+    // one question, no answer, authority or additional record
+    buffer.write_u16::<BigEndian>(1)?;
+    buffer.write_u16::<BigEndian>(0)?;
+    buffer.write_u16::<BigEndian>(0)?;
+    buffer.write_u16::<BigEndian>(0)?;
+
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(BTCP2PError::InvalidDnsMessage);
+        }
+        buffer.write_u8(label.len() as u8)?;
+        buffer.extend(label.as_bytes());
+    }
+    buffer.write_u8(0)?;
+
+    buffer.write_u16::<BigEndian>(record_type)?;
+    buffer.write_u16::<BigEndian>(CLASS_IN)?;
+    Ok(buffer)
+}
+
+/// dns_answers returns the A and AAAA records of a response, or None if it answers another query
+/// a response for a name which does not exist has no record
+fn dns_answers(message: &[u8], id: u16) -> Result<Option<Vec<IpAddr>>> {
+    let mut bytes = message;
+    if bytes.read_u16::<BigEndian>()? != id {
+        return Ok(None);
+    }
+    let flags = bytes.read_u16::<BigEndian>()?;
+    if flags & 0x8000 == 0 {
+        return Ok(None);
+    }
+    match flags & 0x000f {
+        0 => {}
+        // the name does not exist
+        3 => return Ok(Some(vec![])),
+        _ => return Err(BTCP2PError::InvalidDnsMessage),
+    }
+
+    let questions = bytes.read_u16::<BigEndian>()?;
+    let answers = bytes.read_u16::<BigEndian>()?;
+    // authority and additional records are not read
+    bytes.read_u32::<BigEndian>()?;
+
+    for _ in 0..questions {
+        skip_name(&mut bytes)?;
+        // type and class
+        bytes.read_u32::<BigEndian>()?;
+    }
+
+    let mut addrs = vec![];
+    for _ in 0..answers {
+        skip_name(&mut bytes)?;
+        let record_type = bytes.read_u16::<BigEndian>()?;
+        let class = bytes.read_u16::<BigEndian>()?;
+        // time to live
+        bytes.read_u32::<BigEndian>()?;
+        let len = bytes.read_u16::<BigEndian>()? as usize;
+        if bytes.len() < len {
+            return Err(BTCP2PError::InvalidDnsMessage);
+        }
+        let (data, rest) = bytes.split_at(len);
+        bytes = rest;
+
+        // other records, such as the CNAME of an alias, are skipped
+        match (record_type, class, data.len()) {
+            (TYPE_A, CLASS_IN, 4) => addrs.push(IpAddr::from(<[u8; 4]>::try_from(data)?)),
+            (TYPE_AAAA, CLASS_IN, 16) => addrs.push(IpAddr::from(<[u8; 16]>::try_from(data)?)),
+            _ => {}
+        }
+    }
+
+    Ok(Some(addrs))
+}
+
+/// skip_name skips a possibly compressed domain name
+fn skip_name(bytes: &mut &[u8]) -> Result<()> {
+    loop {
+        let len = bytes.read_u8()?;
+        match len {
+            0 => return Ok(()),
+            // a pointer to a name elsewhere in the message ends the name
+            len if len & 0xc0 == 0xc0 => {
+                bytes.read_u8()?;
+                return Ok(());
+            }
+            len if len & 0xc0 == 0 => {
+                let mut label = [0u8; 63];
+                bytes.read_exact(&mut label[..len as usize])?;
+            }
+            _ => return Err(BTCP2PError::InvalidDnsMessage),
+        }
+    }
+}
+
+/// SeedResolver queries the DNS seeds of a network for addresses of nodes
+///
+/// Seeds which support it only return nodes with the wanted services when queried on the `x<hex services>.`
+/// subdomain, e.g. `x9.seed.bitcoin.sipa.be` for NODE_NETWORK and NODE_WITNESS, as Bitcoin Core does.
+/// Seeds are queried concurrently and the addresses they return are deduplicated.
+#[derive(Debug, Clone)]
+pub struct SeedResolver<R = SystemResolver> {
+    resolver: Arc<R>,
+
+    /// The host names of the seeds.
+    pub seeds: Vec<String>,
+
+    /// The port of the returned addresses.
+    pub port: u16,
+
+    /// The services nodes must offer, no filtering subdomain is used if empty.
+    pub services: ServiceFlags,
+}
+
+impl SeedResolver {
+    /// new creates a resolver of the seeds of a network using the resolver of the operating system
+    pub fn new(params: &ChainParams) -> Self {
+        Self::with_resolver(params, SystemResolver)
+    }
+}
+
+impl<R: Resolver> SeedResolver<R> {
+    pub fn with_resolver(params: &ChainParams, resolver: R) -> Self {
+        Self {
+            resolver: Arc::new(resolver),
+            seeds: params
+                .dns_seeds
+                .iter()
+                .map(|seed| seed.to_string())
+                .collect(),
+            port: params.default_port,
+            services: ServiceFlags::UNNAMED,
+        }
+    }
+
+    /// host returns the host name to query for a seed
+    pub fn host(&self, seed: &str) -> String {
+        if self.services.is_empty() {
+            seed.to_string()
+        } else {
+            format!("x{:x}.{}", self.services.to_u64(), seed)
+        }
+    }
+
+    /// lookup returns the addresses returned by a seed
+    pub async fn lookup(&self, seed: &str) -> Result<Vec<SocketAddr>> {
+        self.resolver.resolve(&self.host(seed), self.port).await
+    }
+
+    /// lookup_all returns the result of every seed, in the order of the seeds
+    pub async fn lookup_all(&self) -> Vec<(String, Result<Vec<SocketAddr>>)> {
+        let mut lookups = JoinSet::new();
+        for (index, seed) in self.seeds.iter().enumerate() {
+            let resolver = self.resolver.clone();
+            let host = self.host(seed);
+            let port = self.port;
+            lookups.spawn(async move { (index, resolver.resolve(&host, port).await) });
+        }
+
+        let mut results = Vec::with_capacity(self.seeds.len());
+        while let Some(result) = lookups.join_next().await {
+            // a lookup task only ends early if the runtime shuts down
+            if let Ok(result) = result {
+                results.push(result);
+            }
+        }
+        results.sort_by_key(|(index, _)| *index);
+
+        results
+            .into_iter()
+            .map(|(index, result)| (self.seeds[index].clone(), result))
+            .collect()
+    }
+
+    /// resolve returns the distinct addresses returned by the seeds, seeds which fail are skipped
+    pub async fn resolve(&self) -> Vec<SocketAddr> {
+        let mut seen = HashSet::new();
+        self.lookup_all()
+            .await
+            .into_iter()
+            .filter_map(|(_, result)| result.ok())
+            .flatten()
+            .map(|addr| SocketAddr::new(addr.ip().to_canonical(), addr.port()))
+            .filter(|addr| seen.insert(*addr))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// stub_server answers queries of the names it knows, and answers that other names do not exist
+    async fn stub_server(records: HashMap<&'static str, Vec<IpAddr>>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 512];
+            while let Ok((len, from)) = socket.recv_from(&mut buffer).await {
+                let query = &buffer[..len];
+                let mut bytes = &query[12..];
+                let mut labels = vec![];
+                loop {
+                    let len = bytes.read_u8().unwrap() as usize;
+                    if len == 0 {
+                        break;
+                    }
+                    labels.push(String::from_utf8(bytes[..len].to_vec()).unwrap());
+                    bytes = &bytes[len..];
+                }
+                let record_type = bytes.read_u16::<BigEndian>().unwrap();
+                let question = &query[12..len];
+
+                let name = labels.join(".");
+                let ips = records.get(name.as_str());
+                let answers = ips
+                    .into_iter()
+                    .flatten()
+                    .filter(|ip| (record_type == TYPE_A) == ip.is_ipv4())
+                    .collect::<Vec<_>>();
+
+                let mut response = query[..2].to_vec();
+                let rcode = if ips.is_some() { 0 } else { 3 };
+                response.write_u16::<BigEndian>(0x8180 | rcode).unwrap();
+                response.write_u16::<BigEndian>(1).unwrap();
+                response
+                    .write_u16::<BigEndian>(answers.len() as u16)
+                    .unwrap();
+                response.write_u32::<BigEndian>(0).unwrap();
+                response.extend(question);
+                for ip in answers {
+                    // a pointer to the name of the question
+                    response.write_u16::<BigEndian>(0xc00c).unwrap();
+                    response.write_u16::<BigEndian>(record_type).unwrap();
+                    response.write_u16::<BigEndian>(CLASS_IN).unwrap();
+                    response.write_u32::<BigEndian>(60).unwrap();
+                    match ip {
+                        IpAddr::V4(ip) => {
+                            response.write_u16::<BigEndian>(4).unwrap();
+                            response.extend(ip.octets());
+                        }
+                        IpAddr::V6(ip) => {
+                            response.write_u16::<BigEndian>(16).unwrap();
+                            response.extend(ip.octets());
+                        }
+                    }
+                }
+                socket.send_to(&response, from).await.unwrap();
+            }
+        });
+
+        addr
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_dns_resolver() {
+        let server = stub_server(HashMap::from([(
+            "seed.example",
+            vec![ip("1.2.3.4"), ip("2001:db8::1")],
+        )]))
+        .await;
+        let resolver = DnsResolver::new(server);
+
+        let mut addrs = resolver.resolve("seed.example", 8333).await.unwrap();
+        addrs.sort();
+        assert_eq!(
+            addrs,
+            vec![
+                "1.2.3.4:8333".parse().unwrap(),
+                "[2001:db8::1]:8333".parse().unwrap()
+            ]
+        );
+        assert!(resolver
+            .resolve("unknown.example", 8333)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_seed_resolver() {
+        let server = stub_server(HashMap::from([
+            ("x9.a.example", vec![ip("1.1.1.1"), ip("2.2.2.2")]),
+            ("x9.b.example", vec![ip("2.2.2.2"), ip("3.3.3.3")]),
+            ("b.example", vec![ip("4.4.4.4")]),
+        ]))
+        .await;
+
+        let mut params = ChainParams::regtest();
+        params.dns_seeds = &["a.example", "b.example", "c.example"];
+        let mut seeds = SeedResolver::with_resolver(&params, DnsResolver::new(server));
+        seeds.services = ServiceFlags::NODE_NETWORK | ServiceFlags::NODE_WITNESS;
+        assert_eq!(seeds.host("a.example"), "x9.a.example");
+
+        // addresses are deduplicated, in the order of the seeds
+        let addrs = seeds.resolve().await;
+        assert_eq!(
+            addrs,
+            ["1.1.1.1", "2.2.2.2", "3.3.3.3"]
+                .iter()
+                .map(|ip| SocketAddr::new(self::ip(ip), params.default_port))
+                .collect::<Vec<_>>()
+        );
+
+        // without services the seeds are queried directly
+        seeds.services = ServiceFlags::UNNAMED;
+        assert_eq!(
+            seeds.lookup("b.example").await.unwrap(),
+            vec![SocketAddr::new(ip("4.4.4.4"), params.default_port)]
+        );
+    }
+
+    #[test]
+    fn test_invalid_host() {
+        assert!(dns_query(0, "a..example", TYPE_A).is_err());
+        assert!(dns_query(0, &"a".repeat(64), TYPE_A).is_err());
+        assert!(dns_query(0, "seed.example.", TYPE_A).is_ok());
+    }
+}
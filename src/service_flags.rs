@@ -0,0 +1,249 @@
+use std::{
+    fmt,
+    ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign, Sub, SubAssign},
+};
+
+/// ServiceFlags represents the service flags of a node
+/// https://developer.bitcoin.org/reference/p2p_networking.html#version
+///
+/// Behaves as a set of flags. Bits without a name are preserved so they can be relayed as received.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct ServiceFlags(u64);
+
+impl ServiceFlags {
+    /// This node is not a full node. It may not be able to provide any data except for the transactions it originates.
+    pub const UNNAMED: ServiceFlags = ServiceFlags(0);
+
+    /// This is a full node and can be asked for full blocks. It should implement all protocol features available in its self-reported protocol version
+    pub const NODE_NETWORK: ServiceFlags = ServiceFlags(0x1);
+
+    /// This is a full node capable of responding to the getutxo protocol request. This is not supported by any currently-maintained Bitcoin node.
+    pub const NODE_GETUTXO: ServiceFlags = ServiceFlags(0x2);
+
+    /// This is a full node capable and willing to handle bloom-filtered connections.
+    pub const NODE_BLOOM: ServiceFlags = ServiceFlags(0x4);
+
+    /// This is a full node that can be asked for blocks and transactions including witness data.
+    pub const NODE_WITNESS: ServiceFlags = ServiceFlags(0x8);
+
+    /// This is a full node that supports Xtreme Thinblocks. This is not supported by any currently-maintained Bitcoin node.
+    pub const NODE_XTHIN: ServiceFlags = ServiceFlags(0x10);
+
+    /// This node can serve compact block filters as defined by BIP157 and BIP158.
+    pub const NODE_COMPACT_FILTERS: ServiceFlags = ServiceFlags(0x40);
+
+    /// This is the same as NODE_NETWORK but the node has at least the last 288 blocks (last 2 days).
+    pub const NODE_NETWORK_LIMITED: ServiceFlags = ServiceFlags(0x0400);
+
+    /// This node supports the v2 encrypted transport protocol defined by BIP324.
+    pub const NODE_P2P_V2: ServiceFlags = ServiceFlags(0x0800);
+
+    /// Named flags in bit order, with the name used to display them
+    const NAMED: [(ServiceFlags, &'static str); 8] = [
+        (ServiceFlags::NODE_NETWORK, "NETWORK"),
+        (ServiceFlags::NODE_GETUTXO, "GETUTXO"),
+        (ServiceFlags::NODE_BLOOM, "BLOOM"),
+        (ServiceFlags::NODE_WITNESS, "WITNESS"),
+        (ServiceFlags::NODE_XTHIN, "XTHIN"),
+        (ServiceFlags::NODE_COMPACT_FILTERS, "COMPACT_FILTERS"),
+        (ServiceFlags::NODE_NETWORK_LIMITED, "NETWORK_LIMITED"),
+        (ServiceFlags::NODE_P2P_V2, "P2P_V2"),
+    ];
+
+    /// Gets the integer representation of this ServiceFlags
+    pub fn to_u64(self) -> u64 {
+        self.0
+    }
+
+    /// Gets the ServiceFlags from an integer representation
+    pub fn from_u64(n: u64) -> Self {
+        ServiceFlags(n)
+    }
+
+    /// is_empty checks if no flag is set
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// contains checks if all the flags of other are set
+    pub fn contains(self, other: ServiceFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// intersects checks if any of the flags of other is set
+    pub fn intersects(self, other: ServiceFlags) -> bool {
+        self.0 & other.0 != 0
+    }
+
+    /// union returns the flags set in either
+    pub fn union(self, other: ServiceFlags) -> ServiceFlags {
+        ServiceFlags(self.0 | other.0)
+    }
+
+    /// intersection returns the flags set in both
+    pub fn intersection(self, other: ServiceFlags) -> ServiceFlags {
+        ServiceFlags(self.0 & other.0)
+    }
+
+    /// difference returns the flags set in self but not in other
+    pub fn difference(self, other: ServiceFlags) -> ServiceFlags {
+        ServiceFlags(self.0 & !other.0)
+    }
+
+    /// insert sets the flags of other
+    pub fn insert(&mut self, other: ServiceFlags) {
+        self.0 |= other.0;
+    }
+
+    /// remove clears the flags of other
+    pub fn remove(&mut self, other: ServiceFlags) {
+        self.0 &= !other.0;
+    }
+
+    /// unknown returns the flags set that have no name
+    pub fn unknown(self) -> ServiceFlags {
+        ServiceFlags::NAMED
+            .iter()
+            .fold(self, |flags, (named, _)| flags.difference(*named))
+    }
+
+    /// iter returns the named flags set with their names, in bit order
+    pub fn iter(self) -> impl Iterator<Item = (ServiceFlags, &'static str)> {
+        ServiceFlags::NAMED
+            .into_iter()
+            .filter(move |(named, _)| self.contains(*named))
+    }
+}
+
+impl From<u64> for ServiceFlags {
+    fn from(n: u64) -> Self {
+        ServiceFlags(n)
+    }
+}
+
+impl From<ServiceFlags> for u64 {
+    fn from(flags: ServiceFlags) -> Self {
+        flags.0
+    }
+}
+
+impl BitOr for ServiceFlags {
+    type Output = ServiceFlags;
+
+    fn bitor(self, other: ServiceFlags) -> ServiceFlags {
+        self.union(other)
+    }
+}
+
+impl BitOrAssign for ServiceFlags {
+    fn bitor_assign(&mut self, other: ServiceFlags) {
+        self.insert(other)
+    }
+}
+
+impl BitAnd for ServiceFlags {
+    type Output = ServiceFlags;
+
+    fn bitand(self, other: ServiceFlags) -> ServiceFlags {
+        self.intersection(other)
+    }
+}
+
+impl BitAndAssign for ServiceFlags {
+    fn bitand_assign(&mut self, other: ServiceFlags) {
+        self.0 &= other.0;
+    }
+}
+
+impl Sub for ServiceFlags {
+    type Output = ServiceFlags;
+
+    fn sub(self, other: ServiceFlags) -> ServiceFlags {
+        self.difference(other)
+    }
+}
+
+impl SubAssign for ServiceFlags {
+    fn sub_assign(&mut self, other: ServiceFlags) {
+        self.remove(other)
+    }
+}
+
+/// Displays the flags as `NETWORK|WITNESS`, unknown bits as `UNKNOWN[2^n]` and no flags as `NONE`
+impl fmt::Display for ServiceFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "NONE");
+        }
+
+        let unknown = self.unknown().0;
+        let names = self
+            .iter()
+            .map(|(_, name)| name.to_string())
+            .chain(
+                (0..64)
+                    .filter(|bit| unknown & (1 << bit) != 0)
+                    .map(|bit| format!("UNKNOWN[2^{}]", bit)),
+            )
+            .collect::<Vec<_>>();
+
+        write!(f, "{}", names.join("|"))
+    }
+}
+
+impl fmt::Debug for ServiceFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ServiceFlags({})", self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::{Arbitrary, TestResult};
+    use quickcheck_macros::quickcheck;
+
+    impl Arbitrary for ServiceFlags {
+        fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+            ServiceFlags(u64::arbitrary(g))
+        }
+    }
+
+    #[quickcheck]
+    fn test_unknown_bits_are_preserved(flags: ServiceFlags) -> TestResult {
+        let named = flags
+            .iter()
+            .fold(ServiceFlags::UNNAMED, |acc, (named, _)| acc | named);
+        TestResult::from_bool(named | flags.unknown() == flags)
+    }
+
+    #[test]
+    fn test_set_operations() {
+        let mut flags = ServiceFlags::NODE_NETWORK | ServiceFlags::NODE_WITNESS;
+        assert!(flags.contains(ServiceFlags::NODE_WITNESS));
+        assert!(!flags.contains(ServiceFlags::NODE_WITNESS | ServiceFlags::NODE_P2P_V2));
+        assert!(flags.intersects(ServiceFlags::NODE_WITNESS | ServiceFlags::NODE_P2P_V2));
+
+        flags |= ServiceFlags::NODE_P2P_V2;
+        flags -= ServiceFlags::NODE_NETWORK;
+        assert_eq!(flags & ServiceFlags::NODE_P2P_V2, ServiceFlags::NODE_P2P_V2);
+        assert_eq!(flags.to_u64(), 0x808);
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(ServiceFlags::UNNAMED.to_string(), "NONE");
+        assert_eq!(
+            (ServiceFlags::NODE_NETWORK | ServiceFlags::NODE_WITNESS).to_string(),
+            "NETWORK|WITNESS"
+        );
+        assert_eq!(
+            ServiceFlags::from(0x1 | 0x40 | 0x800 | 0x1000_0000).to_string(),
+            "NETWORK|COMPACT_FILTERS|P2P_V2|UNKNOWN[2^28]"
+        );
+        assert_eq!(
+            format!("{:?}", ServiceFlags::NODE_BLOOM),
+            "ServiceFlags(BLOOM)"
+        );
+    }
+}
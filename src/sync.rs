@@ -0,0 +1,34 @@
+use std::sync::{Mutex, MutexGuard};
+
+/// lock locks a mutex shared by the clones of a handle, ignoring poisoning
+///
+/// The handles using it (bans, version nonces, clock offsets, bandwidth counters, local addresses and port
+/// mappings) hold maps, sets and counters which stay valid whatever step a panicking holder stopped at: at
+/// worst a score, a ban or a count is lost, which is better than panicking in every task sharing the
+/// handle. `AddrMan` indexes its entries from several tables which a panic could leave out of sync, so it
+/// rebuilds them instead.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_lock_poisoned() {
+        let mutex = Arc::new(Mutex::new(vec![1]));
+        let shared = mutex.clone();
+        std::thread::spawn(move || {
+            let mut values = shared.lock().unwrap();
+            values.push(2);
+            panic!("holder panicked");
+        })
+        .join()
+        .unwrap_err();
+
+        assert!(mutex.is_poisoned());
+        assert_eq!(*lock(&mutex), vec![1, 2]);
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    net::IpAddr,
+    sync::{Arc, Mutex, MutexGuard},
+    time::SystemTime,
+};
+
+use super::sync::lock;
+
+/// Most offsets recorded, later peers are ignored
+pub const MAX_TIME_OFFSET_SAMPLES: usize = 200;
+
+/// Largest offset applied to our clock, in seconds (70 minutes, as in Bitcoin Core)
+pub const DEFAULT_MAX_TIME_ADJUSTMENT: i64 = 70 * 60;
+
+/// Offsets needed before the median is used
+const MIN_TIME_OFFSET_SAMPLES: usize = 5;
+
+/// Distance in seconds under which a peer clock is considered to agree with ours
+const CLOCK_AGREEMENT: i64 = 5 * 60;
+
+/// unix_time returns the current Unix epoch time according to our clock
+pub(crate) fn unix_time() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("get timestamp since unix epoch")
+        .as_secs() as i64
+}
+
+/// TimeOffsets computes the network adjusted time from the clock offsets of peers
+/// https://en.bitcoin.it/wiki/Block_timestamp
+///
+/// Each peer address counts once. Once enough offsets are known, the median offset is applied to our clock
+/// as long as it stays within the maximum adjustment. Otherwise our clock is kept, and if no peer agrees
+/// with it a warning is logged and raised, since our clock is likely wrong.
+///
+/// Clones share the same offsets. Peers record theirs through the `time_offsets` of `PeerConfig`, and
+/// `HeaderSync` checks header timestamps against the adjusted time.
+#[derive(Debug, Clone)]
+pub struct TimeOffsets(Arc<Mutex<TimeOffsetsState>>);
+
+#[derive(Debug)]
+struct TimeOffsetsState {
+    max_adjustment: i64,
+    sources: HashSet<IpAddr>,
+    samples: VecDeque<i64>,
+    offset: i64,
+    clock_warning: bool,
+}
+
+impl Default for TimeOffsets {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_TIME_ADJUSTMENT)
+    }
+}
+
+impl TimeOffsets {
+    pub fn new(max_adjustment: i64) -> Self {
+        Self(Arc::new(Mutex::new(TimeOffsetsState {
+            max_adjustment,
+            sources: HashSet::new(),
+            // our own clock counts as a sample with no offset
+            samples: VecDeque::from([0]),
+            offset: 0,
+            clock_warning: false,
+        })))
+    }
+
+    /// add records the clock offset in seconds of a peer, offsets from a known address are ignored
+    pub fn add(&self, source: IpAddr, offset: i64) {
+        let mut state = self.state();
+        if state.samples.len() >= MAX_TIME_OFFSET_SAMPLES || !state.sources.insert(source) {
+            return;
+        }
+        state.samples.push_back(offset);
+
+        // the median is only updated on an odd number of samples, as Bitcoin Core does
+        if state.samples.len() < MIN_TIME_OFFSET_SAMPLES || state.samples.len().is_multiple_of(2) {
+            return;
+        }
+
+        let median = state.median();
+        if median.unsigned_abs() <= state.max_adjustment.unsigned_abs() {
+            state.offset = median;
+            return;
+        }
+
+        state.offset = 0;
+        if !state.clock_warning {
+            state.clock_warning = !state.samples.iter().any(|&offset| {
+                offset != 0 && offset.unsigned_abs() < CLOCK_AGREEMENT.unsigned_abs()
+            });
+            if state.clock_warning {
+                tracing::warn!(
+                    median,
+                    "peers disagree with our clock, please check that the date and time are correct"
+                );
+            }
+        }
+    }
+
+    /// offset returns the offset in seconds applied to our clock
+    pub fn offset(&self) -> i64 {
+        self.state().offset
+    }
+
+    /// len returns the number of peer offsets recorded
+    pub fn len(&self) -> usize {
+        self.state().sources.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state().sources.is_empty()
+    }
+
+    /// clock_warning checks if the peers disagree with our clock by more than the maximum adjustment
+    pub fn clock_warning(&self) -> bool {
+        self.state().clock_warning
+    }
+
+    /// adjusted_time returns the Unix epoch time corrected by the offset of peers
+    /// this is the time block header timestamps are checked against
+    pub fn adjusted_time(&self) -> i64 {
+        unix_time() + self.offset()
+    }
+
+    fn state(&self) -> MutexGuard<'_, TimeOffsetsState> {
+        lock(&self.0)
+    }
+}
+
+impl TimeOffsetsState {
+    fn median(&self) -> i64 {
+        let mut sorted = self.samples.iter().copied().collect::<Vec<_>>();
+        sorted.sort_unstable();
+        sorted[sorted.len() / 2]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::TestResult;
+    use quickcheck_macros::quickcheck;
+    use std::net::Ipv4Addr;
+
+    fn ip(n: u32) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::from(n))
+    }
+
+    #[test]
+    fn test_median_offset() {
+        let offsets = TimeOffsets::default();
+        for (i, offset) in [10, 20, 30].into_iter().enumerate() {
+            offsets.add(ip(i as u32), offset);
+        }
+        // not enough samples yet
+        assert_eq!(offsets.offset(), 0);
+
+        offsets.add(ip(3), 40);
+        assert_eq!(offsets.offset(), 20);
+
+        // an even number of samples leaves the offset unchanged
+        offsets.add(ip(4), 50);
+        assert_eq!(offsets.offset(), 20);
+        offsets.add(ip(5), 60);
+        assert_eq!(offsets.offset(), 30);
+        assert_eq!(offsets.len(), 6);
+
+        // clones share the offsets
+        offsets.clone().add(ip(6), 70);
+        assert_eq!(offsets.len(), 7);
+    }
+
+    #[test]
+    fn test_duplicate_source() {
+        let offsets = TimeOffsets::default();
+        for _ in 0..10 {
+            offsets.add(ip(1), 100);
+        }
+        assert_eq!(offsets.len(), 1);
+        assert_eq!(offsets.offset(), 0);
+    }
+
+    #[test]
+    fn test_clock_warning() {
+        let offsets = TimeOffsets::default();
+        for i in 0..4 {
+            offsets.add(ip(i), 2 * DEFAULT_MAX_TIME_ADJUSTMENT);
+        }
+        assert_eq!(offsets.offset(), 0);
+        assert!(offsets.clock_warning());
+    }
+
+    #[test]
+    fn test_no_clock_warning_when_a_peer_agrees() {
+        let offsets = TimeOffsets::default();
+        offsets.add(ip(0), 60);
+        for i in 1..4 {
+            offsets.add(ip(i), 2 * DEFAULT_MAX_TIME_ADJUSTMENT);
+        }
+        assert_eq!(offsets.offset(), 0);
+        assert!(!offsets.clock_warning());
+    }
+
+    #[quickcheck]
+    fn test_offset_is_bounded(samples: Vec<i64>) -> TestResult {
+        let offsets = TimeOffsets::default();
+        for (i, offset) in samples.into_iter().enumerate() {
+            offsets.add(ip(i as u32), offset);
+        }
+        TestResult::from_bool(offsets.offset().abs() <= DEFAULT_MAX_TIME_ADJUSTMENT)
+    }
+}
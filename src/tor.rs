@@ -0,0 +1,536 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+use super::{
+    address::{Address, NetAddress},
+    encode::{base32_decode, base32_encode},
+    errors::{BTCP2PError, Result},
+    hash::{hmac_sha256, sha3_256},
+    service_flags::ServiceFlags,
+    time_offsets::unix_time,
+};
+
+/// Version byte of v3 onion addresses
+const ONION_VERSION: u8 = 3;
+
+/// Keys of the HMACs of the SAFECOOKIE authentication, https://spec.torproject.org/control-spec/commands.html#authchallenge
+const SAFECOOKIE_SERVER_KEY: &[u8] = b"Tor safe cookie authentication server-to-controller hash";
+const SAFECOOKIE_CLIENT_KEY: &[u8] = b"Tor safe cookie authentication controller-to-server hash";
+
+/// Length of the authentication cookie of Tor
+const COOKIE_LEN: usize = 32;
+
+/// onion_name returns the `.onion` name of a v3 onion service public key
+/// https://spec.torproject.org/rend-spec/encoding-onion-addresses.html
+pub fn onion_name(pubkey: &[u8; 32]) -> String {
+    let mut bytes = pubkey.to_vec();
+    bytes.extend(&onion_checksum(pubkey));
+    bytes.push(ONION_VERSION);
+    format!("{}.onion", base32_encode(&bytes))
+}
+
+/// parse_onion_name returns the public key of a v3 `.onion` name, checking its checksum and version
+pub fn parse_onion_name(name: &str) -> Option<[u8; 32]> {
+    let bytes = base32_decode(name.strip_suffix(".onion")?)?;
+    if bytes.len() != 35 || bytes[34] != ONION_VERSION {
+        return None;
+    }
+
+    let pubkey: [u8; 32] = bytes[..32].try_into().ok()?;
+    (bytes[32..34] == onion_checksum(&pubkey)).then_some(pubkey)
+}
+
+fn onion_checksum(pubkey: &[u8; 32]) -> [u8; 2] {
+    let mut data = b".onion checksum".to_vec();
+    data.extend(pubkey);
+    data.push(ONION_VERSION);
+    let hash = sha3_256(&data);
+    [hash[0], hash[1]]
+}
+
+/// TorControl is a connection to the control port of Tor
+/// https://spec.torproject.org/control-spec/
+#[derive(Debug)]
+pub struct TorControl {
+    stream: BufReader<TcpStream>,
+}
+
+impl TorControl {
+    pub async fn connect(addr: SocketAddr) -> Result<Self> {
+        Ok(Self {
+            stream: BufReader::new(TcpStream::connect(addr).await?),
+        })
+    }
+
+    /// authenticate authenticates with the first method Tor supports, in the order of Bitcoin Core:
+    /// the password if one is given, no authentication, SAFECOOKIE then COOKIE
+    pub async fn authenticate(&mut self, password: Option<&str>) -> Result<()> {
+        let info = self.command("PROTOCOLINFO 1").await?;
+        let auth = info
+            .iter()
+            .find_map(|line| line.strip_prefix("AUTH "))
+            .ok_or_else(|| BTCP2PError::TorControlError("no AUTH in PROTOCOLINFO".to_string()))?;
+        let methods = auth
+            .split_whitespace()
+            .find_map(|field| field.strip_prefix("METHODS="))
+            .unwrap_or_default()
+            .split(',')
+            .collect::<Vec<_>>();
+        let cookie_file = quoted_value(auth, "COOKIEFILE=").map(PathBuf::from);
+
+        if let Some(password) = password {
+            if !methods.contains(&"HASHEDPASSWORD") {
+                return Err(BTCP2PError::TorControlError(
+                    "password authentication is not enabled".to_string(),
+                ));
+            }
+            self.command(&format!("AUTHENTICATE {}", quote(password)))
+                .await?;
+        } else if methods.contains(&"NULL") {
+            self.command("AUTHENTICATE").await?;
+        } else if let (true, Some(cookie_file)) = (methods.contains(&"SAFECOOKIE"), &cookie_file) {
+            self.authenticate_safecookie(cookie_file).await?;
+        } else if let (true, Some(cookie_file)) = (methods.contains(&"COOKIE"), &cookie_file) {
+            let cookie = read_cookie(cookie_file)?;
+            self.command(&format!("AUTHENTICATE {}", hex_encode(&cookie)))
+                .await?;
+        } else {
+            return Err(BTCP2PError::TorControlError(format!(
+                "no supported authentication method in {}",
+                methods.join(",")
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// authenticate_safecookie proves we can read the cookie without sending it, and checks Tor can read it too
+    async fn authenticate_safecookie(&mut self, cookie_file: &Path) -> Result<()> {
+        let cookie = read_cookie(cookie_file)?;
+        let client_nonce: [u8; 32] = rand::random();
+
+        let reply = self
+            .command(&format!(
+                "AUTHCHALLENGE SAFECOOKIE {}",
+                hex_encode(&client_nonce)
+            ))
+            .await?;
+        let challenge = reply.first().map(String::as_str).unwrap_or_default();
+        let field = |name: &str| {
+            challenge
+                .split_whitespace()
+                .find_map(|field| field.strip_prefix(name))
+                .and_then(hex_decode)
+                .ok_or_else(|| BTCP2PError::TorControlError(format!("invalid {}", challenge)))
+        };
+        let server_hash = field("SERVERHASH=")?;
+        let server_nonce = field("SERVERNONCE=")?;
+
+        let mut message = cookie.clone();
+        message.extend(&client_nonce);
+        message.extend(&server_nonce);
+        if hmac_sha256(SAFECOOKIE_SERVER_KEY, &message)[..] != server_hash[..] {
+            return Err(BTCP2PError::TorControlError(
+                "Tor does not know the cookie".to_string(),
+            ));
+        }
+
+        let client_hash = hmac_sha256(SAFECOOKIE_CLIENT_KEY, &message);
+        self.command(&format!("AUTHENTICATE {}", hex_encode(&client_hash)))
+            .await?;
+        Ok(())
+    }
+
+    /// add_onion creates an onion service forwarding a virtual port to a target, such as our listener
+    /// the private key is read from the key file, or generated by Tor and saved to it
+    /// the service is removed by Tor once the returned service, which owns the connection, is dropped
+    pub async fn add_onion<P: AsRef<Path>>(
+        mut self,
+        key_path: P,
+        virtual_port: u16,
+        target: SocketAddr,
+    ) -> Result<OnionService> {
+        let key_path = key_path.as_ref();
+        let key = match std::fs::read_to_string(key_path) {
+            Ok(key) => Some(key.trim().to_string()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err.into()),
+        };
+
+        let reply = self
+            .command(&format!(
+                "ADD_ONION {} Port={},{}",
+                key.as_deref().unwrap_or("NEW:ED25519-V3"),
+                virtual_port,
+                target
+            ))
+            .await?;
+        let value = |name: &str| reply.iter().find_map(|line| line.strip_prefix(name));
+
+        let service_id = value("ServiceID=")
+            .ok_or_else(|| BTCP2PError::TorControlError("no ServiceID".to_string()))?;
+        let pubkey = parse_onion_name(&format!("{}.onion", service_id)).ok_or_else(|| {
+            BTCP2PError::TorControlError(format!("invalid service id {}", service_id))
+        })?;
+
+        if key.is_none() {
+            let private_key = value("PrivateKey=")
+                .ok_or_else(|| BTCP2PError::TorControlError("no PrivateKey".to_string()))?;
+            let tmp = PathBuf::from(format!("{}.new", key_path.display()));
+            std::fs::write(&tmp, private_key)?;
+            std::fs::rename(&tmp, key_path)?;
+        }
+
+        Ok(OnionService {
+            _control: self,
+            pubkey,
+            port: virtual_port,
+        })
+    }
+
+    /// command sends a command and returns the lines of its reply without their status
+    /// a status other than 250 is an error
+    async fn command(&mut self, command: &str) -> Result<Vec<String>> {
+        self.stream
+            .get_mut()
+            .write_all(format!("{}\r\n", command).as_bytes())
+            .await?;
+
+        let mut lines = vec![];
+        loop {
+            let line = self.read_line().await?;
+            if line.len() < 4 || !line.is_char_boundary(3) {
+                return Err(BTCP2PError::TorControlError(format!(
+                    "invalid reply {}",
+                    line
+                )));
+            }
+            let (status, rest) = line.split_at(3);
+            if status != "250" {
+                return Err(BTCP2PError::TorControlError(line));
+            }
+
+            let (separator, text) = rest.split_at(1);
+            lines.push(text.to_string());
+            match separator {
+                " " => return Ok(lines),
+                "-" => {}
+                // data lines follow until a single dot
+                "+" => loop {
+                    let data = self.read_line().await?;
+                    if data == "." {
+                        break;
+                    }
+                    lines.push(data);
+                },
+                _ => {
+                    return Err(BTCP2PError::TorControlError(format!(
+                        "invalid reply {}",
+                        line
+                    )))
+                }
+            }
+        }
+    }
+
+    async fn read_line(&mut self) -> Result<String> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line).await? == 0 {
+            return Err(BTCP2PError::ConnectionClosed);
+        }
+        Ok(line.trim_end_matches(['\r', '\n']).to_string())
+    }
+}
+
+/// OnionService is an onion service created through the control port, which lives as long as it is kept
+#[derive(Debug)]
+pub struct OnionService {
+    _control: TorControl,
+    pubkey: [u8; 32],
+    port: u16,
+}
+
+impl OnionService {
+    /// address returns the address of the service as announced in addrv2 messages
+    pub fn address(&self) -> Address {
+        Address::TorV3(self.pubkey)
+    }
+
+    /// name returns the `.onion` name of the service
+    pub fn name(&self) -> String {
+        onion_name(&self.pubkey)
+    }
+
+    /// port returns the port of the service, which peers connect to
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// net_address returns the address to advertise to peers in addrv2 messages
+    pub fn net_address(&self, services: ServiceFlags) -> NetAddress {
+        NetAddress {
+            time: unix_time() as u32,
+            services,
+            address: self.address(),
+            port: self.port,
+        }
+    }
+}
+
+fn read_cookie(path: &Path) -> Result<Vec<u8>> {
+    let cookie = std::fs::read(path)?;
+    if cookie.len() != COOKIE_LEN {
+        return Err(BTCP2PError::TorControlError(format!(
+            "invalid cookie file {}",
+            path.display()
+        )));
+    }
+    Ok(cookie)
+}
+
+/// quote quotes a string as a QuotedString of the control protocol
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// quoted_value returns the unquoted value of a quoted field of a reply line
+fn quoted_value(line: &str, name: &str) -> Option<String> {
+    let start = line.find(name)? + name.len();
+    let mut chars = line[start..].strip_prefix('"')?.chars();
+    let mut value = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(value),
+            '\\' => value.push(chars.next()?),
+            c => value.push(c),
+        }
+    }
+}
+
+fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+fn hex_decode(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::local_addresses::LocalAddresses;
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+    /// FakeTor is how the stand-in control port authenticates controllers
+    #[derive(Debug, Clone)]
+    struct FakeTor {
+        methods: &'static str,
+        cookie_file: PathBuf,
+        password: &'static str,
+    }
+
+    impl FakeTor {
+        fn new(methods: &'static str) -> Self {
+            let cookie_file = temp_path("cookie");
+            std::fs::write(&cookie_file, rand::random::<[u8; 32]>()).unwrap();
+            Self {
+                methods,
+                cookie_file,
+                password: "secret",
+            }
+        }
+
+        /// start serves the control port, keys are `ED25519-V3:` followed by the public key in hex
+        async fn start(self) -> SocketAddr {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(self.clone().serve(stream));
+                }
+            });
+            addr
+        }
+
+        async fn serve(self, stream: TcpStream) {
+            let mut stream = BufReader::new(stream);
+            let cookie = std::fs::read(&self.cookie_file).unwrap();
+            let mut authenticated = false;
+            let mut expected_hash = None;
+
+            let mut line = String::new();
+            while stream.read_line(&mut line).await.unwrap_or(0) > 0 {
+                let command = line.trim_end().to_string();
+                line.clear();
+                let (name, args) = command.split_once(' ').unwrap_or((&command, ""));
+
+                let reply = match name {
+                    "PROTOCOLINFO" => format!(
+                        "250-PROTOCOLINFO 1\r\n250-AUTH METHODS={} COOKIEFILE={}\r\n250-VERSION Tor=\"0.4.8.9\"\r\n250 OK",
+                        self.methods,
+                        quote(&self.cookie_file.display().to_string())
+                    ),
+                    "AUTHCHALLENGE" => {
+                        let client_nonce = hex_decode(args.split(' ').nth(1).unwrap()).unwrap();
+                        let server_nonce: [u8; 32] = rand::random();
+                        let mut message = cookie.clone();
+                        message.extend(&client_nonce);
+                        message.extend(&server_nonce);
+                        expected_hash = Some(hmac_sha256(SAFECOOKIE_CLIENT_KEY, &message).to_vec());
+                        format!(
+                            "250 AUTHCHALLENGE SERVERHASH={} SERVERNONCE={}",
+                            hex_encode(&hmac_sha256(SAFECOOKIE_SERVER_KEY, &message)),
+                            hex_encode(&server_nonce)
+                        )
+                    }
+                    "AUTHENTICATE" => {
+                        authenticated = match args {
+                            "" => self.methods.contains("NULL"),
+                            quoted if quoted.starts_with('"') => {
+                                quoted_value(&format!("P={}", quoted), "P=").as_deref()
+                                    == Some(self.password)
+                            }
+                            hex => {
+                                let hash = hex_decode(hex);
+                                hash == Some(cookie.clone()) || hash == expected_hash
+                            }
+                        };
+                        if authenticated {
+                            "250 OK".to_string()
+                        } else {
+                            "515 Authentication failed".to_string()
+                        }
+                    }
+                    "ADD_ONION" if authenticated => {
+                        let key = args.split(' ').next().unwrap();
+                        let pubkey: [u8; 32] = match key.strip_prefix("ED25519-V3:") {
+                            Some(hex) => hex_decode(hex).unwrap().try_into().unwrap(),
+                            None => rand::random(),
+                        };
+                        let name = onion_name(&pubkey);
+                        let mut reply = format!("250-ServiceID={}\r\n", name.trim_end_matches(".onion"));
+                        if key.starts_with("NEW:") {
+                            reply += &format!("250-PrivateKey=ED25519-V3:{}\r\n", hex_encode(&pubkey));
+                        }
+                        reply + "250 OK"
+                    }
+                    _ => "514 Authentication required.".to_string(),
+                };
+
+                let stream = stream.get_mut();
+                stream
+                    .write_all(format!("{}\r\n", reply).as_bytes())
+                    .await
+                    .unwrap();
+            }
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("tor-{}-{:x}", name, rand::random::<u64>()))
+    }
+
+    #[test]
+    fn test_onion_name() {
+        let name = "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion";
+        let pubkey = parse_onion_name(name).unwrap();
+        assert_eq!(onion_name(&pubkey), name);
+
+        // a single changed character breaks the checksum
+        let typo = "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscrye.onion";
+        assert!(parse_onion_name(typo).is_none());
+        assert!(parse_onion_name("example.onion").is_none());
+    }
+
+    #[test]
+    fn test_quoted_value() {
+        let line = r#"METHODS=COOKIE COOKIEFILE="/var/lib/tor/a \"b\".cookie""#;
+        assert_eq!(
+            quoted_value(line, "COOKIEFILE=").unwrap(),
+            r#"/var/lib/tor/a "b".cookie"#
+        );
+        assert_eq!(
+            quoted_value(&format!("P={}", quote(r#"a"\b"#)), "P=").unwrap(),
+            r#"a"\b"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_add_onion() {
+        let tor = FakeTor::new("COOKIE,SAFECOOKIE");
+        let cookie_file = tor.cookie_file.clone();
+        let addr = tor.start().await;
+        let key_path = temp_path("key");
+        let target = "127.0.0.1:8334".parse().unwrap();
+
+        let mut control = TorControl::connect(addr).await.unwrap();
+        control.authenticate(None).await.unwrap();
+        let service = control.add_onion(&key_path, 8333, target).await.unwrap();
+        assert_eq!(
+            parse_onion_name(&service.name()),
+            Some(match service.address() {
+                Address::TorV3(pubkey) => pubkey,
+                _ => unreachable!(),
+            })
+        );
+
+        // the key is kept, so the service keeps its address
+        let mut control = TorControl::connect(addr).await.unwrap();
+        control.authenticate(None).await.unwrap();
+        let service2 = control.add_onion(&key_path, 8333, target).await.unwrap();
+        assert_eq!(service2.address(), service.address());
+
+        let address = service.net_address(ServiceFlags::NODE_NETWORK);
+        assert_eq!(
+            (address.address.clone(), address.port),
+            (service.address(), 8333)
+        );
+
+        // the service is advertised once registered as a local address
+        let local_addresses = LocalAddresses::new();
+        local_addresses.add_onion(&service);
+        let advertised = local_addresses.net_addresses(ServiceFlags::NODE_NETWORK, address.time);
+        assert_eq!(advertised, vec![address]);
+
+        std::fs::remove_file(&key_path).unwrap();
+        std::fs::remove_file(&cookie_file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_authenticate() {
+        for methods in ["NULL", "COOKIE", "SAFECOOKIE"] {
+            let tor = FakeTor::new(methods);
+            let cookie_file = tor.cookie_file.clone();
+            let mut control = TorControl::connect(tor.start().await).await.unwrap();
+            control.authenticate(None).await.unwrap();
+            std::fs::remove_file(&cookie_file).unwrap();
+        }
+
+        let tor = FakeTor::new("HASHEDPASSWORD");
+        let cookie_file = tor.cookie_file.clone();
+        let addr = tor.start().await;
+        let mut control = TorControl::connect(addr).await.unwrap();
+        control.authenticate(Some("secret")).await.unwrap();
+
+        let mut control = TorControl::connect(addr).await.unwrap();
+        let result = control.authenticate(Some("wrong")).await;
+        assert!(matches!(result, Err(BTCP2PError::TorControlError(_))));
+
+        // without a password there is no method left
+        let mut control = TorControl::connect(addr).await.unwrap();
+        assert!(control.authenticate(None).await.is_err());
+        std::fs::remove_file(&cookie_file).unwrap();
+    }
+}
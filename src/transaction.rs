@@ -0,0 +1,329 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Read, Write};
+
+use super::{
+    encode::{read_vec_len, write_compact_size},
+    errors::{BTCP2PError, Result},
+    hash::sha256d,
+};
+
+/// Smallest size of a serialized transaction: version, empty input and output counts and lock time
+pub(crate) const MIN_TRANSACTION_SIZE: usize = 10;
+
+/// Flag of the extended serialization marking the presence of witness data (BIP144)
+const WITNESS_FLAG: u8 = 0x01;
+
+/// OutPoint references an output of a previous transaction
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OutPoint {
+    /// The txid of the transaction holding the output in byte order.
+    pub txid: [u8; 32],
+
+    /// The index of the output in the transaction.
+    pub vout: u32,
+}
+
+/// TxIn represents an input of a transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxIn {
+    pub previous_output: OutPoint,
+    pub script_sig: Vec<u8>,
+    pub sequence: u32,
+
+    /// The witness stack of the input, empty for inputs without witness.
+    pub witness: Vec<Vec<u8>>,
+}
+
+/// TxOut represents an output of a transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TxOut {
+    /// The amount of the output in satoshis.
+    pub value: i64,
+    pub script_pubkey: Vec<u8>,
+}
+
+/// Transaction represents a transaction, serialized with witness data as in BIP144
+/// https://developer.bitcoin.org/reference/transactions.html#raw-transaction-format
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transaction {
+    pub version: i32,
+    pub inputs: Vec<TxIn>,
+    pub outputs: Vec<TxOut>,
+    pub lock_time: u32,
+}
+
+impl Transaction {
+    /// has_witness checks if any input carries witness data
+    pub fn has_witness(&self) -> bool {
+        self.inputs.iter().any(|input| !input.witness.is_empty())
+    }
+
+    /// to_bytes converts the transaction to bytes, in the extended format if it has witness data
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut buffer = vec![];
+        self.write(&mut buffer, self.has_witness())?;
+        Ok(buffer)
+    }
+
+    /// from_bytes converts bytes to a transaction
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self> {
+        Self::read(&mut bytes)
+    }
+
+    /// txid returns the hash of the transaction without witness data, in byte order
+    pub fn txid(&self) -> [u8; 32] {
+        let mut buffer = vec![];
+        self.write(&mut buffer, false)
+            .expect("write transaction to vec");
+        sha256d(&buffer)
+    }
+
+    /// wtxid returns the hash of the transaction with witness data, in byte order (BIP141)
+    pub fn wtxid(&self) -> [u8; 32] {
+        sha256d(&self.to_bytes().expect("write transaction to vec"))
+    }
+
+    fn write<W: Write>(&self, writer: &mut W, witness: bool) -> Result<()> {
+        writer.write_i32::<LittleEndian>(self.version)?;
+        if witness {
+            // an empty input count followed by the flag, which old parsers reject
+            writer.write_u8(0x00)?;
+            writer.write_u8(WITNESS_FLAG)?;
+        }
+
+        write_compact_size(writer, self.inputs.len() as u64)?;
+        for input in &self.inputs {
+            writer.write_all(&input.previous_output.txid)?;
+            writer.write_u32::<LittleEndian>(input.previous_output.vout)?;
+            write_bytes(writer, &input.script_sig)?;
+            writer.write_u32::<LittleEndian>(input.sequence)?;
+        }
+
+        write_compact_size(writer, self.outputs.len() as u64)?;
+        for output in &self.outputs {
+            writer.write_i64::<LittleEndian>(output.value)?;
+            write_bytes(writer, &output.script_pubkey)?;
+        }
+
+        if witness {
+            for input in &self.inputs {
+                write_compact_size(writer, input.witness.len() as u64)?;
+                for item in &input.witness {
+                    write_bytes(writer, item)?;
+                }
+            }
+        }
+
+        writer.write_u32::<LittleEndian>(self.lock_time)?;
+        Ok(())
+    }
+
+    /// read reads a transaction, leaving any trailing bytes
+    /// unknown flags and an extended format without witness data are rejected, as Bitcoin Core does
+    /// with no input, the byte after the input count is the flag: a zero flag is the empty output count, as
+    /// outputs without input can't be told from the extended format
+    pub(crate) fn read(bytes: &mut &[u8]) -> Result<Self> {
+        let version = bytes.read_i32::<LittleEndian>()?;
+
+        let mut inputs = read_inputs(bytes)?;
+        let mut flag = 0;
+        let mut outputs = vec![];
+        if inputs.is_empty() {
+            flag = bytes.read_u8()?;
+            if flag != 0 {
+                inputs = read_inputs(bytes)?;
+                outputs = read_outputs(bytes)?;
+            }
+        } else {
+            outputs = read_outputs(bytes)?;
+        }
+        if flag & !WITNESS_FLAG != 0 {
+            return Err(BTCP2PError::InvalidTransaction);
+        }
+
+        let mut transaction = Self {
+            version,
+            inputs,
+            outputs,
+            lock_time: 0,
+        };
+        if flag == WITNESS_FLAG {
+            for input in transaction.inputs.iter_mut() {
+                input.witness = (0..read_vec_len(bytes, 1)?)
+                    .map(|_| read_bytes(bytes))
+                    .collect::<Result<_>>()?;
+            }
+            if !transaction.has_witness() {
+                return Err(BTCP2PError::InvalidTransaction);
+            }
+        }
+
+        transaction.lock_time = bytes.read_u32::<LittleEndian>()?;
+        Ok(transaction)
+    }
+}
+
+/// read_inputs reads the CompactSize prefixed inputs of a transaction, without their witness
+fn read_inputs(bytes: &mut &[u8]) -> Result<Vec<TxIn>> {
+    // 36 bytes of outpoint, a script length and 4 bytes of sequence
+    (0..read_vec_len(bytes, 41)?)
+        .map(|_| {
+            let mut txid = [0u8; 32];
+            bytes.read_exact(&mut txid)?;
+            Ok(TxIn {
+                previous_output: OutPoint {
+                    txid,
+                    vout: bytes.read_u32::<LittleEndian>()?,
+                },
+                script_sig: read_bytes(bytes)?,
+                sequence: bytes.read_u32::<LittleEndian>()?,
+                witness: vec![],
+            })
+        })
+        .collect()
+}
+
+/// read_outputs reads the CompactSize prefixed outputs of a transaction
+fn read_outputs(bytes: &mut &[u8]) -> Result<Vec<TxOut>> {
+    // 8 bytes of value and a script length
+    (0..read_vec_len(bytes, 9)?)
+        .map(|_| {
+            Ok(TxOut {
+                value: bytes.read_i64::<LittleEndian>()?,
+                script_pubkey: read_bytes(bytes)?,
+            })
+        })
+        .collect()
+}
+
+/// write_bytes writes a CompactSize prefixed byte vector, such as a script
+fn write_bytes<W: Write>(writer: &mut W, bytes: &[u8]) -> Result<()> {
+    write_compact_size(writer, bytes.len() as u64)?;
+    writer.write_all(bytes)?;
+    Ok(())
+}
+
+/// read_bytes reads a CompactSize prefixed byte vector
+fn read_bytes(bytes: &mut &[u8]) -> Result<Vec<u8>> {
+    let len = read_vec_len(bytes, 1)?;
+    let mut buffer = vec![0u8; len];
+    bytes.read_exact(&mut buffer)?;
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use quickcheck::{Arbitrary, TestResult};
+    use quickcheck_macros::quickcheck;
+
+    impl Arbitrary for Transaction {
+        fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+            let inputs: Vec<TxIn> = (0..usize::arbitrary(g) % 4)
+                .map(|_| TxIn {
+                    previous_output: OutPoint {
+                        txid: [u8::arbitrary(g); 32],
+                        vout: u32::arbitrary(g),
+                    },
+                    script_sig: Vec::arbitrary(g),
+                    sequence: u32::arbitrary(g),
+                    witness: Vec::arbitrary(g),
+                })
+                .collect();
+            // outputs without input can't be serialized, their count would be read as the flag
+            let len = if inputs.is_empty() {
+                0
+            } else {
+                usize::arbitrary(g) % 4
+            };
+            let outputs = (0..len)
+                .map(|_| TxOut {
+                    value: i64::arbitrary(g),
+                    script_pubkey: Vec::arbitrary(g),
+                })
+                .collect();
+
+            Self {
+                version: i32::arbitrary(g),
+                inputs,
+                outputs,
+                lock_time: u32::arbitrary(g),
+            }
+        }
+    }
+
+    fn transaction(witness: Vec<Vec<u8>>) -> Transaction {
+        Transaction {
+            version: 2,
+            inputs: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: [1; 32],
+                    vout: 0,
+                },
+                script_sig: vec![],
+                sequence: 0xffff_fffd,
+                witness,
+            }],
+            outputs: vec![TxOut {
+                value: 50_000,
+                script_pubkey: vec![0x00, 0x14, 0xaa],
+            }],
+            lock_time: 0,
+        }
+    }
+
+    #[quickcheck]
+    fn test_to_bytes(transaction: Transaction) -> TestResult {
+        let bytes = transaction.to_bytes().unwrap();
+        TestResult::from_bool(transaction == Transaction::from_bytes(&bytes).unwrap())
+    }
+
+    #[test]
+    fn test_no_input() {
+        let empty = Transaction {
+            version: 2,
+            inputs: vec![],
+            outputs: vec![],
+            lock_time: 7,
+        };
+        let bytes = empty.to_bytes().unwrap();
+        assert_eq!(bytes.len(), MIN_TRANSACTION_SIZE);
+
+        // the zero flag is the empty output count, the lock time follows it
+        let mut reader = &[bytes.as_slice(), &[0xff]].concat()[..];
+        assert_eq!(Transaction::read(&mut reader).unwrap(), empty);
+        assert_eq!(reader, &[0xff]);
+    }
+
+    #[test]
+    fn test_txid() {
+        let legacy = transaction(vec![]);
+        assert_eq!(legacy.txid(), legacy.wtxid());
+        assert_eq!(legacy.to_bytes().unwrap().len(), 4 + 1 + 41 + 1 + 12 + 4);
+
+        let segwit = transaction(vec![vec![0x30; 72], vec![0x02; 33]]);
+        let bytes = segwit.to_bytes().unwrap();
+        assert_eq!(&bytes[4..6], &[0x00, WITNESS_FLAG]);
+        assert_eq!(segwit.txid(), legacy.txid());
+        assert_ne!(segwit.wtxid(), legacy.wtxid());
+    }
+
+    #[test]
+    fn test_invalid_extended_format() {
+        let mut bytes = transaction(vec![vec![1]]).to_bytes().unwrap();
+        bytes[5] = 0x02;
+        assert!(matches!(
+            Transaction::from_bytes(&bytes),
+            Err(BTCP2PError::InvalidTransaction)
+        ));
+
+        // the flag is set but no input has witness data
+        let mut bytes = transaction(vec![vec![1]]).to_bytes().unwrap();
+        let len = bytes.len();
+        bytes.splice(len - 7..len - 4, [0x00]);
+        assert!(matches!(
+            Transaction::from_bytes(&bytes),
+            Err(BTCP2PError::InvalidTransaction)
+        ));
+    }
+}